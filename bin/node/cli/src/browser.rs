@@ -63,7 +63,11 @@ async fn start_inner(
 			.map(|(components, rpc_handlers, _, _, _, _)| (components, rpc_handlers))
 			.map_err(|e| format!("{:?}", e))?;
 
-	task_manager.spawn_handle().spawn("telemetry", telemetry_worker.run());
+	task_manager.spawn_handle().spawn("telemetry", async move {
+		if let Err(err) = telemetry_worker.run().await {
+			log::warn!("Telemetry worker stopped: {}", err);
+		}
+	});
 
 	Ok(browser_utils::start_client(task_manager, rpc_handlers))
 }
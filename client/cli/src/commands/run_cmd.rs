@@ -30,7 +30,8 @@ use sc_service::{
 	config::{BasePath, MultiaddrWithPeerId, PrometheusConfig, TransactionPoolOptions},
 	ChainSpec, Role,
 };
-use sc_telemetry::TelemetryEndpoints;
+use sc_telemetry::{TelemetryEndpoint, TelemetryEndpoints};
+use std::convert::TryFrom;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use structopt::StructOpt;
 
@@ -176,8 +177,8 @@ pub struct RunCmd {
 	/// telemetry endpoints. Verbosity levels range from 0-9, with 0 denoting
 	/// the least verbosity.
 	/// Expected format is 'URL VERBOSITY', e.g. `--telemetry-url 'wss://foo/bar 0'`.
-	#[structopt(long = "telemetry-url", value_name = "URL VERBOSITY", parse(try_from_str = parse_telemetry_endpoints))]
-	pub telemetry_endpoints: Vec<(String, u8)>,
+	#[structopt(long = "telemetry-url", value_name = "URL VERBOSITY")]
+	pub telemetry_endpoints: Vec<TelemetryEndpoint>,
 
 	#[allow(missing_docs)]
 	#[structopt(flatten)]
@@ -350,8 +351,8 @@ impl CliConfiguration for RunCmd {
 			None
 		} else if !self.telemetry_endpoints.is_empty() {
 			Some(
-				TelemetryEndpoints::new(self.telemetry_endpoints.clone())
-					.map_err(|e| e.to_string())?,
+				TelemetryEndpoints::try_from(self.telemetry_endpoints.clone())
+					.map_err(|e| Error::Input(e.to_string()))?,
 			)
 		} else {
 			chain_spec.telemetry_endpoints().clone()
@@ -530,37 +531,6 @@ fn rpc_interface(
 	}
 }
 
-#[derive(Debug)]
-enum TelemetryParsingError {
-	MissingVerbosity,
-	VerbosityParsingError(std::num::ParseIntError),
-}
-
-impl std::error::Error for TelemetryParsingError {}
-
-impl std::fmt::Display for TelemetryParsingError {
-	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-		match &*self {
-			TelemetryParsingError::MissingVerbosity => write!(f, "Verbosity level missing"),
-			TelemetryParsingError::VerbosityParsingError(e) => write!(f, "{}", e),
-		}
-	}
-}
-
-fn parse_telemetry_endpoints(s: &str) -> std::result::Result<(String, u8), TelemetryParsingError> {
-	let pos = s.find(' ');
-	match pos {
-		None => Err(TelemetryParsingError::MissingVerbosity),
-		Some(pos_) => {
-			let url = s[..pos_].to_string();
-			let verbosity = s[pos_ + 1..]
-				.parse()
-				.map_err(TelemetryParsingError::VerbosityParsingError)?;
-			Ok((url, verbosity))
-		}
-	}
-}
-
 /// CORS setting
 ///
 /// The type is introduced to overcome `Option<Option<T>>`
@@ -623,4 +593,45 @@ mod tests {
 		assert!(is_node_name_valid("www.visit.me").is_err());
 		assert!(is_node_name_valid("email@domain").is_err());
 	}
+
+	#[test]
+	fn telemetry_urls_above_the_maximum_are_rejected() {
+		use sc_service::{ChainType, GenericChainSpec, NoExtension};
+
+		let chain_spec: Box<dyn ChainSpec> = Box::new(GenericChainSpec::from_genesis(
+			"test",
+			"test_id",
+			ChainType::Development,
+			|| unimplemented!("Not required in tests"),
+			Vec::new(),
+			None,
+			None,
+			None,
+			NoExtension::None,
+		));
+		let urls = (0..=sc_telemetry::MAX_TELEMETRY_ENDPOINTS)
+			.map(|i| format!("wss://telemetry-{}.polkadot.io/submit/", i))
+			.collect::<Vec<_>>();
+		let args = |count: usize| {
+			let mut args = vec!["substrate".to_string()];
+			for url in &urls[..count] {
+				args.push("--telemetry-url".into());
+				args.push(format!("{} 0", url));
+			}
+			args
+		};
+
+		let cmd = RunCmd::from_iter(args(urls.len()));
+		assert!(matches!(cmd.telemetry_endpoints(&chain_spec), Err(Error::Input(_))));
+
+		let cmd = RunCmd::from_iter(args(urls.len() - 1));
+		let expected = urls[..urls.len() - 1]
+			.iter()
+			.map(|url| (url.clone(), 0))
+			.collect();
+		assert_eq!(
+			cmd.telemetry_endpoints(&chain_spec).unwrap(),
+			Some(TelemetryEndpoints::new(expected).unwrap()),
+		);
+	}
 }
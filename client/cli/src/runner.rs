@@ -197,7 +197,12 @@ impl<C: SubstrateCli> Runner<C> {
 	{
 		self.print_node_infos();
 		let mut task_manager = self.tokio_runtime.block_on(initialize(self.config))?;
-		task_manager.spawn_handle().spawn("telemetry_worker", self.telemetry_worker.run());
+		let telemetry_worker = self.telemetry_worker.run().map(|result| {
+			if let Err(err) = result {
+				log::warn!("Telemetry worker stopped: {}", err);
+			}
+		});
+		task_manager.spawn_handle().spawn("telemetry_worker", telemetry_worker);
 		let res = self.tokio_runtime.block_on(main(task_manager.future().fuse()));
 		self.tokio_runtime.block_on(task_manager.clean_shutdown());
 		Ok(res?)
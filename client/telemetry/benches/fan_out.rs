@@ -0,0 +1,164 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Cost of dispatching a telemetry message to several telemetry servers.
+//!
+//! Besides the timings, the number of allocations per message is printed: it must not grow with
+//! the number of endpoints, as they all share the same message.
+//!
+//! The cost of a burst of messages is measured with the worker dispatching them one at a time
+//! and with the default [`TelemetryConfig::max_messages_per_wakeup`].
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use futures::{executor::LocalPool, task::LocalSpawnExt, FutureExt};
+use sc_telemetry::{
+	telemetry, ConnectionMessage, TelemetryConfig, TelemetryEndpoints, TelemetryLayer,
+	TelemetrySpan, SUBSTRATE_INFO,
+};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing_subscriber::prelude::*;
+
+const ENDPOINTS: u16 = 5;
+const MESSAGES: usize = 1_000;
+const BURST_ENDPOINTS: u16 = 3;
+const BURST_MESSAGES: usize = 10_000;
+
+/// Allocator counting the allocations of the process.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+		System.alloc(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		System.dealloc(ptr, layout)
+	}
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn connection_message() -> ConnectionMessage {
+	ConnectionMessage {
+		name: "bench".into(),
+		implementation: "bench".into(),
+		version: "0.1.0".into(),
+		config: "".into(),
+		chain: "bench".into(),
+		genesis_hash: "0x00".into(),
+		authority: false,
+		startup_time: "0".into(),
+		network_id: "bench".into(),
+	}
+}
+
+fn fan_out(c: &mut Criterion) {
+	let (layer, worker) = TelemetryLayer::new(None, None).unwrap();
+	let mut handle = worker.handle();
+	let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+	let mut pool = LocalPool::new();
+	pool.spawner().spawn_local(worker.run().map(|_| ())).unwrap();
+
+	// Nothing listens on these ports: the messages are fanned out to the nodes, which drop them
+	// while they are not connected.
+	let endpoints = (0..ENDPOINTS)
+		.map(|i| (format!("/ip4/127.0.0.1/tcp/{}/ws", 9 + i), SUBSTRATE_INFO))
+		.collect();
+	let span = tracing::dispatcher::with_default(&dispatch, || {
+		let span = TelemetrySpan::new();
+		let endpoints = TelemetryEndpoints::new(endpoints).unwrap();
+		handle.start_telemetry(span.clone(), endpoints, connection_message());
+		span
+	});
+	pool.run_until_stalled();
+
+	let mut send = |messages: usize| {
+		for i in 0..messages {
+			tracing::dispatcher::with_default(&dispatch, || {
+				let _enter = span.enter();
+				telemetry!(SUBSTRATE_INFO; "bench.message"; "index" => i);
+			});
+			pool.run_until_stalled();
+		}
+	};
+
+	send(MESSAGES);
+	let before = ALLOCATIONS.load(Ordering::Relaxed);
+	send(MESSAGES);
+	let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+	println!(
+		"{:.1} allocations per message sent to {} endpoints",
+		allocations as f64 / MESSAGES as f64,
+		ENDPOINTS,
+	);
+
+	c.bench_function("fan_out_to_5_endpoints", |b| b.iter(|| send(1)));
+}
+
+fn burst(c: &mut Criterion) {
+	let mut group = c.benchmark_group("burst_of_10k_messages_to_3_endpoints");
+	group.sample_size(10);
+
+	for &per_wakeup in &[1, TelemetryConfig::default().max_messages_per_wakeup] {
+		let config = TelemetryConfig {
+			buffer_size: BURST_MESSAGES,
+			max_messages_per_wakeup: per_wakeup,
+			..Default::default()
+		};
+		let (layer, worker) = TelemetryLayer::with_config(config, None).unwrap();
+		let mut handle = worker.handle();
+		let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(|_| ())).unwrap();
+
+		let endpoints = (0..BURST_ENDPOINTS)
+			.map(|i| (format!("/ip4/127.0.0.1/tcp/{}/ws", 9 + i), SUBSTRATE_INFO))
+			.collect();
+		let span = tracing::dispatcher::with_default(&dispatch, || {
+			let span = TelemetrySpan::new();
+			let endpoints = TelemetryEndpoints::new(endpoints).unwrap();
+			handle.start_telemetry(span.clone(), endpoints, connection_message());
+			span
+		});
+		pool.run_until_stalled();
+
+		// The whole burst is queued before the worker wakes up.
+		group.bench_function(format!("{}_per_wakeup", per_wakeup), |b| {
+			b.iter(|| {
+				tracing::dispatcher::with_default(&dispatch, || {
+					let _enter = span.enter();
+					for i in 0..BURST_MESSAGES {
+						telemetry!(SUBSTRATE_INFO; "bench.message"; "index" => i);
+					}
+				});
+				pool.run_until_stalled();
+			})
+		});
+	}
+
+	group.finish();
+}
+
+criterion_group!(benches, fan_out, burst);
+criterion_main!(benches);
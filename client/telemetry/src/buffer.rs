@@ -0,0 +1,452 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::StatsCounters;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
+use wasm_timer::Instant;
+
+/// Identifies a message accounted in a [`BufferBudget`]: when it has been queued, along with the
+/// order in which it has been accounted.
+type Key = (Instant, u64);
+
+/// Messages accounted in a [`BufferBudget`], shared by all the queues of the budget.
+#[derive(Debug, Default)]
+struct Accounting {
+	/// Number of bytes of the messages.
+	used: usize,
+	/// Identifier of the next queue.
+	next_queue: u64,
+	/// Sequence number of the next message.
+	next_seq: u64,
+	/// Every message, along with the queue that holds it, from the oldest to the newest.
+	messages: BTreeMap<Key, (u64, Arc<str>)>,
+	/// Number of messages evicted from each queue that the queue hasn't noticed yet.
+	evicted: HashMap<u64, usize>,
+}
+
+impl Accounting {
+	/// Account for `item`, queued at `queued_at` in `queue`, evicting the oldest messages of all
+	/// the queues until the total is at most `max` bytes.
+	///
+	/// Returns `None` if `item` doesn't fit, i.e. if it is larger than `max` or older than all
+	/// the messages that are left.
+	fn insert(
+		&mut self,
+		queue: u64,
+		item: Arc<str>,
+		queued_at: Instant,
+		max: usize,
+	) -> Option<Key> {
+		if item.len() > max {
+			return None;
+		}
+
+		let key = (queued_at, self.next_seq);
+		self.next_seq += 1;
+		while self.used + item.len() > max {
+			let oldest = *self.messages.keys().next().filter(|oldest| **oldest < key)?;
+			if let Some((owner, _)) = self.remove(&oldest) {
+				*self.evicted.entry(owner).or_default() += 1;
+			}
+		}
+
+		self.used += item.len();
+		self.messages.insert(key, (queue, item));
+		Some(key)
+	}
+
+	/// Stop accounting for the message `key`, if it hasn't been evicted.
+	fn remove(&mut self, key: &Key) -> Option<(u64, Arc<str>)> {
+		let (queue, item) = self.messages.remove(key)?;
+		self.used -= item.len();
+		Some((queue, item))
+	}
+
+	/// Remove the messages of `queue` that have been evicted from its `items`, whose keys are
+	/// given by `key`.
+	///
+	/// Returns the number of messages that have been removed.
+	fn sync<T>(&mut self, queue: u64, items: &mut VecDeque<T>, key: impl Fn(&T) -> &Key) -> usize {
+		let evicted = self.evicted.remove(&queue).unwrap_or(0);
+		if evicted > 0 {
+			let messages = &self.messages;
+			items.retain(|item| messages.contains_key(key(item)));
+		}
+		evicted
+	}
+
+	/// Number of messages of `queue` that have been evicted since it has last been synced.
+	fn pending_evictions(&self, queue: u64) -> usize {
+		self.evicted.get(&queue).copied().unwrap_or(0)
+	}
+
+	/// Stop accounting for all the messages of `queue`.
+	fn remove_queue(&mut self, queue: u64, keys: impl Iterator<Item = Key>) {
+		for key in keys {
+			self.remove(&key);
+		}
+		self.evicted.remove(&queue);
+	}
+}
+
+/// Accounting of the approximate number of bytes held in the buffers of the telemetry worker.
+///
+/// The budget is shared by all the queues created with it: when it is exhausted, the oldest
+/// message of any of these queues is evicted to make room for a new one, so that a stalled
+/// telemetry server can't hold the whole budget. The queues report the messages they lost this
+/// way in `take_evicted`.
+///
+/// This object is cheap to clone: all the clones share the same messages and the same limit.
+#[derive(Debug, Clone)]
+pub(crate) struct BufferBudget {
+	accounting: Arc<Mutex<Accounting>>,
+	max: usize,
+	stats: Arc<StatsCounters>,
+}
+
+impl BufferBudget {
+	/// Create a new [`BufferBudget`] allowing up to `max` bytes to be buffered, whose usage is
+	/// reported in `stats`.
+	pub(crate) fn new(max: usize, stats: Arc<StatsCounters>) -> Self {
+		Self {
+			accounting: Default::default(),
+			max,
+			stats,
+		}
+	}
+
+	/// Number of bytes currently buffered.
+	pub(crate) fn used(&self) -> usize {
+		self.stats.buffered_bytes.load(Ordering::Relaxed)
+	}
+
+	/// Identifier of a new queue.
+	fn new_queue(&self) -> u64 {
+		self.update(|accounting| {
+			accounting.next_queue += 1;
+			accounting.next_queue
+		})
+	}
+
+	/// Apply `f` to the accounting, then report the usage in the stats.
+	fn update<R>(&self, f: impl FnOnce(&mut Accounting) -> R) -> R {
+		let mut accounting = self.accounting.lock();
+		let result = f(&mut accounting);
+		self.stats.buffered_bytes.store(accounting.used, Ordering::Relaxed);
+		result
+	}
+}
+
+/// FIFO queue of serialized messages whose size is accounted in a [`BufferBudget`].
+///
+/// The messages are shared with the other queues they have been pushed to, but their size is
+/// accounted in every queue. When the budget is exhausted, the oldest messages of all the queues
+/// of the budget are evicted to make room for the new ones. The accounted bytes are released
+/// when the queue is dropped.
+///
+/// Every message is queued along with a timestamp, by default the time at which it has been
+/// queued, so that the stale ones can be discarded without parsing them.
+#[derive(Debug)]
+pub(crate) struct BudgetedQueue {
+	id: u64,
+	keys: VecDeque<Key>,
+	/// Number of messages evicted by the budget that have not been taken yet.
+	evicted: usize,
+	budget: BufferBudget,
+}
+
+impl BudgetedQueue {
+	/// Create a new empty queue accounted in the given `budget`.
+	pub(crate) fn new(budget: BufferBudget) -> Self {
+		Self {
+			id: budget.new_queue(),
+			keys: VecDeque::new(),
+			evicted: 0,
+			budget,
+		}
+	}
+
+	/// Push a message at the back of the queue.
+	///
+	/// Returns the number of messages of this queue that have been dropped in order to stay within
+	/// the budget, as [`BudgetedQueue::take_evicted`]. This includes `item` itself if it can't fit
+	/// in the budget.
+	pub(crate) fn push(&mut self, item: Arc<str>) -> usize {
+		self.push_queued_at(item, Instant::now())
+	}
+
+	/// Same as [`BudgetedQueue::push`] for a message that has been queued at `queued_at`, e.g. in
+	/// another queue.
+	pub(crate) fn push_queued_at(&mut self, item: Arc<str>, queued_at: Instant) -> usize {
+		let (id, max) = (self.id, self.budget.max);
+		let key = self.budget.update(|accounting| accounting.insert(id, item, queued_at, max));
+		let dropped = self.take_evicted();
+		match key {
+			Some(key) => {
+				self.keys.push_back(key);
+				dropped
+			}
+			None => dropped + 1,
+		}
+	}
+
+	/// Take the number of messages of this queue that have been evicted to make room for newer
+	/// messages, in this queue or in another queue of the budget, since the last call.
+	pub(crate) fn take_evicted(&mut self) -> usize {
+		self.sync();
+		std::mem::take(&mut self.evicted)
+	}
+
+	/// Remove the messages that have been evicted by the budget.
+	fn sync(&mut self) {
+		let (id, keys) = (self.id, &mut self.keys);
+		self.evicted += self.budget.update(|accounting| accounting.sync(id, keys, |key| key));
+	}
+
+	/// Number of messages in the queue.
+	pub(crate) fn len(&self) -> usize {
+		self.keys.len() - self.budget.accounting.lock().pending_evictions(self.id)
+	}
+
+	/// Number of lines of the messages in the queue, i.e. the number of telemetry messages if
+	/// the queue holds batches.
+	pub(crate) fn lines(&self) -> usize {
+		let accounting = self.budget.accounting.lock();
+		self.keys
+			.iter()
+			.filter_map(|key| accounting.messages.get(key))
+			.map(|(_, item)| 1 + item.matches('\n').count())
+			.sum()
+	}
+
+	/// Return `true` if the queue is empty.
+	pub(crate) fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Return when the oldest message of the queue has been queued.
+	pub(crate) fn oldest(&self) -> Option<Instant> {
+		let accounting = self.budget.accounting.lock();
+		self.keys
+			.iter()
+			.find(|key| accounting.messages.contains_key(key))
+			.map(|(queued_at, _)| *queued_at)
+	}
+
+	/// Pop the oldest message of the queue.
+	pub(crate) fn pop(&mut self) -> Option<Arc<str>> {
+		let (id, keys, evicted) = (self.id, &mut self.keys, &mut self.evicted);
+		self.budget.update(|accounting| {
+			*evicted += accounting.sync(id, keys, |key| key);
+			let key = keys.pop_front()?;
+			accounting.remove(&key).map(|(_, item)| item)
+		})
+	}
+
+	/// Pop the oldest message of the queue if it has been queued more than `max_age` ago.
+	pub(crate) fn pop_expired(&mut self, max_age: Duration) -> Option<Arc<str>> {
+		match self.oldest() {
+			Some(queued_at) if queued_at.elapsed() > max_age => self.pop(),
+			_ => None,
+		}
+	}
+}
+
+impl Drop for BudgetedQueue {
+	fn drop(&mut self) {
+		let (id, keys) = (self.id, self.keys.drain(..));
+		self.budget.update(|accounting| accounting.remove_queue(id, keys));
+	}
+}
+
+/// FIFO queue of at most `capacity` messages of a known verbosity, accounted in a
+/// [`BufferBudget`].
+///
+/// When the queue is full, the oldest message of verbosity above 0 is evicted to make room for a
+/// new one. The messages of verbosity 0 are only evicted by other messages of verbosity 0. When
+/// the budget is exhausted, the oldest messages of all the queues of the budget are evicted
+/// whatever their verbosity.
+#[derive(Debug)]
+pub(crate) struct RetentionQueue {
+	id: u64,
+	items: VecDeque<(u8, Key)>,
+	capacity: usize,
+	/// Number of messages evicted by the budget that have not been taken yet.
+	evicted: usize,
+	budget: BufferBudget,
+}
+
+impl RetentionQueue {
+	/// Create a new empty queue of at most `capacity` messages accounted in the given `budget`.
+	pub(crate) fn new(capacity: usize, budget: BufferBudget) -> Self {
+		Self {
+			id: budget.new_queue(),
+			items: VecDeque::new(),
+			capacity,
+			evicted: 0,
+			budget,
+		}
+	}
+
+	/// Push a message of the given `verbosity`, queued at `queued_at`, at the back of the queue.
+	///
+	/// Returns the number of messages that have been dropped in order to make room for it, as
+	/// [`RetentionQueue::take_evicted`], including `item` itself if it has been dropped instead.
+	pub(crate) fn push(&mut self, verbosity: u8, item: Arc<str>, queued_at: Instant) -> usize {
+		let (id, items, capacity, max) = (self.id, &mut self.items, self.capacity, self.budget.max);
+		let mut dropped = std::mem::take(&mut self.evicted);
+
+		let key = self.budget.update(|accounting| {
+			dropped += accounting.sync(id, items, |(_, key)| key);
+			while items.len() >= capacity {
+				let evicted = items
+					.iter()
+					.position(|(verbosity, _)| *verbosity > 0)
+					.or_else(|| Some(0).filter(|_| verbosity == 0 && !items.is_empty()));
+				let (_, key) = evicted.and_then(|index| items.remove(index))?;
+				accounting.remove(&key);
+				dropped += 1;
+			}
+			accounting.insert(id, item, queued_at, max)
+		});
+
+		dropped += self.take_evicted();
+		match key {
+			Some(key) => {
+				self.items.push_back((verbosity, key));
+				dropped
+			}
+			None => dropped + 1,
+		}
+	}
+
+	/// Take the number of messages of this queue that have been evicted to make room for newer
+	/// messages of the other queues of the budget since the last call.
+	pub(crate) fn take_evicted(&mut self) -> usize {
+		let (id, items) = (self.id, &mut self.items);
+		let evicted = self.budget.update(|accounting| accounting.sync(id, items, |(_, key)| key));
+		std::mem::take(&mut self.evicted) + evicted
+	}
+
+	/// Number of messages in the queue.
+	pub(crate) fn len(&self) -> usize {
+		self.items.len() - self.budget.accounting.lock().pending_evictions(self.id)
+	}
+
+	/// Pop the oldest message of the queue, along with when it has been queued.
+	pub(crate) fn pop(&mut self) -> Option<(Instant, Arc<str>)> {
+		let (id, items, evicted) = (self.id, &mut self.items, &mut self.evicted);
+		self.budget.update(|accounting| {
+			*evicted += accounting.sync(id, items, |(_, key)| key);
+			let (_, key) = items.pop_front()?;
+			accounting.remove(&key).map(|(_, item)| (key.0, item))
+		})
+	}
+
+	/// Remove every message of the queue.
+	pub(crate) fn clear(&mut self) {
+		let (id, keys) = (self.id, self.items.drain(..).map(|(_, key)| key));
+		self.budget.update(|accounting| accounting.remove_queue(id, keys));
+		self.evicted = 0;
+	}
+}
+
+impl Drop for RetentionQueue {
+	fn drop(&mut self) {
+		self.clear();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn eviction_keeps_usage_under_the_limit() {
+		let item = |i: u8, len: usize| Arc::from(i.to_string().repeat(len));
+		let stats = Arc::new(StatsCounters::default());
+		let budget = BufferBudget::new(100, stats.clone());
+		let mut first = BudgetedQueue::new(budget.clone());
+		let mut second = BudgetedQueue::new(budget.clone());
+
+		for i in 0..10u8 {
+			assert_eq!(first.push(item(i, 10)), 0);
+		}
+		assert_eq!(budget.used(), 100);
+
+		// The second queue makes room by evicting the oldest message of the first queue.
+		assert_eq!(second.push(item(0, 10)), 0);
+		assert_eq!(budget.used(), 100);
+		assert_eq!(first.len(), 9);
+		assert_eq!(first.take_evicted(), 1);
+		assert_eq!(first.take_evicted(), 0);
+
+		// Pushing in the first queue evicts the oldest messages, its own ones here.
+		assert_eq!(first.push(item(4, 25)), 3);
+		assert!(budget.used() <= 100);
+		assert_eq!(first.pop(), Some(item(4, 10)));
+
+		drop(first);
+		assert_eq!(budget.used(), 10);
+
+		assert_eq!(second.push(item(0, 60)), 0);
+		assert_eq!(second.push(item(0, 60)), 2);
+		assert_eq!(budget.used(), 60);
+		assert_eq!(stats.snapshot().buffered_bytes, 60);
+
+		// A message older than all the others is dropped instead of evicting newer ones.
+		let older = Instant::now() - Duration::from_secs(1);
+		assert_eq!(second.push_queued_at(item(0, 50), older), 1);
+		assert_eq!(second.len(), 1);
+		assert_eq!(second.push(item(0, 101)), 1);
+		assert_eq!(budget.used(), 60);
+	}
+
+	#[test]
+	fn retention_keeps_the_messages_of_verbosity_zero() {
+		let budget = BufferBudget::new(100, Default::default());
+		let mut queue = RetentionQueue::new(3, budget.clone());
+		let now = Instant::now();
+		let mut push = |verbosity, item: &str| queue.push(verbosity, item.into(), now);
+
+		assert_eq!(push(1, "a"), 0);
+		assert_eq!(push(0, "b"), 0);
+		assert_eq!(push(1, "c"), 0);
+		// The oldest message above verbosity 0 makes room.
+		assert_eq!(push(0, "d"), 1);
+		assert_eq!(push(1, "e"), 1);
+		// Only the messages of verbosity 0 are left.
+		assert_eq!(push(0, "f"), 1);
+		assert_eq!(push(1, "g"), 1);
+		// The budget applies too.
+		assert_eq!(push(0, &"h".repeat(99)), 2);
+
+		let items = std::iter::from_fn(|| queue.pop())
+			.map(|(_, item)| item.to_string())
+			.collect::<Vec<_>>();
+		assert_eq!(items, vec!["f".to_string(), "h".repeat(99)]);
+		assert_eq!(budget.used(), 0);
+
+		let mut disabled = RetentionQueue::new(0, budget);
+		assert_eq!(disabled.push(0, "a".into(), now), 1);
+		assert_eq!(disabled.len(), 0);
+	}
+}
@@ -0,0 +1,304 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{CertificatePin, TelemetryProxy};
+use libp2p::Multiaddr;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+/// Configuration of the [`TelemetryLayer`](crate::TelemetryLayer) and of its
+/// [`TelemetryWorker`](crate::TelemetryWorker).
+#[derive(Debug, Clone)]
+pub struct TelemetryConfig {
+	/// Number of telemetry messages that can be queued between the layer and the worker.
+	///
+	/// Defaults to 16.
+	pub buffer_size: usize,
+	/// Number of telemetry messages up to the [`SUBSTRATE_INFO`](crate::SUBSTRATE_INFO)
+	/// verbosity that can be queued between the layer and the worker once the main queue is
+	/// full, so that the most important telemetry gets through when it is flooded by verbose
+	/// messages. The worker receives these messages first.
+	///
+	/// Defaults to 4.
+	pub priority_buffer_size: usize,
+	/// Maximum number of queued telemetry messages that the worker dispatches at once, before
+	/// it flushes the connections to the telemetry servers and looks for new registrations.
+	///
+	/// Defaults to 64.
+	pub max_messages_per_wakeup: usize,
+	/// What the [`TelemetryLayer`](crate::TelemetryLayer) does with a message when the queue to
+	/// the worker is full.
+	///
+	/// Defaults to [`OverflowPolicy::DropNewest`].
+	pub overflow_policy: OverflowPolicy,
+	/// Maximum time a thread waits for room in the queue to the worker with
+	/// [`OverflowPolicy::Block`]. The message is dropped afterwards.
+	///
+	/// Defaults to 1 second.
+	pub overflow_block_timeout: Duration,
+	/// Format of the `ts` field of the telemetry messages.
+	///
+	/// Defaults to [`TimestampFormat::Local`].
+	pub timestamp_format: TimestampFormat,
+	/// Maximum number of bytes held across all the buffers of the worker. The oldest buffered
+	/// messages are evicted when this limit is reached, whichever telemetry server they are for.
+	/// The usage is reported in [`TelemetryStats::buffered_bytes`](crate::TelemetryStats).
+	///
+	/// Defaults to 16 MiB.
+	pub max_buffered_bytes: usize,
+	/// Maximum number of telemetry messages per second that each telemetry span can send. The
+	/// excess is dropped.
+	///
+	/// Defaults to `None`, which means unlimited.
+	pub max_messages_per_second: Option<u32>,
+	/// Number of messages that a telemetry span can send at once before
+	/// [`TelemetryConfig::max_messages_per_second`] applies.
+	///
+	/// Defaults to `None`, which means as many as the messages allowed per second.
+	pub rate_limit_burst: Option<u32>,
+	/// Highest verbosity of the messages that are never rate limited, so that the most important
+	/// telemetry always gets through.
+	///
+	/// Defaults to [`SUBSTRATE_INFO`](crate::SUBSTRATE_INFO). `None` rate limits every message.
+	pub rate_limit_exempt_verbosity: Option<u8>,
+	/// Verbosity applied to every endpoint right after its connection (re-)establishes, for the
+	/// duration of [`TelemetryConfig::connect_verbosity_window`]. It has no effect on the
+	/// endpoints whose verbosity is already higher.
+	///
+	/// This allows the telemetry servers to get a detailed state quickly without sustaining a high
+	/// volume of messages. Defaults to `None`.
+	pub connect_verbosity: Option<u8>,
+	/// Duration of the elevated [`TelemetryConfig::connect_verbosity`] after each (re-)connection.
+	///
+	/// Defaults to 30 seconds.
+	pub connect_verbosity_window: Duration,
+	/// Batching of the messages sent to each telemetry server.
+	///
+	/// Defaults to `None`: every message is sent in its own WebSocket frame.
+	pub batch: Option<BatchConfig>,
+	/// Maximum number of messages queued for each telemetry server while they wait to be written
+	/// to the connection. A batch counts as one message.
+	///
+	/// Defaults to 2048.
+	pub node_queue_capacity: usize,
+	/// What to do with a new message when the queue of a telemetry server is full.
+	///
+	/// Defaults to [`QueuePolicy::DropOldest`].
+	pub node_queue_policy: QueuePolicy,
+	/// Maximum number of messages held for each telemetry server while it is not connected.
+	///
+	/// They are sent after the connection messages once it connects, unless they are older than
+	/// [`TelemetryConfig::max_message_age`] by then. When the buffer is full, the oldest messages
+	/// are dropped first, those of verbosity 0 last. This doesn't apply to the endpoints that
+	/// spill to the [`TelemetryConfig::spill_dir`], and zero disables the buffer.
+	///
+	/// Defaults to 1000.
+	pub disconnected_buffer_size: usize,
+	/// Negotiate the permessage-deflate extension (RFC 7692) with the telemetry servers.
+	///
+	/// The messages are sent uncompressed to the servers that don't support the extension.
+	/// Defaults to `false`.
+	pub websocket_deflate: bool,
+	/// Proxy through which the telemetry servers are reached. It doesn't apply to the external
+	/// transport used in the browser.
+	///
+	/// Overridden by the [`PROXY_ENV_VAR`](crate::PROXY_ENV_VAR) environment variable. Defaults
+	/// to `None`.
+	pub proxy: Option<TelemetryProxy>,
+	/// Expected certificates of secure WebSocket telemetry servers, indexed by the address of the
+	/// endpoint.
+	///
+	/// The connection to a pinned server fails unless it presents a certificate with this
+	/// fingerprint. The certificate authorities are not checked, so a pinned server can use a
+	/// self-signed certificate or an IP address. It doesn't apply to the external transport used
+	/// in the browser. Defaults to no pins.
+	pub certificate_pins: HashMap<Multiaddr, CertificatePin>,
+	/// Time after which a connection attempt to a telemetry server is abandoned, including the
+	/// WebSocket handshake. It applies to the endpoints without a connect timeout of their own.
+	///
+	/// Defaults to 20 seconds.
+	pub connect_timeout: Duration,
+	/// Maximum delay before a new connection attempt to a telemetry server. The delay starts at
+	/// 1 second and doubles after every failed attempt up to this maximum, with 20% of random
+	/// jitter, until a connection is established.
+	///
+	/// Defaults to 60 seconds.
+	pub max_reconnect_delay: Duration,
+	/// Time after which the telemetry of a failover group switches from a disconnected endpoint
+	/// to the next one. See [`TelemetryEndpoints::set_group`](crate::TelemetryEndpoints::set_group).
+	///
+	/// Defaults to 30 seconds.
+	pub failover_delay: Duration,
+	/// Maximum time taken by [`ShutdownHandle::shutdown`](crate::ShutdownHandle::shutdown) to
+	/// send the remaining telemetry before the worker stops.
+	///
+	/// Defaults to 5 seconds.
+	pub shutdown_timeout: Duration,
+	/// Directory where the telemetry that can't be delivered is persisted, one file per
+	/// endpoint.
+	///
+	/// The messages sent to a disconnected telemetry server, and those that have not been sent
+	/// when its connection is lost or when the worker shuts down, are written to the file of the
+	/// endpoint instead of being discarded. They are replayed once the endpoint connects, even
+	/// after a restart, unless they are older than [`TelemetryConfig::spill_max_age`]. Not
+	/// supported in the browser. Defaults to `None`.
+	pub spill_dir: Option<PathBuf>,
+	/// Number of undelivered messages held in memory before they are written to the
+	/// [`TelemetryConfig::spill_dir`].
+	///
+	/// Defaults to 64.
+	pub spill_threshold: usize,
+	/// Age after which the messages of the [`TelemetryConfig::spill_dir`] are not replayed.
+	///
+	/// Defaults to 1 hour.
+	pub spill_max_age: Duration,
+	/// Time without any message written to a telemetry server after which a small
+	/// `system.ping` message is sent to keep the connection alive.
+	///
+	/// A connection that doesn't accept any message for this long is considered dead and is
+	/// re-established right away, instead of holding the telemetry until a write fails. Defaults
+	/// to zero, which disables the keepalive.
+	pub keepalive_interval: Duration,
+	/// Age after which a message that is still queued for a telemetry server is discarded instead
+	/// of being sent late, e.g. because the connection is slow.
+	///
+	/// The age of a message counts from when it has been created. The messages of a batch are as
+	/// old as its oldest message. The spilled messages are replayed
+	/// according to [`TelemetryConfig::spill_max_age`] instead. Defaults to 30 seconds. `None`
+	/// keeps the messages until they are sent.
+	pub max_message_age: Option<Duration>,
+	/// Minimum time between two `fsync` of the file of a `file://` telemetry endpoint.
+	///
+	/// The messages are written to the file as they come, and synced to the disk at most once
+	/// per interval, as well as when the file is closed or reopened. Defaults to 1 second.
+	pub file_sync_interval: Duration,
+	/// Time taken by the worker to dispatch a telemetry message to the telemetry servers and
+	/// the sinks after which a warning is logged, along with the verbosity and the name of the
+	/// message. The distribution of these times is available through
+	/// [`TelemetryHandle::processing_times`](crate::TelemetryHandle::processing_times).
+	///
+	/// Defaults to 200 milliseconds. `None` disables the warning.
+	pub slow_message_threshold: Option<Duration>,
+}
+
+/// Batching of the telemetry messages sent to a telemetry server.
+///
+/// The messages are sent in a single newline-delimited frame once the first message of the batch
+/// is older than `window` or once the batch contains `max_messages` messages.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchConfig {
+	/// Duration after which a batch is sent even if it isn't full.
+	pub window: Duration,
+	/// Maximum number of messages in a batch.
+	pub max_messages: usize,
+}
+
+impl Default for BatchConfig {
+	fn default() -> Self {
+		Self {
+			window: Duration::from_millis(50),
+			max_messages: 64,
+		}
+	}
+}
+
+/// What to do with a new message when a queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+	/// Drop the oldest message of the queue to make room for the new one.
+	DropOldest,
+	/// Drop the new message.
+	DropNewest,
+	/// Wait until there is room in the queue.
+	///
+	/// This stalls the delivery of the messages to all the telemetry servers until then.
+	Block,
+}
+
+/// What the [`TelemetryLayer`](crate::TelemetryLayer) does with a telemetry message when the
+/// queue to the [`TelemetryWorker`](crate::TelemetryWorker) is full.
+///
+/// The dropped messages are reported with a [`TelemetryEvent::Overflow`](crate::TelemetryEvent)
+/// and counted in
+/// [`TelemetryHandle::overflowed_messages`](crate::TelemetryHandle::overflowed_messages).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+	/// Drop the new message.
+	DropNewest,
+	/// Drop the oldest message of the queue to make room for the new one, so that the queue
+	/// always holds the latest telemetry.
+	DropOldest,
+	/// Block the thread that logs the message until there is room in the queue, for at most
+	/// [`TelemetryConfig::overflow_block_timeout`]. The message is dropped as with
+	/// [`OverflowPolicy::DropNewest`] if the queue is still full by then, so that a thread that
+	/// the worker depends on can't deadlock.
+	///
+	/// The worker must run on a thread of its own, started with
+	/// [`TelemetryWorker::spawn_thread`](crate::TelemetryWorker::spawn_thread): a thread of an
+	/// async runtime must not be parked while the tasks it runs, the worker among them, wait.
+	/// Otherwise, and in the browser where the thread can't be blocked, this behaves like
+	/// [`OverflowPolicy::DropNewest`].
+	Block,
+}
+
+/// Format of the time at which a telemetry message has been logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampFormat {
+	/// RFC 3339 string in the local time zone, e.g. `2021-02-03T14:05:06.789+01:00`.
+	Local,
+	/// RFC 3339 string in UTC, e.g. `2021-02-03T13:05:06.789+00:00`.
+	Utc,
+	/// Number of milliseconds since the Unix epoch.
+	UnixMillis,
+}
+
+impl Default for TelemetryConfig {
+	fn default() -> Self {
+		Self {
+			buffer_size: 16,
+			priority_buffer_size: 4,
+			max_messages_per_wakeup: 64,
+			overflow_policy: OverflowPolicy::DropNewest,
+			overflow_block_timeout: Duration::from_secs(1),
+			timestamp_format: TimestampFormat::Local,
+			max_buffered_bytes: 16 * 1024 * 1024,
+			max_messages_per_second: None,
+			rate_limit_burst: None,
+			rate_limit_exempt_verbosity: Some(crate::SUBSTRATE_INFO),
+			connect_verbosity: None,
+			connect_verbosity_window: Duration::from_secs(30),
+			batch: None,
+			node_queue_capacity: 2048,
+			node_queue_policy: QueuePolicy::DropOldest,
+			disconnected_buffer_size: 1000,
+			websocket_deflate: false,
+			proxy: None,
+			certificate_pins: HashMap::new(),
+			connect_timeout: Duration::from_secs(20),
+			max_reconnect_delay: Duration::from_secs(60),
+			failover_delay: Duration::from_secs(30),
+			shutdown_timeout: Duration::from_secs(5),
+			spill_dir: None,
+			spill_threshold: 64,
+			spill_max_age: Duration::from_secs(3600),
+			keepalive_interval: Duration::from_secs(0),
+			max_message_age: Some(Duration::from_secs(30)),
+			file_sync_interval: Duration::from_secs(1),
+			slow_message_threshold: Some(Duration::from_millis(200)),
+		}
+	}
+}
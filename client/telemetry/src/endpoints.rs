@@ -16,42 +16,389 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use libp2p::Multiaddr;
-use serde::{Deserialize, Deserializer, Serialize};
+use crate::{LOG_URL, MAX_TELEMETRY_ENDPOINTS, MAX_VERBOSITY};
+use libp2p::{
+	multiaddr::{FromUrlErr, Protocol},
+	Multiaddr,
+};
+use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+	borrow::Cow, collections::BTreeMap, convert::TryFrom, fmt, net::Ipv6Addr, path::PathBuf,
+	str::FromStr, time::Duration,
+};
 
-/// List of telemetry servers we want to talk to. Contains the URL of the server, and the
-/// maximum verbosity level.
+/// List of telemetry servers we want to talk to. Contains the URL of the server, the maximum
+/// verbosity level and whether the server is enabled.
 ///
-/// The URL string can be either a URL or a multiaddress.
+/// The URL string can be either a URL or a multiaddress. The query string of a WebSocket URL,
+/// e.g. `wss://telemetry.polkadot.io/submit/?chain=foo`, is kept in the path of the WebSocket
+/// request so that the server receives it unchanged.
+///
+/// A `file://` URL with an absolute path, e.g. `file:///var/log/telemetry.jsonl`, appends the
+/// telemetry to that file, one JSON object per line, as if it were sent to a telemetry server.
+/// The path is not percent-decoded. See [`TelemetryHandle::reopen_files`] to rotate the file.
+/// Files are only written for `file://` URLs: `/unix` multiaddresses are rejected.
+///
+/// The `log:` URL prints the telemetry with `log::info!` and the `telemetry-debug` target
+/// instead, e.g. to see the JSON produced by new `telemetry!` calls without a telemetry server.
+///
+/// [`TelemetryHandle::reopen_files`]: crate::TelemetryHandle::reopen_files
+///
+/// Each entry is serialized as `[URL, VERBOSITY]`, or as `[URL, VERBOSITY, false]` if the server is
+/// disabled. Entries without the third element are enabled. Entries with a connect timeout, a
+/// dedup window, a failover group or target verbosities are serialized as objects, e.g.
+/// `{"url": URL, "verbosity": VERBOSITY, "connect_timeout_ms": 5000, "group": "main"}`, with the
+/// optional fields `enabled`, `connect_timeout_ms`, `dedup_window_ms`, `group`, `priority` and
+/// `target_verbosity`.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct TelemetryEndpoints(
-	#[serde(deserialize_with = "url_or_multiaddr_deser")]
-	pub(crate) Vec<(Multiaddr, u8)>,
+	#[serde(
+		serialize_with = "endpoints_ser",
+		deserialize_with = "url_or_multiaddr_deser"
+	)]
+	pub(crate) Vec<TelemetryEndpoint>,
 );
 
+/// An entry of a serialized [`TelemetryEndpoints`].
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EndpointEntry {
+	Enabled(String, u8),
+	WithFlag(String, u8, bool),
+	Detailed(DetailedEntry<String>),
+}
+
+/// An entry of a serialized [`TelemetryEndpoints`] with all its settings.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DetailedEntry<A> {
+	url: A,
+	verbosity: u8,
+	#[serde(default = "enabled_by_default")]
+	enabled: bool,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	connect_timeout_ms: Option<u64>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	dedup_window_ms: Option<u64>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	group: Option<String>,
+	#[serde(default, skip_serializing_if = "is_zero")]
+	priority: u8,
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	target_verbosity: BTreeMap<String, u8>,
+}
+
+impl DetailedEntry<()> {
+	/// Settings of an entry serialized as a tuple.
+	fn with_flag(enabled: bool) -> Self {
+		DetailedEntry {
+			url: (),
+			verbosity: 0,
+			enabled,
+			connect_timeout_ms: None,
+			dedup_window_ms: None,
+			group: None,
+			priority: 0,
+			target_verbosity: BTreeMap::new(),
+		}
+	}
+}
+
+fn enabled_by_default() -> bool {
+	true
+}
+
+fn is_zero(priority: &u8) -> bool {
+	*priority == 0
+}
+
+/// Address of a serialized [`TelemetryEndpoint`].
+struct SerializedAddr<'a>(&'a TelemetryEndpoint);
+
+impl Serialize for SerializedAddr<'_> {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		// Unlike their URLs, the multiaddresses of the files and of the logs can't be parsed back.
+		match &self.0.kind {
+			EndpointKind::Server => self.0.addr.serialize(serializer),
+			kind => serializer.serialize_str(&kind.url(&self.0.addr)),
+		}
+	}
+}
+
 /// Custom deserializer for TelemetryEndpoints, used to convert urls or multiaddr to multiaddr.
-fn url_or_multiaddr_deser<'de, D>(deserializer: D) -> Result<Vec<(Multiaddr, u8)>, D::Error>
+fn url_or_multiaddr_deser<'de, D>(deserializer: D) -> Result<Vec<TelemetryEndpoint>, D::Error>
 where
 	D: Deserializer<'de>,
 {
-	Vec::<(String, u8)>::deserialize(deserializer)?
-		.iter()
-		.map(|e| {
-			url_to_multiaddr(&e.0)
-				.map_err(serde::de::Error::custom)
-				.map(|m| (m, e.1))
+	let entries = Vec::<EndpointEntry>::deserialize(deserializer)?;
+	let mut settings = Vec::with_capacity(entries.len());
+	let endpoints = entries
+		.into_iter()
+		.map(|entry| match entry {
+			EndpointEntry::Enabled(url, verbosity) => {
+				settings.push(DetailedEntry::with_flag(true));
+				(url, verbosity)
+			}
+			EndpointEntry::WithFlag(url, verbosity, flag) => {
+				settings.push(DetailedEntry::with_flag(flag));
+				(url, verbosity)
+			}
+			EndpointEntry::Detailed(entry) => {
+				let DetailedEntry {
+					url,
+					verbosity,
+					enabled,
+					connect_timeout_ms,
+					dedup_window_ms,
+					group,
+					priority,
+					target_verbosity,
+				} = entry;
+				settings.push(DetailedEntry {
+					url: (),
+					verbosity,
+					enabled,
+					connect_timeout_ms,
+					dedup_window_ms,
+					group,
+					priority,
+					target_verbosity,
+				});
+				(url, verbosity)
+			}
+		})
+		.collect::<Vec<_>>();
+
+	let mut endpoints = parse_endpoints(endpoints, MAX_TELEMETRY_ENDPOINTS)
+		.map_err(serde::de::Error::custom)?;
+	for (endpoint, settings) in endpoints.iter_mut().zip(settings) {
+		endpoint.enabled = settings.enabled;
+		endpoint.connect_timeout = settings.connect_timeout_ms.map(Duration::from_millis);
+		endpoint.dedup_window = settings.dedup_window_ms.map(Duration::from_millis);
+		endpoint.group = settings.group;
+		endpoint.priority = settings.priority;
+		if let Some((target, verbosity)) = settings
+			.target_verbosity
+			.iter()
+			.find(|(_, verbosity)| **verbosity > MAX_VERBOSITY)
+		{
+			return Err(serde::de::Error::custom(format!(
+				"Invalid verbosity level {} for target {:?}, expected an integer between 0 and {}",
+				verbosity,
+				target,
+				MAX_VERBOSITY,
+			)));
+		}
+		endpoint.target_verbosity = settings.target_verbosity;
+	}
+	Ok(endpoints)
+}
+
+/// Custom serializer for TelemetryEndpoints, that only writes the settings that differ from the
+/// defaults so that the other endpoints stay readable by older versions.
+fn endpoints_ser<S>(endpoints: &[TelemetryEndpoint], serializer: S) -> Result<S::Ok, S::Error>
+where
+	S: Serializer,
+{
+	let mut seq = serializer.serialize_seq(Some(endpoints.len()))?;
+	for endpoint in endpoints {
+		if endpoint.connect_timeout.is_some()
+			|| endpoint.dedup_window.is_some()
+			|| endpoint.group.is_some()
+			|| !endpoint.target_verbosity.is_empty()
+		{
+			seq.serialize_element(&DetailedEntry {
+				url: SerializedAddr(endpoint),
+				verbosity: endpoint.verbosity,
+				enabled: endpoint.enabled,
+				connect_timeout_ms: endpoint
+					.connect_timeout
+					.map(|timeout| timeout.as_millis() as u64),
+				dedup_window_ms: endpoint
+					.dedup_window
+					.map(|window| window.as_millis() as u64),
+				group: endpoint.group.clone(),
+				priority: endpoint.priority,
+				target_verbosity: endpoint.target_verbosity.clone(),
+			})?;
+		} else if endpoint.enabled {
+			seq.serialize_element(&(SerializedAddr(endpoint), endpoint.verbosity))?;
+		} else {
+			seq.serialize_element(&(SerializedAddr(endpoint), endpoint.verbosity, false))?;
+		}
+	}
+	seq.end()
+}
+
+/// Error while creating a [`TelemetryEndpoints`], listing every invalid entry.
+#[derive(Debug)]
+pub struct TelemetryEndpointsError {
+	/// The invalid entries, in the order of the input.
+	pub entries: Vec<InvalidEndpoint>,
+}
+
+/// An invalid entry of a list of telemetry endpoints.
+#[derive(Debug)]
+pub struct InvalidEndpoint {
+	/// Position of the entry in the list.
+	pub index: usize,
+	/// URL of the entry, as provided.
+	pub url: String,
+	/// Why the entry is invalid.
+	pub reason: InvalidEndpointReason,
+}
+
+/// Why a telemetry endpoint is invalid.
+#[derive(Debug)]
+pub enum InvalidEndpointReason {
+	/// The URL is neither a valid URL nor a valid multiaddress.
+	InvalidUrl(AddrParseError),
+	/// The verbosity is above [`MAX_VERBOSITY`].
+	VerbosityTooHigh(u8),
+	/// The entry comes after the first [`MAX_TELEMETRY_ENDPOINTS`] entries.
+	TooManyEndpoints,
+}
+
+impl fmt::Display for TelemetryEndpointsError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Invalid telemetry endpoints: ")?;
+		for (i, entry) in self.entries.iter().enumerate() {
+			if i > 0 {
+				write!(f, "; ")?;
+			}
+			write!(f, "{}", entry)?;
+		}
+		Ok(())
+	}
+}
+
+impl fmt::Display for InvalidEndpoint {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match &self.reason {
+			InvalidEndpointReason::InvalidUrl(error) => {
+				write!(f, "#{} {}: {}", self.index, self.url, error)
+			}
+			InvalidEndpointReason::VerbosityTooHigh(verbosity) => write!(
+				f,
+				"#{} {}: verbosity {} is above the maximum of {}",
+				self.index, self.url, verbosity, MAX_VERBOSITY,
+			),
+			InvalidEndpointReason::TooManyEndpoints => write!(
+				f,
+				"#{} {}: there are more than the maximum of {} endpoints",
+				self.index, self.url, MAX_TELEMETRY_ENDPOINTS,
+			),
+		}
+	}
+}
+
+impl std::error::Error for TelemetryEndpointsError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		self.entries.iter().find_map(|entry| match &entry.reason {
+			InvalidEndpointReason::InvalidUrl(error) => Some(error as &_),
+			InvalidEndpointReason::VerbosityTooHigh(_) | InvalidEndpointReason::TooManyEndpoints => {
+				None
+			}
 		})
-		.collect()
+	}
 }
 
 impl TelemetryEndpoints {
+	/// Start building a `TelemetryEndpoints` one endpoint at a time.
+	///
+	/// # Example
+	///
+	/// ```
+	/// # use sc_telemetry::TelemetryEndpoints;
+	/// let endpoints = TelemetryEndpoints::builder()
+	/// 	.default_verbosity(1)
+	/// 	.add("wss://telemetry.polkadot.io/submit/")
+	/// 	.add_with_verbosity("/ip4/80.123.90.4/tcp/5432", 4)
+	/// 	.build()
+	/// 	.unwrap();
+	/// ```
+	pub fn builder() -> TelemetryEndpointsBuilder {
+		TelemetryEndpointsBuilder::default()
+	}
+
 	/// Create a `TelemetryEndpoints` based on a list of `(String, u8)`.
-	pub fn new(endpoints: Vec<(String, u8)>) -> Result<Self, libp2p::multiaddr::Error> {
-		let endpoints: Result<Vec<(Multiaddr, u8)>, libp2p::multiaddr::Error> = endpoints
-			.iter()
-			.map(|e| Ok((url_to_multiaddr(&e.0)?, e.1)))
-			.collect();
-		endpoints.map(Self)
+	///
+	/// Fails if a URL is invalid, if a verbosity is above [`MAX_VERBOSITY`] or if there are more
+	/// than [`MAX_TELEMETRY_ENDPOINTS`] endpoints. The error lists every invalid entry.
+	pub fn new(endpoints: Vec<(String, u8)>) -> Result<Self, TelemetryEndpointsError> {
+		parse_endpoints(endpoints, MAX_TELEMETRY_ENDPOINTS).map(Self)
+	}
+
+	/// Same as [`TelemetryEndpoints::new`], but without any limit on the number of endpoints.
+	///
+	/// Every endpoint is a connection, and every telemetry message is copied for every endpoint.
+	pub fn new_unchecked(endpoints: Vec<(String, u8)>) -> Result<Self, TelemetryEndpointsError> {
+		parse_endpoints(endpoints, usize::MAX).map(Self)
+	}
+
+	/// Create a `TelemetryEndpoints` based on a list of `(String, u8)`, where the verbosities
+	/// above [`MAX_VERBOSITY`] are lowered to [`MAX_VERBOSITY`].
+	///
+	/// Fails if a URL is invalid.
+	pub fn new_clamped(endpoints: Vec<(String, u8)>) -> Result<Self, TelemetryEndpointsError> {
+		Self::new(
+			endpoints
+				.into_iter()
+				.map(|(url, verbosity)| (url, verbosity.min(MAX_VERBOSITY)))
+				.collect(),
+		)
+	}
+}
+
+/// Builder of [`TelemetryEndpoints`], created with [`TelemetryEndpoints::builder`].
+///
+/// The endpoints are only validated by [`TelemetryEndpointsBuilder::build`].
+#[derive(Debug, Clone, Default)]
+pub struct TelemetryEndpointsBuilder {
+	default_verbosity: u8,
+	endpoints: Vec<(String, Option<u8>)>,
+}
+
+impl TelemetryEndpointsBuilder {
+	/// Add an endpoint with the default verbosity.
+	///
+	/// The URL can be either a URL or a multiaddress.
+	// Not an arithmetic operation, despite the name.
+	#[allow(clippy::should_implement_trait)]
+	pub fn add(mut self, url: impl Into<String>) -> Self {
+		self.endpoints.push((url.into(), None));
+		self
+	}
+
+	/// Add an endpoint with the given verbosity.
+	///
+	/// The URL can be either a URL or a multiaddress.
+	pub fn add_with_verbosity(mut self, url: impl Into<String>, verbosity: u8) -> Self {
+		self.endpoints.push((url.into(), Some(verbosity)));
+		self
+	}
+
+	/// Set the verbosity of the endpoints added with [`TelemetryEndpointsBuilder::add`], before
+	/// or after this call.
+	///
+	/// Defaults to 0.
+	pub fn default_verbosity(mut self, verbosity: u8) -> Self {
+		self.default_verbosity = verbosity;
+		self
+	}
+
+	/// Build the `TelemetryEndpoints`.
+	///
+	/// Fails if a URL is invalid, if a verbosity is above [`MAX_VERBOSITY`] or if there are more
+	/// than [`MAX_TELEMETRY_ENDPOINTS`] endpoints. The error lists every invalid entry.
+	pub fn build(self) -> Result<TelemetryEndpoints, TelemetryEndpointsError> {
+		let default_verbosity = self.default_verbosity;
+		TelemetryEndpoints::new(
+			self.endpoints
+				.into_iter()
+				.map(|(url, verbosity)| (url, verbosity.unwrap_or(default_verbosity)))
+				.collect(),
+		)
 	}
 }
 
@@ -60,31 +407,625 @@ impl TelemetryEndpoints {
 	pub fn is_empty(&self) -> bool {
 		self.0.is_empty()
 	}
+
+	/// Combine two sets of telemetry endpoints.
+	///
+	/// Endpoints present in both sets are kept once, with the maximum of their verbosities,
+	/// including their target verbosities. They are enabled if they are enabled in either set, and
+	/// keep the connect timeout and the failover group of `self` if it has them.
+	///
+	/// Fails if the combined set has more than [`MAX_TELEMETRY_ENDPOINTS`] endpoints. The error
+	/// lists the endpoints after the maximum.
+	pub fn merge(mut self, other: TelemetryEndpoints) -> Result<Self, TelemetryEndpointsError> {
+		for endpoint in other.0 {
+			self.insert(endpoint);
+		}
+		self.check_len()?;
+		Ok(self)
+	}
+
+	/// Add a list of `(String, u8)` to the telemetry endpoints.
+	///
+	/// Endpoints already present are kept once, with the maximum of their verbosities. Nothing is
+	/// added if any of the endpoints is invalid, or if there would be more than
+	/// [`MAX_TELEMETRY_ENDPOINTS`] endpoints in total.
+	pub fn extend_from_urls<I>(&mut self, endpoints: I) -> Result<(), TelemetryEndpointsError>
+	where
+		I: IntoIterator<Item = (String, u8)>,
+	{
+		let endpoints = parse_endpoints(endpoints, MAX_TELEMETRY_ENDPOINTS)?;
+
+		let mut extended = self.clone();
+		for endpoint in endpoints {
+			extended.insert(endpoint);
+		}
+		extended.check_len()?;
+		*self = extended;
+		Ok(())
+	}
+
+	/// Enable or disable the endpoint with the given address.
+	///
+	/// A disabled endpoint stays in the list but the telemetry doesn't connect to it. Returns
+	/// `false` if there is no endpoint with this address.
+	pub fn set_enabled(&mut self, addr: &Multiaddr, enabled: bool) -> bool {
+		match self.0.iter_mut().find(|endpoint| endpoint.addr == *addr) {
+			Some(endpoint) => {
+				endpoint.enabled = enabled;
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Set the connect timeout of the endpoint with the given address.
+	///
+	/// `None` means [`TelemetryConfig::connect_timeout`](crate::TelemetryConfig::connect_timeout).
+	/// Returns `false` if there is no endpoint with this address.
+	pub fn set_connect_timeout(&mut self, addr: &Multiaddr, timeout: Option<Duration>) -> bool {
+		match self.0.iter_mut().find(|endpoint| endpoint.addr == *addr) {
+			Some(endpoint) => {
+				endpoint.connect_timeout = timeout;
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Set the dedup window of the endpoint with the given address, or disable the dedup with
+	/// `None`, which is the default.
+	///
+	/// A message that is identical to the previous one sent to the endpoint, apart from its `ts`
+	/// field, is not sent if the previous one has been sent less than `window` ago. The number of
+	/// such messages is sent in the `suppressed` field of the next message that is sent. Returns
+	/// `false` if there is no endpoint with this address.
+	pub fn set_dedup_window(&mut self, addr: &Multiaddr, window: Option<Duration>) -> bool {
+		match self.0.iter_mut().find(|endpoint| endpoint.addr == *addr) {
+			Some(endpoint) => {
+				endpoint.dedup_window = window;
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Put the endpoint with the given address in a failover group, or remove it from its group
+	/// with `None`.
+	///
+	/// Only one endpoint of a group receives the telemetry at a time: the one with the lowest
+	/// `priority` that is connected or that has been disconnected for less than
+	/// [`TelemetryConfig::failover_delay`](crate::TelemetryConfig::failover_delay). Returns
+	/// `false` if there is no endpoint with this address.
+	pub fn set_group(&mut self, addr: &Multiaddr, group: Option<(String, u8)>) -> bool {
+		match self.0.iter_mut().find(|endpoint| endpoint.addr == *addr) {
+			Some(endpoint) => {
+				let (group, priority) = match group {
+					Some((group, priority)) => (Some(group), priority),
+					None => (None, 0),
+				};
+				endpoint.group = group;
+				endpoint.priority = priority;
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Set the verbosity of the messages of `target` sent to the endpoint with the given address,
+	/// or remove it with `None`.
+	///
+	/// The target of a message is its name, e.g. `afg.finalized`, and the verbosity applies to
+	/// the names that start with `target`, e.g. `afg.`. When several targets match, the longest
+	/// one applies. The other messages are sent up to the verbosity of the endpoint. The
+	/// verbosity is capped at [`MAX_VERBOSITY`]. Returns `false` if there is no endpoint with
+	/// this address.
+	pub fn set_target_verbosity(
+		&mut self,
+		addr: &Multiaddr,
+		target: impl Into<String>,
+		verbosity: Option<u8>,
+	) -> bool {
+		match self.0.iter_mut().find(|endpoint| endpoint.addr == *addr) {
+			Some(endpoint) => {
+				let target = target.into();
+				match verbosity {
+					Some(verbosity) => {
+						endpoint.target_verbosity.insert(target, verbosity.min(MAX_VERBOSITY));
+					}
+					None => {
+						endpoint.target_verbosity.remove(&target);
+					}
+				}
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Fail with the endpoints after the first [`MAX_TELEMETRY_ENDPOINTS`], if any.
+	fn check_len(&self) -> Result<(), TelemetryEndpointsError> {
+		let invalid = self
+			.0
+			.iter()
+			.enumerate()
+			.skip(MAX_TELEMETRY_ENDPOINTS)
+			.map(|(index, endpoint)| InvalidEndpoint {
+				index,
+				url: endpoint.url(),
+				reason: InvalidEndpointReason::TooManyEndpoints,
+			})
+			.collect::<Vec<_>>();
+		if invalid.is_empty() {
+			Ok(())
+		} else {
+			Err(TelemetryEndpointsError { entries: invalid })
+		}
+	}
+
+	fn insert(&mut self, endpoint: TelemetryEndpoint) {
+		match self.0.iter_mut().find(|existing| existing.addr == endpoint.addr) {
+			Some(existing) => {
+				existing.verbosity = endpoint.verbosity.max(existing.verbosity);
+				existing.enabled |= endpoint.enabled;
+				existing.connect_timeout = existing.connect_timeout.or(endpoint.connect_timeout);
+				existing.dedup_window = existing.dedup_window.or(endpoint.dedup_window);
+				if existing.group.is_none() {
+					existing.group = endpoint.group;
+					existing.priority = endpoint.priority;
+				}
+				for (target, verbosity) in endpoint.target_verbosity {
+					let existing = existing.target_verbosity.entry(target).or_insert(verbosity);
+					*existing = verbosity.max(*existing);
+				}
+			}
+			None => self.0.push(endpoint),
+		}
+	}
+}
+
+/// Lists the endpoints as `URL VERBOSITY`, separated by commas.
+impl fmt::Display for TelemetryEndpoints {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (i, endpoint) in self.0.iter().enumerate() {
+			if i > 0 {
+				write!(f, ", ")?;
+			}
+			write!(f, "{}", endpoint)?;
+		}
+		Ok(())
+	}
+}
+
+impl TryFrom<Vec<TelemetryEndpoint>> for TelemetryEndpoints {
+	type Error = TelemetryEndpointsError;
+
+	/// Endpoints present several times are kept once, with the maximum of their verbosities.
+	///
+	/// An error is returned if there are more than [`MAX_TELEMETRY_ENDPOINTS`] endpoints.
+	fn try_from(endpoints: Vec<TelemetryEndpoint>) -> Result<Self, Self::Error> {
+		let invalid = endpoints
+			.iter()
+			.enumerate()
+			.skip(MAX_TELEMETRY_ENDPOINTS)
+			.map(|(index, endpoint)| InvalidEndpoint {
+				index,
+				url: endpoint.url(),
+				reason: InvalidEndpointReason::TooManyEndpoints,
+			})
+			.collect::<Vec<_>>();
+		if !invalid.is_empty() {
+			return Err(TelemetryEndpointsError { entries: invalid });
+		}
+
+		let mut telemetry_endpoints = TelemetryEndpoints(Vec::new());
+		for endpoint in endpoints {
+			telemetry_endpoints.insert(endpoint);
+		}
+		Ok(telemetry_endpoints)
+	}
+}
+
+/// A single telemetry server: its address, the maximum verbosity level of the messages it
+/// receives and whether it is enabled.
+///
+/// It can be parsed from a `"URL VERBOSITY"` string, e.g. `"wss://telemetry.polkadot.io/submit/ 0"`.
+/// Parsed endpoints are enabled.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TelemetryEndpoint {
+	addr: Multiaddr,
+	kind: EndpointKind,
+	verbosity: u8,
+	enabled: bool,
+	connect_timeout: Option<Duration>,
+	dedup_window: Option<Duration>,
+	group: Option<String>,
+	priority: u8,
+	target_verbosity: BTreeMap<String, u8>,
+}
+
+impl TelemetryEndpoint {
+	/// Create an enabled endpoint, with the default connect timeout and no failover group.
+	pub(crate) fn new(addr: Multiaddr, verbosity: u8) -> Self {
+		TelemetryEndpoint {
+			addr,
+			kind: EndpointKind::Server,
+			verbosity,
+			enabled: true,
+			connect_timeout: None,
+			dedup_window: None,
+			group: None,
+			priority: 0,
+			target_verbosity: BTreeMap::new(),
+		}
+	}
+
+	/// Parse the URL of an endpoint, see [`TelemetryEndpoints`].
+	fn parse(url: &str, verbosity: u8) -> Result<Self, AddrParseError> {
+		// The `/unix` addresses of the files and of the logs only identify their endpoint: parsed
+		// `/unix` multiaddresses are rejected.
+		let (addr, kind) = match url.strip_prefix("file://") {
+			Some(path) => {
+				let path = path.strip_prefix("localhost").unwrap_or(path);
+				if !path.starts_with('/') || path.contains(&['?', '#'][..]) {
+					return Err(AddrParseError::Url(FromUrlErr::BadUrl));
+				}
+				let addr = Multiaddr::empty().with(Protocol::Unix(path.into()));
+				(addr, EndpointKind::File(path.into()))
+			}
+			None if url == LOG_URL => {
+				let addr = Multiaddr::empty().with(Protocol::Unix(LOG_URL.into()));
+				(addr, EndpointKind::Log)
+			}
+			None => (url_to_multiaddr(url)?, EndpointKind::Server),
+		};
+		let mut endpoint = TelemetryEndpoint::new(addr, verbosity);
+		endpoint.kind = kind;
+		Ok(endpoint)
+	}
+
+	/// Address of the telemetry server.
+	///
+	/// The address of a `file://` or `log:` endpoint only identifies it, see
+	/// [`TelemetryEndpoint::kind`].
+	pub fn addr(&self) -> &Multiaddr {
+		&self.addr
+	}
+
+	/// Where the telemetry of the endpoint is sent.
+	pub fn kind(&self) -> &EndpointKind {
+		&self.kind
+	}
+
+	/// URL of the endpoint if possible, its multiaddress otherwise.
+	pub(crate) fn url(&self) -> String {
+		self.kind.url(&self.addr)
+	}
+
+	/// Maximum verbosity level of the messages sent to the telemetry server.
+	pub fn verbosity(&self) -> u8 {
+		self.verbosity
+	}
+
+	/// Return `true` if the telemetry connects to this server, `false` if it is disabled.
+	pub fn enabled(&self) -> bool {
+		self.enabled
+	}
+
+	/// Time after which a connection attempt to the telemetry server is abandoned, or `None` for
+	/// [`TelemetryConfig::connect_timeout`](crate::TelemetryConfig::connect_timeout).
+	pub fn connect_timeout(&self) -> Option<Duration> {
+		self.connect_timeout
+	}
+
+	/// Time during which the messages identical to the previous one are not sent to the
+	/// telemetry server, see [`TelemetryEndpoints::set_dedup_window`].
+	pub fn dedup_window(&self) -> Option<Duration> {
+		self.dedup_window
+	}
+
+	/// Name of the failover group of the endpoint, if any.
+	pub fn group(&self) -> Option<&str> {
+		self.group.as_deref()
+	}
+
+	/// Priority of the endpoint in its failover group. The endpoints with a lower priority are
+	/// preferred.
+	pub fn priority(&self) -> u8 {
+		self.priority
+	}
+
+	/// Verbosity of the messages sent to the telemetry server by target, see
+	/// [`TelemetryEndpoints::set_target_verbosity`].
+	pub fn target_verbosity(&self) -> &BTreeMap<String, u8> {
+		&self.target_verbosity
+	}
+
+	/// Highest verbosity of the messages sent to the telemetry server, whatever their target.
+	pub(crate) fn max_verbosity(&self) -> u8 {
+		self.target_verbosity
+			.values()
+			.fold(self.verbosity, |max, verbosity| max.max(*verbosity))
+	}
+}
+
+/// Formats the endpoint as `URL VERBOSITY`, followed by `(disabled)` if it is disabled.
+impl fmt::Display for TelemetryEndpoint {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} {}", self.url(), self.verbosity)?;
+		if !self.enabled {
+			write!(f, " (disabled)")?;
+		}
+		Ok(())
+	}
+}
+
+/// Where a [`TelemetryEndpoint`] sends the telemetry.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum EndpointKind {
+	/// A telemetry server, reached with the transport of the [`TelemetryWorker`].
+	///
+	/// [`TelemetryWorker`]: crate::TelemetryWorker
+	Server,
+	/// A file, given by a `file://` URL, to which the telemetry is appended.
+	File(PathBuf),
+	/// The logs, given by the `log:` URL, to which the telemetry is printed.
+	Log,
+}
+
+impl EndpointKind {
+	/// URL of an endpoint of this kind whose address is `addr`.
+	pub(crate) fn url(&self, addr: &Multiaddr) -> String {
+		match self {
+			EndpointKind::Server => display_addr(addr),
+			EndpointKind::File(path) => format!("file://{}", path.display()),
+			EndpointKind::Log => LOG_URL.into(),
+		}
+	}
+}
+
+/// Error while parsing a [`TelemetryEndpoint`].
+#[derive(Debug)]
+pub enum TelemetryEndpointParseError {
+	/// The verbosity level is missing.
+	MissingVerbosity,
+	/// The verbosity level is not an integer between 0 and [`MAX_VERBOSITY`].
+	InvalidVerbosity(String),
+	/// The URL is neither a valid URL nor a valid multiaddress.
+	InvalidUrl(AddrParseError),
+}
+
+impl fmt::Display for TelemetryEndpointParseError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			TelemetryEndpointParseError::MissingVerbosity => write!(
+				f,
+				"Verbosity level missing, expected 'URL VERBOSITY'",
+			),
+			TelemetryEndpointParseError::InvalidVerbosity(verbosity) => write!(
+				f,
+				"Invalid verbosity level {:?}, expected an integer between 0 and {}",
+				verbosity, MAX_VERBOSITY,
+			),
+			TelemetryEndpointParseError::InvalidUrl(err) => write!(f, "{}", err),
+		}
+	}
+}
+
+impl std::error::Error for TelemetryEndpointParseError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			TelemetryEndpointParseError::InvalidUrl(err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+impl FromStr for TelemetryEndpoint {
+	type Err = TelemetryEndpointParseError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut parts = s.trim().rsplitn(2, char::is_whitespace);
+		let verbosity = parts.next().unwrap_or_default();
+		let url = parts
+			.next()
+			.map(str::trim_end)
+			.ok_or(TelemetryEndpointParseError::MissingVerbosity)?;
+
+		let verbosity = verbosity
+			.parse()
+			.ok()
+			.filter(|verbosity| *verbosity <= MAX_VERBOSITY)
+			.ok_or_else(|| TelemetryEndpointParseError::InvalidVerbosity(verbosity.to_string()))?;
+		TelemetryEndpoint::parse(url, verbosity).map_err(TelemetryEndpointParseError::InvalidUrl)
+	}
+}
+
+/// Parses a list of endpoints, checking that their verbosities are at most [`MAX_VERBOSITY`] and
+/// that there are at most `max_endpoints` of them.
+///
+/// The parsed endpoints are enabled.
+fn parse_endpoints<I>(
+	endpoints: I,
+	max_endpoints: usize,
+) -> Result<Vec<TelemetryEndpoint>, TelemetryEndpointsError>
+where
+	I: IntoIterator<Item = (String, u8)>,
+{
+	let mut parsed = Vec::new();
+	let mut invalid = Vec::new();
+
+	for (index, (url, verbosity)) in endpoints.into_iter().enumerate() {
+		let reason = if verbosity > MAX_VERBOSITY {
+			InvalidEndpointReason::VerbosityTooHigh(verbosity)
+		} else {
+			match TelemetryEndpoint::parse(&url, verbosity) {
+				Ok(_) if index >= max_endpoints => InvalidEndpointReason::TooManyEndpoints,
+				Ok(endpoint) => {
+					parsed.push(endpoint);
+					continue;
+				}
+				Err(error) => InvalidEndpointReason::InvalidUrl(error),
+			}
+		};
+
+		invalid.push(InvalidEndpoint { index, url, reason });
+	}
+
+	if invalid.is_empty() {
+		Ok(parsed)
+	} else {
+		Err(TelemetryEndpointsError { entries: invalid })
+	}
+}
+
+/// Error while parsing the URL of a telemetry endpoint.
+#[derive(Debug)]
+pub enum AddrParseError {
+	/// The string starts with a `/` but is not a valid multiaddress.
+	Multiaddr(libp2p::multiaddr::Error),
+	/// The string is not a valid WebSocket URL.
+	Url(libp2p::multiaddr::FromUrlErr),
+	/// The address is a `/unix` multiaddress. Files are written for `file://` URLs only.
+	Unix,
+}
+
+impl fmt::Display for AddrParseError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			AddrParseError::Multiaddr(err) => write!(f, "Invalid multiaddress: {}", err),
+			AddrParseError::Url(err) => write!(f, "Invalid URL: {}", err),
+			AddrParseError::Unix => write!(
+				f,
+				"Unix socket addresses are not supported, use a file:// URL to write to a file",
+			),
+		}
+	}
+}
+
+impl std::error::Error for AddrParseError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			AddrParseError::Multiaddr(err) => Some(err),
+			AddrParseError::Url(err) => Some(err),
+			AddrParseError::Unix => None,
+		}
+	}
+}
+
+/// Parses a WebSocket URL or a multiaddress into a libp2p `Multiaddr`.
+///
+/// The query string of the URL is appended to the path of the WebSocket protocol. `/unix`
+/// multiaddresses, e.g. from `unix:` URLs, are rejected so that a file is never written without a
+/// `file://` URL.
+fn url_to_multiaddr(url: &str) -> Result<Multiaddr, AddrParseError> {
+	let addr = parse_addr(url)?;
+	if addr.iter().any(|protocol| matches!(protocol, Protocol::Unix(_))) {
+		return Err(AddrParseError::Unix);
+	}
+	Ok(addr)
 }
 
-/// Parses a WebSocket URL into a libp2p `Multiaddr`.
-fn url_to_multiaddr(url: &str) -> Result<Multiaddr, libp2p::multiaddr::Error> {
-	// First, assume that we have a `Multiaddr`.
-	let parse_error = match url.parse() {
-		Ok(ma) => return Ok(ma),
-		Err(err) => err,
+/// Parses a WebSocket URL or a multiaddress into a libp2p `Multiaddr`.
+fn parse_addr(url: &str) -> Result<Multiaddr, AddrParseError> {
+	// Multiaddresses always start with a `/`, URLs never do.
+	if url.starts_with('/') {
+		return url.parse().map_err(AddrParseError::Multiaddr);
+	}
+
+	// `from_url` rejects the URLs with a query string, so it is removed beforehand. URLs with a
+	// fragment are still rejected.
+	let (url, query) = match url.find('?') {
+		Some(pos) if !url[pos..].contains('#') => (&url[..pos], Some(&url[pos + 1..])),
+		_ => (url, None),
 	};
+	let query = query.filter(|query| !query.is_empty());
 
-	// If not, try the `ws://path/url` format.
-	if let Ok(ma) = libp2p::multiaddr::from_url(url) {
-		return Ok(ma);
+	let addr = libp2p::multiaddr::from_url(url).map_err(AddrParseError::Url)?;
+
+	// `from_url` turns bracketed IPv6 hosts, e.g. `wss://[::1]:443/`, into a DNS name that still
+	// contains the brackets.
+	Ok(addr
+		.iter()
+		.map(|protocol| match protocol {
+			Protocol::Dns(host) => {
+				let ip = host
+					.strip_prefix('[')
+					.and_then(|host| host.strip_suffix(']'))
+					.and_then(|host| host.parse::<Ipv6Addr>().ok());
+				match ip {
+					Some(ip) => Protocol::Ip6(ip),
+					None => Protocol::Dns(host),
+				}
+			}
+			Protocol::Ws(path) => Protocol::Ws(with_query(path, query)),
+			Protocol::Wss(path) => Protocol::Wss(with_query(path, query)),
+			protocol => protocol,
+		})
+		.collect())
+}
+
+/// Appends `query`, if any, to the path of a WebSocket request.
+fn with_query<'a>(path: Cow<'a, str>, query: Option<&str>) -> Cow<'a, str> {
+	match query {
+		Some(query) => format!("{}?{}", path, query).into(),
+		None => path,
 	}
+}
 
-	// If we have no clue about the format of that string, assume that we were expecting a
-	// `Multiaddr`.
-	Err(parse_error)
+/// Converts a WebSocket multiaddress back into a URL, e.g.
+/// `/dns/telemetry.polkadot.io/tcp/443/x-parity-wss/%2Fsubmit%2F` into
+/// `wss://telemetry.polkadot.io:443/submit/`.
+///
+/// Returns `None` if the multiaddress is not a WebSocket address over TCP.
+pub fn multiaddr_to_url(addr: &Multiaddr) -> Option<String> {
+	let mut iter = addr.iter();
+	let host = match iter.next()? {
+		Protocol::Ip4(ip) => ip.to_string(),
+		Protocol::Ip6(ip) => format!("[{}]", ip),
+		Protocol::Dns(host) | Protocol::Dns4(host) | Protocol::Dns6(host) => host.into_owned(),
+		_ => return None,
+	};
+	let port = match iter.next()? {
+		Protocol::Tcp(port) => port,
+		_ => return None,
+	};
+	let (scheme, path) = match iter.next()? {
+		Protocol::Ws(path) => ("ws", path),
+		Protocol::Wss(path) => ("wss", path),
+		_ => return None,
+	};
+
+	if iter.next().is_some() {
+		return None;
+	}
+	Some(format!("{}://{}:{}{}", scheme, host, port, path))
+}
+
+/// Formats an address as a URL if possible, as a multiaddress otherwise.
+pub(crate) fn display_addr(addr: &Multiaddr) -> String {
+	multiaddr_to_url(addr).unwrap_or_else(|| addr.to_string())
 }
 
 #[cfg(test)]
 mod tests {
-	use super::url_to_multiaddr;
-	use super::TelemetryEndpoints;
-	use libp2p::Multiaddr;
+	use super::{multiaddr_to_url, url_to_multiaddr};
+	use super::{
+		AddrParseError, EndpointKind, InvalidEndpointReason, TelemetryEndpoint,
+		TelemetryEndpointParseError, TelemetryEndpoints, TelemetryEndpointsError,
+	};
+	use crate::{MAX_TELEMETRY_ENDPOINTS, MAX_VERBOSITY};
+	use libp2p::{multiaddr::Protocol, Multiaddr};
+	use std::{convert::TryFrom, path::PathBuf, time::Duration};
+
+	/// Same as `TelemetryEndpoints::new`, with the builder.
+	fn build(endpoints: &[(String, u8)]) -> Result<TelemetryEndpoints, TelemetryEndpointsError> {
+		endpoints
+			.iter()
+			.fold(TelemetryEndpoints::builder(), |builder, (url, verbosity)| {
+				builder.add_with_verbosity(url, *verbosity)
+			})
+			.build()
+	}
 
 	#[test]
 	fn valid_endpoints() {
@@ -94,14 +1035,15 @@ mod tests {
 		];
 		let telem =
 			TelemetryEndpoints::new(endp.clone()).expect("Telemetry endpoint should be valid");
-		let mut res: Vec<(Multiaddr, u8)> = vec![];
+		let mut res: Vec<TelemetryEndpoint> = vec![];
 		for (a, b) in endp.iter() {
-			res.push((
+			res.push(TelemetryEndpoint::new(
 				url_to_multiaddr(a).expect("provided url should be valid"),
 				*b,
 			))
 		}
 		assert_eq!(telem.0, res);
+		assert_eq!(build(&endp).unwrap(), telem);
 	}
 
 	#[test]
@@ -110,6 +1052,7 @@ mod tests {
 			("/ip4/...80.123.90.4/tcp/5432".into(), 3),
 			("/ip4/no:!?;rlkqre;;::::///tcp/5432".into(), 4),
 		];
+		assert!(build(&endp).is_err());
 		let telem = TelemetryEndpoints::new(endp);
 		assert!(telem.is_err());
 	}
@@ -120,7 +1063,618 @@ mod tests {
 			("/ip4/80.123.90.4/tcp/5432".into(), 3),
 			("/ip4/no:!?;rlkqre;;::::///tcp/5432".into(), 4),
 		];
+		assert!(build(&endp).is_err());
 		let telem = TelemetryEndpoints::new(endp);
 		assert!(telem.is_err());
 	}
+
+	#[test]
+	fn merge_endpoints() {
+		let from_spec = TelemetryEndpoints::new(vec![
+			("wss://telemetry.polkadot.io/submit/".into(), 0),
+			("/ip4/80.123.90.4/tcp/5432".into(), 4),
+		])
+		.unwrap();
+		let from_cli = TelemetryEndpoints::new(vec![
+			(
+				"/dns/telemetry.polkadot.io/tcp/443/x-parity-wss/%2Fsubmit%2F".into(),
+				5,
+			),
+			("/ip4/80.123.90.5/tcp/5432".into(), 1),
+		])
+		.unwrap();
+
+		let merged = from_spec.merge(from_cli).unwrap();
+		assert_eq!(
+			merged.0,
+			vec![
+				TelemetryEndpoint::new(
+					url_to_multiaddr("wss://telemetry.polkadot.io/submit/").unwrap(),
+					5
+				),
+				TelemetryEndpoint::new("/ip4/80.123.90.4/tcp/5432".parse().unwrap(), 4),
+				TelemetryEndpoint::new("/ip4/80.123.90.5/tcp/5432".parse().unwrap(), 1),
+			],
+		);
+	}
+
+	#[test]
+	fn extend_endpoints_from_urls() {
+		let mut telem = TelemetryEndpoints::new(vec![(
+			"/dns/telemetry.polkadot.io/tcp/443/x-parity-wss/%2Fsubmit%2F".into(),
+			3,
+		)])
+		.unwrap();
+
+		telem
+			.extend_from_urls(vec![
+				("wss://telemetry.polkadot.io/submit/".into(), 1),
+				("/ip4/80.123.90.4/tcp/5432".into(), 4),
+			])
+			.unwrap();
+		assert_eq!(telem.0.len(), 2);
+		assert_eq!(telem.0[0].verbosity, 3);
+
+		assert!(telem
+			.extend_from_urls(vec![
+				("/ip4/80.123.90.5/tcp/5432".into(), 1),
+				("/ip4/no:!?;rlkqre;;::::///tcp/5432".into(), 4),
+			])
+			.is_err());
+		assert_eq!(telem.0.len(), 2);
+	}
+
+	#[test]
+	fn combining_endpoints_past_the_maximum_is_rejected() {
+		let urls = |offset: usize| {
+			(0..MAX_TELEMETRY_ENDPOINTS)
+				.map(|i| (format!("/ip4/80.123.90.{}/tcp/5432", offset + i), 0))
+				.collect::<Vec<_>>()
+		};
+		let first = TelemetryEndpoints::new(urls(0)).unwrap();
+		let second = TelemetryEndpoints::new(urls(MAX_TELEMETRY_ENDPOINTS)).unwrap();
+
+		let err = first.clone().merge(second.clone()).unwrap_err();
+		assert_eq!(err.entries.len(), MAX_TELEMETRY_ENDPOINTS);
+		assert_eq!(err.entries[0].index, MAX_TELEMETRY_ENDPOINTS);
+		assert!(matches!(err.entries[0].reason, InvalidEndpointReason::TooManyEndpoints));
+		assert_eq!(first.clone().merge(first.clone()).unwrap(), first);
+
+		let mut extended = first.clone();
+		assert!(extended.extend_from_urls(urls(MAX_TELEMETRY_ENDPOINTS)).is_err());
+		assert_eq!(extended, first);
+		extended.extend_from_urls(urls(0)).unwrap();
+		assert_eq!(extended, first);
+	}
+
+	#[test]
+	fn verbosity_above_maximum_is_rejected() {
+		let endp = vec![
+			("wss://telemetry.polkadot.io/submit/".into(), MAX_VERBOSITY),
+			("/ip4/80.123.90.4/tcp/5432".into(), 200),
+		];
+
+		let err = TelemetryEndpoints::new(endp.clone()).unwrap_err();
+		assert_eq!(build(&endp).unwrap_err().to_string(), err.to_string());
+		assert_eq!(err.entries.len(), 1);
+		assert_eq!(err.entries[0].index, 1);
+		assert_eq!(err.entries[0].url, "/ip4/80.123.90.4/tcp/5432");
+		assert!(matches!(
+			err.entries[0].reason,
+			InvalidEndpointReason::VerbosityTooHigh(200)
+		));
+
+		let json = r#"[["wss://telemetry.polkadot.io/submit/", 9], ["/ip4/80.123.90.4/tcp/5432", 200]]"#;
+		let err = serde_json::from_str::<TelemetryEndpoints>(json).unwrap_err();
+		assert!(err.to_string().contains("/ip4/80.123.90.4/tcp/5432"), "{}", err);
+
+		let telem = TelemetryEndpoints::new_clamped(endp).unwrap();
+		assert_eq!(telem.0[0].verbosity, MAX_VERBOSITY);
+		assert_eq!(telem.0[1].verbosity, MAX_VERBOSITY);
+	}
+
+	#[test]
+	fn verbosity_above_maximum_is_rejected_in_every_entry_form() {
+		for json in &[
+			r#"[["/ip4/80.123.90.4/tcp/5432", 99]]"#,
+			r#"[["/ip4/80.123.90.4/tcp/5432", 99, false]]"#,
+			r#"[{"url": "/ip4/80.123.90.4/tcp/5432", "verbosity": 99, "group": "main"}]"#,
+		] {
+			let err = serde_json::from_str::<TelemetryEndpoints>(json).unwrap_err();
+			assert!(
+				err.to_string().contains(
+					"#0 /ip4/80.123.90.4/tcp/5432: verbosity 99 is above the maximum of 9"
+				),
+				"{}: {}",
+				json,
+				err,
+			);
+		}
+	}
+
+	#[test]
+	fn every_invalid_endpoint_is_reported() {
+		let endp = vec![
+			("wss://telemetry.polkadot.io/submit/".into(), 0),
+			("/ip4/...80.123.90.4/tcp/5432".into(), 3),
+			("/ip4/80.123.90.4/tcp/5432".into(), 4),
+			("/ip4/no:!?;rlkqre;;::::///tcp/5432".into(), 4),
+		];
+
+		assert_eq!(
+			build(&endp).unwrap_err().to_string(),
+			TelemetryEndpoints::new(endp.clone()).unwrap_err().to_string(),
+		);
+		let err = TelemetryEndpoints::new(endp).unwrap_err();
+		let indexes = err.entries.iter().map(|e| e.index).collect::<Vec<_>>();
+		assert_eq!(indexes, vec![1, 3]);
+
+		let message = err.to_string();
+		assert!(message.contains("/ip4/...80.123.90.4/tcp/5432"), "{}", message);
+		assert!(message.contains("/ip4/no:!?;rlkqre;;::::///tcp/5432"), "{}", message);
+		assert!(!message.contains("telemetry.polkadot.io"), "{}", message);
+
+		let json = r#"[["/ip4/80.123.90.4/tcp/5432", 0], ["not a url", 0]]"#;
+		let err = serde_json::from_str::<TelemetryEndpoints>(json).unwrap_err();
+		assert!(err.to_string().contains("not a url"), "{}", err);
+	}
+
+	#[test]
+	fn number_of_endpoints_is_limited() {
+		let endp = (0..=MAX_TELEMETRY_ENDPOINTS)
+			.map(|i| (format!("/ip4/80.123.90.{}/tcp/5432", i), 0))
+			.collect::<Vec<_>>();
+		assert_eq!(endp.len(), 17);
+
+		let err = TelemetryEndpoints::new(endp.clone()).unwrap_err();
+		assert_eq!(err.entries.len(), 1);
+		assert_eq!(err.entries[0].index, 16);
+		assert!(matches!(err.entries[0].reason, InvalidEndpointReason::TooManyEndpoints));
+		assert!(err.to_string().contains("maximum of 16 endpoints"), "{}", err);
+
+		let json = serde_json::to_string(&endp).unwrap();
+		let err = serde_json::from_str::<TelemetryEndpoints>(&json).unwrap_err();
+		assert!(err.to_string().contains("maximum of 16 endpoints"), "{}", err);
+
+		assert_eq!(TelemetryEndpoints::new_unchecked(endp.clone()).unwrap().0.len(), 17);
+		assert!(TelemetryEndpoints::new(endp[..16].to_vec()).is_ok());
+		assert!(TelemetryEndpoints::new_unchecked(vec![("not a url".into(), 0)]).is_err());
+	}
+
+	#[test]
+	fn parse_endpoint_with_verbosity() {
+		let endpoint: TelemetryEndpoint = "wss://telemetry.polkadot.io/submit/ 3".parse().unwrap();
+		assert_eq!(
+			endpoint.addr(),
+			&url_to_multiaddr("wss://telemetry.polkadot.io/submit/").unwrap(),
+		);
+		assert_eq!(endpoint.verbosity(), 3);
+
+		let endpoint: TelemetryEndpoint = " /ip4/80.123.90.4/tcp/5432 \t 9 ".parse().unwrap();
+		assert_eq!(endpoint.verbosity(), 9);
+
+		assert!(matches!(
+			"wss://telemetry.polkadot.io/submit/".parse::<TelemetryEndpoint>(),
+			Err(TelemetryEndpointParseError::MissingVerbosity)
+		));
+		assert!(matches!(
+			"wss://telemetry.polkadot.io/submit/ high".parse::<TelemetryEndpoint>(),
+			Err(TelemetryEndpointParseError::InvalidVerbosity(v)) if v == "high"
+		));
+		assert!(matches!(
+			"wss://telemetry.polkadot.io/submit/ 10".parse::<TelemetryEndpoint>(),
+			Err(TelemetryEndpointParseError::InvalidVerbosity(v)) if v == "10"
+		));
+		assert!(matches!(
+			"/ip4/no:!?;rlkqre;;::::///tcp/5432 0".parse::<TelemetryEndpoint>(),
+			Err(TelemetryEndpointParseError::InvalidUrl(_))
+		));
+	}
+
+	#[test]
+	fn endpoints_try_from_parsed_endpoints() {
+		let telem = vec![
+			"wss://telemetry.polkadot.io/submit/ 0",
+			"/ip4/80.123.90.4/tcp/5432 4",
+			"/dns/telemetry.polkadot.io/tcp/443/x-parity-wss/%2Fsubmit%2F 2",
+		]
+		.into_iter()
+		.map(|s| s.parse::<TelemetryEndpoint>().unwrap())
+		.collect::<Vec<_>>();
+		let telem = TelemetryEndpoints::try_from(telem).unwrap();
+
+		assert_eq!(
+			telem.0,
+			vec![
+				TelemetryEndpoint::new(
+					url_to_multiaddr("wss://telemetry.polkadot.io/submit/").unwrap(),
+					2
+				),
+				TelemetryEndpoint::new("/ip4/80.123.90.4/tcp/5432".parse().unwrap(), 4),
+			],
+		);
+
+		let addr = |i| format!("/ip4/10.0.0.{}/tcp/80/ws", i).parse().unwrap();
+		let too_many = (0..=MAX_TELEMETRY_ENDPOINTS)
+			.map(|i| TelemetryEndpoint::new(addr(i), 0))
+			.collect::<Vec<_>>();
+		let err = TelemetryEndpoints::try_from(too_many).unwrap_err();
+		assert_eq!(err.entries.len(), 1);
+		assert_eq!(err.entries[0].index, MAX_TELEMETRY_ENDPOINTS);
+		assert!(matches!(err.entries[0].reason, InvalidEndpointReason::TooManyEndpoints));
+	}
+
+	#[test]
+	fn builder_default_verbosity() {
+		let telem = TelemetryEndpoints::builder()
+			.add("wss://telemetry.polkadot.io/submit/")
+			.add_with_verbosity("/ip4/80.123.90.4/tcp/5432", 4)
+			.default_verbosity(2)
+			.add("/ip4/80.123.90.5/tcp/5432")
+			.build()
+			.unwrap();
+		let verbosities = telem.0.iter().map(|e| e.verbosity()).collect::<Vec<_>>();
+		assert_eq!(verbosities, vec![2, 4, 2]);
+
+		assert!(TelemetryEndpoints::builder().build().unwrap().is_empty());
+
+		let err = TelemetryEndpoints::builder()
+			.default_verbosity(MAX_VERBOSITY + 1)
+			.add("wss://telemetry.polkadot.io/submit/")
+			.add_with_verbosity("/ip4/80.123.90.4/tcp/5432", MAX_VERBOSITY)
+			.add("not a url")
+			.build()
+			.unwrap_err();
+		let indexes = err.entries.iter().map(|e| e.index).collect::<Vec<_>>();
+		assert_eq!(indexes, vec![0, 2]);
+	}
+
+	#[test]
+	fn disabled_endpoints() {
+		let json = r#"[
+			["wss://telemetry.polkadot.io/submit/", 0],
+			["/ip4/80.123.90.4/tcp/5432", 4, false],
+			["/ip4/80.123.90.5/tcp/5432", 1, true]
+		]"#;
+		let mut telem = serde_json::from_str::<TelemetryEndpoints>(json).unwrap();
+		let enabled = telem.0.iter().map(|e| e.enabled()).collect::<Vec<_>>();
+		assert_eq!(enabled, vec![true, false, true]);
+
+		// Only the disabled endpoints carry the flag.
+		assert_eq!(
+			serde_json::to_value(&telem).unwrap(),
+			serde_json::json!([
+				["/dns/telemetry.polkadot.io/tcp/443/x-parity-wss/%2Fsubmit%2F", 0],
+				["/ip4/80.123.90.4/tcp/5432", 4, false],
+				["/ip4/80.123.90.5/tcp/5432", 1],
+			]),
+		);
+
+		let addr: Multiaddr = "/ip4/80.123.90.5/tcp/5432".parse().unwrap();
+		assert!(telem.set_enabled(&addr, false));
+		assert!(!telem.0[2].enabled());
+		assert!(!telem.set_enabled(&"/ip4/10.0.0.1/tcp/80".parse().unwrap(), false));
+
+		// An endpoint enabled in either set stays enabled.
+		let merged = telem.merge(
+			TelemetryEndpoints::new(vec![("/ip4/80.123.90.4/tcp/5432".into(), 0)]).unwrap(),
+		)
+		.unwrap();
+		assert!(merged.0[1].enabled());
+		assert_eq!(merged.0[1].verbosity(), 4);
+		assert!(!merged.0[2].enabled());
+
+		let json = r#"[["/ip4/80.123.90.4/tcp/5432", 4, "no"]]"#;
+		assert!(serde_json::from_str::<TelemetryEndpoints>(json).is_err());
+	}
+
+	#[test]
+	fn connect_timeouts() {
+		let json = r#"[
+			["/ip4/80.123.90.4/tcp/5432", 4],
+			{"url": "/ip4/80.123.90.5/tcp/5432", "verbosity": 1, "connect_timeout_ms": 1500},
+			{"url": "/ip4/80.123.90.6/tcp/5432", "verbosity": 0, "enabled": false}
+		]"#;
+		let mut telem = serde_json::from_str::<TelemetryEndpoints>(json).unwrap();
+		let timeouts = telem.0.iter().map(|e| e.connect_timeout()).collect::<Vec<_>>();
+		assert_eq!(timeouts, vec![None, Some(Duration::from_millis(1500)), None]);
+		assert!(!telem.0[2].enabled());
+
+		// Only the endpoints with a timeout use the detailed form.
+		assert_eq!(
+			serde_json::to_value(&telem).unwrap(),
+			serde_json::json!([
+				["/ip4/80.123.90.4/tcp/5432", 4],
+				{"url": "/ip4/80.123.90.5/tcp/5432", "verbosity": 1, "enabled": true, "connect_timeout_ms": 1500},
+				["/ip4/80.123.90.6/tcp/5432", 0, false],
+			]),
+		);
+
+		let addr: Multiaddr = "/ip4/80.123.90.4/tcp/5432".parse().unwrap();
+		assert!(telem.set_connect_timeout(&addr, Some(Duration::from_secs(3))));
+		assert_eq!(telem.0[0].connect_timeout(), Some(Duration::from_secs(3)));
+		assert!(!telem.set_connect_timeout(&"/ip4/10.0.0.1/tcp/80".parse().unwrap(), None));
+
+		let json = r#"[{"url": "/ip4/80.123.90.4/tcp/5432", "verbosity": 1, "timeout": 5}]"#;
+		assert!(serde_json::from_str::<TelemetryEndpoints>(json).is_err());
+	}
+
+	#[test]
+	fn dedup_windows() {
+		let json = r#"[
+			["/ip4/80.123.90.4/tcp/5432", 4],
+			{"url": "/ip4/80.123.90.5/tcp/5432", "verbosity": 1, "dedup_window_ms": 10000}
+		]"#;
+		let mut telem = serde_json::from_str::<TelemetryEndpoints>(json).unwrap();
+		let windows = telem.0.iter().map(|e| e.dedup_window()).collect::<Vec<_>>();
+		assert_eq!(windows, vec![None, Some(Duration::from_secs(10))]);
+
+		let addr: Multiaddr = "/ip4/80.123.90.4/tcp/5432".parse().unwrap();
+		assert!(telem.set_dedup_window(&addr, Some(Duration::from_millis(1500))));
+		assert_eq!(
+			serde_json::to_value(&telem).unwrap(),
+			serde_json::json!([
+				{
+					"url": "/ip4/80.123.90.4/tcp/5432",
+					"verbosity": 4,
+					"enabled": true,
+					"dedup_window_ms": 1500,
+				},
+				{
+					"url": "/ip4/80.123.90.5/tcp/5432",
+					"verbosity": 1,
+					"enabled": true,
+					"dedup_window_ms": 10000,
+				},
+			]),
+		);
+	}
+
+	#[test]
+	fn failover_groups() {
+		let json = r#"[
+			{"url": "/ip4/80.123.90.4/tcp/5432", "verbosity": 0, "group": "main"},
+			{"url": "/ip4/80.123.90.5/tcp/5432", "verbosity": 0, "group": "main", "priority": 1},
+			["/ip4/80.123.90.6/tcp/5432", 0]
+		]"#;
+		let mut telem = serde_json::from_str::<TelemetryEndpoints>(json).unwrap();
+		let groups = telem.0.iter().map(|e| (e.group(), e.priority())).collect::<Vec<_>>();
+		assert_eq!(groups, vec![(Some("main"), 0), (Some("main"), 1), (None, 0)]);
+
+		let addr: Multiaddr = "/ip4/80.123.90.6/tcp/5432".parse().unwrap();
+		assert!(telem.set_group(&addr, Some(("backup".into(), 2))));
+		assert!(telem.set_group(&"/ip4/80.123.90.4/tcp/5432".parse().unwrap(), None));
+		assert_eq!(
+			serde_json::to_value(&telem).unwrap(),
+			serde_json::json!([
+				["/ip4/80.123.90.4/tcp/5432", 0],
+				{
+					"url": "/ip4/80.123.90.5/tcp/5432",
+					"verbosity": 0,
+					"enabled": true,
+					"group": "main",
+					"priority": 1,
+				},
+				{
+					"url": "/ip4/80.123.90.6/tcp/5432",
+					"verbosity": 0,
+					"enabled": true,
+					"group": "backup",
+					"priority": 2,
+				},
+			]),
+		);
+
+		// The group of the endpoints of `self` is kept.
+		let mut other =
+			TelemetryEndpoints::new(vec![("/ip4/80.123.90.6/tcp/5432".into(), 0)]).unwrap();
+		other.set_group(&addr, Some(("main".into(), 0)));
+		let merged = telem.merge(other).unwrap();
+		assert_eq!(merged.0[2].group(), Some("backup"));
+	}
+
+	#[test]
+	fn target_verbosities() {
+		let json = r#"[
+			{"url": "/ip4/80.123.90.4/tcp/5432", "verbosity": 0, "target_verbosity": {"afg.": 4}},
+			["/ip4/80.123.90.5/tcp/5432", 1]
+		]"#;
+		let mut telem = serde_json::from_str::<TelemetryEndpoints>(json).unwrap();
+		assert_eq!(telem.0[0].target_verbosity().get("afg."), Some(&4));
+		assert_eq!(telem.0[0].max_verbosity(), 4);
+
+		let addr: Multiaddr = "/ip4/80.123.90.5/tcp/5432".parse().unwrap();
+		assert!(telem.set_target_verbosity(&addr, "block.", Some(12)));
+		let first: Multiaddr = "/ip4/80.123.90.4/tcp/5432".parse().unwrap();
+		assert!(telem.set_target_verbosity(&first, "afg.", None));
+		assert_eq!(
+			serde_json::to_value(&telem).unwrap(),
+			serde_json::json!([
+				["/ip4/80.123.90.4/tcp/5432", 0],
+				{
+					"url": "/ip4/80.123.90.5/tcp/5432",
+					"verbosity": 1,
+					"enabled": true,
+					"target_verbosity": {"block.": MAX_VERBOSITY},
+				},
+			]),
+		);
+
+		// The highest verbosity of a target is kept.
+		let mut other =
+			TelemetryEndpoints::new(vec![("/ip4/80.123.90.5/tcp/5432".into(), 0)]).unwrap();
+		other.set_target_verbosity(&addr, "block.", Some(2));
+		other.set_target_verbosity(&addr, "afg.", Some(3));
+		let merged = telem.merge(other).unwrap();
+		assert_eq!(merged.0[1].target_verbosity().get("block."), Some(&MAX_VERBOSITY));
+		assert_eq!(merged.0[1].target_verbosity().get("afg."), Some(&3));
+
+		let json = r#"[
+			{"url": "/ip4/80.123.90.4/tcp/5432", "verbosity": 0, "target_verbosity": {"afg.": 10}}
+		]"#;
+		assert!(serde_json::from_str::<TelemetryEndpoints>(json).is_err());
+	}
+
+	#[test]
+	fn endpoints_as_urls() {
+		for url in &[
+			"wss://telemetry.polkadot.io:443/submit/",
+			"ws://127.0.0.1:8000/",
+			"wss://[2001:db8::1]:443/submit",
+			"wss://telemetry.polkadot.io:443/submit/?chain=foo&env=staging",
+		] {
+			let addr = url_to_multiaddr(url).unwrap();
+			assert_eq!(multiaddr_to_url(&addr).as_deref(), Some(*url));
+		}
+
+		let addr = "/dns/telemetry.polkadot.io/tcp/443/x-parity-wss/%2Fsubmit%2F".parse().unwrap();
+		assert_eq!(
+			multiaddr_to_url(&addr).unwrap(),
+			"wss://telemetry.polkadot.io:443/submit/",
+		);
+
+		for addr in &["/ip4/80.123.90.4/tcp/5432", "/memory/1", "/ip4/80.123.90.4/udp/5432/ws"] {
+			assert_eq!(multiaddr_to_url(&addr.parse().unwrap()), None);
+		}
+
+		let mut telem = TelemetryEndpoints::new(vec![
+			("wss://telemetry.polkadot.io/submit/".into(), 0),
+			("/ip4/80.123.90.4/tcp/5432".into(), 4),
+		])
+		.unwrap();
+		assert_eq!(
+			telem.to_string(),
+			"wss://telemetry.polkadot.io:443/submit/ 0, /ip4/80.123.90.4/tcp/5432 4",
+		);
+		let endpoint: TelemetryEndpoint = telem.0[0].to_string().parse().unwrap();
+		assert_eq!(endpoint, telem.0[0]);
+
+		telem.set_enabled(&"/ip4/80.123.90.4/tcp/5432".parse().unwrap(), false);
+		assert_eq!(telem.0[1].to_string(), "/ip4/80.123.90.4/tcp/5432 4 (disabled)");
+	}
+
+	#[test]
+	fn query_strings_are_kept_in_the_websocket_path() {
+		let addr = url_to_multiaddr("wss://telemetry.polkadot.io/submit/?chain=foo&env=staging")
+			.unwrap();
+		assert_eq!(
+			addr.iter().last(),
+			Some(Protocol::Wss("/submit/?chain=foo&env=staging".into())),
+		);
+
+		for url in &["wss://telemetry.polkadot.io/submit/", "wss://telemetry.polkadot.io/submit/?"] {
+			let addr = url_to_multiaddr(url).unwrap();
+			assert_eq!(addr.iter().last(), Some(Protocol::Wss("/submit/".into())));
+		}
+
+		// The fragment would not reach the server.
+		assert!(url_to_multiaddr("wss://telemetry.polkadot.io/submit/?chain=foo#top").is_err());
+
+		let addr = url_to_multiaddr("ws://127.0.0.1:8000?chain=foo").unwrap();
+		assert_eq!(multiaddr_to_url(&addr).unwrap(), "ws://127.0.0.1:8000/?chain=foo");
+	}
+
+	#[test]
+	fn ipv6_endpoints() {
+		let ip = "2001:db8::1".parse().unwrap();
+		let expected = Multiaddr::empty()
+			.with(Protocol::Ip6(ip))
+			.with(Protocol::Tcp(443))
+			.with(Protocol::Wss("/submit/".into()));
+		assert_eq!(
+			url_to_multiaddr("wss://[2001:db8::1]:443/submit/").unwrap(),
+			expected
+		);
+
+		let expected = Multiaddr::empty()
+			.with(Protocol::Ip6(ip))
+			.with(Protocol::Tcp(443))
+			.with(Protocol::Wss("/".into()));
+		assert_eq!(url_to_multiaddr("wss://[2001:db8::1]/").unwrap(), expected);
+		assert_eq!(
+			url_to_multiaddr("/ip6/2001:db8::1/tcp/443/wss").unwrap(),
+			expected
+		);
+
+		let expected = Multiaddr::empty()
+			.with(Protocol::Ip6(ip))
+			.with(Protocol::Tcp(443))
+			.with(Protocol::Wss("/submit".into()));
+		assert_eq!(
+			url_to_multiaddr("wss://[2001:db8::1]:443/submit").unwrap(),
+			expected
+		);
+
+		let expected = Multiaddr::empty()
+			.with(Protocol::Dns6("telemetry.example.com".into()))
+			.with(Protocol::Tcp(443))
+			.with(Protocol::Wss("/".into()));
+		assert_eq!(
+			url_to_multiaddr("/dns6/telemetry.example.com/tcp/443/wss").unwrap(),
+			expected
+		);
+
+		let err = url_to_multiaddr("/ip6/2001:db8::zz/tcp/443/wss").unwrap_err();
+		assert!(matches!(err, AddrParseError::Multiaddr(_)));
+		assert!(err.to_string().contains("multiaddress"), "{}", err);
+
+		let err = url_to_multiaddr("wss://[2001:db8::1/submit/").unwrap_err();
+		assert!(matches!(err, AddrParseError::Url(_)));
+		assert!(err.to_string().contains("URL"), "{}", err);
+	}
+
+	#[test]
+	fn file_endpoints() {
+		let url = "file:///var/log/telemetry.jsonl";
+		let endpoint = format!("{} 2", url).parse::<TelemetryEndpoint>().unwrap();
+		let path = PathBuf::from("/var/log/telemetry.jsonl");
+		assert_eq!(endpoint.kind(), &EndpointKind::File(path.clone()));
+		assert_eq!(endpoint.to_string(), format!("{} 2", url));
+		let endpoint = "file://localhost/var/log/telemetry.jsonl 2".parse::<TelemetryEndpoint>();
+		assert_eq!(endpoint.unwrap().kind(), &EndpointKind::File(path));
+		for url in &["file://telemetry.jsonl", "file://host/telemetry.jsonl", "file:///a?b"] {
+			let err = TelemetryEndpoints::new(vec![(url.to_string(), 0)]).unwrap_err();
+			let reason = &err.entries[0].reason;
+			assert!(
+				matches!(reason, InvalidEndpointReason::InvalidUrl(AddrParseError::Url(_))),
+				"{}",
+				url,
+			);
+		}
+
+		let endpoints = TelemetryEndpoints::new(vec![(url.into(), 2)]).unwrap();
+		let json = serde_json::to_string(&endpoints).unwrap();
+		assert_eq!(json, r#"[["file:///var/log/telemetry.jsonl",2]]"#);
+		assert_eq!(serde_json::from_str::<TelemetryEndpoints>(&json).unwrap(), endpoints);
+	}
+
+	#[test]
+	fn unix_socket_addresses_are_rejected() {
+		let addr = Multiaddr::empty().with(Protocol::Unix("/etc/cron.d/telemetry".into()));
+		assert_eq!(multiaddr_to_url(&addr), None);
+		for url in &["/unix/telemetry.sock", "unix:///etc/cron.d/telemetry"] {
+			assert!(matches!(url_to_multiaddr(url), Err(AddrParseError::Unix)), "{}", url);
+		}
+
+		let json = r#"[["/unix/telemetry.sock", 0]]"#;
+		let err = serde_json::from_str::<TelemetryEndpoints>(json).unwrap_err();
+		assert!(err.to_string().contains("file://"), "{}", err);
+	}
+
+	#[test]
+	fn log_endpoint() {
+		let endpoint = "log: 9".parse::<TelemetryEndpoint>().unwrap();
+		assert_eq!(endpoint.kind(), &EndpointKind::Log);
+		assert_eq!(endpoint.to_string(), "log: 9");
+		assert_eq!(multiaddr_to_url(endpoint.addr()), None);
+
+		let endpoints = TelemetryEndpoints::new(vec![
+			("log:".into(), 9),
+			("wss://telemetry.polkadot.io/submit/".into(), 0),
+		])
+		.unwrap();
+		let json = serde_json::to_string(&endpoints).unwrap();
+		assert!(json.starts_with(r#"[["log:",9],"#), "{}", json);
+		assert_eq!(serde_json::from_str::<TelemetryEndpoints>(&json).unwrap(), endpoints);
+	}
 }
@@ -0,0 +1,133 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::StatsCounters;
+use futures::{channel::mpsc, prelude::*};
+use libp2p::Multiaddr;
+use std::pin::Pin;
+use std::sync::{
+	atomic::{AtomicU64, Ordering},
+	Arc,
+};
+use std::task::{Context, Poll};
+use tracing::Id;
+
+/// Number of [`TelemetryEvent`]s that can be queued before new events are dropped.
+const EVENT_BUFFER_SIZE: usize = 64;
+
+/// Event reported by the telemetry about its own operation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TelemetryEvent {
+	/// The connection to a telemetry server has been established.
+	Connected(Multiaddr),
+	/// The connection to a telemetry server has been lost.
+	Disconnected(Multiaddr),
+	/// A connection attempt to a telemetry server failed, e.g. because its certificate doesn't
+	/// match [`TelemetryConfig::certificate_pins`](crate::TelemetryConfig::certificate_pins).
+	ConnectionFailed {
+		/// Address of the telemetry server.
+		addr: Multiaddr,
+		/// Why the connection failed.
+		error: String,
+	},
+	/// A telemetry message of the given span id has been dropped because the buffer between the
+	/// [`TelemetryLayer`](crate::TelemetryLayer) and the
+	/// [`TelemetryWorker`](crate::TelemetryWorker) is full.
+	Overflow {
+		/// Span id of the dropped message.
+		id: Id,
+	},
+	/// Messages for a telemetry server have been dropped because its queue is full.
+	Dropped {
+		/// Address of the telemetry server.
+		addr: Multiaddr,
+		/// Total number of messages dropped for this telemetry server so far.
+		total: u64,
+	},
+	/// A telemetry message could not be serialized.
+	SerializationError(String),
+}
+
+/// Create a new channel of [`TelemetryEvent`]s.
+pub(crate) fn event_channel() -> (EventSender, TelemetryEvents) {
+	let (sender, receiver) = mpsc::channel(EVENT_BUFFER_SIZE);
+	let dropped = Arc::new(AtomicU64::new(0));
+
+	(
+		EventSender {
+			sender,
+			dropped: dropped.clone(),
+			stats: Arc::new(StatsCounters::default()),
+		},
+		TelemetryEvents { receiver, dropped },
+	)
+}
+
+/// Sending side of the [`TelemetryEvent`]s channel, along with the counters of the
+/// [`TelemetryStats`](crate::TelemetryStats).
+///
+/// Sending never blocks: the events are dropped and counted if the channel is full.
+#[derive(Debug, Clone)]
+pub(crate) struct EventSender {
+	sender: mpsc::Sender<TelemetryEvent>,
+	dropped: Arc<AtomicU64>,
+	stats: Arc<StatsCounters>,
+}
+
+impl EventSender {
+	/// Send an event, or count it as dropped if the channel is full.
+	pub(crate) fn send(&mut self, event: TelemetryEvent) {
+		match self.sender.try_send(event) {
+			Err(err) if err.is_full() => {
+				self.dropped.fetch_add(1, Ordering::Relaxed);
+			}
+			// Nobody is listening.
+			_ => {}
+		}
+	}
+
+	/// Counters of the statistics about the telemetry messages.
+	pub(crate) fn stats(&self) -> &Arc<StatsCounters> {
+		&self.stats
+	}
+}
+
+/// Stream of the [`TelemetryEvent`]s reported by the telemetry.
+///
+/// This stream is bounded: when it is not consumed fast enough, the new events are dropped and
+/// counted in [`TelemetryEvents::dropped`].
+#[derive(Debug)]
+pub struct TelemetryEvents {
+	receiver: mpsc::Receiver<TelemetryEvent>,
+	dropped: Arc<AtomicU64>,
+}
+
+impl TelemetryEvents {
+	/// Number of events that have been dropped because this stream was full.
+	pub fn dropped(&self) -> u64 {
+		self.dropped.load(Ordering::Relaxed)
+	}
+}
+
+impl Stream for TelemetryEvents {
+	type Item = TelemetryEvent;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		self.receiver.poll_next_unpin(cx)
+	}
+}
@@ -0,0 +1,264 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use libp2p::Multiaddr;
+use std::collections::HashMap;
+
+/// State of a member of a failover group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MemberState {
+	/// Connected, or disconnected for less than the failover delay.
+	Available,
+	/// Disconnected for longer than the failover delay.
+	Unavailable,
+	/// Disabled: it never receives the telemetry.
+	Disabled,
+}
+
+/// Failover groups of telemetry endpoints.
+///
+/// Only the active member of a group receives the telemetry: the first available member in the
+/// order of priority. When no member is available, the active member is kept so that the
+/// telemetry doesn't move around while every server of the group is down.
+#[derive(Debug, Default)]
+pub(crate) struct FailoverGroups {
+	groups: HashMap<String, Group>,
+	/// Group of each member.
+	membership: HashMap<Multiaddr, String>,
+}
+
+#[derive(Debug, Default)]
+struct Group {
+	/// Members sorted by priority, and then by order of insertion.
+	members: Vec<(u8, Multiaddr)>,
+	active: Option<Multiaddr>,
+}
+
+/// Change of the active member of a failover group.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Switch {
+	pub(crate) group: String,
+	pub(crate) from: Option<Multiaddr>,
+	pub(crate) to: Option<Multiaddr>,
+}
+
+impl FailoverGroups {
+	/// Add `addr` to `group`, unless it is already a member of a group.
+	///
+	/// Returns `false` if `addr` is already a member of a group.
+	pub(crate) fn insert(&mut self, group: &str, priority: u8, addr: Multiaddr) -> bool {
+		if self.membership.contains_key(&addr) {
+			return false;
+		}
+
+		let members = &mut self.groups.entry(group.into()).or_default().members;
+		let position = members
+			.iter()
+			.position(|(other, _)| *other > priority)
+			.unwrap_or(members.len());
+		members.insert(position, (priority, addr.clone()));
+		self.membership.insert(addr, group.into());
+		true
+	}
+
+	/// Remove the members for which `keep` returns `false`.
+	pub(crate) fn retain(&mut self, mut keep: impl FnMut(&Multiaddr) -> bool) {
+		let groups = &mut self.groups;
+		self.membership.retain(|addr, group| {
+			if keep(addr) {
+				return true;
+			}
+			let members = &mut groups.get_mut(group).expect("members have a group; qed").members;
+			members.retain(|(_, member)| member != addr);
+			if members.is_empty() {
+				groups.remove(group);
+			}
+			false
+		});
+	}
+
+	/// Return `true` if there are no groups.
+	pub(crate) fn is_empty(&self) -> bool {
+		self.groups.is_empty()
+	}
+
+	/// Return `true` if `addr` is a member of a group but not its active member.
+	pub(crate) fn is_standby(&self, addr: &Multiaddr) -> bool {
+		match self.membership.get(addr) {
+			Some(group) => self.groups[group].active.as_ref() != Some(addr),
+			None => false,
+		}
+	}
+
+	/// Members that are preferred to the active member of their group. They must keep
+	/// reconnecting so that the telemetry can switch back to them.
+	pub(crate) fn preferred_standby(&self) -> impl Iterator<Item = &Multiaddr> {
+		self.groups.values().flat_map(|group| {
+			group
+				.members
+				.iter()
+				.map(|(_, addr)| addr)
+				.take_while(move |addr| Some(*addr) != group.active.as_ref())
+		})
+	}
+
+	/// Elect the active member of every group given the `state` of its members.
+	pub(crate) fn update(&mut self, state: impl Fn(&Multiaddr) -> MemberState) -> Vec<Switch> {
+		let mut switches = Vec::new();
+
+		for (name, group) in &mut self.groups {
+			let elected = {
+				let mut members = group.members.iter().map(|(_, addr)| addr);
+				members
+					.clone()
+					.find(|addr| state(addr) == MemberState::Available)
+					.or_else(|| {
+						group
+							.active
+							.as_ref()
+							.filter(|addr| state(addr) != MemberState::Disabled)
+					})
+					.or_else(|| members.find(|addr| state(addr) != MemberState::Disabled))
+					.cloned()
+			};
+
+			if elected != group.active {
+				switches.push(Switch {
+					group: name.clone(),
+					from: group.active.take(),
+					to: elected.clone(),
+				});
+				group.active = elected;
+			}
+		}
+
+		switches
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn addr(port: u16) -> Multiaddr {
+		format!("/ip4/127.0.0.1/tcp/{}/ws", port).parse().unwrap()
+	}
+
+	/// State of the members when `down` is in the given state and the others are available.
+	fn all_but(down: Multiaddr, state: MemberState) -> impl Fn(&Multiaddr) -> MemberState {
+		move |addr| {
+			if *addr == down {
+				state
+			} else {
+				MemberState::Available
+			}
+		}
+	}
+
+	#[test]
+	fn members_are_elected_by_priority() {
+		let mut groups = FailoverGroups::default();
+		assert!(groups.insert("main", 1, addr(2)));
+		assert!(groups.insert("main", 0, addr(1)));
+		assert!(groups.insert("main", 1, addr(3)));
+		assert!(!groups.insert("other", 0, addr(3)));
+
+		let switches = groups.update(|_| MemberState::Available);
+		assert_eq!(
+			switches,
+			vec![Switch {
+				group: "main".into(),
+				from: None,
+				to: Some(addr(1)),
+			}],
+		);
+		assert!(!groups.is_standby(&addr(1)));
+		assert!(groups.is_standby(&addr(2)));
+		assert!(!groups.is_standby(&addr(4)));
+		assert_eq!(groups.preferred_standby().count(), 0);
+
+		// The members with the same priority are elected in order of insertion.
+		groups.update(all_but(addr(1), MemberState::Unavailable));
+		assert!(!groups.is_standby(&addr(2)));
+		assert_eq!(groups.preferred_standby().collect::<Vec<_>>(), vec![&addr(1)]);
+	}
+
+	#[test]
+	fn telemetry_fails_over_and_back() {
+		let mut groups = FailoverGroups::default();
+		groups.insert("main", 0, addr(1));
+		groups.insert("main", 1, addr(2));
+		groups.update(|_| MemberState::Available);
+
+		let switches = groups.update(all_but(addr(1), MemberState::Unavailable));
+		assert_eq!(
+			switches,
+			vec![Switch {
+				group: "main".into(),
+				from: Some(addr(1)),
+				to: Some(addr(2)),
+			}],
+		);
+		assert!(groups.update(all_but(addr(1), MemberState::Unavailable)).is_empty());
+
+		// The backup stays active while every member is down.
+		assert!(groups.update(|_| MemberState::Unavailable).is_empty());
+		assert!(!groups.is_standby(&addr(2)));
+
+		let switches = groups.update(|_| MemberState::Available);
+		assert_eq!(switches[0].to, Some(addr(1)));
+		assert!(groups.is_standby(&addr(2)));
+	}
+
+	#[test]
+	fn removed_members_leave_their_group() {
+		let mut groups = FailoverGroups::default();
+		groups.insert("main", 0, addr(1));
+		groups.insert("main", 1, addr(2));
+		groups.update(|_| MemberState::Available);
+
+		groups.retain(|addr| *addr != self::addr(1));
+		let switches = groups.update(|_| MemberState::Available);
+		assert_eq!(switches[0].to, Some(addr(2)));
+
+		groups.retain(|_| false);
+		assert!(groups.groups.is_empty());
+		assert!(groups.insert("other", 0, addr(1)));
+	}
+
+	#[test]
+	fn disabled_members_are_never_elected() {
+		let mut groups = FailoverGroups::default();
+		groups.insert("main", 0, addr(1));
+		groups.insert("main", 1, addr(2));
+
+		groups.update(|addr| {
+			if *addr == self::addr(1) {
+				MemberState::Disabled
+			} else {
+				MemberState::Unavailable
+			}
+		});
+		assert!(!groups.is_standby(&addr(2)));
+
+		let switches = groups.update(|_| MemberState::Disabled);
+		assert_eq!(switches[0].to, None);
+		assert!(groups.is_standby(&addr(1)));
+		assert!(groups.is_standby(&addr(2)));
+	}
+}
@@ -0,0 +1,364 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::TelemetrySink;
+use futures::{
+	channel::{mpsc, oneshot},
+	future::BoxFuture,
+	prelude::*,
+	ready,
+	stream::BoxStream,
+};
+use libp2p::{
+	core::transport::{ListenerEvent, TransportError},
+	Multiaddr, Transport,
+};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+use wasm_timer::{Delay, Instant};
+
+/// Number of requests that can wait for the thread of a [`FileSink`].
+const QUEUE_LEN: usize = 64;
+
+/// Request to the thread of a [`FileSink`].
+#[derive(Debug)]
+enum Request {
+	/// Append lines to the file.
+	Write(Vec<u8>),
+	/// Sync the file to the disk, once the lines requested before are written.
+	Sync(oneshot::Sender<io::Result<()>>),
+}
+
+/// [`TelemetrySink`] appending the telemetry messages to a file, one JSON object per line.
+///
+/// The file is written and synced by a dedicated thread, so that a slow disk doesn't block the
+/// worker. An error while writing is returned by the next [`TelemetrySink::flush`], and the lines
+/// are dropped until then.
+#[derive(Debug)]
+pub struct FileSink {
+	requests: mpsc::Sender<Request>,
+}
+
+impl FileSink {
+	/// Open the file at `path` in append mode, creating it if it doesn't exist.
+	pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+		let file = OpenOptions::new().create(true).append(true).open(path)?;
+		Self::spawn(move || Ok(file))
+	}
+
+	/// Open the file at `path` from the thread of the sink, in append mode, creating it and its
+	/// directory if needed. An error while opening the file is returned by the first flush.
+	fn open_in_thread(path: PathBuf) -> io::Result<Self> {
+		Self::spawn(move || {
+			if let Some(dir) = path.parent() {
+				fs::create_dir_all(dir)?;
+			}
+			OpenOptions::new().create(true).append(true).open(&path)
+		})
+	}
+
+	/// Start the thread writing to the file returned by `open`.
+	fn spawn<F>(open: F) -> io::Result<Self>
+	where
+		F: FnOnce() -> io::Result<File> + Send + 'static,
+	{
+		let (requests, receiver) = mpsc::channel(QUEUE_LEN);
+		thread::Builder::new()
+			.name("telemetry-file".into())
+			.spawn(move || write_file(open(), receiver))?;
+		Ok(FileSink { requests })
+	}
+
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		self.requests.poll_ready(cx).map_err(|_| stopped())
+	}
+
+	fn start_send(&mut self, request: Request) -> io::Result<()> {
+		self.requests.start_send(request).map_err(|_| stopped())
+	}
+}
+
+impl TelemetrySink for FileSink {
+	fn send<'a>(&'a mut self, _: &'a str, message: &'a str) -> BoxFuture<'a, io::Result<()>> {
+		let mut line = Vec::with_capacity(message.len() + 1);
+		line.extend_from_slice(message.as_bytes());
+		line.push(b'\n');
+		async move {
+			future::poll_fn(|cx| self.poll_ready(cx)).await?;
+			self.start_send(Request::Write(line))
+		}
+		.boxed()
+	}
+
+	fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+		async move {
+			future::poll_fn(|cx| self.poll_ready(cx)).await?;
+			let (done, synced) = oneshot::channel();
+			self.start_send(Request::Sync(done))?;
+			synced.await.unwrap_or_else(|_| Err(stopped()))
+		}
+		.boxed()
+	}
+}
+
+/// Serve the requests of a [`FileSink`] until it is dropped, or until the first sync if the file
+/// could not be opened.
+fn write_file(file: io::Result<File>, requests: mpsc::Receiver<Request>) {
+	let mut requests = futures::executor::block_on_stream(requests);
+	let mut file = match file {
+		Ok(file) => file,
+		Err(err) => {
+			let sync = requests.find_map(|request| match request {
+				Request::Sync(done) => Some(done),
+				Request::Write(_) => None,
+			});
+			if let Some(done) = sync {
+				let _ = done.send(Err(err));
+			}
+			return;
+		}
+	};
+
+	let mut error = None;
+	for request in requests {
+		match request {
+			Request::Write(lines) => {
+				if error.is_none() {
+					error = file.write_all(&lines).err();
+				}
+			}
+			Request::Sync(done) => {
+				let result = match error.take() {
+					Some(err) => Err(err),
+					None => file.sync_data(),
+				};
+				// The requester may have given up.
+				let _ = done.send(result);
+			}
+		}
+	}
+}
+
+fn stopped() -> io::Error {
+	io::Error::new(io::ErrorKind::BrokenPipe, "the thread writing the telemetry file has stopped")
+}
+
+/// Transport of a `file://` telemetry endpoint: dialing opens its file with a [`FileSink`],
+/// creating the file and its directory if needed. The dialed address is ignored.
+///
+/// The connection is established right away, and a failure to open the file is reported by its
+/// first flush.
+#[derive(Debug, Clone)]
+pub(crate) struct FileTransport {
+	path: PathBuf,
+	sync_interval: Duration,
+}
+
+impl FileTransport {
+	/// Create a transport to the file at `path`, which is synced to the disk at most once per
+	/// `sync_interval`.
+	pub(crate) fn new(path: PathBuf, sync_interval: Duration) -> Self {
+		Self { path, sync_interval }
+	}
+}
+
+impl Transport for FileTransport {
+	type Output = FileConnection;
+	type Error = io::Error;
+	type Listener =
+		BoxStream<'static, Result<ListenerEvent<Self::ListenerUpgrade, Self::Error>, Self::Error>>;
+	type ListenerUpgrade = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+	type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+	fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+		Err(TransportError::MultiaddrNotSupported(addr))
+	}
+
+	fn dial(self, _: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+		let sync_interval = self.sync_interval;
+		let connection = FileSink::open_in_thread(self.path).map(|sink| FileConnection {
+			sink,
+			sync_interval,
+			synced_at: None,
+			dirty: false,
+			sync_timer: None,
+			syncing: None,
+		});
+		Ok(future::ready(connection).boxed())
+	}
+
+	fn address_translation(&self, _: &Multiaddr, _: &Multiaddr) -> Option<Multiaddr> {
+		None
+	}
+}
+
+/// File of a `file://` telemetry endpoint, to which every telemetry message is appended as a
+/// line by its [`FileSink`].
+///
+/// Flushing syncs the file to the disk, unless it has already been synced less than the sync
+/// interval ago. The task is then woken up once the file can be synced again. Nothing is ever
+/// received from the file.
+pub(crate) struct FileConnection {
+	sink: FileSink,
+	sync_interval: Duration,
+	/// When the file has last been synced, if ever.
+	synced_at: Option<Instant>,
+	/// Whether lines have been written since the file has last been synced.
+	dirty: bool,
+	/// Fires once the file can be synced again.
+	sync_timer: Option<Delay>,
+	/// Result of the sync in progress, if any.
+	syncing: Option<oneshot::Receiver<io::Result<()>>>,
+}
+
+impl FileConnection {
+	/// Request a sync of the file, once the lines written so far reach it.
+	fn poll_start_sync(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		ready!(self.sink.poll_ready(cx))?;
+		let (done, synced) = oneshot::channel();
+		self.sink.start_send(Request::Sync(done))?;
+		self.dirty = false;
+		self.sync_timer = None;
+		self.synced_at = Some(Instant::now());
+		self.syncing = Some(synced);
+		Poll::Ready(Ok(()))
+	}
+
+	/// Wait for the sync in progress, if any.
+	fn poll_synced(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		if let Some(syncing) = &mut self.syncing {
+			let result = ready!(syncing.poll_unpin(cx));
+			self.syncing = None;
+			return Poll::Ready(result.unwrap_or_else(|_| Err(stopped())));
+		}
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl Stream for FileConnection {
+	type Item = Result<Vec<u8>, io::Error>;
+
+	fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		Poll::Pending
+	}
+}
+
+impl Sink<Vec<u8>> for FileConnection {
+	type Error = io::Error;
+
+	fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		self.get_mut().sink.poll_ready(cx)
+	}
+
+	fn start_send(self: Pin<&mut Self>, mut item: Vec<u8>) -> Result<(), Self::Error> {
+		let this = self.get_mut();
+		item.push(b'\n');
+		this.sink.start_send(Request::Write(item))?;
+		this.dirty = true;
+		Ok(())
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		let this = self.get_mut();
+		ready!(this.poll_synced(cx))?;
+		if !this.dirty {
+			return Poll::Ready(Ok(()));
+		}
+
+		// The lines are already on their way to the file, only their durability is delayed.
+		let sync_interval = this.sync_interval;
+		let remaining = this
+			.synced_at
+			.and_then(|synced_at| sync_interval.checked_sub(synced_at.elapsed()));
+		if let Some(remaining) = remaining {
+			let timer = this.sync_timer.get_or_insert_with(|| Delay::new(remaining));
+			if timer.poll_unpin(cx).is_pending() {
+				return Poll::Ready(Ok(()));
+			}
+		}
+		ready!(this.poll_start_sync(cx))?;
+		this.poll_synced(cx)
+	}
+
+	fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		let this = self.get_mut();
+		if this.dirty {
+			ready!(this.poll_synced(cx))?;
+			ready!(this.poll_start_sync(cx))?;
+		}
+		this.poll_synced(cx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::executor::block_on;
+
+	#[test]
+	fn file_sink_appends_one_message_per_line() {
+		let path = std::env::temp_dir()
+			.join(format!("sc-telemetry-file-sink-{}.jsonl", std::process::id()));
+		fs::write(&path, "{\"msg\":\"before\"}\n").unwrap();
+
+		let mut sink = FileSink::open(&path).unwrap();
+		block_on(async {
+			sink.send("file", r#"{"msg":"0"}"#).await.unwrap();
+			sink.send("file", r#"{"msg":"1"}"#).await.unwrap();
+			sink.flush().await.unwrap();
+		});
+
+		let content = fs::read_to_string(&path).unwrap();
+		fs::remove_file(&path).unwrap();
+		assert_eq!(content, "{\"msg\":\"before\"}\n{\"msg\":\"0\"}\n{\"msg\":\"1\"}\n");
+	}
+
+	#[test]
+	fn lines_are_appended_and_synced_at_most_once_per_interval() {
+		let path = std::env::temp_dir()
+			.join(format!("sc-telemetry-file-{}", std::process::id()))
+			.join("telemetry.jsonl");
+		let transport = FileTransport::new(path.clone(), Duration::from_secs(3600));
+
+		let mut conn = block_on(transport.dial(Multiaddr::empty()).unwrap()).unwrap();
+		block_on(async {
+			conn.send(br#"{"msg":"0"}"#.to_vec()).await.unwrap();
+			// The first flush syncs the file, the next one waits for the interval.
+			assert!(!conn.dirty);
+			conn.send(br#"{"msg":"1"}"#.to_vec()).await.unwrap();
+			assert!(conn.dirty);
+			assert!(conn.sync_timer.is_some());
+			conn.close().await.unwrap();
+			assert!(!conn.dirty);
+		});
+
+		let content = fs::read_to_string(&path).unwrap();
+		assert_eq!(content, "{\"msg\":\"0\"}\n{\"msg\":\"1\"}\n");
+
+		// The failure to open a file is reported by the first flush.
+		let transport = FileTransport::new(path.join("nested"), Duration::from_secs(3600));
+		let mut conn = block_on(transport.dial(Multiaddr::empty()).unwrap()).unwrap();
+		assert!(block_on(conn.send(br#"{"msg":"2"}"#.to_vec())).is_err());
+		fs::remove_dir_all(path.parent().unwrap()).unwrap();
+	}
+}
@@ -0,0 +1,197 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+	Register, TelemetrySender, TelemetrySpan, TelemetryWorker, TrySendError, SUBSTRATE_INFO,
+};
+use futures::{channel::mpsc, prelude::*};
+use std::{fmt, time::Duration};
+use tracing::Id;
+use wasm_timer::{Instant, Interval, TimerHandle};
+
+/// Fields of a `system.interval` message.
+pub type HeartbeatFields = serde_json::Map<String, serde_json::Value>;
+
+/// Periodic `system.interval` telemetry of a telemetry span.
+///
+/// At every interval, the fields returned by the callback are sent as a `system.interval`
+/// message with the [`SUBSTRATE_INFO`] verbosity, as if they had been logged in the span with
+/// the [`telemetry!`](crate::telemetry) macro. It should run as a background task using the
+/// [`TelemetryHeartbeat::run`] method, which completes once the [`TelemetryWorker`] has stopped.
+pub struct TelemetryHeartbeat {
+	id: Option<Id>,
+	message_sender: TelemetrySender,
+	register_sender: mpsc::UnboundedSender<Register>,
+	interval: Duration,
+	timer: Option<TimerHandle>,
+	fields: Box<dyn FnMut() -> HeartbeatFields + Send>,
+}
+
+impl TelemetryHeartbeat {
+	/// Create the heartbeat of `span`, sending the fields returned by `fields` to `worker` every
+	/// `interval`.
+	///
+	/// Nothing is sent if the span is disabled.
+	pub fn new(
+		worker: &TelemetryWorker,
+		span: &TelemetrySpan,
+		interval: Duration,
+		fields: impl FnMut() -> HeartbeatFields + Send + 'static,
+	) -> Self {
+		Self {
+			id: span.telemetry_id(),
+			message_sender: worker.sender(),
+			register_sender: worker.register_sender(),
+			interval,
+			timer: None,
+			fields: Box::new(fields),
+		}
+	}
+
+	/// Use `timer` instead of the default timer, for example a [`wasm_timer::Timer`] that is
+	/// advanced manually in tests.
+	pub fn with_timer(mut self, timer: TimerHandle) -> Self {
+		self.timer = Some(timer);
+		self
+	}
+
+	/// Send the `system.interval` messages until the worker stops.
+	pub async fn run(self) {
+		let Self {
+			id,
+			mut message_sender,
+			register_sender,
+			interval,
+			timer,
+			mut fields,
+		} = self;
+		let id = match id {
+			Some(id) => id,
+			None => return,
+		};
+		let mut ticks = match timer {
+			Some(timer) => Interval::new_handle(Instant::now() + interval, interval, timer),
+			None => Interval::new(interval),
+		};
+
+		while ticks.next().await.is_some() {
+			// The worker closes its registration channel when it stops, while the message
+			// channel stays open as long as the `TelemetryLayer` is alive.
+			if register_sender.is_closed() {
+				break;
+			}
+
+			let mut payload = fields();
+			payload.insert("msg".into(), "system.interval".into());
+			match message_sender.send_json(&id, SUBSTRATE_INFO, payload.into()) {
+				Ok(()) => {}
+				Err(TrySendError::Full) => log::debug!(
+					target: "telemetry",
+					"Skipping the system.interval telemetry: the queue to the worker is full",
+				),
+				Err(TrySendError::Disconnected) => break,
+				Err(err) => log::error!(
+					target: "telemetry",
+					"Could not serialize the system.interval telemetry: {}",
+					err,
+				),
+			}
+		}
+	}
+}
+
+impl fmt::Debug for TelemetryHeartbeat {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("TelemetryHeartbeat")
+			.field("id", &self.id)
+			.field("interval", &self.interval)
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::TelemetryLayer;
+	use futures::executor::LocalPool;
+	use futures::task::LocalSpawnExt;
+	use tracing_subscriber::prelude::*;
+	use wasm_timer::Timer;
+
+	#[test]
+	fn heartbeat_follows_the_clock_and_stops_with_the_worker() {
+		let (layer, worker) = TelemetryLayer::new(None, None).unwrap();
+		let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+		let span = tracing::dispatcher::with_default(&dispatch, TelemetrySpan::new);
+		let id = span.telemetry_id().unwrap();
+
+		let mut timer = Timer::new();
+		let interval = Duration::from_secs(5);
+		let mut height = 0;
+		let heartbeat = TelemetryHeartbeat::new(&worker, &span, interval, move || {
+			height += 1;
+			let mut fields = HeartbeatFields::new();
+			fields.insert("height".into(), height.into());
+			fields
+		})
+		.with_timer(timer.handle());
+		let receiver = worker.message_receiver();
+
+		let mut pool = LocalPool::new();
+		let stopped = pool.spawner().spawn_local_with_handle(heartbeat.run()).unwrap();
+		pool.run_until_stalled();
+		// The first tick is due at most one interval from now.
+		let start = Instant::now();
+		let mut advance_to = |pool: &mut LocalPool, at: Instant| {
+			pool.run_until_stalled();
+			// Registers the interval with the timer before firing it.
+			assert!((&mut timer).now_or_never().is_none());
+			timer.advance_to(at);
+			pool.run_until_stalled();
+		};
+		let received = || {
+			std::iter::from_fn(|| receiver.lock().try_next().ok().flatten())
+				.map(|message| {
+					let json: serde_json::Value = serde_json::from_str(&message.payload).unwrap();
+					(message.id, message.verbosity, json["payload"].clone())
+				})
+				.collect::<Vec<_>>()
+		};
+
+		advance_to(&mut pool, start + interval / 2);
+		assert!(received().is_empty());
+
+		advance_to(&mut pool, start + interval);
+		advance_to(&mut pool, start + interval * 2);
+		let expected = |height: u64| {
+			serde_json::json!({ "msg": "system.interval", "height": height })
+		};
+		assert_eq!(
+			received(),
+			vec![
+				(id.clone(), SUBSTRATE_INFO, expected(1)),
+				(id.clone(), SUBSTRATE_INFO, expected(2)),
+			],
+		);
+
+		drop(worker);
+		advance_to(&mut pool, start + interval * 3);
+		assert!(received().is_empty());
+		pool.run_until(stopped);
+	}
+}
@@ -16,21 +16,53 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::{initialize_transport, TelemetryWorker};
-use futures::channel::mpsc;
+use crate::{
+	initialize_transport, EventSender, OverflowPolicy, Register, StatsCounters, TelemetryConfig,
+	TelemetryEvent, TelemetryMessage, TelemetrySender, TelemetryTransport, TelemetryWorker,
+	TimestampFormat, SUBSTRATE_INFO,
+};
+use futures::{channel::mpsc, prelude::*};
 use libp2p::wasm_ext::ExtTransport;
 use parking_lot::Mutex;
 use std::convert::TryInto;
 use std::io;
-use tracing::{Event, Id, Subscriber};
+use std::sync::{
+	atomic::{AtomicU8, Ordering},
+	Arc,
+};
+use std::time::Duration;
+use tracing::{span::Attributes, Event, Id, Subscriber};
 use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
 
 /// Span name used to report the telemetry.
 pub const TELEMETRY_LOG_SPAN: &str = "telemetry-logger";
 
 /// `Layer` that handles the logs for telemetries.
+///
+/// The logs whose verbosity is higher than the verbosity of every endpoint registered with a
+/// [`TelemetryHandle`](crate::TelemetryHandle) are dropped before their JSON is parsed. When a
+/// telemetry span is closed, the [`TelemetryWorker`] forgets about it and closes the connections
+/// that no other span uses.
+///
+/// When the queue to the worker is full, the messages up to the [`SUBSTRATE_INFO`] verbosity
+/// are queued in a small priority queue instead, see
+/// [`TelemetryConfig::priority_buffer_size`]. The other messages, and those that don't fit in
+/// the priority queue either, are handled according to the [`OverflowPolicy`] of the
+/// [`TelemetryConfig`].
 #[derive(Debug)]
-pub struct TelemetryLayer(Mutex<mpsc::Sender<(Id, u8, String)>>);
+pub struct TelemetryLayer {
+	message_sender: Mutex<TelemetrySender>,
+	message_receiver: Arc<Mutex<mpsc::Receiver<TelemetryMessage>>>,
+	priority_sender: Mutex<mpsc::Sender<TelemetryMessage>>,
+	overflow_policy: OverflowPolicy,
+	overflow_block_timeout: Duration,
+	timestamp_format: TimestampFormat,
+	event_sender: Mutex<EventSender>,
+	stats: Arc<StatsCounters>,
+	max_verbosity: Arc<AtomicU8>,
+	register_sender: mpsc::UnboundedSender<Register>,
+	worker_thread: Arc<Mutex<Option<std::thread::ThreadId>>>,
+}
 
 impl TelemetryLayer {
 	/// Create a new [`TelemetryLayer`] and [`TelemetryWorker`].
@@ -47,22 +79,193 @@ impl TelemetryLayer {
 		buffer_size: Option<usize>,
 		telemetry_external_transport: Option<ExtTransport>,
 	) -> io::Result<(Self, TelemetryWorker)> {
-		let transport = initialize_transport(telemetry_external_transport)?;
-		let worker = TelemetryWorker::new(buffer_size.unwrap_or(16), transport);
-		let sender = worker.message_sender();
-		Ok((Self(Mutex::new(sender)), worker))
+		let mut config = TelemetryConfig::default();
+		if let Some(buffer_size) = buffer_size {
+			config.buffer_size = buffer_size;
+		}
+		Self::with_config(config, telemetry_external_transport)
+	}
+
+	/// Create a new [`TelemetryLayer`] and [`TelemetryWorker`] using the given
+	/// [`TelemetryConfig`].
+	///
+	/// See [`TelemetryLayer::new`] for more information on the [`ExtTransport`].
+	pub fn with_config(
+		config: TelemetryConfig,
+		telemetry_external_transport: Option<ExtTransport>,
+	) -> io::Result<(Self, TelemetryWorker)> {
+		let transport = initialize_transport(telemetry_external_transport, &config)?;
+		Ok(Self::with_transport(config, transport))
+	}
+
+	/// Create a new [`TelemetryLayer`] and [`TelemetryWorker`] connecting to the telemetry
+	/// servers with `transport` instead of the transport built by [`initialize_transport`].
+	///
+	/// This allows to embed the telemetry where the default networking isn't available, or to
+	/// test it with in-process telemetry servers, see [`boxed_transport`](crate::boxed_transport).
+	/// The [`TelemetryConfig`] options that apply to the default transport, such as the proxy or
+	/// the certificate pins, are ignored.
+	pub fn with_transport(
+		config: TelemetryConfig,
+		transport: TelemetryTransport,
+	) -> (Self, TelemetryWorker) {
+		let overflow_policy = config.overflow_policy;
+		let overflow_block_timeout = config.overflow_block_timeout;
+		let timestamp_format = config.timestamp_format;
+		let worker = TelemetryWorker::new(config, transport);
+		let layer = Self {
+			message_sender: Mutex::new(worker.sender()),
+			message_receiver: worker.message_receiver(),
+			priority_sender: Mutex::new(worker.priority_sender()),
+			overflow_policy,
+			overflow_block_timeout,
+			timestamp_format,
+			event_sender: Mutex::new(worker.event_sender()),
+			stats: worker.event_sender().stats().clone(),
+			max_verbosity: worker.max_verbosity(),
+			register_sender: worker.register_sender(),
+			worker_thread: worker.worker_thread(),
+		};
+		(layer, worker)
+	}
+}
+
+impl TelemetryLayer {
+	/// Queue a message for the worker, in the priority queue if the main queue is full and the
+	/// message is important enough, or according to the [`OverflowPolicy`].
+	///
+	/// The messages sent after the worker stopped are silently discarded.
+	fn send_message(&self, message: TelemetryMessage) {
+		let mut sender = self.message_sender.lock();
+		let message = match sender.try_send(message) {
+			Ok(()) => return self.queued(false),
+			Err(err) if err.is_full() => err.into_inner(),
+			Err(_) => return,
+		};
+
+		// `SUBSTRATE_INFO` is the lowest verbosity.
+		let message = if message.verbosity == SUBSTRATE_INFO {
+			match self.priority_sender.lock().try_send(message) {
+				Ok(()) => return self.queued(true),
+				Err(err) if err.is_full() => err.into_inner(),
+				Err(_) => return,
+			}
+		} else {
+			message
+		};
+
+		match self.overflow_policy {
+			OverflowPolicy::DropNewest => self.overflowed(message.id),
+			OverflowPolicy::DropOldest => {
+				// The worker can't receive while the oldest messages are dropped.
+				let mut receiver = self.message_receiver.lock();
+				let mut message = message;
+				loop {
+					message = match sender.try_send(message) {
+						Ok(()) => return self.queued(false),
+						Err(err) if err.is_full() => err.into_inner(),
+						Err(_) => return,
+					};
+					match receiver.try_next() {
+						Ok(Some(message)) => self.overflowed(message.id),
+						// Nothing to drop, the queue can't be full.
+						_ => break,
+					}
+				}
+			}
+			OverflowPolicy::Block => {
+				// The worker can only make room while this thread waits if it runs on another
+				// thread of its own.
+				let current = std::thread::current().id();
+				if !matches!(*self.worker_thread.lock(), Some(worker) if worker != current) {
+					return self.overflowed(message.id);
+				}
+				// The sender stays locked while waiting so that the other threads wait in turn
+				// instead of dropping their messages in the meantime.
+				if !wait_until_ready(&mut sender, self.overflow_block_timeout) {
+					return self.overflowed(message.id);
+				}
+				match sender.try_send(message) {
+					Ok(()) => self.queued(false),
+					Err(err) if err.is_full() => self.overflowed(err.into_inner().id),
+					Err(_) => {}
+				}
+			}
+		}
+	}
+
+	/// Count a message queued for the worker, in the priority queue if `priority`.
+	fn queued(&self, priority: bool) {
+		let counter = if priority {
+			&self.stats.messages_queued_priority
+		} else {
+			&self.stats.messages_queued
+		};
+		counter.fetch_add(1, Ordering::Relaxed);
 	}
+
+	/// Report a message of the span `id` dropped because the queue to the worker is full.
+	fn overflowed(&self, id: Id) {
+		let mut event_sender = self.event_sender.lock();
+		event_sender
+			.stats()
+			.messages_dropped_buffer
+			.fetch_add(1, Ordering::Relaxed);
+		event_sender.send(TelemetryEvent::Overflow { id });
+	}
+}
+
+/// Wait for at most `timeout` until `sender` has room for a message.
+///
+/// Returns `false` if the timeout expired.
+#[cfg(not(target_os = "unknown"))]
+fn wait_until_ready(sender: &mut TelemetrySender, timeout: Duration) -> bool {
+	let ready = future::poll_fn(|cx| sender.poll_ready(cx));
+	let timeout = wasm_timer::Delay::new(timeout);
+	futures::executor::block_on(async {
+		futures::select! {
+			_ = ready.fuse() => true,
+			_ = timeout.fuse() => false,
+		}
+	})
+}
+
+/// The thread can't be blocked in the browser.
+#[cfg(target_os = "unknown")]
+fn wait_until_ready(_: &mut TelemetrySender, _: Duration) -> bool {
+	false
 }
 
 impl<S> Layer<S> for TelemetryLayer
 where
 	S: Subscriber + for<'a> LookupSpan<'a>,
 {
+	fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<S>) {
+		if attrs.metadata().name() != TELEMETRY_LOG_SPAN {
+			return;
+		}
+
+		let mut telemetry_id = TelemetryIdVisitor(None);
+		attrs.record(&mut telemetry_id);
+		if let (Some(telemetry_id), Some(span)) = (telemetry_id.0, ctx.span(id)) {
+			span.extensions_mut().insert(TelemetryId(telemetry_id));
+		}
+	}
+
 	fn on_event(&self, event: &Event<'_>, ctx: Context<S>) {
 		if event.metadata().target() != TELEMETRY_LOG_SPAN {
 			return;
 		}
 
+		// Drop the messages that no registered endpoint would receive before serializing them.
+		let mut verbosity = VerbosityVisitor(None);
+		event.record(&mut verbosity);
+		if let Some(verbosity) = verbosity.0 {
+			if verbosity > u64::from(self.max_verbosity.load(Ordering::Relaxed)) {
+				return;
+			}
+		}
+
 		if let Some(span) = ctx.lookup_current() {
 			let parents = span.parents();
 
@@ -70,27 +273,32 @@ where
 				.chain(parents)
 				.find(|x| x.name() == TELEMETRY_LOG_SPAN)
 			{
-				let id = span.id();
-				let mut attrs = TelemetryAttrs::new(id.clone());
+				let id = match span.extensions().get::<TelemetryId>() {
+					Some(TelemetryId(id)) => id.clone(),
+					// Not a span created by `TelemetrySpan::new`.
+					None => return,
+				};
+				let mut attrs = TelemetryAttrs::new(id.clone(), self.timestamp_format);
 				let mut vis = TelemetryAttrsVisitor(&mut attrs);
 				event.record(&mut vis);
 
-				if let TelemetryAttrs {
+				if let Some(err) = attrs.error {
+					self.event_sender
+						.lock()
+						.send(TelemetryEvent::SerializationError(err.to_string()));
+				} else if let TelemetryAttrs {
 					verbosity: Some(verbosity),
 					json: Some(json),
 					..
 				} = attrs
 				{
-					match self.0.lock().try_send((
+					self.send_message(TelemetryMessage::new(
 						id,
 						verbosity
 							.try_into()
 							.expect("telemetry log message verbosity are u8; qed"),
 						json,
-					)) {
-						Err(err) if err.is_full() => eprintln!("Telemetry buffer overflowed!"),
-						_ => {}
-					}
+					));
 				} else {
 					// NOTE: logging in this function doesn't work
 					eprintln!(
@@ -103,25 +311,86 @@ where
 			}
 		}
 	}
+
+	fn on_close(&self, id: Id, ctx: Context<S>) {
+		if let Some(span) = ctx.span(&id) {
+			if let Some(TelemetryId(id)) = span.extensions().get::<TelemetryId>() {
+				// Fails only if the worker has stopped, in which case there is nothing to clean up.
+				let _ = self
+					.register_sender
+					.unbounded_send(Register::Close { id: id.clone() });
+			}
+		}
+	}
 }
 
 #[derive(Debug)]
 struct TelemetryAttrs {
 	verbosity: Option<u64>,
 	json: Option<String>,
+	error: Option<serde_json::Error>,
 	id: Id,
+	timestamp_format: TimestampFormat,
 }
 
 impl TelemetryAttrs {
-	fn new(id: Id) -> Self {
+	fn new(id: Id, timestamp_format: TimestampFormat) -> Self {
 		Self {
 			verbosity: None,
 			json: None,
+			error: None,
 			id,
+			timestamp_format,
 		}
 	}
 }
 
+/// Wrap the JSON object `payload` of a telemetry log into the message sent to the telemetry
+/// servers, along with the span id and the current time in the given format.
+fn telemetry_message(
+	id: &Id,
+	payload: &str,
+	timestamp_format: TimestampFormat,
+) -> serde_json::Result<String> {
+	wrap_payload(id, serde_json::from_str(payload)?, timestamp_format)
+}
+
+/// Same as [`telemetry_message`] for a payload that has already been parsed.
+pub(crate) fn wrap_payload(
+	id: &Id,
+	payload: serde_json::Map<String, serde_json::Value>,
+	timestamp_format: TimestampFormat,
+) -> serde_json::Result<String> {
+	let ts = match timestamp_format {
+		TimestampFormat::Local => chrono::Local::now().to_rfc3339().into(),
+		TimestampFormat::Utc => chrono::Utc::now().to_rfc3339().into(),
+		TimestampFormat::UnixMillis => chrono::Utc::now().timestamp_millis().into(),
+	};
+
+	let mut message = serde_json::Map::new();
+	message.insert("id".into(), id.into_u64().into());
+	message.insert("ts".into(), ts);
+	message.insert("payload".into(), payload.into());
+	serde_json::to_string(&message)
+}
+
+/// Return the name of a message built by [`wrap_payload`], i.e. the `msg` field of its payload.
+pub(crate) fn message_name(message: &str) -> Option<String> {
+	#[derive(serde::Deserialize)]
+	struct Message {
+		payload: Payload,
+	}
+
+	#[derive(serde::Deserialize)]
+	struct Payload {
+		msg: String,
+	}
+
+	serde_json::from_str::<Message>(message)
+		.ok()
+		.map(|message| message.payload.msg)
+}
+
 #[derive(Debug)]
 struct TelemetryAttrsVisitor<'a>(&'a mut TelemetryAttrs);
 
@@ -138,12 +407,549 @@ impl<'a> tracing::field::Visit for TelemetryAttrsVisitor<'a> {
 
 	fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
 		if field.name() == "json" {
-			(*self.0).json = Some(format!(
-				r#"{{"id":{},"ts":{:?},"payload":{}}}"#,
-				self.0.id.into_u64(),
-				chrono::Local::now().to_rfc3339().to_string(),
-				value,
-			));
+			match telemetry_message(&self.0.id, value, self.0.timestamp_format) {
+				Ok(json) => (*self.0).json = Some(json),
+				Err(err) => (*self.0).error = Some(err),
+			}
+		}
+	}
+}
+
+/// Id of a telemetry span in the telemetry, stored in the extensions of the span.
+#[derive(Debug)]
+struct TelemetryId(Id);
+
+#[derive(Debug)]
+struct TelemetryIdVisitor(Option<Id>);
+
+impl tracing::field::Visit for TelemetryIdVisitor {
+	fn record_debug(&mut self, _field: &tracing::field::Field, _value: &dyn std::fmt::Debug) {
+		// noop
+	}
+
+	fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+		if field.name() == "telemetry_id" && value > 0 {
+			self.0 = Some(Id::from_u64(value));
+		}
+	}
+}
+
+/// Visitor that only reads the verbosity of a telemetry log.
+#[derive(Debug)]
+struct VerbosityVisitor(Option<u64>);
+
+impl tracing::field::Visit for VerbosityVisitor {
+	fn record_debug(&mut self, _field: &tracing::field::Field, _value: &dyn std::fmt::Debug) {
+		// noop
+	}
+
+	fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+		if field.name() == "verbosity" {
+			self.0 = Some(value);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		telemetry, tests::connection_message, MessageReceivers, TelemetryEndpoints,
+		TelemetrySpan, CONSENSUS_DEBUG, SUBSTRATE_DEBUG, SUBSTRATE_INFO,
+	};
+	use tracing_subscriber::layer::SubscriberExt;
+
+	#[test]
+	fn overflow_is_reported_as_event() {
+		let config = TelemetryConfig {
+			buffer_size: 0,
+			..Default::default()
+		};
+		let (layer, mut worker) = TelemetryLayer::with_config(config, None).unwrap();
+		// The verbose messages don't go to the priority queue.
+		worker.max_verbosity.store(SUBSTRATE_DEBUG, Ordering::Relaxed);
+		let mut events = worker.events().unwrap();
+		let subscriber = tracing_subscriber::registry().with(layer);
+
+		tracing::subscriber::with_default(subscriber, || {
+			let span = TelemetrySpan::new();
+			let _enter = span.enter();
+			for _ in 0..100 {
+				telemetry!(SUBSTRATE_DEBUG; "test.overflow"; "n" => 1);
+			}
+		});
+
+		let mut overflows = 0;
+		while let Some(Some(event)) = events.next().now_or_never() {
+			assert!(matches!(event, TelemetryEvent::Overflow { .. }));
+			overflows += 1;
+		}
+
+		// The channel of size 0 accepts a single message.
+		assert_eq!(overflows + events.dropped(), 99);
+		assert!(events.dropped() > 0);
+		assert_eq!(worker.handle().stats().messages_dropped_buffer, 99);
+	}
+
+	#[test]
+	fn oldest_messages_are_dropped_on_overflow() {
+		let config = TelemetryConfig {
+			buffer_size: 2,
+			overflow_policy: OverflowPolicy::DropOldest,
+			..Default::default()
+		};
+		let (layer, mut worker) = TelemetryLayer::with_config(config, None).unwrap();
+		worker.max_verbosity.store(SUBSTRATE_DEBUG, Ordering::Relaxed);
+		let mut events = worker.events().unwrap();
+		let handle = worker.handle();
+		let subscriber = tracing_subscriber::registry().with(layer);
+
+		tracing::subscriber::with_default(subscriber, || {
+			let span = TelemetrySpan::new();
+			let _enter = span.enter();
+			for n in 0..10 {
+				telemetry!(SUBSTRATE_DEBUG; "test.drop_oldest"; "n" => n);
+			}
+		});
+
+		// The channel of size 2 holds 3 messages.
+		let mut receiver = worker.message_receiver.lock();
+		let received = std::iter::from_fn(|| receiver.try_next().ok().flatten())
+			.map(|message| serde_json::from_str::<serde_json::Value>(&message.payload).unwrap())
+			.map(|message| message["payload"]["n"].as_u64().unwrap())
+			.collect::<Vec<_>>();
+		assert_eq!(received, vec![7, 8, 9]);
+
+		let overflows = std::iter::from_fn(|| events.next().now_or_never().flatten())
+			.filter(|event| matches!(event, TelemetryEvent::Overflow { .. }))
+			.count();
+		assert_eq!(overflows, 7);
+		assert_eq!(handle.overflowed_messages(), 7);
+	}
+
+	#[test]
+	fn important_messages_are_queued_apart_when_the_queue_is_full() {
+		let config = TelemetryConfig {
+			buffer_size: 0,
+			priority_buffer_size: 0,
+			..Default::default()
+		};
+		let (layer, mut worker) = TelemetryLayer::with_config(config, None).unwrap();
+		worker.max_verbosity.store(SUBSTRATE_DEBUG, Ordering::Relaxed);
+		let handle = worker.handle();
+		let subscriber = tracing_subscriber::registry().with(layer);
+
+		tracing::subscriber::with_default(subscriber, || {
+			let span = TelemetrySpan::new();
+			let _enter = span.enter();
+			for n in 0..3 {
+				telemetry!(SUBSTRATE_DEBUG; "test.debug"; "n" => n);
+			}
+			for n in 0..3 {
+				telemetry!(SUBSTRATE_INFO; "test.info"; "n" => n);
+			}
+		});
+
+		let stats = handle.stats();
+		assert_eq!(stats.messages_queued, 1);
+		assert_eq!(stats.messages_queued_priority, 1);
+		assert_eq!(stats.messages_dropped_buffer, 4);
+
+		// The worker takes the messages of the priority queue first.
+		let priority = std::mem::replace(&mut worker.priority_receiver, mpsc::channel(0).1);
+		let receivers = MessageReceivers {
+			priority: Mutex::new(priority),
+			normal: worker.message_receiver(),
+			stats: worker.event_sender().stats().clone(),
+		};
+		let received = std::iter::from_fn(|| receivers.try_next())
+			.map(|message| serde_json::from_str::<serde_json::Value>(&message.payload).unwrap())
+			.map(|message| message["payload"]["msg"].as_str().unwrap().to_owned())
+			.collect::<Vec<_>>();
+		assert_eq!(received, vec!["test.info", "test.debug"]);
+	}
+
+	#[test]
+	fn blocking_overflow_policy_waits_for_the_worker() {
+		let config = TelemetryConfig {
+			buffer_size: 0,
+			overflow_policy: OverflowPolicy::Block,
+			overflow_block_timeout: Duration::from_secs(60),
+			..Default::default()
+		};
+		let (layer, mut worker) = TelemetryLayer::with_config(config, None).unwrap();
+		worker.max_verbosity.store(SUBSTRATE_DEBUG, Ordering::Relaxed);
+		let mut events = worker.events().unwrap();
+		let mut message_receiver =
+			std::mem::replace(&mut *worker.message_receiver.lock(), mpsc::channel(0).1);
+		let subscriber = tracing_subscriber::registry().with(layer);
+
+		// Stands for the worker running on its own thread.
+		let receiver = std::thread::spawn(move || {
+			futures::executor::block_on(async {
+				let mut received = Vec::new();
+				while let Some(message) = message_receiver.next().await {
+					wasm_timer::Delay::new(Duration::from_millis(1)).await.unwrap();
+					received.push(message.payload);
+				}
+				received
+			})
+		});
+		*worker.worker_thread.lock() = Some(receiver.thread().id());
+
+		tracing::subscriber::with_default(subscriber, || {
+			let span = TelemetrySpan::new();
+			let _enter = span.enter();
+			for n in 0..20 {
+				telemetry!(SUBSTRATE_DEBUG; "test.block"; "n" => n);
+			}
+		});
+
+		// The queue closes once every sender is dropped.
+		drop(worker);
+		let received = receiver.join().unwrap();
+		assert_eq!(received.len(), 20);
+		assert!(received[19].contains(r#""n":19"#));
+		assert!(events.next().now_or_never().flatten().is_none());
+	}
+
+	#[test]
+	fn blocking_overflow_policy_gives_up_after_the_timeout() {
+		let config = TelemetryConfig {
+			buffer_size: 0,
+			overflow_policy: OverflowPolicy::Block,
+			overflow_block_timeout: Duration::from_millis(10),
+			..Default::default()
+		};
+		let (layer, mut worker) = TelemetryLayer::with_config(config, None).unwrap();
+		worker.max_verbosity.store(SUBSTRATE_DEBUG, Ordering::Relaxed);
+		let mut events = worker.events().unwrap();
+		let subscriber = tracing_subscriber::registry().with(layer);
+
+		// Nothing drains the queue: the worker isn't running.
+		tracing::subscriber::with_default(subscriber, || {
+			let span = TelemetrySpan::new();
+			let _enter = span.enter();
+			for _ in 0..3 {
+				telemetry!(SUBSTRATE_DEBUG; "test.block"; "n" => 1);
+			}
+		});
+
+		let overflows = std::iter::from_fn(|| events.next().now_or_never().flatten())
+			.filter(|event| matches!(event, TelemetryEvent::Overflow { .. }))
+			.count();
+		assert_eq!(overflows, 2);
+		assert_eq!(worker.handle().overflowed_messages(), 2);
+	}
+
+	#[test]
+	fn blocking_overflow_policy_waits_for_the_worker_thread() {
+		let config = TelemetryConfig {
+			buffer_size: 0,
+			overflow_policy: OverflowPolicy::Block,
+			overflow_block_timeout: Duration::from_secs(60),
+			..Default::default()
+		};
+		let (layer, worker) = TelemetryLayer::with_config(config, None).unwrap();
+		worker.max_verbosity.store(SUBSTRATE_DEBUG, Ordering::Relaxed);
+		let handle = worker.handle();
+		let subscriber = tracing_subscriber::registry().with(layer);
+		let worker = worker.spawn_thread().unwrap();
+
+		tracing::subscriber::with_default(subscriber, || {
+			let span = TelemetrySpan::new();
+			let _enter = span.enter();
+			for n in 0..20 {
+				telemetry!(SUBSTRATE_DEBUG; "test.block"; "n" => n);
+			}
+		});
+
+		let stats = handle.stats();
+		assert_eq!(stats.messages_queued, 20);
+		assert_eq!(stats.messages_dropped_buffer, 0);
+		// The worker stops once every sender is dropped.
+		drop(handle);
+		worker.join().unwrap().unwrap();
+	}
+
+	#[test]
+	fn blocking_overflow_policy_does_not_park_the_thread_of_the_worker() {
+		let config = TelemetryConfig {
+			buffer_size: 0,
+			overflow_policy: OverflowPolicy::Block,
+			overflow_block_timeout: Duration::from_secs(10),
+			..Default::default()
+		};
+		let (layer, worker) = TelemetryLayer::with_config(config, None).unwrap();
+		worker.max_verbosity.store(SUBSTRATE_DEBUG, Ordering::Relaxed);
+		let handle = worker.handle();
+		let subscriber = tracing_subscriber::registry().with(layer);
+		let mut runtime = tokio::runtime::Builder::new()
+			.basic_scheduler()
+			.build()
+			.unwrap();
+		let local = tokio::task::LocalSet::new();
+		local.spawn_local(worker.run());
+
+		// The worker can't drain the queue while the thread it shares with the logs is parked.
+		let started = std::time::Instant::now();
+		tracing::subscriber::with_default(subscriber, || {
+			local.block_on(&mut runtime, async {
+				let span = TelemetrySpan::new();
+				let _enter = span.enter();
+				for n in 0..3 {
+					telemetry!(SUBSTRATE_DEBUG; "test.block"; "n" => n);
+				}
+				let () = tokio::task::yield_now().await;
+				telemetry!(SUBSTRATE_DEBUG; "test.block"; "n" => 3);
+			})
+		});
+
+		assert!(started.elapsed() < Duration::from_secs(10));
+		let stats = handle.stats();
+		assert_eq!(stats.messages_dropped_buffer, 2);
+		assert_eq!(stats.messages_queued, 2);
+	}
+
+	#[test]
+	fn payload_is_wrapped_in_message() {
+		const LOCAL: TimestampFormat = TimestampFormat::Local;
+		let id = Id::from_u64(42);
+
+		let message: serde_json::Value =
+			serde_json::from_str(&telemetry_message(&id, "{}", LOCAL).unwrap()).unwrap();
+		assert_eq!(message["id"], 42);
+		assert!(message["ts"].is_string());
+		assert_eq!(message["payload"], serde_json::json!({}));
+
+		let payload = r#"{"msg":"system.interval","peers":3,"best":"0x00"}"#;
+		let message: serde_json::Value =
+			serde_json::from_str(&telemetry_message(&id, payload, LOCAL).unwrap()).unwrap();
+		assert_eq!(message["id"], 42);
+		assert_eq!(
+			message["payload"],
+			serde_json::from_str::<serde_json::Value>(payload).unwrap(),
+		);
+
+		for payload in &[r#"{"msg":"#, r#"["msg"]"#, r#""{""#, r#"{"a":1} {"b":2}"#] {
+			assert!(telemetry_message(&id, payload, LOCAL).is_err(), "{}", payload);
+		}
+	}
+
+	#[test]
+	fn timestamps_are_formatted_as_configured() {
+		let id = Id::from_u64(42);
+		let ts = |format| {
+			let message: serde_json::Value =
+				serde_json::from_str(&telemetry_message(&id, "{}", format).unwrap()).unwrap();
+			message["ts"].clone()
+		};
+
+		let local = ts(TimestampFormat::Local);
+		let local = chrono::DateTime::parse_from_rfc3339(local.as_str().unwrap()).unwrap();
+		assert_eq!(
+			local.offset().local_minus_utc(),
+			chrono::Local::now().offset().local_minus_utc(),
+		);
+
+		let utc = ts(TimestampFormat::Utc);
+		let utc = utc.as_str().unwrap();
+		assert!(utc.ends_with("+00:00"), "{}", utc);
+		let utc = chrono::DateTime::parse_from_rfc3339(utc).unwrap();
+		assert!((chrono::Utc::now() - utc.with_timezone(&chrono::Utc)).num_seconds() < 60);
+
+		let millis = ts(TimestampFormat::UnixMillis).as_i64().unwrap();
+		assert!((chrono::Utc::now().timestamp_millis() - millis).abs() < 60_000);
+	}
+
+	#[test]
+	fn invalid_payload_is_reported_as_event() {
+		let (layer, mut worker) = TelemetryLayer::new(None, None).unwrap();
+		let mut events = worker.events().unwrap();
+		let subscriber = tracing_subscriber::registry().with(layer);
+
+		tracing::subscriber::with_default(subscriber, || {
+			let span = TelemetrySpan::new();
+			let _enter = span.enter();
+			tracing::info!(
+				target: TELEMETRY_LOG_SPAN,
+				verbosity = SUBSTRATE_INFO,
+				json = r#"{"msg":"#,
+			);
+		});
+
+		assert!(matches!(
+			events.next().now_or_never(),
+			Some(Some(TelemetryEvent::SerializationError(_)))
+		));
+	}
+
+	#[test]
+	fn messages_above_the_registered_verbosities_are_dropped() {
+		let (layer, mut worker) = TelemetryLayer::new(None, None).unwrap();
+		let mut events = worker.events().unwrap();
+		let mut message_receiver =
+			std::mem::replace(&mut *worker.message_receiver.lock(), mpsc::channel(0).1);
+		let mut handle = worker.handle();
+		let subscriber = tracing_subscriber::registry().with(layer);
+
+		let received = |receiver: &mut mpsc::Receiver<TelemetryMessage>| {
+			std::iter::from_fn(|| receiver.next().now_or_never().flatten())
+				.map(|message| message.verbosity)
+				.collect::<Vec<u8>>()
+		};
+
+		tracing::subscriber::with_default(subscriber, || {
+			let span = TelemetrySpan::new();
+			let _enter = span.enter();
+
+			telemetry!(SUBSTRATE_INFO; "test.info"; "n" => 1);
+			telemetry!(CONSENSUS_DEBUG; "test.debug"; "n" => 1);
+			// The JSON of dropped messages is not even parsed.
+			tracing::info!(
+				target: TELEMETRY_LOG_SPAN,
+				verbosity = CONSENSUS_DEBUG,
+				json = r#"{"msg":"#,
+			);
+			assert_eq!(received(&mut message_receiver), vec![SUBSTRATE_INFO]);
+			assert!(events.next().now_or_never().is_none());
+
+			let endpoints = TelemetryEndpoints::new(vec![
+				("/ip4/80.123.90.4/tcp/5432/ws".into(), SUBSTRATE_INFO),
+				("/ip4/80.123.90.5/tcp/5432/ws".into(), CONSENSUS_DEBUG),
+			])
+			.unwrap();
+			handle.start_telemetry(span.clone(), endpoints, connection_message());
+
+			telemetry!(CONSENSUS_DEBUG; "test.debug"; "n" => 1);
+			telemetry!(SUBSTRATE_DEBUG; "test.debug"; "n" => 1);
+			assert_eq!(received(&mut message_receiver), vec![CONSENSUS_DEBUG]);
+		});
+	}
+
+	#[test]
+	fn closed_telemetry_spans_are_reported_to_the_worker() {
+		let (layer, mut worker) = TelemetryLayer::new(None, None).unwrap();
+		let subscriber = tracing_subscriber::registry().with(layer);
+
+		let ids = tracing::subscriber::with_default(subscriber, || {
+			let _other = tracing::info_span!("not-telemetry");
+			(0..3)
+				.map(|_| {
+					let span = TelemetrySpan::new();
+					let id = span.telemetry_id().unwrap();
+					drop(span);
+					id
+				})
+				.collect::<Vec<_>>()
+		});
+
+		let closed = std::iter::from_fn(|| worker.register_receiver.next().now_or_never().flatten())
+			.map(|register| match register {
+				Register::Close { id } => id,
+				other => panic!("unexpected registration: {:?}", other),
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(closed, ids);
+	}
+
+	#[test]
+	fn reused_span_ids_do_not_mix_the_telemetry_of_spans() {
+		const SPANS: usize = 8192;
+		let (layer, mut worker) = TelemetryLayer::new(None, None).unwrap();
+		let receiver = worker.message_receiver();
+		let subscriber = tracing_subscriber::registry().with(layer);
+
+		let spans = tracing::subscriber::with_default(subscriber, || {
+			(0..SPANS)
+				.map(|n| {
+					let span = TelemetrySpan::new();
+					let _enter = span.enter();
+					telemetry!(SUBSTRATE_INFO; "test.reuse"; "n" => n);
+					let message = receiver.lock().try_next().unwrap().unwrap();
+					(span.span().id().unwrap(), span.telemetry_id().unwrap(), message.id)
+				})
+				.collect::<Vec<_>>()
+		});
+
+		// The registry reuses the id of the closed spans once their generation wraps.
+		assert_eq!(spans[SPANS - 1].0, spans[0].0);
+		let mut telemetry_ids = spans.iter().map(|(_, id, _)| id.into_u64()).collect::<Vec<_>>();
+		telemetry_ids.dedup();
+		assert_eq!(telemetry_ids.len(), SPANS);
+		assert!(spans.iter().all(|(_, telemetry_id, message_id)| telemetry_id == message_id));
+
+		let closed = std::iter::from_fn(|| worker.register_receiver.next().now_or_never().flatten())
+			.map(|register| match register {
+				Register::Close { id } => id,
+				other => panic!("unexpected registration: {:?}", other),
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(closed, spans.into_iter().map(|(_, id, _)| id).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn telemetry_is_not_started_in_a_disabled_span() {
+		let (layer, mut worker) = TelemetryLayer::new(None, None).unwrap();
+		let mut handle = worker.handle();
+		let subscriber = tracing_subscriber::registry()
+			.with(tracing_subscriber::EnvFilter::new("sc_telemetry=off"))
+			.with(layer);
+
+		let on_connect = tracing::subscriber::with_default(subscriber, || {
+			let span = TelemetrySpan::new();
+			assert!(span.span().is_disabled());
+			let endpoints =
+				TelemetryEndpoints::new(vec![("/ip4/80.123.90.4/tcp/5432/ws".into(), 0)]).unwrap();
+			handle
+				.start_telemetry(span, endpoints, connection_message())
+				.on_connect_stream()
+		});
+
+		// The notifier is not attached to any endpoint.
+		match worker.register_receiver.next().now_or_never().flatten() {
+			Some(Register::Notifier { addresses, .. }) => assert!(addresses.is_empty()),
+			other => panic!("unexpected registration: {:?}", other),
+		}
+		assert!(worker.register_receiver.next().now_or_never().is_none());
+		drop(worker);
+		assert_eq!(futures::executor::block_on(on_connect.collect::<Vec<_>>()), vec![]);
+	}
+
+	#[test]
+	fn stopped_telemetry_spans_are_unregistered() {
+		let (layer, mut worker) = TelemetryLayer::new(None, None).unwrap();
+		let mut handle = worker.handle();
+		let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+		let mut registrations = || {
+			std::iter::from_fn(|| worker.register_receiver.next().now_or_never().flatten())
+				.collect::<Vec<_>>()
+		};
+
+		let (id, other) = tracing::dispatcher::with_default(&dispatch, || {
+			let span = TelemetrySpan::new();
+			let id = span.telemetry_id().unwrap();
+			let other = span.clone();
+			let endpoints =
+				TelemetryEndpoints::new(vec![("/ip4/80.123.90.4/tcp/5432/ws".into(), 0)]).unwrap();
+			handle.start_telemetry(span.clone(), endpoints, connection_message());
+			handle.stop_telemetry(span);
+			(id, other)
+		});
+
+		match registrations().as_slice() {
+			[Register::Telemetry { id: registered, .. }, Register::Close { id: closed }] => {
+				assert_eq!(registered, &id);
+				assert_eq!(closed, &id);
+			}
+			other => panic!("unexpected registrations: {:?}", other),
+		}
+
+		// The span is closed once its last clone is dropped.
+		tracing::dispatcher::with_default(&dispatch, || drop(other));
+		match registrations().as_slice() {
+			[Register::Close { id: closed }] => assert_eq!(closed, &id),
+			other => panic!("unexpected registrations: {:?}", other),
 		}
 	}
 }
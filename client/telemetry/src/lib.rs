@@ -36,26 +36,73 @@
 
 #![warn(missing_docs)]
 
-use futures::{channel::mpsc, prelude::*};
+use futures::{
+	channel::{mpsc, oneshot},
+	prelude::*,
+};
 use libp2p::Multiaddr;
 use log::{error, warn};
+use parking_lot::Mutex;
 use serde::Serialize;
 use sp_utils::mpsc::{tracing_unbounded, TracingUnboundedReceiver};
 use std::collections::HashMap;
+use std::sync::{
+	atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+	Arc,
+};
 use tracing::Id;
+use wasm_timer::Instant;
 
 pub use libp2p::wasm_ext::ExtTransport;
 pub use serde_json;
 pub use tracing;
 
+mod buffer;
+mod config;
 mod endpoints;
+mod events;
+mod failover;
+mod file;
+mod heartbeat;
 mod layer;
+mod log_endpoint;
 mod node;
+mod pinning;
+mod proxy;
+mod rate_limit;
+mod sender;
+mod sink;
+mod spill;
+mod stats;
+mod status;
 mod transport;
 
+use buffer::*;
+pub use config::*;
 pub use endpoints::*;
+pub use events::*;
+use failover::*;
+pub use file::FileSink;
+use file::FileTransport;
+pub use heartbeat::{HeartbeatFields, TelemetryHeartbeat};
 pub use layer::*;
+use log_endpoint::{LogTransport, LOG_URL};
 use node::*;
+pub use pinning::{CertificatePin, PinParseError};
+#[cfg(not(target_os = "unknown"))]
+use pinning::{tcp_pins, PinnedTlsTransport, PinnedWsTransport};
+pub use proxy::{ProxyParseError, TelemetryProxy, PROXY_ENV_VAR};
+#[cfg(not(target_os = "unknown"))]
+use proxy::ProxyTransport;
+use rate_limit::*;
+pub use sender::{TelemetrySender, TrySendError};
+pub use sink::{StdoutSink, TelemetrySink};
+use sink::Sinks;
+use spill::Spill;
+pub use stats::{ProcessingTimes, TelemetryStats};
+use stats::{ConnectedEndpoint, RunningWorker, StatsCounters};
+pub use status::{ConnectionState, EndpointStatus, TelemetryStatusEntry};
+pub use transport::{boxed_transport, initialize_transport, StreamAndSink, TelemetryTransport};
 use transport::*;
 
 /// Substrate DEBUG log level.
@@ -72,11 +119,57 @@ pub const CONSENSUS_WARN: u8 = 4;
 /// Consensus INFO log level.
 pub const CONSENSUS_INFO: u8 = 1;
 
-pub(crate) type TelemetryMessage = (Id, u8, String);
+/// Highest verbosity level accepted for a telemetry endpoint.
+pub const MAX_VERBOSITY: u8 = 9;
+
+/// Maximum number of endpoints accepted by [`TelemetryEndpoints::new`] and by the deserializer of
+/// [`TelemetryEndpoints`]. Use [`TelemetryEndpoints::new_unchecked`] for more endpoints.
+pub const MAX_TELEMETRY_ENDPOINTS: usize = 16;
+
+/// Minimum interval between two elections of the failover groups.
+const MIN_FAILOVER_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// A telemetry message of a telemetry span, sent to the [`TelemetryWorker`].
+#[derive(Debug, Clone)]
+pub(crate) struct TelemetryMessage {
+	/// Id of the telemetry span.
+	pub(crate) id: Id,
+	/// Verbosity of the message.
+	pub(crate) verbosity: u8,
+	/// JSON of the message, shared by all the telemetry servers that receive it.
+	pub(crate) payload: Arc<str>,
+	/// When the message has been created.
+	pub(crate) enqueued_at: Instant,
+}
+
+impl TelemetryMessage {
+	/// Create a message of the span `id` created now.
+	pub(crate) fn new(id: Id, verbosity: u8, payload: impl Into<Arc<str>>) -> Self {
+		Self {
+			id,
+			verbosity,
+			payload: payload.into(),
+			enqueued_at: Instant::now(),
+		}
+	}
+}
+
+impl From<(Id, u8, String)> for TelemetryMessage {
+	fn from((id, verbosity, payload): (Id, u8, String)) -> Self {
+		Self::new(id, verbosity, payload)
+	}
+}
 
 /// A handle representing a telemetry span, with the capability to enter the span if it exists.
+///
+/// The telemetry identifies the span by an id that is unique in the process, see
+/// [`TelemetrySpan::telemetry_id`], and not by its `tracing` id which is reused once the span is
+/// closed.
 #[derive(Debug, Clone)]
-pub struct TelemetrySpan(tracing::Span);
+pub struct TelemetrySpan(tracing::Span, Id);
+
+/// Id of the next telemetry span.
+static NEXT_TELEMETRY_ID: AtomicU64 = AtomicU64::new(1);
 
 impl TelemetrySpan {
 	/// Enters this span, returning a guard that will exit the span when dropped.
@@ -86,7 +179,18 @@ impl TelemetrySpan {
 
 	/// Constructs a new [`TelemetrySpan`].
 	pub fn new() -> Self {
-		Self(tracing::info_span!(TELEMETRY_LOG_SPAN))
+		let id = NEXT_TELEMETRY_ID.fetch_add(1, Ordering::Relaxed);
+		Self(
+			tracing::info_span!(TELEMETRY_LOG_SPAN, telemetry_id = id),
+			Id::from_u64(id),
+		)
+	}
+
+	/// Id of this span in the telemetry, e.g. in the `id` field of its telemetry messages.
+	///
+	/// This returns `None` if the span is disabled.
+	pub fn telemetry_id(&self) -> Option<Id> {
+		self.0.id().map(|_| self.1.clone())
 	}
 
 	/// Return a clone of the underlying `tracing::Span` instance.
@@ -96,7 +200,7 @@ impl TelemetrySpan {
 }
 
 /// Message sent when the connection (re-)establishes.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ConnectionMessage {
 	/// Node's name.
 	pub name: String,
@@ -123,26 +227,61 @@ pub struct ConnectionMessage {
 /// It should run as a background task using the [`TelemetryWorker::run`] method. This method
 /// will consume the object and any further attempts of initializing a new telemetry through its
 /// handle will fail (without being fatal).
+///
+/// The worker doesn't yield anything itself: its operation, such as the connections to the
+/// telemetry servers and the messages they dropped, is reported by the stream of
+/// [`TelemetryEvent`]s of [`TelemetryWorker::events`], to take before running the worker.
 #[derive(Debug)]
 pub struct TelemetryWorker {
-	message_receiver: mpsc::Receiver<TelemetryMessage>,
+	message_receiver: Arc<Mutex<mpsc::Receiver<TelemetryMessage>>>,
 	message_sender: mpsc::Sender<TelemetryMessage>,
+	priority_receiver: mpsc::Receiver<TelemetryMessage>,
+	priority_sender: mpsc::Sender<TelemetryMessage>,
 	register_receiver: mpsc::UnboundedReceiver<Register>,
 	register_sender: mpsc::UnboundedSender<Register>,
 	transport: WsTrans,
+	budget: BufferBudget,
+	event_sender: EventSender,
+	events: Option<TelemetryEvents>,
+	rate_limiter: Option<RateLimiter>,
+	max_verbosity: Arc<AtomicU8>,
+	sinks: Sinks,
+	worker_thread: Arc<Mutex<Option<std::thread::ThreadId>>>,
+	config: TelemetryConfig,
 }
 
 impl TelemetryWorker {
-	pub(crate) fn new(buffer_size: usize, transport: WsTrans) -> Self {
-		let (message_sender, message_receiver) = mpsc::channel(buffer_size);
+	pub(crate) fn new(config: TelemetryConfig, transport: WsTrans) -> Self {
+		let (message_sender, message_receiver) = mpsc::channel(config.buffer_size);
+		let (priority_sender, priority_receiver) = mpsc::channel(config.priority_buffer_size);
 		let (register_sender, register_receiver) = mpsc::unbounded();
+		let (event_sender, events) = event_channel();
+		let rate_limiter = config.max_messages_per_second.map(|rate| {
+			RateLimiter::new(
+				rate,
+				config.rate_limit_burst.unwrap_or(rate),
+				config.rate_limit_exempt_verbosity,
+				event_sender.stats().clone(),
+			)
+		});
 
 		Self {
-			message_receiver,
+			message_receiver: Arc::new(Mutex::new(message_receiver)),
 			message_sender,
+			priority_receiver,
+			priority_sender,
 			register_receiver,
 			register_sender,
 			transport,
+			budget: BufferBudget::new(config.max_buffered_bytes, event_sender.stats().clone()),
+			event_sender,
+			events: Some(events),
+			rate_limiter,
+			// The messages up to the connect verbosity can be sent to any endpoint.
+			max_verbosity: Arc::new(AtomicU8::new(config.connect_verbosity.unwrap_or(0))),
+			sinks: Sinks::default(),
+			worker_thread: Arc::new(Mutex::new(None)),
+			config,
 		}
 	}
 
@@ -152,72 +291,649 @@ impl TelemetryWorker {
 	pub fn handle(&self) -> TelemetryHandle {
 		TelemetryHandle {
 			message_sender: self.register_sender.clone(),
+			budget: self.budget.clone(),
+			stats: self.event_sender.stats().clone(),
+			max_verbosity: self.max_verbosity.clone(),
 		}
 	}
 
-	/// Get a clone of the channel's `Sender` used to send telemetry events.
+	/// Get a new [`ShutdownHandle`], used to stop the worker once the telemetry has been sent.
+	pub fn shutdown_handle(&self) -> ShutdownHandle {
+		ShutdownHandle {
+			register_sender: self.register_sender.clone(),
+		}
+	}
+
+	/// Get a [`TelemetrySender`] to send telemetry messages without going through the
+	/// [`TelemetryLayer`].
+	pub fn sender(&self) -> TelemetrySender {
+		TelemetrySender::new(self.message_sender.clone(), self.config.timestamp_format)
+	}
+
+	/// Get a clone of the channel's `Sender` used to send telemetry events, to send the messages
+	/// of the tests as they are.
+	#[cfg(test)]
 	pub(crate) fn message_sender(&self) -> mpsc::Sender<TelemetryMessage> {
 		self.message_sender.clone()
 	}
 
+	/// Get a clone of the `Sender` of the channel of the important telemetry events that don't
+	/// fit in the main channel.
+	pub(crate) fn priority_sender(&self) -> mpsc::Sender<TelemetryMessage> {
+		self.priority_sender.clone()
+	}
+
+	/// Get the channel's `Receiver` of the telemetry events, shared with the
+	/// [`TelemetryLayer`] so that it can drop the oldest ones.
+	pub(crate) fn message_receiver(&self) -> Arc<Mutex<mpsc::Receiver<TelemetryMessage>>> {
+		self.message_receiver.clone()
+	}
+
+	/// Get a clone of the channel's `Sender` used to register with the worker.
+	pub(crate) fn register_sender(&self) -> mpsc::UnboundedSender<Register> {
+		self.register_sender.clone()
+	}
+
+	/// Get the highest verbosity of the messages that can be sent to an endpoint, shared with
+	/// the [`TelemetryHandle`]s that register the endpoints.
+	pub(crate) fn max_verbosity(&self) -> Arc<AtomicU8> {
+		self.max_verbosity.clone()
+	}
+
+	/// Get the thread that runs the worker if it has been started with
+	/// [`TelemetryWorker::spawn_thread`], shared with the [`TelemetryLayer`] that only waits for
+	/// a worker running on its own thread.
+	pub(crate) fn worker_thread(&self) -> Arc<Mutex<Option<std::thread::ThreadId>>> {
+		self.worker_thread.clone()
+	}
+
+	/// Get a clone of the `EventSender` used to report [`TelemetryEvent`]s.
+	pub(crate) fn event_sender(&self) -> EventSender {
+		self.event_sender.clone()
+	}
+
+	/// Send the telemetry to `sink` in addition to the telemetry servers.
+	///
+	/// The sink receives the messages of every telemetry span started with
+	/// [`TelemetryHandle::start_telemetry`], up to the given verbosity, even if the span has no
+	/// endpoint. The `label` identifies the sink in the logs. The verbosity must be at most
+	/// [`MAX_VERBOSITY`].
+	pub fn add_sink(
+		&mut self,
+		label: impl Into<String>,
+		verbosity: u8,
+		sink: impl TelemetrySink + 'static,
+	) {
+		let label = label.into();
+		if verbosity > MAX_VERBOSITY {
+			error!(
+				target: "telemetry",
+				"Could not add telemetry sink {}: verbosity {} is above the maximum of {}",
+				label,
+				verbosity,
+				MAX_VERBOSITY,
+			);
+			return;
+		}
+
+		self.max_verbosity.fetch_max(verbosity, Ordering::Relaxed);
+		self.sinks.push(label, verbosity, Box::new(sink));
+	}
+
+	/// Forward the telemetry to another [`TelemetryWorker`] through `sender`, as the telemetry
+	/// of its telemetry span `id`, in addition to the telemetry servers.
+	///
+	/// Like a sink, the other worker receives the messages of every telemetry span, up to the
+	/// given verbosity, which must be at most [`MAX_VERBOSITY`]. The messages are forwarded as
+	/// they are, without being serialized again: their `id` field is still the one of their span
+	/// in this worker. The messages that don't fit in the queue to the other worker are dropped.
+	pub fn forward_to(&mut self, sender: TelemetrySender, id: Id, verbosity: u8) {
+		if verbosity > MAX_VERBOSITY {
+			error!(
+				target: "telemetry",
+				"Could not forward the telemetry: verbosity {} is above the maximum of {}",
+				verbosity,
+				MAX_VERBOSITY,
+			);
+			return;
+		}
+
+		self.max_verbosity.fetch_max(verbosity, Ordering::Relaxed);
+		self.sinks.forward(id, verbosity, sender);
+	}
+
+	/// Get the stream of [`TelemetryEvent`]s reported by the telemetry.
+	///
+	/// This returns `None` if the stream has already been taken.
+	pub fn events(&mut self) -> Option<TelemetryEvents> {
+		self.events.take()
+	}
+
 	/// Run the telemetry worker.
 	///
-	/// This should be run in a background task.
-	pub async fn run(self) {
+	/// This should be run in a background task. It completes after a call to
+	/// [`ShutdownHandle::shutdown`], or once the [`TelemetryLayer`] and every other sender of
+	/// telemetry messages have been dropped. In both cases the remaining telemetry is sent to the
+	/// telemetry servers first.
+	///
+	/// See [`TelemetryWorker::spawn_thread`] to run it on a dedicated thread instead, as required
+	/// by [`OverflowPolicy::Block`].
+	pub async fn run(self) -> Result<(), TelemetryError> {
 		let Self {
-			mut message_receiver,
-			message_sender: _,
+			message_receiver,
+			message_sender,
+			priority_receiver,
+			priority_sender,
 			mut register_receiver,
-			register_sender: _,
+			register_sender,
 			transport,
+			budget,
+			mut event_sender,
+			events: _,
+			mut rate_limiter,
+			max_verbosity: _,
+			mut sinks,
+			worker_thread: _,
+			config,
 		} = self;
+		let _running = RunningWorker::new(event_sender.stats().clone());
+		// The channels close once the senders held outside of the worker have been dropped.
+		drop(message_sender);
+		drop(priority_sender);
+		drop(register_sender);
+		let message_receivers = MessageReceivers {
+			priority: Mutex::new(priority_receiver),
+			normal: message_receiver,
+			stats: event_sender.stats().clone(),
+		};
 
 		let mut node_map: HashMap<Id, Vec<(u8, Multiaddr)>> = HashMap::new();
 		let mut node_pool: HashMap<Multiaddr, _> = HashMap::new();
+		// Connection message of every telemetry span, for the endpoints added later.
+		let mut connection_messages: HashMap<Id, ConnectionMessage> = HashMap::new();
+		// Connection notifiers of every telemetry span, for the endpoints added later.
+		let mut notifiers: HashMap<Id, Vec<ConnectionNotifierSender>> = HashMap::new();
+		// Own verbosity of the endpoints of the spans whose verbosity is overridden.
+		let mut base_verbosities: HashMap<(Id, Multiaddr), u8> = HashMap::new();
+		let mut failover_groups = FailoverGroups::default();
+		let mut batch_interval = match config.batch {
+			Some(batch) => wasm_timer::Interval::new(batch.window).boxed(),
+			None => stream::pending().boxed(),
+		}
+		.fuse();
+		// Elects the failover groups even when no telemetry is sent, at most a quarter of the
+		// failover delay late.
+		let mut failover_interval = wasm_timer::Interval::new(
+			(config.failover_delay / 4).max(MIN_FAILOVER_CHECK_INTERVAL),
+		)
+		.fuse();
 
 		loop {
-			futures::select! {
-				message = message_receiver.next() => Self::process_message(
-					message,
-					&mut node_pool,
-					&node_map,
-				).await,
-				init_payload = register_receiver.next() => Self::process_register(
-					init_payload,
-					&mut node_pool,
-					&mut node_map,
-					transport.clone(),
-				).await,
+			// The registrations are processed before the messages that have been logged after
+			// them, and the nodes are flushed even when messages keep coming.
+			futures::select_biased! {
+				init_payload = register_receiver.next() => {
+					if let Some(Register::Shutdown { done }) = init_payload {
+						let result = Self::shutdown(
+							&message_receivers,
+							&mut node_pool,
+							&node_map,
+							&failover_groups,
+							&mut rate_limiter,
+							&mut sinks,
+							&config,
+						).await;
+						let _ = done.send(());
+						return result;
+					}
+
+					let registrations = match init_payload {
+						Some(Register::AddEndpoint { endpoint }) => Self::add_endpoint(
+							endpoint,
+							&mut node_map,
+							&connection_messages,
+							&mut base_verbosities,
+						),
+						Some(Register::VerbosityOverride { id, addr, verbosity }) => {
+							Self::override_verbosity(
+								&id,
+								addr.as_ref(),
+								verbosity,
+								&mut node_map,
+								&mut base_verbosities,
+							);
+							Vec::new()
+						}
+						Some(Register::Flush { timeout, done }) => {
+							Self::process_pending_messages(
+								&message_receivers,
+								&mut node_pool,
+								&node_map,
+								&failover_groups,
+								&mut rate_limiter,
+								&mut sinks,
+								&config,
+							).await;
+							let result = Self::flush(&mut node_pool, &mut sinks, timeout).await;
+							// The requester may have given up.
+							let _ = done.send(result);
+							Vec::new()
+						}
+						Some(Register::Fallback { endpoints }) => vec![
+							Register::Close { id: fallback_id() },
+							Register::Fallback { endpoints },
+						],
+						Some(Register::UpdateEndpoints { id, endpoints }) => {
+							Self::update_endpoints(
+								id,
+								endpoints,
+								&mut node_map,
+								&mut node_pool,
+								&connection_messages,
+								&notifiers,
+								&mut base_verbosities,
+							)
+						}
+						Some(input) => {
+							match &input {
+								Register::Telemetry { id, connection_message, .. } => {
+									connection_messages
+										.insert(id.clone(), connection_message.clone());
+								}
+								Register::Notifier {
+									id: Some(id),
+									connection_notifier,
+									..
+								} => {
+									notifiers
+										.entry(id.clone())
+										.or_default()
+										.push(connection_notifier.clone());
+								}
+								_ => {}
+							}
+							vec![input]
+						}
+						None => Vec::new(),
+					};
+
+					for registration in registrations {
+						Self::process_register(
+							registration,
+							&mut node_pool,
+							&mut node_map,
+							transport.clone(),
+							&budget,
+							&mut event_sender,
+							&config,
+						).await;
+					}
+					// Forget the spans that have been closed and the endpoints that have been
+					// removed.
+					connection_messages.retain(|id, _| node_map.contains_key(id));
+					notifiers.retain(|id, _| node_map.contains_key(id));
+					base_verbosities.retain(|(id, addr), _| {
+						matches!(
+							node_map.get(id),
+							Some(nodes) if nodes.iter().any(|(_, other_addr)| other_addr == addr)
+						)
+					});
+					if let Some(rate_limiter) = &mut rate_limiter {
+						rate_limiter.retain(|id| node_map.contains_key(id));
+					}
+					// The endpoints that have been added or removed join or leave their group.
+					Self::update_failover_groups(&mut failover_groups, &mut node_pool, &config);
+				},
+				// Wakes up the worker so that the batches that are due get sent.
+				_ = batch_interval.next() => {},
+				_ = failover_interval.next() => if !failover_groups.is_empty() {
+					Self::update_failover_groups(&mut failover_groups, &mut node_pool, &config);
+				},
+				_ = future::poll_fn(|cx| {
+					Self::poll_flush_nodes(&mut node_pool, &failover_groups, cx)
+				}).fuse() => {},
+				message = future::poll_fn(|cx| {
+					message_receivers.poll_next(cx)
+				}).fuse() => match message {
+					Some(message) => {
+						// The messages that are already queued are dispatched along with this
+						// one, so that the nodes are flushed once for all of them.
+						let mut next = Some(message);
+						let mut dispatched = 0;
+						while let Some(message) = next {
+							let started = Instant::now();
+							let (verbosity, payload) =
+								(message.verbosity, message.payload.clone());
+							Self::process_message(
+								message,
+								&mut node_pool,
+								&node_map,
+								&failover_groups,
+								&mut rate_limiter,
+								&mut sinks,
+								&config,
+							).await;
+							message_receivers.processed(verbosity, &payload, started, &config);
+
+							dispatched += 1;
+							next = if dispatched < config.max_messages_per_wakeup {
+								message_receivers.try_next()
+							} else {
+								None
+							};
+						}
+					}
+					None => {
+						log::debug!(
+							target: "telemetry",
+							"Stopping the telemetry worker: every sender of telemetry messages \
+							has been dropped",
+						);
+						return Self::shutdown(
+							&message_receivers,
+							&mut node_pool,
+							&node_map,
+							&failover_groups,
+							&mut rate_limiter,
+							&mut sinks,
+							&config,
+						).await;
+					}
+				},
+			}
+		}
+	}
+
+	/// Run the telemetry worker on a new thread dedicated to it, see [`TelemetryWorker::run`].
+	///
+	/// With [`OverflowPolicy::Block`], the threads that log telemetry only wait for room in the
+	/// queue to a worker started this way: a worker that shares its thread with them, e.g. on a
+	/// current-thread runtime, could not make room while they wait.
+	#[cfg(not(target_os = "unknown"))]
+	pub fn spawn_thread(
+		self,
+	) -> std::io::Result<std::thread::JoinHandle<Result<(), TelemetryError>>> {
+		let worker_thread = self.worker_thread.clone();
+		let thread = std::thread::Builder::new()
+			.name("telemetry-worker".into())
+			.spawn(move || futures::executor::block_on(self.run()))?;
+		// Nothing waits once the worker has stopped: the queue is closed.
+		*worker_thread.lock() = Some(thread.thread().id());
+		Ok(thread)
+	}
+
+	/// Add `endpoint` to every telemetry span.
+	///
+	/// Only the verbosity of the endpoint changes in the spans that already use it, once their
+	/// verbosity override is cleared if they have one. Returns the registrations of the endpoint
+	/// with the other spans.
+	fn add_endpoint(
+		endpoint: TelemetryEndpoint,
+		node_map: &mut HashMap<Id, Vec<(u8, Multiaddr)>>,
+		connection_messages: &HashMap<Id, ConnectionMessage>,
+		base_verbosities: &mut HashMap<(Id, Multiaddr), u8>,
+	) -> Vec<Register> {
+		if node_map.is_empty() {
+			log::warn!(
+				target: "telemetry",
+				"Cannot add telemetry endpoint {}: no telemetry has been started",
+				display_addr(endpoint.addr()),
+			);
+		}
+
+		let mut registrations = Vec::new();
+		for (id, nodes) in node_map.iter_mut().filter(|(id, _)| **id != fallback_id()) {
+			match nodes.iter_mut().find(|(_, addr)| addr == endpoint.addr()) {
+				Some((verbosity, addr)) => {
+					match base_verbosities.get_mut(&(id.clone(), addr.clone())) {
+						Some(base_verbosity) => *base_verbosity = endpoint.verbosity(),
+						None => *verbosity = endpoint.verbosity(),
+					}
+				}
+				None => registrations.push(Register::Telemetry {
+					id: id.clone(),
+					endpoints: TelemetryEndpoints(vec![endpoint.clone()]),
+					connection_message: connection_messages[id].clone(),
+					overrides: HashMap::new(),
+				}),
+			}
+		}
+		registrations
+	}
+
+	/// Replace the endpoints of the span `id` with `endpoints`.
+	///
+	/// The removed endpoints are released right away, and only the verbosity changes for the
+	/// endpoints that the span keeps. Returns the registrations of the added endpoints with the
+	/// span, and of its connection notifiers with them.
+	fn update_endpoints(
+		id: Id,
+		endpoints: TelemetryEndpoints,
+		node_map: &mut HashMap<Id, Vec<(u8, Multiaddr)>>,
+		node_pool: &mut HashMap<Multiaddr, Node<WsTrans>>,
+		connection_messages: &HashMap<Id, ConnectionMessage>,
+		notifiers: &HashMap<Id, Vec<ConnectionNotifierSender>>,
+		base_verbosities: &mut HashMap<(Id, Multiaddr), u8>,
+	) -> Vec<Register> {
+		let nodes = match node_map.get_mut(&id) {
+			Some(nodes) if id != fallback_id() => nodes,
+			_ => {
+				log::warn!(
+					target: "telemetry",
+					"Cannot update the telemetry endpoints of unknown span {:?}",
+					id,
+				);
+				return Vec::new();
+			}
+		};
+
+		let mut removed = Vec::new();
+		nodes.retain(|(_, addr)| {
+			let kept = endpoints.0.iter().any(|endpoint| endpoint.addr() == addr);
+			if !kept {
+				removed.push(addr.clone());
+			}
+			kept
+		});
+		for (verbosity, addr) in nodes.iter_mut() {
+			let endpoint = endpoints
+				.0
+				.iter()
+				.find(|endpoint| endpoint.addr() == addr)
+				.expect("the other endpoints have been removed above; qed");
+			match base_verbosities.get_mut(&(id.clone(), addr.clone())) {
+				Some(base_verbosity) => *base_verbosity = endpoint.verbosity(),
+				None => *verbosity = endpoint.verbosity(),
+			}
+		}
+		let added = endpoints
+			.0
+			.into_iter()
+			.filter(|endpoint| !nodes.iter().any(|(_, addr)| addr == endpoint.addr()))
+			.collect::<Vec<_>>();
+
+		for addr in removed {
+			base_verbosities.remove(&(id.clone(), addr.clone()));
+			Self::release_endpoint(&id, addr, node_map, node_pool);
+		}
+
+		if added.is_empty() {
+			return Vec::new();
+		}
+		let addresses = added
+			.iter()
+			.map(|endpoint| endpoint.addr().clone())
+			.collect::<Vec<_>>();
+		let mut registrations = vec![Register::Telemetry {
+			id: id.clone(),
+			endpoints: TelemetryEndpoints(added),
+			connection_message: connection_messages[&id].clone(),
+			overrides: HashMap::new(),
+		}];
+		for notifier in notifiers.get(&id).into_iter().flatten() {
+			registrations.push(Register::Notifier {
+				id: Some(id.clone()),
+				addresses: addresses.clone(),
+				connection_notifier: notifier.clone(),
+			});
+		}
+		registrations
+	}
+
+	/// Release the endpoint `addr` that the span `id` doesn't use anymore: its connection is
+	/// closed unless another span uses it.
+	fn release_endpoint(
+		id: &Id,
+		addr: Multiaddr,
+		node_map: &HashMap<Id, Vec<(u8, Multiaddr)>>,
+		node_pool: &mut HashMap<Multiaddr, Node<WsTrans>>,
+	) {
+		let used = node_map
+			.values()
+			.flatten()
+			.any(|(_, other_addr)| *other_addr == addr);
+		if used {
+			if let Some(node) = node_pool.get_mut(&addr) {
+				node.remove_connection_messages(id);
+			}
+		} else if node_pool.remove(&addr).is_some() {
+			log::debug!(
+				target: "telemetry",
+				"Closing telemetry endpoint {}: no telemetry span uses it anymore",
+				display_addr(&addr),
+			);
+		}
+	}
+
+	/// Set the verbosity of the endpoints of the span `id`, or of its endpoint `addr` only, or
+	/// restore their own verbosity if `verbosity` is `None`.
+	///
+	/// The own verbosity of the overridden endpoints is kept in `base_verbosities`.
+	fn override_verbosity(
+		id: &Id,
+		addr: Option<&Multiaddr>,
+		verbosity: Option<u8>,
+		node_map: &mut HashMap<Id, Vec<(u8, Multiaddr)>>,
+		base_verbosities: &mut HashMap<(Id, Multiaddr), u8>,
+	) {
+		let nodes = match node_map.get_mut(id) {
+			Some(nodes) => nodes,
+			None => {
+				log::warn!(
+					target: "telemetry",
+					"Cannot override the telemetry verbosity of unknown span {:?}",
+					id,
+				);
+				return;
+			}
+		};
+
+		let mut found = false;
+		let selected = nodes
+			.iter_mut()
+			.filter(|(_, node_addr)| addr.is_none() || addr == Some(node_addr));
+		for (node_verbosity, node_addr) in selected {
+			found = true;
+			let key = (id.clone(), node_addr.clone());
+			match verbosity {
+				Some(verbosity) => {
+					base_verbosities.entry(key).or_insert(*node_verbosity);
+					*node_verbosity = verbosity;
+				}
+				None => {
+					if let Some(base_verbosity) = base_verbosities.remove(&key) {
+						*node_verbosity = base_verbosity;
+					}
+				}
 			}
 		}
+
+		if let (Some(addr), false) = (addr, found) {
+			log::warn!(
+				target: "telemetry",
+				"Cannot override the telemetry verbosity of endpoint {}: span {:?} doesn't use it",
+				display_addr(addr),
+				id,
+			);
+		}
+	}
+
+	/// Create the node of `endpoint`, with the settings of the endpoint.
+	fn new_node(
+		endpoint: &TelemetryEndpoint,
+		transport: WsTrans,
+		budget: &BufferBudget,
+		event_sender: &EventSender,
+		config: &TelemetryConfig,
+	) -> Node<WsTrans> {
+		let addr = endpoint.addr();
+		if let Some(reason) = unsupported_reason(endpoint, config) {
+			log::warn!(
+				target: "telemetry",
+				"❌ Telemetry endpoint {} is not supported: {}",
+				endpoint.url(),
+				reason,
+			);
+		}
+
+		let mut node = Node::new(
+			endpoint_transport(endpoint.kind(), config).unwrap_or(transport),
+			addr.clone(),
+			Vec::new(),
+			Vec::new(),
+			budget.clone(),
+			event_sender.clone(),
+			config,
+		);
+		node.set_kind(endpoint.kind().clone());
+		node.set_enabled(endpoint.enabled());
+		if let Some(timeout) = endpoint.connect_timeout() {
+			node.set_connect_timeout(timeout);
+		}
+		if let Some(window) = endpoint.dedup_window() {
+			node.set_dedup_window(window);
+		}
+		if let Some(group) = endpoint.group() {
+			node.set_failover_group(group, endpoint.priority());
+		}
+		node.set_target_verbosity(endpoint.target_verbosity().clone());
+		node
 	}
 
 	async fn process_register(
-		input: Option<Register>,
+		input: Register,
 		node_pool: &mut HashMap<Multiaddr, Node<WsTrans>>,
 		node_map: &mut HashMap<Id, Vec<(u8, Multiaddr)>>,
 		transport: WsTrans,
+		budget: &BufferBudget,
+		event_sender: &mut EventSender,
+		config: &TelemetryConfig,
 	) {
-		let input = input.expect("the stream is never closed; qed");
-
 		match input {
 			Register::Telemetry {
 				id,
 				endpoints,
 				connection_message,
+				overrides,
 			} => {
 				let endpoints = endpoints.0;
+				// The span is registered even without endpoints, for the sinks.
+				node_map.entry(id.clone()).or_default();
 
 				let connection_message = match serde_json::to_value(&connection_message) {
 					Ok(serde_json::Value::Object(mut value)) => {
 						value.insert("msg".into(), "system.connected".into());
-						let mut obj = serde_json::Map::new();
-						obj.insert("id".to_string(), id.into_u64().into());
-						obj.insert("payload".to_string(), value.into());
-						Some(obj)
+						Some(value)
 					}
-					Ok(_) => {
-						unreachable!("ConnectionMessage always serialize to an object; qed")
+					Ok(value) => {
+						log::error!(
+							target: "telemetry",
+							"Connection message is not a JSON object: {}",
+							value,
+						);
+						let error = format!("connection message is not a JSON object: {}", value);
+						event_sender.send(TelemetryEvent::SerializationError(error));
+						None
 					}
 					Err(err) => {
 						log::error!(
@@ -225,26 +941,73 @@ impl TelemetryWorker {
 							"Could not serialize connection message: {}",
 							err,
 						);
+						event_sender.send(TelemetryEvent::SerializationError(err.to_string()));
 						None
 					}
 				};
 
-				for (addr, verbosity) in endpoints {
+				for endpoint in endpoints {
+					let addr = endpoint.addr().clone();
+					let verbosity = endpoint.verbosity();
+
+					// Checked by `start_telemetry_with_overrides`, but a bad override must not
+					// affect the other endpoints.
+					if matches!(overrides.get(&addr), Some(overlay) if !overlay.is_object()) {
+						let err = ConnectionMessageError::OverrideNotAnObject(addr.clone());
+						log::error!(target: "telemetry", "Skipping telemetry endpoint: {}", err);
+						event_sender.send(TelemetryEvent::SerializationError(err.to_string()));
+						continue;
+					}
+
 					node_map
 						.entry(id.clone())
 						.or_default()
 						.push((verbosity, addr.clone()));
 
 					let node = node_pool.entry(addr.clone()).or_insert_with(|| {
-						Node::new(transport.clone(), addr.clone(), Vec::new(), Vec::new())
+						Self::new_node(&endpoint, transport.clone(), budget, event_sender, config)
+					});
+
+					let connection_message = connection_message.clone().map(|mut value| {
+						if let Some(serde_json::Value::Object(overlay)) = overrides.get(&addr) {
+							for (key, field) in overlay {
+								if key == "msg" {
+									log::warn!(
+										target: "telemetry",
+										"Ignoring the reserved `msg` field of the connection \
+										message override for {}",
+										display_addr(&addr),
+									);
+									continue;
+								}
+								value.insert(key.clone(), field.clone());
+							}
+						}
+
+						let mut obj = serde_json::Map::new();
+						obj.insert("id".to_string(), id.into_u64().into());
+						obj.insert("payload".to_string(), value.into());
+						obj
 					});
 
-					node.connection_messages.extend(connection_message.clone());
+					if let Some(connection_message) = connection_message {
+						node.add_connection_message(connection_message);
+					}
 				}
 			}
+			Register::SetEnabled { addr, enabled } => match node_pool.get_mut(&addr) {
+				Some(node) => node.set_enabled(enabled),
+				None => log::warn!(
+					target: "telemetry",
+					"Cannot {} unknown telemetry endpoint {}",
+					if enabled { "enable" } else { "disable" },
+					display_addr(&addr),
+				),
+			},
 			Register::Notifier {
 				addresses,
 				connection_notifier,
+				..
 			} => {
 				for addr in addresses {
 					if let Some(node) = node_pool.get_mut(&addr) {
@@ -259,23 +1022,108 @@ impl TelemetryWorker {
 					}
 				}
 			}
+			Register::AddEndpoint { .. }
+			| Register::UpdateEndpoints { .. }
+			| Register::VerbosityOverride { .. }
+			| Register::Flush { .. }
+			| Register::Shutdown { .. } => {
+				unreachable!("handled by `TelemetryWorker::run`; qed")
+			}
+			Register::RemoveEndpoint { addr } => {
+				for nodes in node_map.values_mut() {
+					nodes.retain(|(_, other_addr)| *other_addr != addr);
+				}
+				// Dropping the node closes its connection.
+				if node_pool.remove(&addr).is_some() {
+					log::debug!(
+						target: "telemetry",
+						"Removed telemetry endpoint {}",
+						display_addr(&addr),
+					);
+				} else {
+					log::warn!(
+						target: "telemetry",
+						"Cannot remove unknown telemetry endpoint {}",
+						display_addr(&addr),
+					);
+				}
+			}
+			Register::Fallback { endpoints } => {
+				for endpoint in endpoints.0 {
+					let addr = endpoint.addr().clone();
+					node_map
+						.entry(fallback_id())
+						.or_default()
+						.push((endpoint.verbosity(), addr.clone()));
+					node_pool.entry(addr).or_insert_with(|| {
+						Self::new_node(&endpoint, transport.clone(), budget, event_sender, config)
+					});
+				}
+			}
+			Register::ReopenFiles => {
+				for node in node_pool.values_mut() {
+					if let EndpointKind::File(_) = node.kind() {
+						future::poll_fn(|cx| node.poll_reopen(cx)).await;
+					}
+				}
+			}
+			Register::Registered { id, reply } => {
+				let _ = reply.send(node_map.contains_key(&id));
+			}
+			Register::EndpointStatus { reply } => {
+				// The requester may have given up.
+				let _ = reply.send(node_pool.values().map(Node::status).collect());
+			}
+			Register::Status { reply } => {
+				let mut status = node_map
+					.iter()
+					.filter(|(id, _)| **id != fallback_id())
+					.map(|(id, nodes)| TelemetryStatusEntry {
+						id: id.clone(),
+						endpoints: nodes
+							.iter()
+							.filter_map(|(verbosity, addr)| {
+								Some((*verbosity, node_pool.get(addr)?.status()))
+							})
+							.collect(),
+					})
+					.collect::<Vec<_>>();
+				status.sort_by_key(|entry| entry.id.into_u64());
+				let _ = reply.send(status);
+			}
+			Register::Close { id } => {
+				let nodes = node_map.remove(&id).unwrap_or_default();
+				for (_, addr) in nodes {
+					Self::release_endpoint(&id, addr, node_map, node_pool);
+				}
+			}
 		}
 	}
 
 	// dispatch messages to the telemetry nodes
 	async fn process_message(
-		input: Option<TelemetryMessage>,
+		input: TelemetryMessage,
 		node_pool: &mut HashMap<Multiaddr, Node<WsTrans>>,
 		node_map: &HashMap<Id, Vec<(u8, Multiaddr)>>,
+		failover_groups: &FailoverGroups,
+		rate_limiter: &mut Option<RateLimiter>,
+		sinks: &mut Sinks,
+		config: &TelemetryConfig,
 	) {
-		let (id, verbosity, message) = input.expect("the stream is never closed; qed");
+		let TelemetryMessage {
+			id,
+			verbosity,
+			payload: message,
+			..
+		} = &input;
+		let verbosity = *verbosity;
 
-		let nodes = if let Some(nodes) = node_map.get(&id) {
+		let nodes = if let Some(nodes) = node_map.get(id).or_else(|| node_map.get(&fallback_id())) {
 			nodes
 		} else {
 			// This is a normal error because the telemetry span is entered before the telemetry
 			// is initialized so it is possible that some messages in the beginning don't get
-			// through.
+			// through, unless there are fallback endpoints.
 			log::trace!(
 				target: "telemetry",
 				"Received telemetry log for unknown id ({:?}): {}",
@@ -285,19 +1133,26 @@ impl TelemetryWorker {
 			return;
 		};
 
-		for (node_max_verbosity, addr) in nodes {
-			if verbosity > *node_max_verbosity {
+		if let Some(rate_limiter) = rate_limiter {
+			if !rate_limiter.check(id, verbosity) {
 				log::trace!(
 					target: "telemetry",
-					"Skipping {} for log entry with verbosity {:?}",
-					addr,
-					verbosity,
+					"Rate limit exceeded for id {:?}, dropping message: {}",
+					id,
+					message,
 				);
-				continue;
+				return;
 			}
+		}
 
-			if let Some(node) = node_pool.get_mut(&addr) {
-				let _ = node.send(message.clone()).await;
+		sinks.send(verbosity, message).await;
+
+		// Only parsed if an endpoint has target verbosities.
+		let mut name = None;
+
+		for (node_max_verbosity, addr) in nodes {
+			let node = if let Some(node) = node_pool.get_mut(&addr) {
+				node
 			} else {
 				log::error!(
 					target: "telemetry",
@@ -306,187 +1161,3377 @@ impl TelemetryWorker {
 					addr,
 					message,
 				);
-			}
-		}
-	}
-}
+				continue;
+			};
 
-/// Handle to the [`TelemetryWorker`] thats allows initializing the telemetry for a Substrate node.
-#[derive(Debug, Clone)]
-pub struct TelemetryHandle {
-	message_sender: mpsc::UnboundedSender<Register>,
-}
+			if !node.is_enabled() {
+				log::trace!(
+					target: "telemetry",
+					"Skipping disabled endpoint {} for log entry",
+					addr,
+				);
+				continue;
+			}
 
-impl TelemetryHandle {
-	/// Initialize the telemetry with the endpoints provided in argument for the current substrate
-	/// node.
-	///
-	/// This method must be called during the substrate node initialization.
-	///
-	/// The `endpoints` argument is a collection of telemetry WebSocket servers with a corresponding
-	/// verbosity level.
-	///
-	/// The `connection_message` argument is a JSON object that is sent every time the connection
-	/// (re-)establishes.
-	pub fn start_telemetry(
-		&mut self,
-		span: TelemetrySpan,
-		endpoints: TelemetryEndpoints,
-		connection_message: ConnectionMessage,
-	) -> TelemetryConnectionNotifier {
-		let Self { message_sender } = self;
+			if failover_groups.is_standby(addr) {
+				log::trace!(
+					target: "telemetry",
+					"Skipping endpoint {} in standby for log entry",
+					addr,
+				);
+				continue;
+			}
 
-		let connection_notifier = TelemetryConnectionNotifier {
-			message_sender: message_sender.clone(),
-			addresses: endpoints.0.iter().map(|(addr, _)| addr.clone()).collect(),
-		};
+			let node_max_verbosity = if node.has_target_verbosity() {
+				let name = name.get_or_insert_with(|| layer::message_name(message));
+				name.as_deref()
+					.and_then(|name| node.target_verbosity(name))
+					.unwrap_or(*node_max_verbosity)
+			} else {
+				*node_max_verbosity
+			};
 
-		match span.0.id() {
-			Some(id) => {
-				match message_sender.unbounded_send(Register::Telemetry {
-					id,
-					endpoints,
-					connection_message,
-				}) {
-					Ok(()) => {}
-					Err(err) => error!(
-						target: "telemetry",
-						"Could not initialize telemetry: \
-						the telemetry is probably already running: {}",
-						err,
-					),
+			let node_max_verbosity = match config.connect_verbosity {
+				Some(connect_verbosity)
+					if matches!(
+						node.connected_since(),
+						Some(since) if since.elapsed() < config.connect_verbosity_window
+					) =>
+				{
+					connect_verbosity.max(node_max_verbosity)
 				}
-			}
-			None => error!(
-				target: "telemetry",
-				"Could not initialize telemetry: the span could not be entered",
-			),
+				_ => node_max_verbosity,
+			};
+
+			if verbosity > node_max_verbosity {
+				log::trace!(
+					target: "telemetry",
+					"Skipping {} for log entry with verbosity {:?}",
+					addr,
+					verbosity,
+				);
+				continue;
+			}
+
+			// The message is queued by the node and sent by `poll_flush_nodes`: a slow telemetry
+			// server must not delay the others.
+			let _ = node.feed(input.clone()).await;
+		}
+	}
+
+	/// Dispatch the messages that have already been logged, then send everything the nodes hold
+	/// and close their connections, within [`TelemetryConfig::shutdown_timeout`]. What could not
+	/// be sent in time is spilled, if enabled. The sinks are flushed.
+	async fn shutdown(
+		message_receivers: &MessageReceivers,
+		node_pool: &mut HashMap<Multiaddr, Node<WsTrans>>,
+		node_map: &HashMap<Id, Vec<(u8, Multiaddr)>>,
+		failover_groups: &FailoverGroups,
+		rate_limiter: &mut Option<RateLimiter>,
+		sinks: &mut Sinks,
+		config: &TelemetryConfig,
+	) -> Result<(), TelemetryError> {
+		Self::process_pending_messages(
+			message_receivers,
+			node_pool,
+			node_map,
+			failover_groups,
+			rate_limiter,
+			sinks,
+			config,
+		)
+		.await;
+
+		let close = future::poll_fn(|cx| {
+			let mut closed = true;
+			for node in node_pool.values_mut() {
+				closed &= node.poll_shutdown(cx).is_ready();
+			}
+			if closed {
+				std::task::Poll::Ready(())
+			} else {
+				std::task::Poll::Pending
+			}
+		});
+		let timeout = wasm_timer::Delay::new(config.shutdown_timeout);
+
+		let closed = futures::select! {
+			_ = close.fuse() => true,
+			_ = timeout.fuse() => false,
+		};
+		sinks.flush().await;
+
+		if closed {
+			log::debug!(target: "telemetry", "Telemetry worker stopped");
+			Ok(())
+		} else {
+			for node in node_pool.values_mut() {
+				node.abort_shutdown();
+			}
+			Err(TelemetryError::ShutdownTimeout(config.shutdown_timeout))
+		}
+	}
+
+	/// Dispatch the messages that are waiting in the channels from the layer.
+	async fn process_pending_messages(
+		message_receivers: &MessageReceivers,
+		node_pool: &mut HashMap<Multiaddr, Node<WsTrans>>,
+		node_map: &HashMap<Id, Vec<(u8, Multiaddr)>>,
+		failover_groups: &FailoverGroups,
+		rate_limiter: &mut Option<RateLimiter>,
+		sinks: &mut Sinks,
+		config: &TelemetryConfig,
+	) {
+		while let Some(message) = message_receivers.try_next() {
+			let started = Instant::now();
+			let (verbosity, payload) = (message.verbosity, message.payload.clone());
+			Self::process_message(
+				message,
+				node_pool,
+				node_map,
+				failover_groups,
+				rate_limiter,
+				sinks,
+				config,
+			)
+			.await;
+			message_receivers.processed(verbosity, &payload, started, config);
+		}
+	}
+
+	/// Write what the nodes hold to their connection and flush them, as well as the sinks, for
+	/// at most `timeout`.
+	async fn flush(
+		node_pool: &mut HashMap<Multiaddr, Node<WsTrans>>,
+		sinks: &mut Sinks,
+		timeout: std::time::Duration,
+	) -> FlushResult {
+		let sent = |node_pool: &HashMap<Multiaddr, Node<WsTrans>>| {
+			node_pool.values().map(Node::messages_sent).sum::<u64>()
+		};
+		let sent_before = sent(node_pool);
+
+		let flush = future::poll_fn(|cx| {
+			let mut flushed = true;
+			for node in node_pool.values_mut() {
+				flushed &= node.poll_flush_all(cx).is_ready();
+			}
+			if flushed {
+				std::task::Poll::Ready(())
+			} else {
+				std::task::Poll::Pending
+			}
+		});
+		let timeout = wasm_timer::Delay::new(timeout);
+		futures::select! {
+			_ = flush.fuse() => {},
+			_ = timeout.fuse() => {},
+		}
+		sinks.flush().await;
+
+		FlushResult {
+			flushed: sent(node_pool) - sent_before,
+			abandoned: node_pool.values().map(|node| node.pending_messages() as u64).sum(),
+		}
+	}
+
+	/// Elect the endpoints that receive the telemetry of their failover group, and put the others
+	/// in standby.
+	fn update_failover_groups(
+		failover_groups: &mut FailoverGroups,
+		node_pool: &mut HashMap<Multiaddr, Node<WsTrans>>,
+		config: &TelemetryConfig,
+	) {
+		// The nodes that have been closed leave their group, and the nodes registered since the
+		// last update join their group in standby.
+		failover_groups.retain(|addr| node_pool.contains_key(addr));
+		for (addr, node) in node_pool.iter_mut() {
+			if let Some((group, priority)) = node.failover_group() {
+				if failover_groups.insert(group, priority, addr.clone()) {
+					node.set_standby(true);
+				}
+			}
+		}
+
+		let switches = failover_groups.update(|addr| match node_pool.get(addr) {
+			Some(node) if !node.is_enabled() => MemberState::Disabled,
+			Some(node) => match node.disconnected_since() {
+				Some(since) if since.elapsed() >= config.failover_delay => {
+					MemberState::Unavailable
+				}
+				_ => MemberState::Available,
+			},
+			None => MemberState::Disabled,
+		});
+
+		for Switch { group, from, to } in switches {
+			if let Some(from) = &from {
+				log::info!(
+					target: "telemetry",
+					"Telemetry group {} switched from {} to {}",
+					group,
+					display_addr(from),
+					to.as_ref().map_or_else(|| "no endpoint".into(), display_addr),
+				);
+			}
+			if let Some(node) = from.and_then(|addr| node_pool.get_mut(&addr)) {
+				node.set_standby(true);
+			}
+			if let Some(node) = to.and_then(|addr| node_pool.get_mut(&addr)) {
+				node.set_standby(false);
+			}
 		}
+	}
 
-		connection_notifier
+	/// Send the messages queued by every node, and let the nodes in standby that are preferred
+	/// to the active node of their failover group reconnect, so that the telemetry can switch
+	/// back to them.
+	///
+	/// This never completes: it only keeps the messages flowing as the connections become ready.
+	fn poll_flush_nodes(
+		node_pool: &mut HashMap<Multiaddr, Node<WsTrans>>,
+		failover_groups: &FailoverGroups,
+		cx: &mut std::task::Context,
+	) -> std::task::Poll<()> {
+		for node in node_pool.values_mut() {
+			let _ = node.poll_flush_unpin(cx);
+		}
+		for addr in failover_groups.preferred_standby() {
+			if let Some(node) = node_pool.get_mut(addr) {
+				let _ = node.poll_ready_unpin(cx);
+			}
+		}
+		std::task::Poll::Pending
 	}
 }
 
-/// Used to create a stream of events with only one event: when a telemetry connection
-/// (re-)establishes.
-#[derive(Clone, Debug)]
-pub struct TelemetryConnectionNotifier {
-	message_sender: mpsc::UnboundedSender<Register>,
-	addresses: Vec<Multiaddr>,
+/// Receiving ends of the channels of the telemetry messages from the [`TelemetryLayer`].
+struct MessageReceivers {
+	/// Messages up to [`SUBSTRATE_INFO`] that did not fit in the main channel.
+	priority: Mutex<mpsc::Receiver<TelemetryMessage>>,
+	/// Shared with the layer so that it can drop the oldest messages.
+	normal: Arc<Mutex<mpsc::Receiver<TelemetryMessage>>>,
+	/// Where the time taken to dispatch the received messages is recorded.
+	stats: Arc<StatsCounters>,
 }
 
-impl TelemetryConnectionNotifier {
-	/// Get event stream for telemetry connection established events.
+impl MessageReceivers {
+	/// Poll for the next message, taken from the priority channel first.
 	///
-	/// This function will return an error if the telemetry has already been started by
-	/// [`TelemetryHandle::start_telemetry`].
-	pub fn on_connect_stream(&self) -> TracingUnboundedReceiver<()> {
-		let (message_sender, message_receiver) = tracing_unbounded("mpsc_telemetry_on_connect");
-		if let Err(err) = self.message_sender.unbounded_send(Register::Notifier {
-			addresses: self.addresses.clone(),
-			connection_notifier: message_sender,
-		}) {
-			error!(
+	/// Returns `None` once every sender of the main channel has been dropped.
+	fn poll_next(
+		&self,
+		cx: &mut std::task::Context,
+	) -> std::task::Poll<Option<TelemetryMessage>> {
+		if let std::task::Poll::Ready(Some(message)) = self.priority.lock().poll_next_unpin(cx) {
+			return std::task::Poll::Ready(Some(message));
+		}
+		self.normal.lock().poll_next_unpin(cx)
+	}
+
+	/// Take the next message that is already waiting, from the priority channel first.
+	fn try_next(&self) -> Option<TelemetryMessage> {
+		let priority = self.priority.lock().try_next().ok().flatten();
+		priority.or_else(|| self.normal.lock().try_next().ok().flatten())
+	}
+
+	/// Record the time taken to dispatch the message `payload` since `started`, and warn if it
+	/// took longer than the [`TelemetryConfig::slow_message_threshold`].
+	fn processed(&self, verbosity: u8, payload: &str, started: Instant, config: &TelemetryConfig) {
+		let elapsed = started.elapsed();
+		self.stats.record_processing_time(elapsed);
+		if matches!(config.slow_message_threshold, Some(threshold) if elapsed > threshold) {
+			log::warn!(
 				target: "telemetry",
-				"Could not create a telemetry connection notifier: \
-				the telemetry is probably already running: {}",
-				err,
+				"Dispatching the telemetry message {} with verbosity {} took {:?}",
+				layer::message_name(payload).unwrap_or_else(|| "without a name".into()),
+				verbosity,
+				elapsed,
 			);
 		}
-		message_receiver
 	}
 }
 
-#[derive(Debug)]
-enum Register {
-	Telemetry {
-		id: Id,
+/// Handle to the [`TelemetryWorker`] thats allows initializing the telemetry for a Substrate node.
+#[derive(Debug, Clone)]
+pub struct TelemetryHandle {
+	message_sender: mpsc::UnboundedSender<Register>,
+	budget: BufferBudget,
+	stats: Arc<StatsCounters>,
+	max_verbosity: Arc<AtomicU8>,
+}
+
+impl TelemetryHandle {
+	/// Initialize the telemetry with the endpoints provided in argument for the current substrate
+	/// node.
+	///
+	/// This method must be called during the substrate node initialization.
+	///
+	/// The `endpoints` argument is a collection of telemetry WebSocket servers with a corresponding
+	/// verbosity level.
+	///
+	/// The `connection_message` argument is a JSON object that is sent every time the connection
+	/// (re-)establishes.
+	///
+	/// If the `span` is disabled, for example by the log filters, the telemetry is not started
+	/// and the returned [`TelemetryConnectionNotifier`] never fires.
+	///
+	/// The telemetry is registered once the [`TelemetryWorker`] runs, which
+	/// [`TelemetryConnectionNotifier::registered`] waits for.
+	pub fn start_telemetry(
+		&mut self,
+		span: TelemetrySpan,
 		endpoints: TelemetryEndpoints,
 		connection_message: ConnectionMessage,
-	},
-	Notifier {
-		addresses: Vec<Multiaddr>,
-		connection_notifier: ConnectionNotifierSender,
-	},
-}
+	) -> TelemetryConnectionNotifier {
+		self.start_telemetry_with_overrides(span, endpoints, connection_message, HashMap::new())
+			.expect("there is no override to validate; qed")
+	}
 
-/// Report a telemetry.
-///
-/// Translates to [`tracing::info`], but contains an additional verbosity parameter which the log
-/// record is tagged with. Additionally the verbosity parameter is added to the record as a
-/// key-value pair.
-///
-/// # Example
-///
-/// ```no_run
-/// # use sc_telemetry::*;
-/// # let authority_id = 42_u64;
-/// # let set_id = (43_u64, 44_u64);
-/// # let authorities = vec![45_u64];
-/// telemetry!(CONSENSUS_INFO; "afg.authority_set";
-/// 	"authority_id" => authority_id.to_string(),
-/// 	"authority_set_id" => ?set_id,
-/// 	"authorities" => authorities,
-/// );
-/// ```
-#[macro_export(local_inner_macros)]
-macro_rules! telemetry {
-	( $verbosity:expr; $msg:expr; $( $t:tt )* ) => {{
-		let verbosity: u8 = $verbosity;
-		match format_fields_to_json!($($t)*) {
-			Err(err) => {
-				$crate::tracing::error!(
+	/// Same as [`TelemetryHandle::start_telemetry`] but with additional fields for the connection
+	/// message of some endpoints.
+	///
+	/// The `overrides` argument maps the address of an endpoint to a JSON object whose fields are
+	/// merged into the `connection_message` sent to this endpoint only. The fields of the override
+	/// take precedence over the fields of the `connection_message`, except for the `msg` field
+	/// that is reserved. An error is returned if an override is not a JSON object.
+	///
+	/// The merged fields are sent under the `payload` key of the message, next to the `id` of the
+	/// span: an `id` field in an override is therefore sent as is and does not replace it.
+	pub fn start_telemetry_with_overrides(
+		&mut self,
+		span: TelemetrySpan,
+		endpoints: TelemetryEndpoints,
+		connection_message: ConnectionMessage,
+		overrides: HashMap<Multiaddr, serde_json::Value>,
+	) -> Result<TelemetryConnectionNotifier, ConnectionMessageError> {
+		let Self {
+			message_sender,
+			max_verbosity,
+			..
+		} = self;
+
+		if let Some((addr, _)) = overrides.iter().find(|(_, overlay)| !overlay.is_object()) {
+			return Err(ConnectionMessageError::OverrideNotAnObject(addr.clone()));
+		}
+
+		// Let the messages for these endpoints through the `TelemetryLayer` before they are
+		// registered, so that none of them is lost. Disabled endpoints can be enabled later.
+		if let Some(verbosity) = endpoints.0.iter().map(|e| e.max_verbosity()).max() {
+			max_verbosity.fetch_max(verbosity, Ordering::Relaxed);
+		}
+
+		let id = match span.telemetry_id() {
+			Some(id) => id,
+			None => {
+				// Every node of the process would log this.
+				static WARNED: AtomicBool = AtomicBool::new(false);
+				if !WARNED.swap(true, Ordering::Relaxed) {
+					warn!(
+						target: "telemetry",
+						"Telemetry is disabled: the telemetry span is disabled by the log filters",
+					);
+				}
+				return Ok(TelemetryConnectionNotifier {
+					message_sender: message_sender.clone(),
+					id: None,
+					addresses: Vec::new(),
+				});
+			}
+		};
+
+		let connection_notifier = TelemetryConnectionNotifier {
+			message_sender: message_sender.clone(),
+			id: Some(id.clone()),
+			addresses: endpoints.0.iter().map(|e| e.addr().clone()).collect(),
+		};
+
+		if let Err(err) = message_sender.unbounded_send(Register::Telemetry {
+			id,
+			endpoints,
+			connection_message,
+			overrides,
+		}) {
+			error!(
+				target: "telemetry",
+				"Could not initialize telemetry: the telemetry is probably already running: {}",
+				err,
+			);
+		}
+
+		Ok(connection_notifier)
+	}
+
+	/// Stop the telemetry started with [`TelemetryHandle::start_telemetry`] for `span`.
+	///
+	/// The endpoints are unregistered from the span, and the connections that no other telemetry
+	/// span uses are closed. The messages logged in the span afterwards are discarded. This
+	/// happens anyway once every clone of the span has been dropped, which closes the span:
+	/// `span` is dropped, but the span stays open as long as its other clones are alive.
+	pub fn stop_telemetry(&self, span: TelemetrySpan) {
+		// A span without an id has never been registered.
+		if let Some(id) = span.telemetry_id() {
+			if let Err(err) = self.message_sender.unbounded_send(Register::Close { id }) {
+				error!(
 					target: "telemetry",
-					"Could not serialize value for telemetry: {}",
+					"Could not stop telemetry: the telemetry is probably not running: {}",
 					err,
 				);
-			},
-			Ok(mut json) => {
-				// NOTE: the span id will be added later in the JSON for the greater good
-				json.insert("msg".into(), $msg.into());
-				let serialized_json = $crate::serde_json::to_string(&json)
-					.expect("contains only string keys; qed");
-				$crate::tracing::info!(target: $crate::TELEMETRY_LOG_SPAN,
-					verbosity,
-					json = serialized_json.as_str(),
-				);
-			},
+			}
 		}
-	}};
-}
+	}
 
-#[macro_export(local_inner_macros)]
-#[doc(hidden)]
-macro_rules! format_fields_to_json {
-	( $k:literal => $v:expr $(,)? $(, $($t:tt)+ )? ) => {{
-		$crate::serde_json::to_value(&$v)
-			.map(|value| {
-				let mut map = $crate::serde_json::Map::new();
-				map.insert($k.into(), value);
-				map
-			})
-			$(
-				.and_then(|mut prev_map| {
-					format_fields_to_json!($($t)*)
-						.map(move |mut other_map| {
-							prev_map.append(&mut other_map);
-							prev_map
-						})
-				})
-			)*
-	}};
-	( $k:literal => ? $v:expr $(,)? $(, $($t:tt)+ )? ) => {{
-		let mut map = $crate::serde_json::Map::new();
-		map.insert($k.into(), std::format!("{:?}", &$v).into());
-		$crate::serde_json::Result::Ok(map)
-		$(
-			.and_then(|mut prev_map| {
-				format_fields_to_json!($($t)*)
-					.map(move |mut other_map| {
-						prev_map.append(&mut other_map);
-						prev_map
-					})
-			})
-		)*
-	}};
+	/// Enable or disable the telemetry endpoint with the given address, for every telemetry
+	/// span that uses it.
+	///
+	/// A disabled endpoint is disconnected and doesn't receive any message. It reconnects when it
+	/// is enabled again. This has no effect on endpoints that have not been registered with
+	/// [`TelemetryHandle::start_telemetry`] yet.
+	pub fn set_endpoint_enabled(&self, addr: &Multiaddr, enabled: bool) {
+		if let Err(err) = self.message_sender.unbounded_send(Register::SetEnabled {
+			addr: addr.clone(),
+			enabled,
+		}) {
+			error!(
+				target: "telemetry",
+				"Could not change the state of telemetry endpoint {}: \
+				the telemetry is probably not running: {}",
+				addr,
+				err,
+			);
+		}
+	}
+
+	/// Add a telemetry endpoint to every telemetry span started with
+	/// [`TelemetryHandle::start_telemetry`], without restarting the [`TelemetryWorker`].
+	///
+	/// The endpoint receives the connection message of every span, and the messages up to the
+	/// given verbosity. Only the verbosity changes if the endpoint is already used. The verbosity
+	/// must be at most [`MAX_VERBOSITY`].
+	pub fn add_endpoint(&self, addr: &Multiaddr, verbosity: u8) {
+		if verbosity > MAX_VERBOSITY {
+			error!(
+				target: "telemetry",
+				"Could not add telemetry endpoint {}: verbosity {} is above the maximum of {}",
+				addr,
+				verbosity,
+				MAX_VERBOSITY,
+			);
+			return;
+		}
+
+		self.max_verbosity.fetch_max(verbosity, Ordering::Relaxed);
+		if let Err(err) = self.message_sender.unbounded_send(Register::AddEndpoint {
+			endpoint: TelemetryEndpoint::new(addr.clone(), verbosity),
+		}) {
+			error!(
+				target: "telemetry",
+				"Could not add telemetry endpoint {}: \
+				the telemetry is probably not running: {}",
+				addr,
+				err,
+			);
+		}
+	}
+
+	/// Override the verbosity of the endpoints of the telemetry span `id`, or of its endpoint
+	/// `endpoint` only, without restarting the [`TelemetryWorker`].
+	///
+	/// The endpoints receive the messages of the span up to `max_verbosity` instead of their own
+	/// verbosity until [`TelemetryHandle::clear_verbosity_override`] is called. The verbosity
+	/// must be at most [`MAX_VERBOSITY`].
+	pub fn set_verbosity_override(&self, id: Id, endpoint: Option<Multiaddr>, max_verbosity: u8) {
+		if max_verbosity > MAX_VERBOSITY {
+			error!(
+				target: "telemetry",
+				"Could not override the telemetry verbosity: verbosity {} is above the maximum \
+				of {}",
+				max_verbosity,
+				MAX_VERBOSITY,
+			);
+			return;
+		}
+
+		self.max_verbosity.fetch_max(max_verbosity, Ordering::Relaxed);
+		self.send_verbosity_override(id, endpoint, Some(max_verbosity));
+	}
+
+	/// Restore the verbosity of the endpoints of the telemetry span `id`, or of its endpoint
+	/// `endpoint` only, overridden by [`TelemetryHandle::set_verbosity_override`].
+	pub fn clear_verbosity_override(&self, id: Id, endpoint: Option<Multiaddr>) {
+		self.send_verbosity_override(id, endpoint, None);
+	}
+
+	fn send_verbosity_override(&self, id: Id, addr: Option<Multiaddr>, verbosity: Option<u8>) {
+		if let Err(err) = self.message_sender.unbounded_send(Register::VerbosityOverride {
+			id,
+			addr,
+			verbosity,
+		}) {
+			error!(
+				target: "telemetry",
+				"Could not override the telemetry verbosity: \
+				the telemetry is probably not running: {}",
+				err,
+			);
+		}
+	}
+
+	/// Replace the endpoints of the telemetry span `id`, see [`TelemetrySpan::telemetry_id`],
+	/// without restarting the [`TelemetryWorker`].
+	///
+	/// The added endpoints receive the connection message of the span, without the overrides of
+	/// [`TelemetryHandle::start_telemetry_with_overrides`], and the connection notifiers of the
+	/// span fire when they connect. The removed endpoints are closed unless another span uses
+	/// them. Only the verbosity changes for the endpoints that the span keeps.
+	pub fn update_endpoints(&self, id: Id, endpoints: TelemetryEndpoints) {
+		if let Some(verbosity) = endpoints.0.iter().map(|e| e.max_verbosity()).max() {
+			self.max_verbosity.fetch_max(verbosity, Ordering::Relaxed);
+		}
+		if let Err(err) = self
+			.message_sender
+			.unbounded_send(Register::UpdateEndpoints { id, endpoints })
+		{
+			error!(
+				target: "telemetry",
+				"Could not update the telemetry endpoints: \
+				the telemetry is probably not running: {}",
+				err,
+			);
+		}
+	}
+
+	/// Remove a telemetry endpoint from every telemetry span and close its connection.
+	pub fn remove_endpoint(&self, addr: &Multiaddr) {
+		if let Err(err) = self
+			.message_sender
+			.unbounded_send(Register::RemoveEndpoint { addr: addr.clone() })
+		{
+			error!(
+				target: "telemetry",
+				"Could not remove telemetry endpoint {}: \
+				the telemetry is probably not running: {}",
+				addr,
+				err,
+			);
+		}
+	}
+
+	/// Send the messages of the telemetry spans that have no telemetry, e.g. because they are
+	/// logged before [`TelemetryHandle::start_telemetry`] or after
+	/// [`TelemetryHandle::stop_telemetry`], to `endpoints` instead of discarding them.
+	///
+	/// The endpoints receive these messages up to their verbosity, without any connection
+	/// message. They replace the previous fallback endpoints, if any, and an empty list removes
+	/// them.
+	pub fn set_fallback_endpoints(&self, endpoints: TelemetryEndpoints) {
+		if let Some(verbosity) = endpoints.0.iter().map(|e| e.max_verbosity()).max() {
+			self.max_verbosity.fetch_max(verbosity, Ordering::Relaxed);
+		}
+		if let Err(err) = self
+			.message_sender
+			.unbounded_send(Register::Fallback { endpoints })
+		{
+			error!(
+				target: "telemetry",
+				"Could not set the fallback telemetry endpoints: \
+				the telemetry is probably not running: {}",
+				err,
+			);
+		}
+	}
+
+	/// Close the files of the `file://` endpoints and open them again, e.g. after they have been
+	/// renamed by a log rotation.
+	///
+	/// The messages held for the endpoints are written to the old files before they are closed,
+	/// and the connection messages are written again at the beginning of the new files.
+	pub fn reopen_files(&self) {
+		if let Err(err) = self.message_sender.unbounded_send(Register::ReopenFiles) {
+			error!(
+				target: "telemetry",
+				"Could not reopen the telemetry files: the telemetry is probably not running: {}",
+				err,
+			);
+		}
+	}
+
+	/// Approximate number of bytes currently held in the buffers of the [`TelemetryWorker`].
+	///
+	/// This is bounded by [`TelemetryConfig::max_buffered_bytes`].
+	pub fn buffered_bytes(&self) -> usize {
+		self.budget.used()
+	}
+
+	/// Number of telemetry messages dropped because of
+	/// [`TelemetryConfig::max_messages_per_second`].
+	pub fn rate_limited_messages(&self) -> u64 {
+		self.stats.messages_dropped_rate_limit.load(Ordering::Relaxed)
+	}
+
+	/// Number of telemetry messages dropped by the [`TelemetryLayer`] because the queue to the
+	/// [`TelemetryWorker`] was full. See [`TelemetryConfig::overflow_policy`].
+	pub fn overflowed_messages(&self) -> u64 {
+		self.stats.messages_dropped_buffer.load(Ordering::Relaxed)
+	}
+
+	/// Statistics about the telemetry messages since the [`TelemetryWorker`] has been created.
+	pub fn stats(&self) -> TelemetryStats {
+		self.stats.snapshot()
+	}
+
+	/// Distribution of the time taken by the [`TelemetryWorker`] to dispatch each telemetry
+	/// message since it has been created.
+	pub fn processing_times(&self) -> ProcessingTimes {
+		self.stats.processing_times()
+	}
+
+	/// Whether the future of [`TelemetryWorker::run`] has been polled and is still alive.
+	///
+	/// Until then, the telemetry started with [`TelemetryHandle::start_telemetry`] waits for the
+	/// worker, see [`TelemetryConnectionNotifier::registered`].
+	pub fn is_worker_running(&self) -> bool {
+		self.stats.worker_running()
+	}
+
+	/// Number of telemetry servers that the [`TelemetryWorker`] is currently connected to,
+	/// including the endpoints in standby in a failover group.
+	pub fn connected_endpoints(&self) -> usize {
+		self.stats.connected_endpoints()
+	}
+
+	/// State of the connection to every telemetry server, in no particular order.
+	///
+	/// This is answered by the [`TelemetryWorker`] in between the messages it sends, and is empty
+	/// if the worker is not running.
+	pub async fn endpoint_status(&self) -> Vec<EndpointStatus> {
+		let (reply, status) = oneshot::channel();
+		if self
+			.message_sender
+			.unbounded_send(Register::EndpointStatus { reply })
+			.is_err()
+		{
+			return Vec::new();
+		}
+		status.await.unwrap_or_default()
+	}
+
+	/// Send the telemetry logged so far to the telemetry servers, waiting for at most `timeout`,
+	/// e.g. before the process exits.
+	///
+	/// The messages waiting for the [`TelemetryWorker`] are dispatched, then written to the
+	/// connections of the telemetry servers along with the current batches, and the connections
+	/// and the [`TelemetrySink`]s are flushed. Unlike [`ShutdownHandle::shutdown`], the worker
+	/// keeps running. This completes right away if the worker is not running.
+	pub async fn flush(&self, timeout: std::time::Duration) -> FlushResult {
+		let (done, result) = oneshot::channel();
+		if self
+			.message_sender
+			.unbounded_send(Register::Flush { timeout, done })
+			.is_err()
+		{
+			return FlushResult::default();
+		}
+		result.await.unwrap_or_default()
+	}
+
+	/// Endpoints of every telemetry span, by increasing span id, with the state of their
+	/// connection and the number of messages sent and dropped.
+	///
+	/// Like [`TelemetryHandle::endpoint_status`], this is answered by the [`TelemetryWorker`] in
+	/// between the messages it sends, and is empty if the worker is not running.
+	pub async fn status(&self) -> Vec<TelemetryStatusEntry> {
+		let (reply, status) = oneshot::channel();
+		if self
+			.message_sender
+			.unbounded_send(Register::Status { reply })
+			.is_err()
+		{
+			return Vec::new();
+		}
+		status.await.unwrap_or_default()
+	}
+}
+
+/// Outcome of [`TelemetryHandle::flush`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlushResult {
+	/// Number of messages written to the connections of the telemetry servers during the flush,
+	/// including the connection messages.
+	pub flushed: u64,
+	/// Number of messages that were still waiting for the connection of a telemetry server when
+	/// the flush timed out. They are sent later if the worker keeps running.
+	pub abandoned: u64,
+}
+
+/// Error returned by [`TelemetryWorker::run`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TelemetryError {
+	/// The worker stopped before the remaining telemetry could be sent to every telemetry
+	/// server, after [`TelemetryConfig::shutdown_timeout`].
+	ShutdownTimeout(std::time::Duration),
+}
+
+impl std::fmt::Display for TelemetryError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			TelemetryError::ShutdownTimeout(timeout) => write!(
+				f,
+				"the remaining telemetry could not be sent within {:?}",
+				timeout,
+			),
+		}
+	}
+}
+
+impl std::error::Error for TelemetryError {}
+
+/// Error returned by [`TelemetryHandle::start_telemetry_with_overrides`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionMessageError {
+	/// The override of the connection message of the endpoint with this address is not a JSON
+	/// object.
+	OverrideNotAnObject(Multiaddr),
+}
+
+impl std::fmt::Display for ConnectionMessageError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			ConnectionMessageError::OverrideNotAnObject(addr) => write!(
+				f,
+				"the connection message override of {} is not a JSON object",
+				display_addr(addr),
+			),
+		}
+	}
+}
+
+impl std::error::Error for ConnectionMessageError {}
+
+/// Handle to stop the [`TelemetryWorker`] gracefully.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+	register_sender: mpsc::UnboundedSender<Register>,
+}
+
+impl ShutdownHandle {
+	/// Stop the [`TelemetryWorker`] after sending the telemetry logged so far.
+	///
+	/// The connections being established are awaited so that the connection messages reach the
+	/// telemetry servers, then the connections are closed. This completes once the worker has
+	/// stopped, which takes at most [`TelemetryConfig::shutdown_timeout`], or right away if the
+	/// worker is not running anymore.
+	pub async fn shutdown(self) {
+		let (done, stopped) = oneshot::channel();
+		if self
+			.register_sender
+			.unbounded_send(Register::Shutdown { done })
+			.is_ok()
+		{
+			// Cancelled if the worker is dropped without running.
+			let _ = stopped.await;
+		}
+	}
+}
+
+/// Used to create a stream of events with only one event: when a telemetry connection
+/// (re-)establishes.
+#[derive(Clone, Debug)]
+pub struct TelemetryConnectionNotifier {
+	message_sender: mpsc::UnboundedSender<Register>,
+	id: Option<Id>,
+	addresses: Vec<Multiaddr>,
+}
+
+impl TelemetryConnectionNotifier {
+	/// Get event stream for telemetry connection established events.
+	///
+	/// This function will return an error if the telemetry has already been started by
+	/// [`TelemetryHandle::start_telemetry`].
+	pub fn on_connect_stream(&self) -> TracingUnboundedReceiver<()> {
+		let (message_sender, message_receiver) = tracing_unbounded("mpsc_telemetry_on_connect");
+		if let Err(err) = self.message_sender.unbounded_send(Register::Notifier {
+			id: self.id.clone(),
+			addresses: self.addresses.clone(),
+			connection_notifier: message_sender,
+		}) {
+			error!(
+				target: "telemetry",
+				"Could not create a telemetry connection notifier: \
+				the telemetry is probably already running: {}",
+				err,
+			);
+		}
+		message_receiver
+	}
+
+	/// Wait for the [`TelemetryWorker`] to register the telemetry started by
+	/// [`TelemetryHandle::start_telemetry`].
+	///
+	/// Resolves to `false` if the telemetry is disabled, if it has been stopped, or if the worker
+	/// is not running anymore. This doesn't resolve while the worker has not been spawned, see
+	/// [`TelemetryHandle::is_worker_running`].
+	pub async fn registered(&self) -> bool {
+		let id = match &self.id {
+			Some(id) => id.clone(),
+			None => return false,
+		};
+		let (reply, registered) = oneshot::channel();
+		if self
+			.message_sender
+			.unbounded_send(Register::Registered { id, reply })
+			.is_err()
+		{
+			return false;
+		}
+		registered.await.unwrap_or(false)
+	}
+}
+
+// Registrations are rare, there is no point in boxing the large variant.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+enum Register {
+	Telemetry {
+		id: Id,
+		endpoints: TelemetryEndpoints,
+		connection_message: ConnectionMessage,
+		overrides: HashMap<Multiaddr, serde_json::Value>,
+	},
+	SetEnabled {
+		addr: Multiaddr,
+		enabled: bool,
+	},
+	/// Notify `connection_notifier` of the connections to `addresses`, and to the endpoints
+	/// added later to the span `id`.
+	Notifier {
+		id: Option<Id>,
+		addresses: Vec<Multiaddr>,
+		connection_notifier: ConnectionNotifierSender,
+	},
+	/// The telemetry span has been closed.
+	Close {
+		id: Id,
+	},
+	/// Add an endpoint to every telemetry span.
+	AddEndpoint {
+		endpoint: TelemetryEndpoint,
+	},
+	/// Remove an endpoint from every telemetry span.
+	RemoveEndpoint {
+		addr: Multiaddr,
+	},
+	/// Replace the endpoints of a telemetry span.
+	UpdateEndpoints {
+		id: Id,
+		endpoints: TelemetryEndpoints,
+	},
+	/// Override the verbosity of the endpoints of a telemetry span, or of one of them, or
+	/// restore their own verbosity with `None`.
+	VerbosityOverride {
+		id: Id,
+		addr: Option<Multiaddr>,
+		verbosity: Option<u8>,
+	},
+	/// Send the messages of the unknown telemetry spans to `endpoints`. Always preceded by the
+	/// `Close` of the previous fallback.
+	Fallback {
+		endpoints: TelemetryEndpoints,
+	},
+	/// Close and reopen the files of the `file://` endpoints.
+	ReopenFiles,
+	/// Report on `reply` whether the telemetry span is registered.
+	Registered {
+		id: Id,
+		reply: oneshot::Sender<bool>,
+	},
+	/// Report the state of every endpoint on `reply`.
+	EndpointStatus {
+		reply: oneshot::Sender<Vec<EndpointStatus>>,
+	},
+	/// Report the endpoints of every telemetry span on `reply`.
+	Status {
+		reply: oneshot::Sender<Vec<TelemetryStatusEntry>>,
+	},
+	/// Send the pending telemetry for at most `timeout` and report how it went on `done`.
+	Flush {
+		timeout: std::time::Duration,
+		done: oneshot::Sender<FlushResult>,
+	},
+	/// Stop the worker and report it on `done`.
+	Shutdown {
+		done: oneshot::Sender<()>,
+	},
+}
+
+/// Id under which the fallback endpoints are registered in the node map of the worker, see
+/// [`TelemetryHandle::set_fallback_endpoints`]. The ids of the spans are allocated from 1 and
+/// never reach it.
+fn fallback_id() -> Id {
+	Id::from_u64(u64::MAX)
+}
+
+/// Report a telemetry.
+///
+/// Translates to [`tracing::info`], but contains an additional verbosity parameter which the log
+/// record is tagged with. Additionally the verbosity parameter is added to the record as a
+/// key-value pair.
+///
+/// The values are serialized with `serde`, or formatted with `Debug` when prefixed with `?` and
+/// with `Display` when prefixed with `%`. A group of fields in braces is serialized as a nested
+/// object.
+///
+/// # Example
+///
+/// ```no_run
+/// # use sc_telemetry::*;
+/// # let authority_id = 42_u64;
+/// # let set_id = (43_u64, 44_u64);
+/// # let authorities = vec![45_u64];
+/// telemetry!(CONSENSUS_INFO; "afg.authority_set";
+/// 	"authority_id" => %authority_id,
+/// 	"authority_set_id" => ?set_id,
+/// 	"authorities" => authorities,
+/// 	"voter" => {
+/// 		"id" => authority_id,
+/// 		"set_id" => ?set_id,
+/// 	},
+/// );
+/// ```
+#[macro_export(local_inner_macros)]
+macro_rules! telemetry {
+	( $verbosity:expr; $msg:expr; $( $t:tt )* ) => {{
+		let verbosity: u8 = $verbosity;
+		match format_fields_to_json!($($t)*) {
+			Err(err) => {
+				$crate::tracing::error!(
+					target: "telemetry",
+					"Could not serialize value for telemetry: {}",
+					err,
+				);
+			},
+			Ok(mut json) => {
+				// NOTE: the span id will be added later in the JSON for the greater good
+				json.insert("msg".into(), $msg.into());
+				let serialized_json = $crate::serde_json::to_string(&json)
+					.expect("contains only string keys; qed");
+				$crate::tracing::info!(target: $crate::TELEMETRY_LOG_SPAN,
+					verbosity,
+					json = serialized_json.as_str(),
+				);
+			},
+		}
+	}};
+}
+
+#[macro_export(local_inner_macros)]
+#[doc(hidden)]
+macro_rules! format_fields_to_json {
+	// Must come before the `$v:expr` arm, which would parse the braces as a block.
+	( $k:literal => { $($inner:tt)+ } $(,)? $(, $($t:tt)+ )? ) => {{
+		format_fields_to_json!($($inner)+)
+			.map(|inner| {
+				let mut map = $crate::serde_json::Map::new();
+				map.insert($k.into(), $crate::serde_json::Value::Object(inner));
+				map
+			})
+			$(
+				.and_then(|mut prev_map| {
+					format_fields_to_json!($($t)*)
+						.map(move |mut other_map| {
+							prev_map.append(&mut other_map);
+							prev_map
+						})
+				})
+			)*
+	}};
+	( $k:literal => $v:expr $(,)? $(, $($t:tt)+ )? ) => {{
+		$crate::serde_json::to_value(&$v)
+			.map(|value| {
+				let mut map = $crate::serde_json::Map::new();
+				map.insert($k.into(), value);
+				map
+			})
+			$(
+				.and_then(|mut prev_map| {
+					format_fields_to_json!($($t)*)
+						.map(move |mut other_map| {
+							prev_map.append(&mut other_map);
+							prev_map
+						})
+				})
+			)*
+	}};
+	( $k:literal => ? $v:expr $(,)? $(, $($t:tt)+ )? ) => {{
+		let mut map = $crate::serde_json::Map::new();
+		map.insert($k.into(), std::format!("{:?}", &$v).into());
+		$crate::serde_json::Result::Ok(map)
+		$(
+			.and_then(|mut prev_map| {
+				format_fields_to_json!($($t)*)
+					.map(move |mut other_map| {
+						prev_map.append(&mut other_map);
+						prev_map
+					})
+			})
+		)*
+	}};
+	( $k:literal => % $v:expr $(,)? $(, $($t:tt)+ )? ) => {{
+		let mut map = $crate::serde_json::Map::new();
+		map.insert($k.into(), std::format!("{}", &$v).into());
+		$crate::serde_json::Result::Ok(map)
+		$(
+			.and_then(|mut prev_map| {
+				format_fields_to_json!($($t)*)
+					.map(move |mut other_map| {
+						prev_map.append(&mut other_map);
+						prev_map
+					})
+			})
+		)*
+	}};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::executor::LocalPool;
+	use futures::task::LocalSpawnExt;
+	use libp2p::core::transport::{memory::Channel, ListenerEvent, MemoryTransport};
+	use libp2p::Transport;
+	use std::pin::Pin;
+	use std::sync::atomic::AtomicUsize;
+	use std::time::Duration;
+
+	/// Transport of the telemetry nodes that dials `/memory/N` addresses.
+	fn memory_transport() -> WsTrans {
+		boxed_transport(MemoryTransport)
+	}
+
+	/// Fake telemetry server listening on a `/memory/N` address, yielding every frame it receives
+	/// on any connection.
+	struct FakeServer {
+		listener: stream::BoxStream<'static, Channel<Vec<u8>>>,
+		connections: stream::SelectAll<stream::BoxStream<'static, String>>,
+	}
+
+	impl FakeServer {
+		fn new(addr: &Multiaddr) -> Self {
+			let listener = MemoryTransport
+				.listen_on(addr.clone())
+				.unwrap()
+				.try_filter_map(|event| async move {
+					Ok(match event {
+						ListenerEvent::Upgrade { upgrade, .. } => Some(upgrade.await?),
+						_ => None,
+					})
+				})
+				.map(|channel| channel.expect("memory transport never fails"))
+				.boxed();
+
+			Self {
+				listener,
+				connections: stream::SelectAll::new(),
+			}
+		}
+
+		/// Frames received so far.
+		fn received_frames(&mut self) -> Vec<String> {
+			let mut received = Vec::new();
+			while let Some(Some(frame)) = self.next().now_or_never() {
+				received.push(frame);
+			}
+			received
+		}
+
+		/// Messages received so far, batched or not.
+		fn received(&mut self) -> Vec<serde_json::Value> {
+			self.received_frames()
+				.iter()
+				.flat_map(|frame| frame.lines())
+				.map(|message| serde_json::from_str(message).unwrap())
+				.collect()
+		}
+	}
+
+	impl Stream for FakeServer {
+		type Item = String;
+
+		fn poll_next(
+			mut self: Pin<&mut Self>,
+			cx: &mut std::task::Context<'_>,
+		) -> std::task::Poll<Option<Self::Item>> {
+			while let std::task::Poll::Ready(Some(channel)) = self.listener.poll_next_unpin(cx) {
+				// Each frame is written in one call to `write` and is read in one call to `read`.
+				let frames = stream::unfold(channel, |mut channel| async move {
+					let mut buf = vec![0; 64 * 1024];
+					match channel.read(&mut buf).await {
+						Ok(0) | Err(_) => None,
+						Ok(n) => Some((String::from_utf8(buf[..n].to_vec()).unwrap(), channel)),
+					}
+				});
+				self.connections.push(frames.boxed());
+			}
+
+			if self.connections.is_empty() {
+				return std::task::Poll::Pending;
+			}
+			self.connections.poll_next_unpin(cx)
+		}
+	}
+
+	pub(crate) fn connection_message() -> ConnectionMessage {
+		ConnectionMessage {
+			name: "node".into(),
+			implementation: "substrate".into(),
+			version: "1.0.0".into(),
+			config: String::new(),
+			chain: "local".into(),
+			genesis_hash: "0x00".into(),
+			authority: false,
+			startup_time: "0".into(),
+			network_id: "peer".into(),
+		}
+	}
+
+	#[test]
+	fn connection_message_overrides_apply_to_matching_endpoint_only() {
+		let public: Multiaddr = "/dns/telemetry.polkadot.io/tcp/443/x-parity-wss/%2Fsubmit%2F"
+			.parse()
+			.unwrap();
+		let private: Multiaddr = "/ip4/10.0.0.1/tcp/8000/ws".parse().unwrap();
+		let endpoints = TelemetryEndpoints(vec![
+			TelemetryEndpoint::new(public.clone(), 0),
+			TelemetryEndpoint::new(private.clone(), 0),
+		]);
+		let mut overrides = HashMap::new();
+		overrides.insert(
+			private.clone(),
+			serde_json::json!({
+				"datacenter": { "name": "dc-1", "racks": [1, 2] },
+				"name": "internal-name",
+				"msg": "system.hijacked",
+			}),
+		);
+
+		let mut node_pool = HashMap::new();
+		let mut node_map = HashMap::new();
+		futures::executor::block_on(TelemetryWorker::process_register(
+			Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints,
+				connection_message: connection_message(),
+				overrides,
+			},
+			&mut node_pool,
+			&mut node_map,
+			initialize_transport(None, &Default::default()).unwrap(),
+			&BufferBudget::new(usize::MAX, Default::default()),
+			&mut event_channel().0,
+			&TelemetryConfig::default(),
+		));
+
+		let payload = |addr: &Multiaddr| {
+			let node: &Node<WsTrans> = &node_pool[addr];
+			assert_eq!(node.connection_messages.len(), 1);
+			assert_eq!(node.connection_messages[0]["id"], 1);
+			node.connection_messages[0]["payload"].clone()
+		};
+
+		let public_payload = payload(&public);
+		assert_eq!(public_payload["name"], "node");
+		assert_eq!(public_payload["msg"], "system.connected");
+		assert!(public_payload.get("datacenter").is_none());
+
+		let private_payload = payload(&private);
+		assert_eq!(private_payload["name"], "internal-name");
+		assert_eq!(private_payload["msg"], "system.connected");
+		assert_eq!(
+			private_payload["datacenter"],
+			serde_json::json!({ "name": "dc-1", "racks": [1, 2] }),
+		);
+	}
+
+	#[test]
+	fn override_fields_do_not_collide_with_the_span_id() {
+		let addr: Multiaddr = "/ip4/10.0.0.1/tcp/8000/ws".parse().unwrap();
+		let mut overrides = HashMap::new();
+		overrides.insert(
+			addr.clone(),
+			serde_json::json!({ "id": "user-node-id", "msg": "x", "name": "override" }),
+		);
+
+		let mut node_pool = HashMap::new();
+		let mut node_map = HashMap::new();
+		futures::executor::block_on(TelemetryWorker::process_register(
+			Register::Telemetry {
+				id: Id::from_u64(7),
+				endpoints: TelemetryEndpoints(vec![TelemetryEndpoint::new(addr.clone(), 0)]),
+				connection_message: connection_message(),
+				overrides,
+			},
+			&mut node_pool,
+			&mut node_map,
+			initialize_transport(None, &Default::default()).unwrap(),
+			&BufferBudget::new(usize::MAX, Default::default()),
+			&mut event_channel().0,
+			&TelemetryConfig::default(),
+		));
+
+		let node: &Node<WsTrans> = &node_pool[&addr];
+		let message = &node.connection_messages[0];
+		// The span id stays at the top level, the user fields are in the payload.
+		assert_eq!(message["id"], 7);
+		assert_eq!(message["payload"]["id"], "user-node-id");
+		// `msg` is reserved, any other field of the override wins.
+		assert_eq!(message["payload"]["msg"], "system.connected");
+		assert_eq!(message["payload"]["name"], "override");
+	}
+
+	#[test]
+	fn endpoints_with_invalid_overrides_are_skipped_by_the_worker() {
+		let valid: Multiaddr = "/ip4/10.0.0.1/tcp/8000/ws".parse().unwrap();
+		let invalid: Multiaddr = "/ip4/10.0.0.2/tcp/8000/ws".parse().unwrap();
+		let endpoints = TelemetryEndpoints(vec![
+			TelemetryEndpoint::new(valid.clone(), 0),
+			TelemetryEndpoint::new(invalid.clone(), 0),
+		]);
+		let mut overrides = HashMap::new();
+		overrides.insert(invalid.clone(), serde_json::json!("not an object"));
+		let (mut event_sender, mut events) = event_channel();
+
+		let mut node_pool = HashMap::new();
+		let mut node_map = HashMap::new();
+		futures::executor::block_on(TelemetryWorker::process_register(
+			Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints,
+				connection_message: connection_message(),
+				overrides,
+			},
+			&mut node_pool,
+			&mut node_map,
+			memory_transport(),
+			&BufferBudget::new(usize::MAX, Default::default()),
+			&mut event_sender,
+			&TelemetryConfig::default(),
+		));
+
+		assert_eq!(node_pool.keys().collect::<Vec<_>>(), vec![&valid]);
+		assert_eq!(node_map[&Id::from_u64(1)], vec![(0, valid)]);
+		let error = ConnectionMessageError::OverrideNotAnObject(invalid).to_string();
+		assert_eq!(
+			events.next().now_or_never(),
+			Some(Some(TelemetryEvent::SerializationError(error))),
+		);
+	}
+
+	#[test]
+	fn connection_message_overrides_must_be_objects() {
+		let addr: Multiaddr = "/ip4/10.0.0.1/tcp/8000/ws".parse().unwrap();
+		let worker = TelemetryWorker::new(TelemetryConfig::default(), memory_transport());
+		let mut handle = worker.handle();
+		let mut start = |overlay| {
+			let endpoints = TelemetryEndpoints(vec![TelemetryEndpoint::new(addr.clone(), 0)]);
+			let mut overrides = HashMap::new();
+			overrides.insert(addr.clone(), overlay);
+			handle
+				.start_telemetry_with_overrides(
+					TelemetrySpan::new(),
+					endpoints,
+					connection_message(),
+					overrides,
+				)
+				.map(|_| ())
+		};
+
+		let invalid = Err(ConnectionMessageError::OverrideNotAnObject(addr.clone()));
+		assert_eq!(start(serde_json::Value::Null), invalid);
+		assert_eq!(start(serde_json::json!("not an object")), invalid);
+		assert_eq!(start(serde_json::json!([{ "name": "in-an-array" }])), invalid);
+		assert_eq!(start(serde_json::json!({ "nested": { "name": "node" } })), Ok(()));
+	}
+
+	#[test]
+	fn closed_spans_are_forgotten() {
+		let shared: Multiaddr = "/ip4/10.0.0.1/tcp/8000/ws".parse().unwrap();
+		let own: Multiaddr = "/ip4/10.0.0.2/tcp/8000/ws".parse().unwrap();
+		let mut node_pool = HashMap::new();
+		let mut node_map = HashMap::new();
+		fn register(
+			node_pool: &mut HashMap<Multiaddr, Node<WsTrans>>,
+			node_map: &mut HashMap<Id, Vec<(u8, Multiaddr)>>,
+			register: Register,
+		) {
+			futures::executor::block_on(TelemetryWorker::process_register(
+				register,
+				node_pool,
+				node_map,
+				initialize_transport(None, &Default::default()).unwrap(),
+				&BufferBudget::new(usize::MAX, Default::default()),
+				&mut event_channel().0,
+				&TelemetryConfig::default(),
+			))
+		}
+
+		for (id, endpoints) in &[(1, vec![&shared, &own]), (2, vec![&shared])] {
+			let endpoints = TelemetryEndpoints(
+				endpoints
+					.iter()
+					.map(|addr| TelemetryEndpoint::new((*addr).clone(), SUBSTRATE_INFO))
+					.collect(),
+			);
+			let telemetry = Register::Telemetry {
+				id: Id::from_u64(*id),
+				endpoints,
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			};
+			register(&mut node_pool, &mut node_map, telemetry);
+		}
+
+		let close = |id| Register::Close { id: Id::from_u64(id) };
+		register(&mut node_pool, &mut node_map, close(1));
+		assert_eq!(node_map.keys().collect::<Vec<_>>(), vec![&Id::from_u64(2)]);
+		assert_eq!(node_pool.keys().collect::<Vec<_>>(), vec![&shared]);
+		let node: &Node<WsTrans> = &node_pool[&shared];
+		assert_eq!(node.connection_messages.len(), 1);
+		assert_eq!(node.connection_messages[0]["id"], 2);
+
+		register(&mut node_pool, &mut node_map, close(2));
+		assert!(node_map.is_empty());
+		assert!(node_pool.is_empty());
+	}
+
+	#[test]
+	fn spans_announcing_the_same_node_share_its_connection_message() {
+		let addr: Multiaddr = "/memory/10211".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let (mut pool, handle, mut message_sender, _events) =
+			config_worker(&addr, TelemetryConfig::default());
+		handle
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(2),
+				endpoints: TelemetryEndpoints(vec![TelemetryEndpoint::new(
+					addr.clone(), SUBSTRATE_INFO,
+				)]),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+		pool.run_until_stalled();
+
+		let mut send = |pool: &mut LocalPool, id, name: &str| {
+			let message = TelemetryMessage::new(Id::from_u64(id), SUBSTRATE_INFO, name.to_owned());
+			pool.run_until(message_sender.send(message)).unwrap();
+			pool.run_until_stalled();
+		};
+		send(&mut pool, 1, r#"{"msg":"first"}"#);
+		send(&mut pool, 2, r#"{"msg":"second"}"#);
+		let received = server.received();
+		assert_eq!(received.len(), 3);
+		assert_eq!(received[0]["payload"]["msg"], "system.connected");
+		assert_eq!(received[0]["id"], 1);
+		assert_eq!(received[1]["msg"], "first");
+		assert_eq!(received[2]["msg"], "second");
+
+		// The other span announces the node once the first one is closed.
+		handle
+			.message_sender
+			.unbounded_send(Register::Close { id: Id::from_u64(1) })
+			.unwrap();
+		send(&mut pool, 2, r#"{"msg":"third"}"#);
+		let received = server.received();
+		assert_eq!(received.len(), 2);
+		assert_eq!(received[0]["payload"]["msg"], "system.connected");
+		assert_eq!(received[0]["id"], 2);
+		assert_eq!(received[1]["msg"], "third");
+	}
+
+	#[test]
+	fn messages_are_rate_limited_per_id() {
+		let addr: Multiaddr = "/memory/1008".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let config = TelemetryConfig {
+			max_messages_per_second: Some(10),
+			..Default::default()
+		};
+		let worker = TelemetryWorker::new(config, memory_transport());
+		let handle = worker.handle();
+		let mut message_sender = worker.message_sender();
+		let id = Id::from_u64(1);
+
+		handle
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: id.clone(),
+				endpoints: TelemetryEndpoints(vec![TelemetryEndpoint::new(
+					addr, CONSENSUS_INFO,
+				)]),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		pool.run_until_stalled();
+		pool.run_until(async {
+			for i in 0..1000 {
+				// The `SUBSTRATE_INFO` messages are exempt from the rate limit.
+				let verbosity = if i % 100 == 0 { SUBSTRATE_INFO } else { CONSENSUS_INFO };
+				message_sender
+					.send(TelemetryMessage::new(id.clone(), verbosity, r#"{"msg":"test"}"#))
+					.await
+					.unwrap();
+			}
+		});
+		pool.run_until_stalled();
+
+		let received = server.received();
+		assert_eq!(received[0]["payload"]["msg"], "system.connected");
+		let sent = received.len() - 1 - 10;
+		assert!((10..=11).contains(&sent), "{} messages sent", sent);
+		assert_eq!(handle.rate_limited_messages(), 990 - sent as u64);
+		assert_eq!(handle.stats().messages_dropped_rate_limit, 990 - sent as u64);
+	}
+
+	#[test]
+	fn connect_verbosity_applies_right_after_connecting() {
+		let addr: Multiaddr = "/memory/1009".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let window = Duration::from_millis(100);
+		let config = TelemetryConfig {
+			connect_verbosity: Some(CONSENSUS_DEBUG),
+			connect_verbosity_window: window,
+			..Default::default()
+		};
+		let worker = TelemetryWorker::new(config, memory_transport());
+		let handle = worker.handle();
+		let mut message_sender = worker.message_sender();
+		let id = Id::from_u64(1);
+
+		handle
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: id.clone(),
+				endpoints: TelemetryEndpoints(vec![TelemetryEndpoint::new(
+					addr, SUBSTRATE_INFO,
+				)]),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		pool.run_until_stalled();
+		let mut send = |pool: &mut LocalPool, verbosity: u8, msg: &str| {
+			let message = (id.clone(), verbosity, format!(r#"{{"msg":"{}"}}"#, msg)).into();
+			pool.run_until(message_sender.send(message)).unwrap();
+			pool.run_until_stalled();
+		};
+
+		send(&mut pool, SUBSTRATE_INFO, "connect");
+		send(&mut pool, CONSENSUS_DEBUG, "elevated");
+		send(&mut pool, CONSENSUS_TRACE, "too-verbose");
+		// The window started before the messages above were sent.
+		pool.run_until(wasm_timer::Delay::new(window)).unwrap();
+		send(&mut pool, CONSENSUS_DEBUG, "steady");
+		send(&mut pool, SUBSTRATE_INFO, "info");
+
+		let received = server
+			.received()
+			.into_iter()
+			.skip(1)
+			.map(|message| message["msg"].as_str().unwrap().to_string())
+			.collect::<Vec<_>>();
+		assert_eq!(received, vec!["connect", "elevated", "info"]);
+	}
+
+	#[test]
+	fn target_verbosity_overrides_the_verbosity_of_the_endpoint() {
+		let addr: Multiaddr = "/memory/10201".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let worker = TelemetryWorker::new(Default::default(), memory_transport());
+		let handle = worker.handle();
+		let mut message_sender = worker.message_sender();
+		let id = Id::from_u64(1);
+
+		let mut endpoints = TelemetryEndpoints(vec![TelemetryEndpoint::new(
+			addr.clone(),
+			SUBSTRATE_INFO,
+		)]);
+		assert!(endpoints.set_target_verbosity(&addr, "afg.", Some(CONSENSUS_DEBUG)));
+		assert!(endpoints.set_target_verbosity(&addr, "afg.received", Some(SUBSTRATE_INFO)));
+		handle
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: id.clone(),
+				endpoints,
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		pool.run_until_stalled();
+		let mut send = |verbosity: u8, msg: &str| {
+			let json = format!(r#"{{"id":1,"ts":0,"payload":{{"msg":"{}"}}}}"#, msg);
+			let message = TelemetryMessage::new(id.clone(), verbosity, json);
+			pool.run_until(message_sender.send(message)).unwrap();
+			pool.run_until_stalled();
+		};
+
+		send(CONSENSUS_DEBUG, "afg.finalized");
+		send(CONSENSUS_TRACE, "afg.finalized");
+		send(CONSENSUS_DEBUG, "afg.received_commit");
+		send(CONSENSUS_DEBUG, "block.import");
+		send(SUBSTRATE_INFO, "block.import");
+
+		let received = server
+			.received()
+			.into_iter()
+			.skip(1)
+			.map(|message| message["payload"]["msg"].as_str().unwrap().to_string())
+			.collect::<Vec<_>>();
+		assert_eq!(received, vec!["afg.finalized", "block.import"]);
+	}
+
+	#[test]
+	fn verbosity_overrides_apply_until_they_are_cleared() {
+		let first: Multiaddr = "/memory/10203".parse().unwrap();
+		let second: Multiaddr = "/memory/10204".parse().unwrap();
+		let mut first_server = FakeServer::new(&first);
+		let mut second_server = FakeServer::new(&second);
+		let worker = TelemetryWorker::new(Default::default(), memory_transport());
+		let handle = worker.handle();
+		let mut message_sender = worker.message_sender();
+		let id = Id::from_u64(1);
+
+		handle
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: id.clone(),
+				endpoints: TelemetryEndpoints(vec![
+					TelemetryEndpoint::new(first.clone(), SUBSTRATE_INFO),
+					TelemetryEndpoint::new(second, CONSENSUS_INFO),
+				]),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		pool.run_until_stalled();
+		first_server.received();
+		second_server.received();
+		let mut send = |pool: &mut LocalPool, msg: &str| {
+			let json = format!(r#"{{"msg":"{}"}}"#, msg);
+			let message = TelemetryMessage::new(id.clone(), CONSENSUS_DEBUG, json);
+			pool.run_until(message_sender.send(message)).unwrap();
+		};
+		let mut received = |pool: &mut LocalPool| {
+			pool.run_until_stalled();
+			vec![drain(pool, &mut first_server), drain(pool, &mut second_server)]
+		};
+
+		send(&mut pool, "base");
+		assert_eq!(received(&mut pool), vec![Vec::<String>::new(), vec![]]);
+
+		handle.set_verbosity_override(id.clone(), Some(first.clone()), CONSENSUS_DEBUG);
+		send(&mut pool, "first");
+		assert_eq!(received(&mut pool), vec![vec!["first".to_string()], vec![]]);
+
+		handle.set_verbosity_override(id.clone(), None, CONSENSUS_TRACE);
+		send(&mut pool, "all");
+		assert_eq!(received(&mut pool), vec![vec!["all".to_string()], vec!["all".to_string()]]);
+
+		// The endpoints go back to the verbosity they had before their first override.
+		handle.clear_verbosity_override(id.clone(), Some(first));
+		send(&mut pool, "second");
+		assert_eq!(received(&mut pool), vec![vec![], vec!["second".to_string()]]);
+
+		handle.clear_verbosity_override(id.clone(), None);
+		send(&mut pool, "cleared");
+		assert_eq!(received(&mut pool), vec![Vec::<String>::new(), vec![]]);
+	}
+
+	#[test]
+	fn layer_sends_the_telemetry_with_a_custom_transport() {
+		use tracing_subscriber::prelude::*;
+
+		let addr: Multiaddr = "/memory/10205".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let transport = boxed_transport(MemoryTransport);
+		let (layer, worker) = TelemetryLayer::with_transport(Default::default(), transport);
+		let mut handle = worker.handle();
+		let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+
+		let span = tracing::dispatcher::with_default(&dispatch, || {
+			let span = TelemetrySpan::new();
+			let endpoints = TelemetryEndpoints::new(vec![(addr.to_string(), SUBSTRATE_INFO)]);
+			handle.start_telemetry(span.clone(), endpoints.unwrap(), connection_message());
+			span
+		});
+		pool.run_until_stalled();
+		tracing::dispatcher::with_default(&dispatch, || {
+			let _enter = span.enter();
+			telemetry!(SUBSTRATE_INFO; "test.custom_transport"; "n" => 1);
+		});
+		pool.run_until_stalled();
+
+		let received = server
+			.received()
+			.into_iter()
+			.map(|message| message["payload"]["msg"].as_str().unwrap().to_string())
+			.collect::<Vec<_>>();
+		assert_eq!(received, vec!["system.connected", "test.custom_transport"]);
+	}
+
+	#[test]
+	fn telemetry_is_registered_once_the_worker_runs() {
+		use tracing_subscriber::prelude::*;
+
+		let transport = boxed_transport(MemoryTransport);
+		let (layer, worker) = TelemetryLayer::with_transport(Default::default(), transport);
+		let mut handle = worker.handle();
+		let shutdown = worker.shutdown_handle();
+		let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+		let (span, notifier) = tracing::dispatcher::with_default(&dispatch, || {
+			let span = TelemetrySpan::new();
+			let endpoints = TelemetryEndpoints::new(vec![("/memory/10217".into(), SUBSTRATE_INFO)]);
+			let notifier =
+				handle.start_telemetry(span.clone(), endpoints.unwrap(), connection_message());
+			(span, notifier)
+		});
+
+		// The registration waits for the worker.
+		assert!(!handle.is_worker_running());
+		let mut registered = notifier.registered().boxed_local();
+		assert!((&mut registered).now_or_never().is_none());
+
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		pool.run_until_stalled();
+		assert!(handle.is_worker_running());
+		assert!(pool.run_until(registered));
+
+		handle.stop_telemetry(span);
+		assert!(!pool.run_until(notifier.registered()));
+
+		pool.run_until(shutdown.shutdown());
+		assert!(!handle.is_worker_running());
+		assert!(!pool.run_until(notifier.registered()));
+	}
+
+	/// Start a worker sending the messages of the span `1` to `addr`.
+	fn batching_worker(
+		addr: &Multiaddr,
+		batch: BatchConfig,
+	) -> (LocalPool, mpsc::Sender<TelemetryMessage>) {
+		let config = TelemetryConfig {
+			batch: Some(batch),
+			..Default::default()
+		};
+		let worker = TelemetryWorker::new(config, memory_transport());
+		let message_sender = worker.message_sender();
+
+		worker
+			.handle()
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints: TelemetryEndpoints(vec![TelemetryEndpoint::new(
+					addr.clone(), SUBSTRATE_INFO,
+				)]),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+
+		let pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		(pool, message_sender)
+	}
+
+	#[test]
+	fn batches_are_sent_when_full() {
+		let addr: Multiaddr = "/memory/10092".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let batch = BatchConfig {
+			window: Duration::from_secs(3600),
+			max_messages: 3,
+		};
+		let (mut pool, mut message_sender) = batching_worker(&addr, batch);
+		pool.run_until_stalled();
+
+		for i in 0..7 {
+			let json = format!(r#"{{"msg":"{}"}}"#, i);
+			let message = TelemetryMessage::new(Id::from_u64(1), SUBSTRATE_INFO, json);
+			pool.run_until(message_sender.send(message)).unwrap();
+			pool.run_until_stalled();
+		}
+
+		let frames = server.received_frames();
+		assert_eq!(frames.len(), 3, "{:?}", frames);
+		assert!(frames[0].contains("system.connected"));
+		assert_eq!(
+			frames[1],
+			"{\"msg\":\"0\"}\n{\"msg\":\"1\"}\n{\"msg\":\"2\"}"
+		);
+		assert_eq!(
+			frames[2],
+			"{\"msg\":\"3\"}\n{\"msg\":\"4\"}\n{\"msg\":\"5\"}"
+		);
+	}
+
+	#[test]
+	fn batches_are_sent_when_the_window_elapses() {
+		let addr: Multiaddr = "/memory/10093".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let batch = BatchConfig {
+			window: Duration::from_millis(50),
+			max_messages: 100,
+		};
+		let (mut pool, mut message_sender) = batching_worker(&addr, batch);
+		pool.run_until_stalled();
+
+		for i in 0..2 {
+			let json = format!(r#"{{"msg":"{}"}}"#, i);
+			let message = TelemetryMessage::new(Id::from_u64(1), SUBSTRATE_INFO, json);
+			pool.run_until(message_sender.send(message)).unwrap();
+			pool.run_until_stalled();
+		}
+		assert_eq!(server.received_frames().len(), 1);
+
+		// The batch is sent by the worker once the window elapses.
+		let frame = pool.run_until(server.next());
+		assert_eq!(frame, Some("{\"msg\":\"0\"}\n{\"msg\":\"1\"}".to_string()));
+		assert!(server.received_frames().is_empty());
+	}
+
+	#[test]
+	fn shutdown_sends_the_pending_batch_and_closes_the_connections() {
+		let addr: Multiaddr = "/memory/10170".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let batch = BatchConfig {
+			window: Duration::from_secs(3600),
+			max_messages: 100,
+		};
+		let config = TelemetryConfig {
+			batch: Some(batch),
+			..Default::default()
+		};
+		let worker = TelemetryWorker::new(config, memory_transport());
+		let mut message_sender = worker.message_sender();
+		let shutdown = worker.shutdown_handle();
+		worker
+			.handle()
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints: TelemetryEndpoints(vec![TelemetryEndpoint::new(
+					addr.clone(), SUBSTRATE_INFO,
+				)]),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+
+		let mut pool = LocalPool::new();
+		let stopped = pool.spawner().spawn_local_with_handle(worker.run()).unwrap();
+		pool.run_until_stalled();
+
+		// The messages are held in the batch.
+		for i in 0..2 {
+			let json = format!(r#"{{"msg":"{}"}}"#, i);
+			let message = TelemetryMessage::new(Id::from_u64(1), SUBSTRATE_INFO, json);
+			pool.run_until(message_sender.send(message)).unwrap();
+		}
+		pool.run_until_stalled();
+		let frames = server.received_frames();
+		assert_eq!(frames.len(), 1);
+		assert!(frames[0].contains("system.connected"));
+
+		pool.run_until(shutdown.shutdown());
+		assert_eq!(pool.run_until(stopped), Ok(()));
+		// The batch is sent, then the connection is closed.
+		assert_eq!(
+			std::iter::from_fn(|| server.next().now_or_never())
+				.take(3)
+				.collect::<Vec<_>>(),
+			vec![Some("{\"msg\":\"0\"}\n{\"msg\":\"1\"}".to_string()), None],
+		);
+	}
+
+	/// Logger that records every log along with the thread that emitted it.
+	struct CapturingLogger;
+
+	type CapturedLog = (std::thread::ThreadId, log::Level, String);
+
+	static CAPTURED_LOGS: Mutex<Vec<CapturedLog>> = parking_lot::const_mutex(Vec::new());
+
+	impl log::Log for CapturingLogger {
+		fn enabled(&self, _: &log::Metadata) -> bool {
+			true
+		}
+
+		fn log(&self, record: &log::Record) {
+			let thread = std::thread::current().id();
+			CAPTURED_LOGS.lock().push((thread, record.level(), record.args().to_string()));
+		}
+
+		fn flush(&self) {}
+	}
+
+	/// Run `f` and return the logs emitted by the current thread meanwhile.
+	fn capture_logs<R>(f: impl FnOnce() -> R) -> (R, Vec<(log::Level, String)>) {
+		static LOGGER: CapturingLogger = CapturingLogger;
+		// Fails if another test has already installed the logger.
+		let _ = log::set_logger(&LOGGER);
+		log::set_max_level(log::LevelFilter::Trace);
+
+		let thread = std::thread::current().id();
+		let take_logs = || {
+			let mut logs = Vec::new();
+			CAPTURED_LOGS.lock().retain(|(other, level, log)| {
+				if *other != thread {
+					return true;
+				}
+				logs.push((*level, log.clone()));
+				false
+			});
+			logs
+		};
+
+		take_logs();
+		let result = f();
+		(result, take_logs())
+	}
+
+	#[test]
+	fn worker_stops_cleanly_once_the_senders_are_dropped() {
+		let addr: Multiaddr = "/memory/10190".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let worker = TelemetryWorker::new(TelemetryConfig::default(), memory_transport());
+		let mut message_sender = worker.message_sender();
+		worker
+			.handle()
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints: TelemetryEndpoints(vec![TelemetryEndpoint::new(
+					addr.clone(), SUBSTRATE_INFO,
+				)]),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+
+		let (result, logs) = capture_logs(|| {
+			let mut pool = LocalPool::new();
+			let stopped = pool.spawner().spawn_local_with_handle(worker.run()).unwrap();
+			let message =
+				TelemetryMessage::new(Id::from_u64(1), SUBSTRATE_INFO, r#"{"msg":"test"}"#);
+			pool.run_until(message_sender.send(message)).unwrap();
+			drop(message_sender);
+			pool.run_until(stopped)
+		});
+
+		assert_eq!(result, Ok(()));
+		assert!(logs.iter().all(|(level, _)| *level > log::Level::Error), "{:?}", logs);
+		assert!(logs
+			.iter()
+			.any(|(_, log)| log.contains("every sender of telemetry messages has been dropped")));
+
+		// Everything has been sent before the connection was closed.
+		let frames = std::iter::from_fn(|| server.next().now_or_never())
+			.take(4)
+			.collect::<Vec<_>>();
+		assert_eq!(frames.len(), 3, "{:?}", frames);
+		assert!(frames[0].as_ref().unwrap().contains("system.connected"));
+		assert_eq!(frames[1].as_deref(), Some(r#"{"msg":"test"}"#));
+		assert_eq!(frames[2], None);
+	}
+
+	#[test]
+	fn shutdown_waits_for_the_connections_being_established() {
+		let addr: Multiaddr = "/memory/10171".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let worker = TelemetryWorker::new(TelemetryConfig::default(), memory_transport());
+		let shutdown = worker.shutdown_handle();
+		worker
+			.handle()
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints: TelemetryEndpoints(vec![TelemetryEndpoint::new(
+					addr.clone(), SUBSTRATE_INFO,
+				)]),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+
+		// The worker is stopped before it even started dialing.
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		pool.run_until(shutdown.shutdown());
+
+		let received = server.received();
+		assert_eq!(received.len(), 1);
+		assert_eq!(received[0]["payload"]["msg"], "system.connected");
+	}
+
+	#[test]
+	fn undelivered_messages_are_spilled_and_replayed_after_a_restart() {
+		let addr: Multiaddr = "/memory/10172".parse().unwrap();
+		let spill_dir = std::env::temp_dir()
+			.join(format!("sc-telemetry-spill-replay-{}", std::process::id()));
+		let config = TelemetryConfig {
+			spill_dir: Some(spill_dir.clone()),
+			spill_threshold: 2,
+			..Default::default()
+		};
+		let start = |first: usize, last: usize| {
+			let worker = TelemetryWorker::new(config.clone(), memory_transport());
+			let mut message_sender = worker.message_sender();
+			let shutdown = worker.shutdown_handle();
+			worker
+				.handle()
+				.message_sender
+				.unbounded_send(Register::Telemetry {
+					id: Id::from_u64(1),
+					endpoints: TelemetryEndpoints(vec![TelemetryEndpoint::new(
+						addr.clone(), SUBSTRATE_INFO,
+					)]),
+					connection_message: connection_message(),
+					overrides: HashMap::new(),
+				})
+				.unwrap();
+
+			let mut pool = LocalPool::new();
+			let stopped = pool.spawner().spawn_local_with_handle(worker.run()).unwrap();
+			for i in first..=last {
+				pool.run_until(message_sender.send(numbered_message(i)))
+					.unwrap();
+				pool.run_until_stalled();
+			}
+			(pool, shutdown, stopped)
+		};
+
+		// Nothing listens on the address: the messages are spilled, the last one at shutdown.
+		let (mut pool, shutdown, stopped) = start(0, 2);
+		pool.run_until(shutdown.shutdown());
+		assert_eq!(pool.run_until(stopped), Ok(()));
+		let spilled = std::fs::read_dir(&spill_dir).unwrap().next().unwrap().unwrap();
+		assert_eq!(std::fs::read_to_string(spilled.path()).unwrap().lines().count(), 3);
+
+		let mut server = FakeServer::new(&addr);
+		let (mut pool, _shutdown, _stopped) = start(3, 3);
+		let received = server.received();
+		assert_eq!(received[0]["payload"]["msg"], "system.connected");
+		assert_eq!(
+			received[1..]
+				.iter()
+				.map(|message| message["msg"].as_str().unwrap())
+				.collect::<Vec<_>>(),
+			vec!["00000", "00001", "00002", "00003"],
+		);
+		assert!(!spilled.path().exists());
+		assert!(drain(&mut pool, &mut server).is_empty());
+		std::fs::remove_dir_all(&spill_dir).unwrap();
+	}
+
+	/// [`TelemetrySink`] recording the messages it receives.
+	#[derive(Debug, Clone, Default)]
+	struct MemorySink {
+		messages: Arc<Mutex<Vec<String>>>,
+		flushed: Arc<AtomicBool>,
+	}
+
+	impl TelemetrySink for MemorySink {
+		fn send<'a>(
+			&'a mut self,
+			_: &'a str,
+			message: &'a str,
+		) -> future::BoxFuture<'a, std::io::Result<()>> {
+			self.messages.lock().push(message.into());
+			future::ready(Ok(())).boxed()
+		}
+
+		fn flush(&mut self) -> future::BoxFuture<'_, std::io::Result<()>> {
+			self.flushed.store(true, Ordering::Relaxed);
+			future::ready(Ok(())).boxed()
+		}
+	}
+
+	#[test]
+	fn sinks_receive_the_telemetry_up_to_their_verbosity() {
+		let mut worker = TelemetryWorker::new(TelemetryConfig::default(), memory_transport());
+		let sink = MemorySink::default();
+		worker.add_sink("memory", CONSENSUS_INFO, sink.clone());
+		worker.add_sink("too verbose", MAX_VERBOSITY + 1, MemorySink::default());
+		assert_eq!(worker.max_verbosity().load(Ordering::Relaxed), CONSENSUS_INFO);
+		let mut message_sender = worker.message_sender();
+		let shutdown = worker.shutdown_handle();
+		// The span has no endpoint.
+		worker
+			.handle()
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints: TelemetryEndpoints(Vec::new()),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+
+		let mut pool = LocalPool::new();
+		let stopped = pool.spawner().spawn_local_with_handle(worker.run()).unwrap();
+		let messages = vec![
+			(1, SUBSTRATE_INFO, "0"),
+			(1, CONSENSUS_DEBUG, "1"),
+			// This span has not been registered.
+			(2, SUBSTRATE_INFO, "2"),
+			(1, CONSENSUS_INFO, "3"),
+		];
+		for (id, verbosity, message) in messages {
+			let message = TelemetryMessage::new(Id::from_u64(id), verbosity, message);
+			pool.run_until(message_sender.send(message)).unwrap();
+		}
+		pool.run_until(shutdown.shutdown());
+		assert_eq!(pool.run_until(stopped), Ok(()));
+
+		assert_eq!(*sink.messages.lock(), vec!["0".to_string(), "3".to_string()]);
+		assert!(sink.flushed.load(Ordering::Relaxed));
+	}
+
+	#[test]
+	fn telemetry_can_be_forwarded_to_another_worker() {
+		let mut downstream = TelemetryWorker::new(TelemetryConfig::default(), memory_transport());
+		let sink = MemorySink::default();
+		downstream.add_sink("memory", MAX_VERBOSITY, sink.clone());
+		let mut upstream = TelemetryWorker::new(TelemetryConfig::default(), memory_transport());
+		upstream.forward_to(downstream.sender(), Id::from_u64(7), CONSENSUS_INFO);
+		assert_eq!(upstream.max_verbosity().load(Ordering::Relaxed), CONSENSUS_INFO);
+		let mut message_sender = upstream.message_sender();
+
+		let mut stopped = Vec::new();
+		let mut shutdowns = Vec::new();
+		let mut pool = LocalPool::new();
+		let workers = vec![(downstream, 7), (upstream, 1)];
+		for (worker, id) in workers {
+			worker
+				.handle()
+				.message_sender
+				.unbounded_send(Register::Telemetry {
+					id: Id::from_u64(id),
+					endpoints: TelemetryEndpoints(Vec::new()),
+					connection_message: connection_message(),
+					overrides: HashMap::new(),
+				})
+				.unwrap();
+			shutdowns.push(worker.shutdown_handle());
+			stopped.push(pool.spawner().spawn_local_with_handle(worker.run()).unwrap());
+		}
+
+		for (verbosity, message) in &[(SUBSTRATE_INFO, "0"), (CONSENSUS_DEBUG, "1")] {
+			let message = TelemetryMessage::new(Id::from_u64(1), *verbosity, *message);
+			pool.run_until(message_sender.send(message)).unwrap();
+		}
+		pool.run_until_stalled();
+		assert_eq!(*sink.messages.lock(), vec!["0".to_string()]);
+
+		// The forwarding stops with the other worker.
+		pool.run_until(shutdowns.remove(0).shutdown());
+		assert_eq!(pool.run_until(stopped.remove(0)), Ok(()));
+		let ((), logs) = capture_logs(|| {
+			let message = TelemetryMessage::new(Id::from_u64(1), SUBSTRATE_INFO, "2");
+			pool.run_until(message_sender.send(message)).unwrap();
+			pool.run_until_stalled();
+		});
+		assert!(logs.iter().any(|(level, log)| {
+			*level == log::Level::Warn && log.starts_with("Stopped forwarding the telemetry")
+		}));
+		assert_eq!(*sink.messages.lock(), vec!["0".to_string()]);
+	}
+
+	#[cfg(feature = "test-helpers")]
+	#[test]
+	fn memory_endpoints_are_supported() {
+		let addr: Multiaddr = "/memory/1010".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let config = TelemetryConfig::default();
+		let worker = TelemetryWorker::new(
+			config.clone(),
+			initialize_transport(None, &config).unwrap(),
+		);
+		let mut message_sender = worker.message_sender();
+		let id = Id::from_u64(1);
+
+		worker
+			.handle()
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: id.clone(),
+				endpoints: TelemetryEndpoints(vec![TelemetryEndpoint::new(
+					addr, SUBSTRATE_INFO,
+				)]),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		pool.run_until_stalled();
+		let message = TelemetryMessage::new(id, SUBSTRATE_INFO, r#"{"msg":"test"}"#);
+		pool.run_until(message_sender.send(message)).unwrap();
+		pool.run_until_stalled();
+
+		let received = server.received();
+		assert_eq!(received.len(), 2);
+		assert_eq!(received[0]["payload"]["msg"], "system.connected");
+		assert_eq!(received[1]["msg"], "test");
+	}
+
+	/// Start a worker whose node queues hold up to 16 messages, and connect it to `addr`.
+	fn queueing_worker(
+		addr: &Multiaddr,
+		node_queue_policy: QueuePolicy,
+	) -> (
+		LocalPool,
+		TelemetryHandle,
+		mpsc::Sender<TelemetryMessage>,
+		TelemetryEvents,
+	) {
+		let config = TelemetryConfig {
+			node_queue_capacity: 16,
+			node_queue_policy,
+			..Default::default()
+		};
+		config_worker(addr, config)
+	}
+
+	/// Start a worker with `config` sending the messages of the span `1` to `addr`.
+	fn config_worker(
+		addr: &Multiaddr,
+		config: TelemetryConfig,
+	) -> (
+		LocalPool,
+		TelemetryHandle,
+		mpsc::Sender<TelemetryMessage>,
+		TelemetryEvents,
+	) {
+		transport_worker(addr, config, memory_transport())
+	}
+
+	/// Start a worker with `config` sending the messages of the span `1` to `addr` with
+	/// `transport`.
+	fn transport_worker(
+		addr: &Multiaddr,
+		config: TelemetryConfig,
+		transport: WsTrans,
+	) -> (
+		LocalPool,
+		TelemetryHandle,
+		mpsc::Sender<TelemetryMessage>,
+		TelemetryEvents,
+	) {
+		let mut worker = TelemetryWorker::new(config, transport);
+		let message_sender = worker.message_sender();
+		let events = worker.events().unwrap();
+		let handle = worker.handle();
+
+		handle
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints: TelemetryEndpoints(vec![TelemetryEndpoint::new(
+					addr.clone(), SUBSTRATE_INFO,
+				)]),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		pool.run_until_stalled();
+		(pool, handle, message_sender, events)
+	}
+
+	fn numbered_message(i: usize) -> TelemetryMessage {
+		TelemetryMessage::new(Id::from_u64(1), SUBSTRATE_INFO, format!(r#"{{"msg":"{:05}"}}"#, i))
+	}
+
+	/// Read everything the node sends to `server`, letting the worker refill the connection.
+	fn drain(pool: &mut LocalPool, server: &mut FakeServer) -> Vec<String> {
+		let mut received = Vec::new();
+		loop {
+			pool.run_until_stalled();
+			let messages = server.received();
+			if messages.is_empty() {
+				return received;
+			}
+			received.extend(
+				messages
+					.into_iter()
+					.filter_map(|message| message["msg"].as_str().map(String::from)),
+			);
+		}
+	}
+
+	/// Update `total` with the number of dropped messages reported in `events`, checking that it
+	/// only increases.
+	fn update_total_dropped(events: &mut TelemetryEvents, total: &mut u64) {
+		while let Some(Some(event)) = events.next().now_or_never() {
+			if let TelemetryEvent::Dropped { total: new_total, .. } = event {
+				assert!(new_total > *total);
+				*total = new_total;
+			}
+		}
+	}
+
+	#[test]
+	fn saturated_node_queue_drops_messages() {
+		const MESSAGES: usize = 5000;
+
+		for (i, policy) in [QueuePolicy::DropOldest, QueuePolicy::DropNewest]
+			.iter()
+			.enumerate()
+		{
+			let addr: Multiaddr = format!("/memory/{}", 10112 + i).parse().unwrap();
+			let mut server = FakeServer::new(&addr);
+			let (mut pool, handle, mut message_sender, mut events) =
+				queueing_worker(&addr, *policy);
+
+			// The server doesn't read anything until all the messages have been sent.
+			let mut dropped = 0;
+			for i in 0..MESSAGES {
+				pool.run_until(message_sender.send(numbered_message(i)))
+					.unwrap();
+				pool.run_until_stalled();
+				update_total_dropped(&mut events, &mut dropped);
+			}
+			assert!(dropped > 0);
+			assert_eq!(events.dropped(), 0);
+			assert!(handle.buffered_bytes() <= 16 * numbered_message(0).payload.len());
+
+			let received = drain(&mut pool, &mut server);
+			assert_eq!(received.len() as u64, MESSAGES as u64 - dropped);
+			let stats = handle.stats();
+			assert_eq!(stats.messages_dropped_queue, dropped);
+			// Along with the connection message.
+			assert_eq!(stats.messages_sent, received.len() as u64 + 1);
+			let last = received.last().unwrap();
+			match policy {
+				QueuePolicy::DropOldest => assert_eq!(last, &format!("{:05}", MESSAGES - 1)),
+				_ => assert!(last < &format!("{:05}", MESSAGES - 1)),
+			}
+		}
+	}
+
+	#[test]
+	fn messages_for_disconnected_endpoints_are_counted() {
+		// Nothing listens on this address.
+		let addr: Multiaddr = "/memory/10115".parse().unwrap();
+		let config = TelemetryConfig {
+			disconnected_buffer_size: 0,
+			..Default::default()
+		};
+		let (mut pool, handle, mut message_sender, _events) = config_worker(&addr, config);
+
+		for i in 0..3 {
+			pool.run_until(message_sender.send(numbered_message(i)))
+				.unwrap();
+		}
+		pool.run_until_stalled();
+
+		assert_eq!(
+			handle.stats(),
+			TelemetryStats {
+				messages_dropped_disconnected: 3,
+				..Default::default()
+			},
+		);
+	}
+
+	#[test]
+	fn processing_times_are_recorded_and_slow_messages_reported() {
+		// Nothing listens on this address.
+		let addr: Multiaddr = "/memory/10212".parse().unwrap();
+		let config = TelemetryConfig {
+			slow_message_threshold: Some(Duration::from_secs(0)),
+			..Default::default()
+		};
+		let (mut pool, handle, mut message_sender, _events) = config_worker(&addr, config);
+
+		let ((), logs) = capture_logs(|| {
+			let message = TelemetryMessage::new(
+				Id::from_u64(1),
+				CONSENSUS_INFO,
+				r#"{"id":1,"payload":{"msg":"test.slow"}}"#,
+			);
+			pool.run_until(message_sender.send(message)).unwrap();
+			pool.run_until_stalled();
+		});
+		let expected = "Dispatching the telemetry message test.slow with verbosity 1 took";
+		assert!(logs
+			.iter()
+			.any(|(level, log)| *level == log::Level::Warn && log.starts_with(expected)));
+
+		let times = handle.processing_times();
+		assert_eq!(times.count(), 1);
+		let buckets = times.buckets().collect::<Vec<_>>();
+		assert_eq!(buckets.len(), 9);
+		assert_eq!(buckets[0].0, Some(Duration::from_micros(100)));
+		assert_eq!(buckets[8], (None, 0));
+	}
+
+	/// Connection of the `MemoryTransport` counting how many times it is flushed.
+	struct CountingFlushes(Channel<Vec<u8>>, Arc<AtomicUsize>);
+
+	impl AsyncRead for CountingFlushes {
+		fn poll_read(
+			mut self: Pin<&mut Self>,
+			cx: &mut std::task::Context<'_>,
+			buf: &mut [u8],
+		) -> std::task::Poll<std::io::Result<usize>> {
+			Pin::new(&mut self.0).poll_read(cx, buf)
+		}
+	}
+
+	impl AsyncWrite for CountingFlushes {
+		fn poll_write(
+			mut self: Pin<&mut Self>,
+			cx: &mut std::task::Context<'_>,
+			buf: &[u8],
+		) -> std::task::Poll<std::io::Result<usize>> {
+			Pin::new(&mut self.0).poll_write(cx, buf)
+		}
+
+		fn poll_flush(
+			mut self: Pin<&mut Self>,
+			cx: &mut std::task::Context<'_>,
+		) -> std::task::Poll<std::io::Result<()>> {
+			self.1.fetch_add(1, Ordering::Relaxed);
+			Pin::new(&mut self.0).poll_flush(cx)
+		}
+
+		fn poll_close(
+			mut self: Pin<&mut Self>,
+			cx: &mut std::task::Context<'_>,
+		) -> std::task::Poll<std::io::Result<()>> {
+			Pin::new(&mut self.0).poll_close(cx)
+		}
+	}
+
+	#[test]
+	fn queued_messages_are_dispatched_together() {
+		let addr: Multiaddr = "/memory/10213".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		const MESSAGES: usize = 32;
+		// Number of times the connection is flushed to send `MESSAGES` messages that have been
+		// queued at once, when the worker dispatches up to `per_wakeup` of them at a time.
+		let flushes = |per_wakeup: usize, server: &mut FakeServer| {
+			let flushes = Arc::new(AtomicUsize::new(0));
+			let transport = {
+				let flushes = flushes.clone();
+				boxed_transport(MemoryTransport.map(move |channel, _| {
+					CountingFlushes(channel, flushes.clone())
+				}))
+			};
+			let config = TelemetryConfig {
+				buffer_size: MESSAGES,
+				max_messages_per_wakeup: per_wakeup,
+				..Default::default()
+			};
+			let (mut pool, _handle, mut message_sender, _events) =
+				transport_worker(&addr, config, transport);
+			// Connects the node.
+			message_sender.try_send(numbered_message(0)).unwrap();
+			assert_eq!(drain(&mut pool, server), vec!["00000"]);
+
+			flushes.store(0, Ordering::Relaxed);
+			for i in 0..MESSAGES {
+				message_sender.try_send(numbered_message(i)).unwrap();
+			}
+			let received = drain(&mut pool, server);
+			assert_eq!(received, (0..MESSAGES).map(|i| format!("{:05}", i)).collect::<Vec<_>>());
+			flushes.load(Ordering::Relaxed)
+		};
+
+		let one_at_a_time = flushes(1, &mut server);
+		let together = flushes(64, &mut server);
+		assert!(one_at_a_time >= MESSAGES, "{} flushes", one_at_a_time);
+		assert!(together <= 2, "{} flushes", together);
+	}
+
+	#[test]
+	fn saturated_node_queue_blocks_the_worker() {
+		let addr: Multiaddr = "/memory/10114".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let (mut pool, handle, mut message_sender, mut events) =
+			queueing_worker(&addr, QueuePolicy::Block);
+
+		let mut accepted = 0;
+		let mut dropped = 0;
+		while message_sender.try_send(numbered_message(accepted)).is_ok() {
+			accepted += 1;
+			pool.run_until_stalled();
+			update_total_dropped(&mut events, &mut dropped);
+			assert!(accepted < 100_000, "the worker never blocks");
+		}
+
+		assert_eq!(dropped, 0);
+		assert!(handle.buffered_bytes() <= 16 * numbered_message(0).payload.len());
+		let received = drain(&mut pool, &mut server);
+		assert_eq!(received.len(), accepted);
+	}
+
+	#[test]
+	fn stalled_endpoint_does_not_hold_the_buffer_budget() {
+		const MESSAGES: usize = 5000;
+		let stalled_addr: Multiaddr = "/memory/10220".parse().unwrap();
+		let healthy_addr: Multiaddr = "/memory/10221".parse().unwrap();
+		// The stalled server never reads anything.
+		let _stalled = FakeServer::new(&stalled_addr);
+		let mut healthy = FakeServer::new(&healthy_addr);
+		let max_buffered_bytes = 32 * numbered_message(0).payload.len();
+		let config = TelemetryConfig {
+			max_buffered_bytes,
+			node_queue_capacity: 100_000,
+			..Default::default()
+		};
+		let mut worker = TelemetryWorker::new(config, memory_transport());
+		let mut message_sender = worker.message_sender();
+		let mut events = worker.events().unwrap();
+		let handle = worker.handle();
+
+		handle
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints: TelemetryEndpoints(vec![
+					TelemetryEndpoint::new(stalled_addr.clone(), SUBSTRATE_INFO),
+					TelemetryEndpoint::new(healthy_addr.clone(), SUBSTRATE_INFO),
+				]),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		pool.run_until_stalled();
+
+		let mut received = Vec::new();
+		let mut dropped = 0;
+		for i in 0..MESSAGES {
+			pool.run_until(message_sender.send(numbered_message(i))).unwrap();
+			pool.run_until_stalled();
+			received.extend(
+				healthy
+					.received()
+					.into_iter()
+					.filter_map(|message| message["msg"].as_str().map(String::from)),
+			);
+			while let Some(Some(event)) = events.next().now_or_never() {
+				if let TelemetryEvent::Dropped { addr, total } = event {
+					assert_eq!(addr, stalled_addr);
+					dropped = total;
+				}
+			}
+			assert!(handle.stats().buffered_bytes <= max_buffered_bytes as u64);
+		}
+
+		// The messages of the stalled endpoint are evicted to make room for the healthy one.
+		assert_eq!(received, (0..MESSAGES).map(|i| format!("{:05}", i)).collect::<Vec<_>>());
+		assert!(dropped > 0);
+		let stats = handle.stats();
+		assert_eq!(stats.messages_dropped_queue, dropped);
+		assert!(stats.buffered_bytes > 0);
+		assert_eq!(stats.buffered_bytes, handle.buffered_bytes() as u64);
+	}
+
+	#[test]
+	fn stale_messages_are_dropped_instead_of_sent_late() {
+		let addr: Multiaddr = "/memory/10202".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let max_message_age = Duration::from_millis(100);
+		let config = TelemetryConfig {
+			node_queue_capacity: 16,
+			node_queue_policy: QueuePolicy::Block,
+			max_message_age: Some(max_message_age),
+			..Default::default()
+		};
+		let (mut pool, handle, mut message_sender, _events) = config_worker(&addr, config);
+
+		// Fill the queue of the node while the server doesn't read anything.
+		let mut accepted = 0;
+		while message_sender.try_send(numbered_message(accepted)).is_ok() {
+			accepted += 1;
+			pool.run_until_stalled();
+			assert!(accepted < 100_000, "the worker never blocks");
+		}
+		let queued = Instant::now();
+		pool.run_until(wasm_timer::Delay::new_at(queued + max_message_age)).unwrap();
+
+		// The messages that have waited in the queue are dropped, but not the ones that had
+		// reached the socket or that were still waiting to be queued.
+		let received = drain(&mut pool, &mut server);
+		let expired = handle.stats().messages_dropped_expired as usize;
+		assert!(expired >= 16);
+		assert_eq!(received.len() + expired, accepted);
+		assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+
+		pool.run_until(message_sender.send(numbered_message(accepted)))
+			.unwrap();
+		assert_eq!(drain(&mut pool, &mut server), vec![format!("{:05}", accepted)]);
+	}
+
+	#[test]
+	fn file_endpoints_receive_the_telemetry_and_can_be_reopened() {
+		let dir = std::env::temp_dir()
+			.join(format!("sc-telemetry-file-endpoint-{}", std::process::id()));
+		let path = dir.join("telemetry.jsonl");
+		let url = format!("file://{}", path.display());
+		let endpoints = TelemetryEndpoints::new(vec![(url, SUBSTRATE_INFO)]).unwrap();
+		let config = TelemetryConfig {
+			keepalive_interval: Duration::from_millis(20),
+			..Default::default()
+		};
+		let transport = initialize_transport(None, &config).unwrap();
+		let worker = TelemetryWorker::new(config, transport);
+		let mut message_sender = worker.message_sender();
+		let handle = worker.handle();
+		handle
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints,
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+
+		let mut send = |pool: &mut LocalPool, verbosity, msg: &str| {
+			let json = format!(r#"{{"msg":"{}"}}"#, msg);
+			let message = TelemetryMessage::new(Id::from_u64(1), verbosity, json);
+			pool.run_until(message_sender.send(message)).unwrap();
+			pool.run_until_stalled();
+		};
+		let lines = |path: &std::path::Path| {
+			std::fs::read_to_string(path)
+				.unwrap_or_default()
+				.lines()
+				.map(|line| serde_json::from_str(line).unwrap())
+				.collect::<Vec<serde_json::Value>>()
+		};
+		// The files are written by the threads of their sinks.
+		let wait_for_lines = |pool: &mut LocalPool, path: &std::path::Path, count| {
+			pool.run_until(async {
+				while lines(path).len() < count {
+					wasm_timer::Delay::new(Duration::from_millis(10)).await.unwrap();
+				}
+			})
+		};
+
+		send(&mut pool, SUBSTRATE_INFO, "0");
+		send(&mut pool, SUBSTRATE_DEBUG, "debug");
+		wait_for_lines(&mut pool, &path, 2);
+		let rotated = dir.join("telemetry.jsonl.1");
+		std::fs::rename(&path, &rotated).unwrap();
+		send(&mut pool, SUBSTRATE_INFO, "1");
+		wait_for_lines(&mut pool, &rotated, 3);
+		handle.reopen_files();
+		pool.run_until_stalled();
+		send(&mut pool, SUBSTRATE_INFO, "2");
+		wait_for_lines(&mut pool, &path, 2);
+		// Files are never pinged by the keepalive.
+		pool.run_until(wasm_timer::Delay::new(Duration::from_millis(100)))
+			.unwrap();
+
+		// The file is written like a connection to a telemetry server.
+		let rotated = lines(&rotated);
+		assert_eq!(rotated.len(), 3);
+		assert_eq!(rotated[0]["payload"]["msg"], "system.connected");
+		assert_eq!(rotated[1]["msg"], "0");
+		assert_eq!(rotated[2]["msg"], "1");
+		let reopened = lines(&path);
+		assert_eq!(reopened.len(), 2);
+		assert_eq!(reopened[0]["payload"]["msg"], "system.connected");
+		assert_eq!(reopened[1]["msg"], "2");
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn log_endpoint_prints_the_telemetry_alongside_the_other_endpoints() {
+		use tracing_subscriber::prelude::*;
+
+		let path = std::env::temp_dir()
+			.join(format!("sc-telemetry-log-endpoint-{}.jsonl", std::process::id()));
+		let endpoints = TelemetryEndpoints::new(vec![
+			("log:".into(), SUBSTRATE_INFO),
+			(format!("file://{}", path.display()), SUBSTRATE_DEBUG),
+		])
+		.unwrap();
+		let config = TelemetryConfig::default();
+		let transport = initialize_transport(None, &config).unwrap();
+		let (layer, worker) = TelemetryLayer::with_transport(config, transport);
+		let mut handle = worker.handle();
+		let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+
+		let (id, logs) = capture_logs(|| {
+			let mut pool = LocalPool::new();
+			pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+			let span = tracing::dispatcher::with_default(&dispatch, || {
+				let span = TelemetrySpan::new();
+				handle.start_telemetry(span.clone(), endpoints, connection_message());
+				span
+			});
+			pool.run_until_stalled();
+			tracing::dispatcher::with_default(&dispatch, || {
+				let _enter = span.enter();
+				telemetry!(SUBSTRATE_INFO; "test.info"; "n" => 1);
+				telemetry!(SUBSTRATE_DEBUG; "test.debug"; "n" => 2);
+			});
+			pool.run_until_stalled();
+			// The file is written by the thread of its sink.
+			pool.run_until(async {
+				while std::fs::read_to_string(&path).unwrap_or_default().lines().count() < 3 {
+					wasm_timer::Delay::new(Duration::from_millis(10)).await.unwrap();
+				}
+			});
+			span.telemetry_id().unwrap()
+		});
+
+		// The other logs of the worker are not JSON.
+		let printed = logs
+			.iter()
+			.filter_map(|(_, log)| serde_json::from_str::<serde_json::Value>(log).ok())
+			.collect::<Vec<_>>();
+		let names = printed
+			.iter()
+			.map(|message| message["payload"]["msg"].as_str().unwrap())
+			.collect::<Vec<_>>();
+		assert_eq!(names, vec!["system.connected", "test.info"]);
+		assert!(printed.iter().all(|message| message["id"] == id.into_u64()));
+
+		let written = std::fs::read_to_string(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(written.lines().count(), 3);
+	}
+
+	#[test]
+	fn disabled_endpoints_are_disconnected() {
+		let addr: Multiaddr = "/memory/10140".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let (mut pool, handle, mut message_sender, mut events) =
+			queueing_worker(&addr, QueuePolicy::DropOldest);
+		let mut send = |pool: &mut LocalPool, i| {
+			pool.run_until(message_sender.send(numbered_message(i)))
+				.unwrap();
+		};
+
+		send(&mut pool, 0);
+		assert_eq!(drain(&mut pool, &mut server), vec!["00000".to_string()]);
+
+		handle.set_endpoint_enabled(&addr, false);
+		pool.run_until_stalled();
+		let disconnected = std::iter::from_fn(|| events.next().now_or_never().flatten())
+			.any(|event| event == TelemetryEvent::Disconnected(addr.clone()));
+		assert!(disconnected);
+
+		send(&mut pool, 1);
+		assert!(drain(&mut pool, &mut server).is_empty());
+
+		handle.set_endpoint_enabled(&addr, true);
+		pool.run_until_stalled();
+		send(&mut pool, 2);
+		pool.run_until_stalled();
+		let received = server.received();
+		assert_eq!(received.len(), 2);
+		assert_eq!(received[0]["payload"]["msg"], "system.connected");
+		assert_eq!(received[1]["msg"], "00002");
+	}
+
+	#[test]
+	fn connected_endpoints_follow_a_flapping_server() {
+		let addr: Multiaddr = "/memory/10214".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let (mut pool, handle, mut message_sender, _events) =
+			queueing_worker(&addr, QueuePolicy::DropOldest);
+		let mut send = |pool: &mut LocalPool, i| {
+			pool.run_until(message_sender.send(numbered_message(i)))
+				.unwrap();
+			pool.run_until_stalled();
+		};
+		assert_eq!(handle.connected_endpoints(), 0);
+
+		for round in 0..3 {
+			send(&mut pool, 0);
+			assert_eq!(drain(&mut pool, &mut server), vec!["00000".to_string()]);
+			assert_eq!(handle.connected_endpoints(), 1, "round {}", round);
+
+			// The node notices that the server is gone when it writes to the connection.
+			drop(server);
+			send(&mut pool, 1);
+			assert_eq!(handle.connected_endpoints(), 0, "round {}", round);
+
+			// Reconnects right away instead of after the reconnection delay.
+			server = FakeServer::new(&addr);
+			handle.set_endpoint_enabled(&addr, false);
+			handle.set_endpoint_enabled(&addr, true);
+		}
+
+		send(&mut pool, 2);
+		assert_eq!(handle.connected_endpoints(), 1);
+		handle.remove_endpoint(&addr);
+		pool.run_until_stalled();
+		assert_eq!(handle.connected_endpoints(), 0);
+	}
+
+	#[test]
+	fn updated_endpoints_take_over_the_traffic_of_the_span() {
+		let first: Multiaddr = "/memory/10215".parse().unwrap();
+		let second: Multiaddr = "/memory/10216".parse().unwrap();
+		let mut first_server = FakeServer::new(&first);
+		let mut second_server = FakeServer::new(&second);
+		let (mut pool, handle, mut message_sender, _events) =
+			config_worker(&first, Default::default());
+		let (sender, mut notifier) = tracing_unbounded("test_telemetry_on_connect");
+		handle
+			.message_sender
+			.unbounded_send(Register::Notifier {
+				id: Some(Id::from_u64(1)),
+				addresses: vec![first.clone()],
+				connection_notifier: sender,
+			})
+			.unwrap();
+
+		pool.run_until(message_sender.send(numbered_message(0)))
+			.unwrap();
+		assert_eq!(drain(&mut pool, &mut first_server), vec!["00000".to_string()]);
+		assert_eq!(notifier.next().now_or_never(), Some(Some(())));
+
+		handle.update_endpoints(
+			Id::from_u64(1),
+			TelemetryEndpoints(vec![TelemetryEndpoint::new(second.clone(), SUBSTRATE_INFO)]),
+		);
+		pool.run_until_stalled();
+		assert_eq!(handle.connected_endpoints(), 0);
+
+		pool.run_until(message_sender.send(numbered_message(1)))
+			.unwrap();
+		pool.run_until_stalled();
+		assert!(first_server.received().is_empty());
+		let received = second_server.received();
+		assert_eq!(received.len(), 2);
+		assert_eq!(received[0]["id"], 1);
+		assert_eq!(received[0]["payload"]["msg"], "system.connected");
+		assert_eq!(received[1]["msg"], "00001");
+		assert_eq!(notifier.next().now_or_never(), Some(Some(())));
+		assert_eq!(handle.connected_endpoints(), 1);
+	}
+
+	#[test]
+	fn messages_are_held_while_the_server_is_gone() {
+		let addr: Multiaddr = "/memory/10219".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let config = TelemetryConfig {
+			disconnected_buffer_size: 3,
+			max_reconnect_delay: Duration::from_millis(50),
+			..Default::default()
+		};
+		let worker = TelemetryWorker::new(config, memory_transport());
+		let mut message_sender = worker.message_sender();
+		let handle = worker.handle();
+		handle
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints: TelemetryEndpoints(vec![TelemetryEndpoint::new(
+					addr.clone(), CONSENSUS_INFO,
+				)]),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		let mut send = |pool: &mut LocalPool, message| {
+			pool.run_until(message_sender.send(message)).unwrap();
+			pool.run_until_stalled();
+		};
+
+		send(&mut pool, numbered_message(0));
+		assert_eq!(drain(&mut pool, &mut server), vec!["00000".to_string()]);
+
+		// The node notices that the server is gone when it writes to the connection.
+		drop(server);
+		send(&mut pool, numbered_message(1));
+		assert_eq!(handle.connected_endpoints(), 0);
+		let verbose = TelemetryMessage::new(Id::from_u64(1), CONSENSUS_INFO, r#"{"msg":"v"}"#);
+		send(&mut pool, verbose);
+		for i in 2..5 {
+			send(&mut pool, numbered_message(i));
+		}
+		// The verbose message makes room for the last one.
+		assert_eq!(handle.stats().messages_dropped_disconnected, 1);
+
+		let mut server = FakeServer::new(&addr);
+		pool.run_until(wasm_timer::Delay::new(Duration::from_millis(100)))
+			.unwrap();
+		send(&mut pool, numbered_message(5));
+		let received = server.received();
+		assert_eq!(received[0]["payload"]["msg"], "system.connected");
+		let received = received[1..]
+			.iter()
+			.map(|message| message["msg"].as_str().unwrap())
+			.collect::<Vec<_>>();
+		assert_eq!(received, vec!["00002", "00003", "00004", "00005"]);
+		assert_eq!(handle.stats().messages_dropped_disconnected, 1);
+	}
+
+	#[test]
+	fn endpoint_status_tracks_connections_and_sent_messages() {
+		let addr: Multiaddr = "/memory/10142".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let (mut pool, handle, mut message_sender, _events) =
+			queueing_worker(&addr, QueuePolicy::DropOldest);
+		let mut send = |pool: &mut LocalPool, i| {
+			pool.run_until(message_sender.send(numbered_message(i)))
+				.unwrap();
+		};
+		let status = |pool: &mut LocalPool| {
+			let mut status = pool.run_until(handle.endpoint_status());
+			assert_eq!(status.len(), 1);
+			status.remove(0)
+		};
+
+		send(&mut pool, 0);
+		assert_eq!(drain(&mut pool, &mut server).len(), 1);
+		let first = status(&mut pool);
+		assert_eq!(first.addr, addr);
+		assert!(first.connected);
+		assert_eq!(first.reconnects, 0);
+		assert!(first.last_sent.unwrap() >= first.last_connected.unwrap());
+
+		// Force a disconnection.
+		handle.set_endpoint_enabled(&addr, false);
+		pool.run_until_stalled();
+		let disconnected = status(&mut pool);
+		assert!(!disconnected.connected);
+		assert_eq!(disconnected.last_sent, first.last_sent);
+
+		handle.set_endpoint_enabled(&addr, true);
+		pool.run_until_stalled();
+		send(&mut pool, 1);
+		assert_eq!(drain(&mut pool, &mut server).len(), 1);
+		let reconnected = status(&mut pool);
+		assert!(reconnected.connected);
+		assert_eq!(reconnected.reconnects, 1);
+		assert!(reconnected.last_connected > first.last_connected);
+		assert!(reconnected.last_sent > first.last_sent);
+	}
+
+	#[test]
+	fn flush_sends_the_pending_messages_and_batches() {
+		let addr: Multiaddr = "/memory/10210".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let config = TelemetryConfig {
+			batch: Some(BatchConfig {
+				window: Duration::from_secs(3600),
+				max_messages: 100,
+			}),
+			..Default::default()
+		};
+		let (mut pool, handle, mut message_sender, _events) = config_worker(&addr, config);
+		pool.run_until(message_sender.send(numbered_message(0)))
+			.unwrap();
+		pool.run_until_stalled();
+		// Only the connection message has been sent, the message waits in the batch.
+		assert_eq!(server.received().len(), 1);
+
+		// The next messages are still in the channel to the worker.
+		for i in 1..3 {
+			message_sender.try_send(numbered_message(i)).unwrap();
+		}
+		let result = pool.run_until(handle.flush(Duration::from_secs(5)));
+		assert_eq!(result, FlushResult { flushed: 3, abandoned: 0 });
+		assert_eq!(
+			server.received_frames(),
+			vec!["{\"msg\":\"00000\"}\n{\"msg\":\"00001\"}\n{\"msg\":\"00002\"}".to_string()],
+		);
+
+		// Nothing is left to send.
+		let result = pool.run_until(handle.flush(Duration::from_secs(5)));
+		assert_eq!(result, FlushResult::default());
+	}
+
+	#[test]
+	fn messages_of_unknown_spans_go_to_the_fallback_endpoints() {
+		let fallback: Multiaddr = "/memory/10208".parse().unwrap();
+		let addr: Multiaddr = "/memory/10209".parse().unwrap();
+		let mut fallback_server = FakeServer::new(&fallback);
+		let mut server = FakeServer::new(&addr);
+		let worker = TelemetryWorker::new(TelemetryConfig::default(), memory_transport());
+		let mut message_sender = worker.message_sender();
+		let handle = worker.handle();
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		let mut send = |pool: &mut LocalPool, id, verbosity, msg: &str| {
+			let json = format!(r#"{{"msg":"{}"}}"#, msg);
+			let message = TelemetryMessage::new(Id::from_u64(id), verbosity, json);
+			pool.run_until(message_sender.send(message)).unwrap();
+		};
+
+		handle.set_fallback_endpoints(TelemetryEndpoints(vec![TelemetryEndpoint::new(
+			fallback.clone(),
+			SUBSTRATE_INFO,
+		)]));
+		pool.run_until_stalled();
+		send(&mut pool, 1, SUBSTRATE_INFO, "unknown");
+		send(&mut pool, 1, SUBSTRATE_DEBUG, "too verbose");
+		assert_eq!(
+			drain(&mut pool, &mut fallback_server),
+			vec!["unknown".to_string()]
+		);
+
+		handle
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints: TelemetryEndpoints(vec![TelemetryEndpoint::new(
+					addr.clone(), SUBSTRATE_INFO,
+				)]),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+		send(&mut pool, 1, SUBSTRATE_INFO, "known");
+		send(&mut pool, 2, SUBSTRATE_INFO, "other");
+		assert_eq!(drain(&mut pool, &mut server), vec!["known".to_string()]);
+		assert_eq!(drain(&mut pool, &mut fallback_server), vec!["other".to_string()]);
+		assert!(pool.run_until(handle.status()).iter().all(|entry| entry.id == Id::from_u64(1)));
+
+		// Removing the fallback endpoints closes their connection.
+		handle.set_fallback_endpoints(TelemetryEndpoints(Vec::new()));
+		send(&mut pool, 2, SUBSTRATE_INFO, "discarded");
+		pool.run_until_stalled();
+		assert_eq!(pool.run_until(fallback_server.next()), None);
+	}
+
+	#[test]
+	fn status_lists_the_endpoints_of_every_span() {
+		let addr: Multiaddr = "/memory/10206".parse().unwrap();
+		let unreachable: Multiaddr = "/memory/10207".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let worker = TelemetryWorker::new(TelemetryConfig::default(), memory_transport());
+		let mut message_sender = worker.message_sender();
+		let handle = worker.handle();
+		handle
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints: TelemetryEndpoints(vec![
+					TelemetryEndpoint::new(addr.clone(), SUBSTRATE_INFO),
+					TelemetryEndpoint::new(unreachable.clone(), SUBSTRATE_DEBUG),
+				]),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		pool.run_until_stalled();
+		for i in 0..2 {
+			pool.run_until(message_sender.send(numbered_message(i)))
+				.unwrap();
+		}
+		assert_eq!(drain(&mut pool, &mut server).len(), 2);
+
+		let status = pool.run_until(handle.status());
+		assert_eq!(status.len(), 1);
+		assert_eq!(status[0].id, Id::from_u64(1));
+		let endpoints = &status[0].endpoints;
+		assert_eq!(endpoints.len(), 2);
+		let (verbosity, connected) = &endpoints[0];
+		assert_eq!((*verbosity, &connected.addr), (SUBSTRATE_INFO, &addr));
+		assert!(matches!(connected.state, ConnectionState::Connected { .. }));
+		assert_eq!((connected.messages_sent, connected.messages_dropped), (3, 0));
+		let (verbosity, retrying) = &endpoints[1];
+		assert_eq!((*verbosity, &retrying.addr), (SUBSTRATE_DEBUG, &unreachable));
+		assert!(matches!(
+			retrying.state,
+			ConnectionState::Retrying { until } if until > Instant::now()
+		));
+		// The messages are held until it connects.
+		assert_eq!((retrying.messages_sent, retrying.messages_dropped), (0, 0));
+
+		handle.set_endpoint_enabled(&unreachable, false);
+		let status = pool.run_until(handle.status());
+		assert_eq!(status[0].endpoints[1].1.state, ConnectionState::Disabled);
+	}
+
+	#[test]
+	fn keepalive_pings_idle_connections_and_replaces_stalled_ones() {
+		let addr: Multiaddr = "/memory/10200".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let config = TelemetryConfig {
+			node_queue_capacity: 16,
+			keepalive_interval: Duration::from_millis(100),
+			..Default::default()
+		};
+		let worker = TelemetryWorker::new(config, memory_transport());
+		let mut message_sender = worker.message_sender();
+		let handle = worker.handle();
+		handle
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints: TelemetryEndpoints(vec![TelemetryEndpoint::new(
+					addr.clone(), SUBSTRATE_INFO,
+				)]),
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		let mut send = |pool: &mut LocalPool, i| {
+			pool.run_until(message_sender.send(numbered_message(i)))
+				.unwrap();
+			pool.run_until_stalled();
+		};
+		let wait = |pool: &mut LocalPool| {
+			pool.run_until(wasm_timer::Delay::new(Duration::from_millis(250)))
+				.unwrap();
+		};
+
+		send(&mut pool, 0);
+		assert_eq!(drain(&mut pool, &mut server), vec!["00000"]);
+		wait(&mut pool);
+		let pings = drain(&mut pool, &mut server);
+		assert!(!pings.is_empty());
+		assert!(pings.iter().all(|msg| msg == "system.ping"), "{:?}", pings);
+
+		// The server stops reading, until the connection can't take any more messages.
+		for i in 1..5000 {
+			send(&mut pool, i);
+		}
+		wait(&mut pool);
+		let status = pool.run_until(handle.endpoint_status()).remove(0);
+		assert!(!status.connected);
+
+		send(&mut pool, 5000);
+		let status = pool.run_until(handle.endpoint_status()).remove(0);
+		assert!(status.connected);
+		assert_eq!(status.reconnects, 1);
+	}
+
+	#[test]
+	fn failed_connections_are_reported() {
+		// Nothing listens on this address.
+		let addr: Multiaddr = "/memory/10141".parse().unwrap();
+		let (mut pool, _handle, mut message_sender, mut events) =
+			queueing_worker(&addr, QueuePolicy::DropOldest);
+
+		pool.run_until(message_sender.send(numbered_message(0)))
+			.unwrap();
+		pool.run_until_stalled();
+
+		let failed = std::iter::from_fn(|| events.next().now_or_never().flatten()).any(|event| {
+			matches!(event, TelemetryEvent::ConnectionFailed { addr: failed, .. } if failed == addr)
+		});
+		assert!(failed);
+	}
+
+	#[test]
+	fn connect_timeouts_apply_per_endpoint() {
+		// These listeners accept TCP connections but never answer the WebSocket handshake.
+		let slow = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let patient = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let ws_addr = |listener: &std::net::TcpListener| -> Multiaddr {
+			format!("/ip4/127.0.0.1/tcp/{}/ws", listener.local_addr().unwrap().port())
+				.parse()
+				.unwrap()
+		};
+		let (short_addr, long_addr) = (ws_addr(&slow), ws_addr(&patient));
+
+		let config = TelemetryConfig::default();
+		let mut worker =
+			TelemetryWorker::new(config.clone(), initialize_transport(None, &config).unwrap());
+		let mut message_sender = worker.message_sender();
+		let mut events = worker.events().unwrap();
+		let mut endpoints = TelemetryEndpoints(vec![
+			TelemetryEndpoint::new(short_addr.clone(), SUBSTRATE_INFO),
+			TelemetryEndpoint::new(long_addr, SUBSTRATE_INFO),
+		]);
+		assert!(endpoints.set_connect_timeout(&short_addr, Some(Duration::from_millis(100))));
+		worker
+			.handle()
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints,
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		pool.run_until_stalled();
+		pool.run_until(message_sender.send(numbered_message(0)))
+			.unwrap();
+		pool.run_until(wasm_timer::Delay::new(Duration::from_millis(500)))
+			.unwrap();
+		pool.run_until(message_sender.send(numbered_message(1)))
+			.unwrap();
+		pool.run_until_stalled();
+
+		let failed: Vec<_> = std::iter::from_fn(|| events.next().now_or_never().flatten())
+			.filter_map(|event| match event {
+				TelemetryEvent::ConnectionFailed { addr, .. } => Some(addr),
+				_ => None,
+			})
+			.collect();
+		assert_eq!(failed, vec![short_addr]);
+	}
+
+	#[test]
+	fn telemetry_can_be_registered_after_the_worker_started() {
+		let addr: Multiaddr = "/memory/10160".parse().unwrap();
+		let late_addr: Multiaddr = "/memory/10161".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let late_server = FakeServer::new(&late_addr);
+		let (mut pool, handle, mut message_sender, _events) =
+			queueing_worker(&addr, QueuePolicy::DropOldest);
+
+		pool.run_until(message_sender.send(numbered_message(0)))
+			.unwrap();
+		assert_eq!(drain(&mut pool, &mut server), vec!["00000".to_string()]);
+		pool.run_until(wasm_timer::Delay::new(Duration::from_millis(50)))
+			.unwrap();
+
+		// A new span registers both with the connected endpoint and with a new endpoint.
+		handle
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(2),
+				endpoints: TelemetryEndpoints(vec![
+					TelemetryEndpoint::new(addr.clone(), SUBSTRATE_INFO),
+					TelemetryEndpoint::new(late_addr.clone(), SUBSTRATE_INFO),
+				]),
+				// Another node, the connection messages of a same node are not sent twice.
+				connection_message: ConnectionMessage {
+					name: "late".into(),
+					..connection_message()
+				},
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+		pool.run_until_stalled();
+
+		let message = TelemetryMessage::new(Id::from_u64(2), SUBSTRATE_INFO, r#"{"msg":"late"}"#);
+		pool.run_until(message_sender.send(message)).unwrap();
+		pool.run_until_stalled();
+
+		for server in &mut [server, late_server] {
+			let received = server.received();
+			assert_eq!(received.len(), 2);
+			assert_eq!(received[0]["id"], 2);
+			assert_eq!(received[0]["payload"]["msg"], "system.connected");
+			assert_eq!(received[1]["msg"], "late");
+		}
+	}
+
+	#[test]
+	fn endpoints_can_be_added_and_removed_at_runtime() {
+		let addr: Multiaddr = "/memory/10180".parse().unwrap();
+		let added_addr: Multiaddr = "/memory/10181".parse().unwrap();
+		let mut server = FakeServer::new(&addr);
+		let mut added_server = FakeServer::new(&added_addr);
+		let (mut pool, handle, mut message_sender, _events) =
+			queueing_worker(&addr, QueuePolicy::DropOldest);
+
+		handle.add_endpoint(&added_addr, SUBSTRATE_INFO);
+		// Adding an endpoint twice only updates its verbosity.
+		handle.add_endpoint(&added_addr, SUBSTRATE_INFO);
+		pool.run_until_stalled();
+		pool.run_until(message_sender.send(numbered_message(0)))
+			.unwrap();
+		pool.run_until_stalled();
+
+		let received = added_server.received();
+		assert_eq!(received.len(), 2);
+		assert_eq!(received[0]["id"], 1);
+		assert_eq!(received[0]["payload"]["msg"], "system.connected");
+		assert_eq!(received[1]["msg"], "00000");
+
+		handle.remove_endpoint(&added_addr);
+		pool.run_until_stalled();
+		// The connection has been closed.
+		assert_eq!(added_server.next().now_or_never(), Some(None));
+
+		pool.run_until(message_sender.send(numbered_message(1)))
+			.unwrap();
+		assert_eq!(drain(&mut pool, &mut server), vec!["00000", "00001"]);
+		assert!(added_server.received().is_empty());
+	}
+
+	#[test]
+	fn failover_group_switches_to_the_backup() {
+		// Nothing listens on the address of the primary.
+		let primary: Multiaddr = "/memory/10150".parse().unwrap();
+		let backup: Multiaddr = "/memory/10151".parse().unwrap();
+		let mut server = FakeServer::new(&backup);
+
+		let config = TelemetryConfig {
+			failover_delay: Duration::from_millis(50),
+			..Default::default()
+		};
+		let worker = TelemetryWorker::new(config, memory_transport());
+		let mut message_sender = worker.message_sender();
+		let handle = worker.handle();
+
+		let mut endpoints = TelemetryEndpoints(vec![
+			TelemetryEndpoint::new(backup.clone(), SUBSTRATE_INFO),
+			TelemetryEndpoint::new(primary.clone(), SUBSTRATE_INFO),
+		]);
+		assert!(endpoints.set_group(&primary, Some(("main".into(), 0))));
+		assert!(endpoints.set_group(&backup, Some(("main".into(), 1))));
+		handle
+			.message_sender
+			.unbounded_send(Register::Telemetry {
+				id: Id::from_u64(1),
+				endpoints,
+				connection_message: connection_message(),
+				overrides: HashMap::new(),
+			})
+			.unwrap();
+		let notifier = |addr: &Multiaddr| {
+			let (sender, receiver) = tracing_unbounded("test_telemetry_on_connect");
+			handle
+				.message_sender
+				.unbounded_send(Register::Notifier {
+					id: Some(Id::from_u64(1)),
+					addresses: vec![addr.clone()],
+					connection_notifier: sender,
+				})
+				.unwrap();
+			receiver
+		};
+		let mut primary_notifier = notifier(&primary);
+		let mut backup_notifier = notifier(&backup);
+
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(worker.run().map(Result::unwrap)).unwrap();
+		pool.run_until_stalled();
+
+		// The primary is active until it has been disconnected for longer than the delay.
+		pool.run_until(message_sender.send(numbered_message(0)))
+			.unwrap();
+		pool.run_until_stalled();
+		assert!(server.received().is_empty());
+
+		pool.run_until(wasm_timer::Delay::new(Duration::from_millis(100)))
+			.unwrap();
+		pool.run_until(message_sender.send(numbered_message(1)))
+			.unwrap();
+		pool.run_until_stalled();
+		let received = server.received();
+		assert_eq!(received.len(), 2);
+		assert_eq!(received[0]["payload"]["msg"], "system.connected");
+		assert_eq!(received[1]["msg"], "00001");
+
+		assert_eq!(backup_notifier.next().now_or_never(), Some(Some(())));
+		assert!(primary_notifier.next().now_or_never().is_none());
+
+		// The telemetry switches back to the primary once it is reachable, without waiting for
+		// more telemetry.
+		let mut primary_server = FakeServer::new(&primary);
+		pool.run_until(wasm_timer::Delay::new(Duration::from_millis(1500)))
+			.unwrap();
+		// The message queued while the primary was active is delivered.
+		let received = primary_server.received();
+		assert_eq!(received.len(), 2);
+		assert_eq!(received[0]["payload"]["msg"], "system.connected");
+		assert_eq!(received[1]["msg"], "00000");
+		assert_eq!(primary_notifier.next().now_or_never(), Some(Some(())));
+	}
+
+	#[test]
+	fn nested_fields_are_serialized_as_objects() {
+		let set_id = (43_u64, 44_u64);
+		let fields = format_fields_to_json!(
+			"authority_id" => 42,
+			"voter" => {
+				"set_id" => ?set_id,
+				"round" => {
+					"number" => 7,
+					"votes" => vec![1, 2],
+				},
+			},
+			"authorities" => vec![45],
+		)
+		.unwrap();
+
+		assert_eq!(
+			serde_json::Value::Object(fields),
+			serde_json::json!({
+				"authority_id": 42,
+				"voter": {
+					"set_id": "(43, 44)",
+					"round": { "number": 7, "votes": [1, 2] },
+				},
+				"authorities": [45],
+			}),
+		);
+	}
+
+	#[test]
+	fn fields_mix_serialized_debug_and_display_values() {
+		let addr: Multiaddr = "/ip4/127.0.0.1/tcp/30333".parse().unwrap();
+		let set_id = (43_u64, 44_u64);
+		let fields = format_fields_to_json!(
+			"addr" => %addr,
+			"set_id" => ?set_id,
+			"authorities" => vec![45],
+			"peer" => {
+				"verbosity" => SUBSTRATE_INFO,
+				"addr" => %addr,
+				"set_id" => ?set_id,
+			},
+			"first_authority" => %45,
+		)
+		.unwrap();
+
+		assert_eq!(
+			serde_json::Value::Object(fields),
+			serde_json::json!({
+				"addr": "/ip4/127.0.0.1/tcp/30333",
+				"set_id": "(43, 44)",
+				"authorities": [45],
+				"peer": {
+					"verbosity": 0,
+					"addr": "/ip4/127.0.0.1/tcp/30333",
+					"set_id": "(43, 44)",
+				},
+				"first_authority": "45",
+			}),
+		);
+	}
 }
@@ -0,0 +1,94 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use futures::{future::BoxFuture, prelude::*, stream::BoxStream};
+use libp2p::{
+	core::transport::{ListenerEvent, TransportError},
+	Multiaddr, Transport,
+};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// URL of the endpoint that prints the telemetry to the logs.
+pub(crate) const LOG_URL: &str = "log:";
+
+/// Target of the logs of the `log:` endpoint.
+pub(crate) const LOG_TARGET: &str = "telemetry-debug";
+
+/// Transport of the `log:` endpoint, whose connection prints every telemetry message with
+/// `log::info!`, e.g. to see the telemetry of a node without a telemetry server. The dialed
+/// address is ignored.
+#[derive(Debug, Clone)]
+pub(crate) struct LogTransport;
+
+impl Transport for LogTransport {
+	type Output = LogConnection;
+	type Error = io::Error;
+	type Listener =
+		BoxStream<'static, Result<ListenerEvent<Self::ListenerUpgrade, Self::Error>, Self::Error>>;
+	type ListenerUpgrade = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+	type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+	fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+		Err(TransportError::MultiaddrNotSupported(addr))
+	}
+
+	fn dial(self, _: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+		Ok(future::ready(Ok(LogConnection)).boxed())
+	}
+
+	fn address_translation(&self, _: &Multiaddr, _: &Multiaddr) -> Option<Multiaddr> {
+		None
+	}
+}
+
+/// Connection of the `log:` endpoint. Every message of a batch is logged separately, and nothing
+/// is ever received.
+pub(crate) struct LogConnection;
+
+impl Stream for LogConnection {
+	type Item = Result<Vec<u8>, io::Error>;
+
+	fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		Poll::Pending
+	}
+}
+
+impl Sink<Vec<u8>> for LogConnection {
+	type Error = io::Error;
+
+	fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+		for message in String::from_utf8_lossy(&item).lines() {
+			log::info!(target: LOG_TARGET, "{}", message);
+		}
+		Ok(())
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		Poll::Ready(Ok(()))
+	}
+}
@@ -16,15 +16,26 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::{
+	display_addr, BatchConfig, BudgetedQueue, BufferBudget, ConnectedEndpoint, ConnectionState,
+	EndpointKind, EndpointStatus, EventSender, QueuePolicy, RetentionQueue, Spill,
+	TelemetryConfig, TelemetryEvent, TelemetryMessage,
+};
 use futures::prelude::*;
 use libp2p::core::transport::Transport;
 use libp2p::Multiaddr;
 use rand::Rng as _;
+use std::collections::BTreeMap;
+use std::sync::{atomic::Ordering, Arc};
 use std::{fmt, mem, pin::Pin, task::Context, task::Poll, time::Duration};
-use wasm_timer::Delay;
+use tracing::Id;
+use wasm_timer::{Delay, Instant, TimerHandle};
 
 pub(crate) type ConnectionNotifierSender = sp_utils::mpsc::TracingUnboundedSender<()>;
 
+/// Message sent to keep an idle connection alive.
+const PING: &[u8] = br#"{"msg":"system.ping"}"#;
+
 /// Handler for a single telemetry node.
 ///
 /// This is a wrapper `Sink` around a network `Sink` with 3 particularities:
@@ -33,13 +44,18 @@ pub(crate) type ConnectionNotifierSender = sp_utils::mpsc::TracingUnboundedSende
 ///  -  It holds a list of "connection messages" which are sent automatically when the connection is
 ///     (re-)established. This is used for the "system.connected" message that needs to be send for
 ///     every substrate node that connects.
-///  -  It doesn't stay in pending while waiting for connection. Instead, it moves data into the
-///     void if the connection could not be established. This is important for the `Dispatcher`
-///     `Sink` which we don't want to block if one connection is broken.
+///  -  It doesn't stay in pending while waiting for connection. Instead, it holds a bounded
+///     number of messages until the connection is established and drops the others. This is
+///     important for the `Dispatcher` `Sink` which we don't want to block if one connection is
+///     broken.
 #[derive(Debug)]
 pub(crate) struct Node<TTrans: Transport> {
 	/// Address of the node.
 	addr: Multiaddr,
+	/// Address of the node as a URL, for the logs.
+	url: String,
+	/// Where the node sends the telemetry.
+	kind: EndpointKind,
 	/// State of the connection.
 	socket: NodeSocket<TTrans>,
 	/// Transport used to establish new connections.
@@ -48,26 +64,153 @@ pub(crate) struct Node<TTrans: Transport> {
 	pub(crate) connection_messages: Vec<serde_json::Map<String, serde_json::Value>>,
 	/// Notifier for when the connection (re-)establishes.
 	pub(crate) telemetry_connection_notifier: Vec<ConnectionNotifierSender>,
+	/// Accounting of the bytes buffered by this node.
+	budget: BufferBudget,
+	/// Where to report the [`TelemetryEvent`]s of this node.
+	events: EventSender,
+	/// Batching of the messages, if enabled.
+	batch_config: Option<BatchConfig>,
+	/// Maximum number of frames in the queue of the connection.
+	queue_capacity: usize,
+	/// What to do when the queue of the connection is full.
+	queue_policy: QueuePolicy,
+	/// Number of messages dropped because the queue of the connection was full.
+	dropped: u64,
+	/// Time after which a connection attempt is abandoned.
+	connect_timeout: Duration,
+	/// Maximum delay before a new connection attempt.
+	max_reconnect_delay: Duration,
+	/// Timer of the reconnection delays and of the connection attempts.
+	timer: TimerHandle,
+	/// Number of connection attempts that have failed since the last established connection.
+	failed_attempts: u32,
+	/// When the last connection has been lost or the first connection attempt has failed, if
+	/// the node is not connected.
+	disconnected_since: Option<Instant>,
+	/// When the last connection has been established.
+	last_connected: Option<Instant>,
+	/// What has been written to the connections.
+	delivery: Delivery,
+	/// Number of connections established so far.
+	connections: u64,
+	/// Failover group of the node and its priority in the group, if any.
+	failover_group: Option<(String, u8)>,
+	/// If `true`, another endpoint of the failover group of the node receives the telemetry.
+	standby: bool,
+	/// Where the messages that can't be delivered are persisted, if enabled.
+	spill: Option<Spill>,
+	/// Time without any write after which a ping is sent, if the keepalive is enabled.
+	keepalive_interval: Option<Duration>,
+	/// Verbosity of the messages by prefix of their name, instead of the verbosity of the
+	/// endpoint.
+	target_verbosity: BTreeMap<String, u8>,
+	/// Age after which the queued messages are discarded instead of being sent, if any.
+	max_message_age: Option<Duration>,
+	/// Messages held while the node is not connected, sent once it connects.
+	held: RetentionQueue,
+	/// Suppression of the repeated messages, if enabled.
+	dedup: Option<Dedup>,
+}
+
+/// Suppression of the messages identical to the previous one, see
+/// [`TelemetryEndpoints::set_dedup_window`](crate::TelemetryEndpoints::set_dedup_window).
+#[derive(Debug)]
+struct Dedup {
+	window: Duration,
+	/// Previous message that went through, without its `ts` field, and when.
+	previous: Option<(serde_json::Map<String, serde_json::Value>, Instant)>,
+	/// Number of messages suppressed since then.
+	suppressed: u64,
+}
+
+impl Dedup {
+	fn new(window: Duration) -> Self {
+		Dedup {
+			window,
+			previous: None,
+			suppressed: 0,
+		}
+	}
+
+	/// Return the message to send instead of `message` at `now`, or `None` if it is suppressed.
+	///
+	/// The number of messages suppressed before it is added to the returned message.
+	fn filter(&mut self, message: Arc<str>, now: Instant) -> Option<Arc<str>> {
+		let mut json = match serde_json::from_str(&message) {
+			Ok(serde_json::Value::Object(json)) => json,
+			_ => return Some(message),
+		};
+		let ts = json.remove("ts");
+
+		if let Some((previous, sent_at)) = &self.previous {
+			if *previous == json && now.duration_since(*sent_at) < self.window {
+				self.suppressed += 1;
+				return None;
+			}
+		}
+
+		let suppressed = mem::replace(&mut self.suppressed, 0);
+		if suppressed == 0 {
+			self.previous = Some((json, now));
+			return Some(message);
+		}
+		let mut reported = json.clone();
+		self.previous = Some((json, now));
+		if let Some(ts) = ts {
+			reported.insert("ts".into(), ts);
+		}
+		reported.insert("suppressed".into(), suppressed.into());
+		Some(serde_json::Value::Object(reported).to_string().into())
+	}
 }
 
 enum NodeSocket<TTrans: Transport> {
 	/// We're connected to the node. This is the normal state.
-	Connected(NodeSocketConnected<TTrans>),
-	/// We are currently dialing the node.
-	Dialing(TTrans::Dial),
+	Connected(Box<NodeSocketConnected<TTrans>>),
+	/// We are currently dialing the node. The connection attempt is abandoned when the delay
+	/// expires.
+	Dialing(TTrans::Dial, Delay),
 	/// A new connection should be started as soon as possible.
 	ReconnectNow,
-	/// Waiting before attempting to dial again.
-	WaitingReconnect(Delay),
+	/// Waiting before attempting to dial again, until the given time.
+	WaitingReconnect(Delay, Instant),
+	/// The endpoint has been disabled: we don't connect until it is enabled again.
+	Disabled,
 	/// Temporary transition state.
 	Poisoned,
 }
 
-impl<TTrans: Transport> NodeSocket<TTrans> {
-	fn wait_reconnect() -> NodeSocket<TTrans> {
-		let random_delay = rand::thread_rng().gen_range(5, 10);
-		let delay = Delay::new(Duration::from_secs(random_delay));
-		NodeSocket::WaitingReconnect(delay)
+/// Delay before the first new connection attempt.
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Maximum fraction of the reconnection delay added or removed at random, so that the nodes
+/// that lost their connection at the same time don't reconnect all at once.
+const RECONNECT_JITTER: f64 = 0.2;
+
+/// Delay before a new connection attempt after `failed_attempts` failed ones, at most `max`,
+/// shifted by the fraction `jitter`.
+fn reconnect_delay(failed_attempts: u32, max: Duration, jitter: f64) -> Duration {
+	let delay = (INITIAL_RECONNECT_DELAY * 2_u32.saturating_pow(failed_attempts)).min(max);
+	delay.mul_f64(1.0 + jitter).min(max)
+}
+
+/// Accounting of the messages of a node.
+#[derive(Debug, Default)]
+struct Delivery {
+	/// When a message has last been written to a connection.
+	last_sent: Option<Instant>,
+	/// Number of messages written to the connections.
+	sent: u64,
+	/// Number of messages discarded because the node was not connected or because they had been
+	/// queued for too long. The messages dropped from a full queue are counted separately.
+	discarded: u64,
+}
+
+impl Delivery {
+	/// Account for `messages` written to the connection.
+	fn sent(&mut self, messages: u64) {
+		self.sent += messages;
+		self.last_sent = Some(Instant::now());
 	}
 }
 
@@ -75,7 +218,108 @@ struct NodeSocketConnected<TTrans: Transport> {
 	/// Where to send data.
 	sink: TTrans::Output,
 	/// Queue of packets to send before accepting new packets.
-	buf: Vec<Vec<u8>>,
+	buf: BudgetedQueue,
+	/// When the connection has been established.
+	connected_since: Instant,
+	/// Frames waiting for the socket to accept them.
+	queue: BudgetedQueue,
+	/// Messages waiting to be sent in a single frame.
+	batch: BudgetedQueue,
+	/// When the first message of the current batch has been queued.
+	batch_started: Option<Instant>,
+	/// Keepalive interval and when it expires, if the keepalive is enabled.
+	keepalive: Option<(Duration, Delay)>,
+	/// `true` if frames have been written to the socket since it has last been flushed.
+	unflushed: bool,
+	/// Counts the connection in [`TelemetryHandle::connected_endpoints`] until it is dropped.
+	///
+	/// [`TelemetryHandle::connected_endpoints`]: crate::TelemetryHandle::connected_endpoints
+	_connected: ConnectedEndpoint,
+}
+
+impl<TTrans: Transport> NodeSocketConnected<TTrans> {
+	/// Record that a frame has been written to the socket, which restarts the keepalive.
+	fn written(&mut self) {
+		self.unflushed = true;
+		if let Some((interval, keepalive)) = &mut self.keepalive {
+			keepalive.reset(*interval);
+		}
+	}
+
+	/// Discard the frames at the front of the queue that are older than `max_age`.
+	///
+	/// Returns the number of messages that have been discarded.
+	fn drop_expired(&mut self, max_age: Duration) -> usize {
+		let mut expired = 0;
+		while let Some(frame) = self.queue.pop_expired(max_age) {
+			// A batch holds one message per line.
+			expired += 1 + frame.matches('\n').count();
+		}
+		expired
+	}
+
+	/// Take the number of messages of the connection that have been evicted from the
+	/// [`BufferBudget`] since the last call.
+	fn take_evicted(&mut self) -> usize {
+		self.buf.take_evicted() + self.queue.take_evicted() + self.batch.take_evicted()
+	}
+
+	/// Number of telemetry messages held by the connection.
+	fn pending_messages(&self) -> usize {
+		self.buf.len() + self.queue.lines() + self.batch.len()
+	}
+
+	/// Return `true` if the current batch must be sent.
+	fn batch_is_due(&self, config: BatchConfig) -> bool {
+		match self.batch_started {
+			Some(started) => {
+				self.batch.len() >= config.max_messages || started.elapsed() >= config.window
+			}
+			None => false,
+		}
+	}
+
+	/// Take the messages of the current batch as a single newline-delimited frame, along with
+	/// when its oldest message has been queued.
+	fn take_batch(&mut self) -> (Arc<str>, Instant) {
+		let queued_at = self.batch.oldest().unwrap_or_else(Instant::now);
+		self.batch_started = None;
+		let mut frame = String::new();
+		while let Some(message) = self.batch.pop() {
+			if !frame.is_empty() {
+				frame.push('\n');
+			}
+			frame.push_str(&message);
+		}
+		(frame.into(), queued_at)
+	}
+
+	/// Push a frame queued at `queued_at` at the back of the queue, applying `policy` if the
+	/// queue already contains `capacity` frames.
+	///
+	/// Returns the number of frames that have been dropped. [`QueuePolicy::Block`] is enforced by
+	/// [`Node::poll_ready`]: if the queue is full anyway, the oldest frame is dropped.
+	fn enqueue(
+		&mut self,
+		frame: Arc<str>,
+		queued_at: Instant,
+		capacity: usize,
+		policy: QueuePolicy,
+	) -> usize {
+		let mut dropped = 0;
+
+		if self.queue.len() >= capacity.max(1) {
+			match policy {
+				QueuePolicy::DropNewest => return 1,
+				QueuePolicy::DropOldest | QueuePolicy::Block => {
+					self.queue.pop();
+					dropped += 1;
+				}
+			}
+		}
+
+		dropped + self.queue.push_queued_at(frame, queued_at)
+	}
 }
 
 impl<TTrans: Transport> Node<TTrans> {
@@ -85,13 +329,494 @@ impl<TTrans: Transport> Node<TTrans> {
 		addr: Multiaddr,
 		connection_messages: Vec<serde_json::Map<String, serde_json::Value>>,
 		telemetry_connection_notifier: Vec<ConnectionNotifierSender>,
+		budget: BufferBudget,
+		events: EventSender,
+		config: &TelemetryConfig,
 	) -> Self {
+		let spill = config.spill_dir.as_ref().map(|dir| {
+			Spill::new(dir, &addr, config.spill_threshold, config.spill_max_age)
+		});
+		let held = RetentionQueue::new(config.disconnected_buffer_size, budget.clone());
+
 		Node {
+			url: display_addr(&addr),
 			addr,
+			kind: EndpointKind::Server,
 			socket: NodeSocket::ReconnectNow,
 			transport,
 			connection_messages,
 			telemetry_connection_notifier,
+			budget,
+			events,
+			batch_config: config.batch,
+			queue_capacity: config.node_queue_capacity,
+			queue_policy: config.node_queue_policy,
+			dropped: 0,
+			connect_timeout: config.connect_timeout,
+			max_reconnect_delay: config.max_reconnect_delay,
+			timer: TimerHandle::default(),
+			failed_attempts: 0,
+			disconnected_since: None,
+			last_connected: None,
+			delivery: Delivery::default(),
+			connections: 0,
+			failover_group: None,
+			standby: false,
+			spill,
+			keepalive_interval: Some(config.keepalive_interval)
+				.filter(|interval| *interval > Duration::from_secs(0)),
+			target_verbosity: BTreeMap::new(),
+			max_message_age: config.max_message_age,
+			held,
+			dedup: None,
+		}
+	}
+
+	/// Return when the connection to the node has been established, or `None` if the node is not
+	/// connected.
+	pub(crate) fn connected_since(&self) -> Option<Instant> {
+		match &self.socket {
+			NodeSocket::Connected(conn) => Some(conn.connected_since),
+			_ => None,
+		}
+	}
+
+	/// Return the state of the connection to the node.
+	pub(crate) fn status(&self) -> EndpointStatus {
+		EndpointStatus {
+			addr: self.addr.clone(),
+			connected: self.connected_since().is_some(),
+			last_connected: self.last_connected,
+			last_sent: self.delivery.last_sent,
+			reconnects: self.connections.saturating_sub(1),
+			failed_attempts: self.failed_attempts,
+			state: match &self.socket {
+				NodeSocket::Connected(conn) => ConnectionState::Connected {
+					since: conn.connected_since,
+				},
+				NodeSocket::WaitingReconnect(_, until) => {
+					ConnectionState::Retrying { until: *until }
+				}
+				NodeSocket::Disabled => ConnectionState::Disabled,
+				_ => ConnectionState::Connecting,
+			},
+			messages_sent: self.delivery.sent,
+			messages_dropped: self.dropped + self.delivery.discarded,
+		}
+	}
+
+	/// Number of messages written to the connections of the node so far.
+	pub(crate) fn messages_sent(&self) -> u64 {
+		self.delivery.sent
+	}
+
+	/// Number of messages held by the node, waiting to be written to its connection.
+	pub(crate) fn pending_messages(&self) -> usize {
+		match &self.socket {
+			NodeSocket::Connected(conn) => conn.pending_messages(),
+			_ => 0,
+		}
+	}
+
+	/// Where the node sends the telemetry.
+	pub(crate) fn kind(&self) -> &EndpointKind {
+		&self.kind
+	}
+
+	/// Set where the node sends the telemetry, which changes its URL in the logs and disables the
+	/// keepalive pings of the endpoints that aren't servers: the transport of the node must be the
+	/// one of `kind`.
+	pub(crate) fn set_kind(&mut self, kind: EndpointKind) {
+		self.url = kind.url(&self.addr);
+		self.kind = kind;
+	}
+
+	/// Return `false` if the endpoint has been disabled with [`Node::set_enabled`].
+	pub(crate) fn is_enabled(&self) -> bool {
+		!matches!(self.socket, NodeSocket::Disabled)
+	}
+
+	/// Enable or disable the connection to the node.
+	///
+	/// Disabling the node closes its connection, if any, and discards the messages that have not
+	/// been sent. The node reconnects as soon as it is enabled again.
+	pub(crate) fn set_enabled(&mut self, enabled: bool) {
+		match (&self.socket, enabled) {
+			(NodeSocket::Disabled, true) => {
+				log::debug!(target: "telemetry", "Telemetry endpoint {} enabled", self.url);
+				self.socket = NodeSocket::ReconnectNow;
+				self.disconnected_since = None;
+			}
+			(NodeSocket::Disabled, false) | (_, true) => {}
+			(socket, false) => {
+				log::debug!(target: "telemetry", "Telemetry endpoint {} disabled", self.url);
+				if let NodeSocket::Connected(_) = socket {
+					self.disconnected();
+				}
+				self.socket = NodeSocket::Disabled;
+				self.held.clear();
+			}
+		}
+	}
+
+	/// Wait before the next connection attempt, twice as long as before the previous one if it
+	/// has failed.
+	fn wait_reconnect(&mut self) -> NodeSocket<TTrans> {
+		let jitter = rand::thread_rng().gen_range(-RECONNECT_JITTER, RECONNECT_JITTER);
+		let delay = reconnect_delay(self.failed_attempts, self.max_reconnect_delay, jitter);
+		self.failed_attempts = self.failed_attempts.saturating_add(1);
+		log::debug!(
+			target: "telemetry",
+			"Reconnecting to {} in {:?}",
+			self.url,
+			delay,
+		);
+		let until = Instant::now() + delay;
+		NodeSocket::WaitingReconnect(Delay::new_handle(until, self.timer.clone()), until)
+	}
+
+	/// Set the time after which a connection attempt is abandoned, starting with the next one.
+	pub(crate) fn set_connect_timeout(&mut self, timeout: Duration) {
+		self.connect_timeout = timeout;
+	}
+
+	/// Suppress the messages identical to the previous one sent less than `window` ago.
+	pub(crate) fn set_dedup_window(&mut self, window: Duration) {
+		self.dedup = Some(Dedup::new(window));
+	}
+
+	/// Set the verbosity of the messages by prefix of their name.
+	pub(crate) fn set_target_verbosity(&mut self, target_verbosity: BTreeMap<String, u8>) {
+		self.target_verbosity = target_verbosity;
+	}
+
+	/// Return `true` if some messages have another verbosity than the one of the endpoint.
+	pub(crate) fn has_target_verbosity(&self) -> bool {
+		!self.target_verbosity.is_empty()
+	}
+
+	/// Return the verbosity of the messages named `name`, if it is not the one of the endpoint.
+	///
+	/// The longest target that `name` starts with applies.
+	pub(crate) fn target_verbosity(&self, name: &str) -> Option<u8> {
+		self.target_verbosity
+			.iter()
+			.filter(|(target, _)| name.starts_with(target.as_str()))
+			.max_by_key(|(target, _)| target.len())
+			.map(|(_, verbosity)| *verbosity)
+	}
+
+	/// Return when the node has lost its last connection or failed to connect for the first time,
+	/// or `None` if it is connected or has not failed to connect yet.
+	pub(crate) fn disconnected_since(&self) -> Option<Instant> {
+		self.disconnected_since
+	}
+
+	/// Set the failover group of the node and its priority in the group.
+	pub(crate) fn set_failover_group(&mut self, group: &str, priority: u8) {
+		self.failover_group = Some((group.into(), priority));
+	}
+
+	/// Return the failover group of the node and its priority in the group, if any.
+	pub(crate) fn failover_group(&self) -> Option<(&str, u8)> {
+		self.failover_group
+			.as_ref()
+			.map(|(group, priority)| (group.as_str(), *priority))
+	}
+
+	/// Put the node in standby, or make it the one that receives the telemetry of its failover
+	/// group.
+	///
+	/// The connection notifiers of a node in standby are not notified when it connects. They are
+	/// notified when it leaves the standby if it is connected.
+	pub(crate) fn set_standby(&mut self, standby: bool) {
+		let activated = self.standby && !standby;
+		self.standby = standby;
+		if activated && self.connected_since().is_some() {
+			self.notify_connected();
+		}
+	}
+
+	fn notify_connected(&self) {
+		for sender in &self.telemetry_connection_notifier {
+			let _ = sender.unbounded_send(());
+		}
+	}
+
+	/// Report a lost connection.
+	fn disconnected(&mut self) {
+		self.disconnected_since.get_or_insert_with(Instant::now);
+		let addr = self.addr.clone();
+		self.events.send(TelemetryEvent::Disconnected(addr));
+	}
+
+	/// Report a failed connection attempt.
+	fn connection_failed(&mut self, error: &dyn fmt::Display) {
+		self.disconnected_since.get_or_insert_with(Instant::now);
+		let event = TelemetryEvent::ConnectionFailed {
+			addr: self.addr.clone(),
+			error: error.to_string(),
+		};
+		self.events.send(event);
+	}
+
+	/// Account for `count` messages discarded because the node was not connected.
+	fn discarded(&mut self, count: u64) {
+		self.delivery.discarded += count;
+		self.events
+			.stats()
+			.messages_dropped_disconnected
+			.fetch_add(count, Ordering::Relaxed);
+	}
+
+	/// Account for `dropped` messages that could not be queued.
+	fn record_dropped(&mut self, dropped: usize) {
+		if dropped == 0 {
+			return;
+		}
+
+		self.dropped += dropped as u64;
+		self.events
+			.stats()
+			.messages_dropped_queue
+			.fetch_add(dropped as u64, Ordering::Relaxed);
+		log::warn!(
+			target: "telemetry",
+			"Telemetry queue is full: dropped {} message(s) for {}",
+			dropped,
+			self.url,
+		);
+		let event = TelemetryEvent::Dropped {
+			addr: self.addr.clone(),
+			total: self.dropped,
+		};
+		self.events.send(event);
+	}
+
+	/// Account for the messages of the node that have been evicted from the [`BufferBudget`] to
+	/// make room for the messages of the other nodes.
+	fn record_evicted(&mut self) {
+		if let NodeSocket::Connected(conn) = &mut self.socket {
+			let dropped = conn.take_evicted();
+			self.record_dropped(dropped);
+		}
+		let discarded = self.held.take_evicted();
+		if discarded > 0 {
+			self.discarded(discarded as u64);
+		}
+	}
+
+	/// Spill a message that can't be delivered, writing the spill to disk if it holds enough
+	/// messages.
+	fn spill_message(&mut self, message: Arc<str>) {
+		if let Some(spill) = &mut self.spill {
+			if spill.push(message) {
+				self.persist_spill();
+			}
+		}
+	}
+
+	/// Write the spilled messages to disk.
+	fn persist_spill(&mut self) {
+		let spill = match &mut self.spill {
+			Some(spill) => spill,
+			None => return,
+		};
+
+		let pending = spill.pending();
+		if let Err(err) = spill.persist() {
+			log::warn!(
+				target: "telemetry",
+				"Could not spill {} telemetry message(s) for {}: {}",
+				pending,
+				self.url,
+				err,
+			);
+			self.discarded(pending as u64);
+		}
+	}
+
+	/// Spill the messages that have not been sent on the connection `conn`.
+	fn spill_unsent(&mut self, conn: &mut NodeSocketConnected<TTrans>) {
+		if self.spill.is_none() {
+			return;
+		}
+
+		while let Some(frame) = conn.queue.pop() {
+			// A batch holds one message per line.
+			for message in frame.split('\n') {
+				self.spill_message(message.into());
+			}
+		}
+		while let Some(message) = conn.batch.pop() {
+			self.spill_message(message);
+		}
+		self.persist_spill();
+	}
+
+	/// Queue the messages held while the node was not connected on the newly established
+	/// connection `conn`.
+	fn send_held(&mut self, conn: &mut NodeSocketConnected<TTrans>) {
+		if self.held.len() > 0 {
+			log::debug!(
+				target: "telemetry",
+				"Sending {} message(s) held while {} was disconnected",
+				self.held.len(),
+				self.url,
+			);
+		}
+		let mut dropped = 0;
+		while let Some((queued_at, message)) = self.held.pop() {
+			dropped += conn.enqueue(message, queued_at, self.queue_capacity, self.queue_policy);
+		}
+		self.record_dropped(dropped);
+	}
+
+	/// Queue the spilled messages on the newly established connection `conn`.
+	fn replay_spill(&mut self, conn: &mut NodeSocketConnected<TTrans>) {
+		let messages = match self.spill.as_mut().map(Spill::take) {
+			Some(Ok(messages)) => messages,
+			Some(Err(err)) => {
+				log::warn!(
+					target: "telemetry",
+					"Could not read the spilled telemetry of {}: {}",
+					self.url,
+					err,
+				);
+				return;
+			}
+			None => return,
+		};
+
+		if !messages.is_empty() {
+			log::debug!(
+				target: "telemetry",
+				"Replaying {} spilled message(s) to {}",
+				messages.len(),
+				self.url,
+			);
+		}
+		// The spilled messages have their own maximum age.
+		let mut dropped = 0;
+		for message in messages {
+			let now = Instant::now();
+			dropped += conn.enqueue(message, now, self.queue_capacity, self.queue_policy);
+		}
+		self.record_dropped(dropped);
+	}
+
+	/// Give up on sending what the node holds, after [`Node::poll_shutdown`] took too long. The
+	/// messages that have not been sent are spilled, if enabled. The node must not be used
+	/// afterwards.
+	pub(crate) fn abort_shutdown(&mut self) {
+		let socket = mem::replace(&mut self.socket, NodeSocket::Disabled);
+		if let NodeSocket::Connected(mut conn) = socket {
+			self.spill_unsent(&mut conn);
+		}
+		self.persist_spill();
+	}
+
+	/// Serialize the connection messages in a new buffer, ready to be sent on a newly established
+	/// connection.
+	fn connection_messages_buffer(&mut self) -> BudgetedQueue {
+		let mut buf = BudgetedQueue::new(self.budget.clone());
+		for json in self.announcing_connection_messages() {
+			self.push_connection_message(&mut buf, json);
+		}
+		buf
+	}
+
+	/// The connection messages that are sent, i.e. all of them but those that announce the same
+	/// node as an earlier one: the telemetry servers would show the node twice.
+	fn announcing_connection_messages(&self) -> Vec<serde_json::Map<String, serde_json::Value>> {
+		(0..self.connection_messages.len())
+			.filter(|index| !is_duplicate(&self.connection_messages, *index))
+			.map(|index| self.connection_messages[index].clone())
+			.collect()
+	}
+
+	/// Add a message sent when the connection (re-)establishes. If the node is already connected,
+	/// the message is also sent right away.
+	///
+	/// A message that announces the same node as an earlier one is only sent once the telemetry
+	/// span of the earlier one is closed.
+	pub(crate) fn add_connection_message(
+		&mut self,
+		json: serde_json::Map<String, serde_json::Value>,
+	) {
+		self.connection_messages.push(json.clone());
+		if is_duplicate(&self.connection_messages, self.connection_messages.len() - 1) {
+			log::warn!(
+				target: "telemetry",
+				"Several telemetry spans announce the same node to {}: only the connection \
+				message of the first one is sent, the telemetry of span {} may be ignored by \
+				the telemetry server",
+				self.url,
+				json.get("id").unwrap_or(&serde_json::Value::Null),
+			);
+			return;
+		}
+		self.send_connection_messages(vec![json]);
+	}
+
+	/// Remove the connection messages of the telemetry span `id`.
+	///
+	/// The messages that announced the same node as the removed ones are sent in their place.
+	pub(crate) fn remove_connection_messages(&mut self, id: &Id) {
+		let before = self.announcing_connection_messages();
+		let id = serde_json::Value::from(id.into_u64());
+		self.connection_messages
+			.retain(|message| message.get("id") != Some(&id));
+		let announcing = self
+			.announcing_connection_messages()
+			.into_iter()
+			.filter(|message| !before.contains(message))
+			.collect();
+		self.send_connection_messages(announcing);
+	}
+
+	/// Send `messages` right away if the node is connected.
+	fn send_connection_messages(
+		&mut self,
+		messages: Vec<serde_json::Map<String, serde_json::Value>>,
+	) {
+		let mut socket = mem::replace(&mut self.socket, NodeSocket::Poisoned);
+		if let NodeSocket::Connected(conn) = &mut socket {
+			for json in messages {
+				self.push_connection_message(&mut conn.buf, json);
+			}
+		}
+		self.socket = socket;
+	}
+
+	/// Timestamp a connection message and push it to `buf`.
+	fn push_connection_message(
+		&mut self,
+		buf: &mut BudgetedQueue,
+		mut json: serde_json::Map<String, serde_json::Value>,
+	) {
+		json.insert("ts".to_string(), chrono::Local::now().to_rfc3339().into());
+
+		match serde_json::to_string(&json) {
+			Ok(message) => {
+				let dropped = buf.push(message.into());
+				if dropped > 0 {
+					log::warn!(
+						target: "telemetry",
+						"Telemetry buffers are full: dropped {} connection message(s) for {}",
+						dropped,
+						self.url,
+					);
+				}
+			}
+			Err(err) => {
+				log::error!(
+					target: "telemetry",
+					"An error occurred while generating new connection messages: {}",
+					err,
+				);
+				self.events.send(TelemetryEvent::SerializationError(err.to_string()));
+			}
 		}
 	}
 }
@@ -107,23 +832,213 @@ where
 	// NOTE: this code has been inspired from `Buffer` (`futures_util::sink::Buffer`).
 	//       https://docs.rs/futures-util/0.3.8/src/futures_util/sink/buffer.rs.html#32
 	fn try_send_connection_messages(
-		self: Pin<&mut Self>,
+		mut self: Pin<&mut Self>,
 		cx: &mut Context<'_>,
 		conn: &mut NodeSocketConnected<TTrans>,
 	) -> Poll<Result<(), TSinkErr>> {
 		while let Some(item) = conn.buf.pop() {
-			if let Err(e) = conn.sink.start_send_unpin(item) {
+			if let Err(e) = conn.sink.start_send_unpin(item.as_bytes().to_vec()) {
 				return Poll::Ready(Err(e));
 			}
+			self.events.stats().messages_sent.fetch_add(1, Ordering::Relaxed);
+			self.delivery.sent(1);
+			conn.written();
 			futures::ready!(conn.sink.poll_ready_unpin(cx))?;
 		}
 		Poll::Ready(Ok(()))
 	}
+
+	/// Send the queued frames to the socket, updating `delivery`. The frames older than
+	/// `max_age` are discarded instead.
+	fn poll_send_queue(
+		conn: &mut NodeSocketConnected<TTrans>,
+		events: &EventSender,
+		delivery: &mut Delivery,
+		max_age: Option<Duration>,
+		cx: &mut Context<'_>,
+	) -> Poll<Result<(), TSinkErr>> {
+		loop {
+			let expired = max_age.map_or(0, |max_age| conn.drop_expired(max_age));
+			if expired > 0 {
+				delivery.discarded += expired as u64;
+				events
+					.stats()
+					.messages_dropped_expired
+					.fetch_add(expired as u64, Ordering::Relaxed);
+				log::debug!(
+					target: "telemetry",
+					"Dropped {} telemetry message(s) queued for too long",
+					expired,
+				);
+			}
+			if conn.queue.is_empty() {
+				break;
+			}
+
+			futures::ready!(conn.sink.poll_ready_unpin(cx))?;
+			let frame = conn.queue.pop().expect("the queue is not empty; qed");
+			// A batch holds one message per line.
+			let messages = 1 + frame.matches('\n').count();
+			conn.sink.start_send_unpin(frame.as_bytes().to_vec())?;
+			events
+				.stats()
+				.messages_sent
+				.fetch_add(messages as u64, Ordering::Relaxed);
+			delivery.sent(messages as u64);
+			conn.written();
+		}
+		Poll::Ready(Ok(()))
+	}
+
+	/// Send a ping on the connection `conn` if nothing has been written to it for a whole
+	/// keepalive interval.
+	///
+	/// Returns `false` if the connection looks dead: it has not accepted or flushed anything
+	/// during the whole interval.
+	fn poll_keepalive(
+		conn: &mut NodeSocketConnected<TTrans>,
+		cx: &mut Context<'_>,
+	) -> Result<bool, TSinkErr> {
+		let expired = match &mut conn.keepalive {
+			Some((_, keepalive)) => Future::poll(Pin::new(keepalive), cx).is_ready(),
+			None => false,
+		};
+		if !expired {
+			return Ok(true);
+		}
+
+		let stalled = conn.unflushed || !conn.buf.is_empty() || !conn.queue.is_empty();
+		if stalled || conn.sink.poll_ready_unpin(cx)?.is_pending() {
+			return Ok(false);
+		}
+		conn.sink.start_send_unpin(PING.to_vec())?;
+		conn.written();
+		Ok(true)
+	}
+
+	/// Send the connection messages and the queued frames, and close the connection.
+	fn poll_close_connection(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		conn: &mut NodeSocketConnected<TTrans>,
+	) -> Poll<Result<(), TSinkErr>> {
+		futures::ready!(conn.sink.poll_ready_unpin(cx))?;
+		futures::ready!(self.as_mut().try_send_connection_messages(cx, conn))?;
+		let this = &mut *self;
+		futures::ready!(Self::poll_send_queue(
+			conn,
+			&this.events,
+			&mut this.delivery,
+			this.max_message_age,
+			cx,
+		))?;
+		conn.sink.poll_close_unpin(cx)
+	}
+
+	/// Send everything the node holds, including the current batch, and close the connection.
+	///
+	/// A connection that is being established is awaited first so that the connection messages
+	/// reach the telemetry server. A node that is waiting to reconnect or that is disabled has
+	/// nothing to send, apart from its spilled messages that are written to disk. The node must
+	/// not be used afterwards.
+	pub(crate) fn poll_shutdown(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+		loop {
+			match self.socket {
+				NodeSocket::ReconnectNow | NodeSocket::Dialing(..) => {
+					let _ = self.poll_ready_unpin(cx);
+					match self.socket {
+						NodeSocket::Connected(_) => {}
+						NodeSocket::Dialing(..) => return Poll::Pending,
+						_ => {
+							self.persist_spill();
+							return Poll::Ready(());
+						}
+					}
+				}
+				NodeSocket::Connected(_) => break,
+				_ => {
+					self.persist_spill();
+					return Poll::Ready(());
+				}
+			}
+		}
+
+		let mut conn = match mem::replace(&mut self.socket, NodeSocket::Poisoned) {
+			NodeSocket::Connected(conn) => conn,
+			_ => unreachable!("the node is connected; qed"),
+		};
+		if !conn.batch.is_empty() {
+			let (frame, queued_at) = conn.take_batch();
+			let dropped = conn.enqueue(frame, queued_at, self.queue_capacity, self.queue_policy);
+			self.record_dropped(dropped);
+		}
+
+		let result = Pin::new(&mut *self).poll_close_connection(cx, &mut conn);
+		match result {
+			Poll::Pending => {
+				self.socket = NodeSocket::Connected(conn);
+				return Poll::Pending;
+			}
+			Poll::Ready(Ok(())) => {
+				log::debug!(target: "telemetry", "Closed the connection to {}", self.url);
+			}
+			Poll::Ready(Err(err)) => {
+				log::warn!(
+					target: "telemetry",
+					"⚠️  Disconnected from {} while shutting down: {:?}",
+					self.url,
+					err,
+				);
+				self.spill_unsent(&mut conn);
+			}
+		}
+		self.socket = NodeSocket::Disabled;
+		Poll::Ready(())
+	}
+
+	/// Send everything the node holds, including the current batch, and flush the connection.
+	///
+	/// Unlike [`Node::poll_shutdown`], the connections being established are not awaited, as they
+	/// have nothing to send yet.
+	pub(crate) fn poll_flush_all(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+		if let NodeSocket::Connected(conn) = &mut self.socket {
+			if !conn.batch.is_empty() {
+				let (frame, queued_at) = conn.take_batch();
+				let dropped =
+					conn.enqueue(frame, queued_at, self.queue_capacity, self.queue_policy);
+				self.record_dropped(dropped);
+			}
+		}
+
+		// Sends the connection messages, then the queue.
+		let _ = self.poll_ready_unpin(cx);
+		let _ = futures::ready!(self.poll_flush_unpin(cx));
+		if self.pending_messages() == 0 {
+			Poll::Ready(())
+		} else {
+			Poll::Pending
+		}
+	}
+
+	/// Close the connection like [`Node::poll_shutdown`], then connect again right away. This has
+	/// no effect on a disabled node.
+	pub(crate) fn poll_reopen(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+		if !self.is_enabled() {
+			return Poll::Ready(());
+		}
+		let connected = self.connected_since().is_some();
+		futures::ready!(self.poll_shutdown(cx));
+		if connected {
+			self.disconnected();
+		}
+		self.socket = NodeSocket::ReconnectNow;
+		Poll::Ready(())
+	}
 }
 
 pub(crate) enum Infallible {}
 
-impl<TTrans: Transport, TSinkErr> Sink<String> for Node<TTrans>
+impl<TTrans: Transport, TSinkErr> Sink<TelemetryMessage> for Node<TTrans>
 where
 	TTrans: Clone + Unpin,
 	TTrans::Dial: Unpin,
@@ -137,92 +1052,126 @@ where
 		let mut socket = mem::replace(&mut self.socket, NodeSocket::Poisoned);
 		self.socket = loop {
 			match socket {
-				NodeSocket::Connected(mut conn) => match conn.sink.poll_ready_unpin(cx) {
-					Poll::Ready(Ok(())) => {
-						match self.as_mut().try_send_connection_messages(cx, &mut conn) {
-							Poll::Ready(Err(err)) => {
-								log::warn!(target: "telemetry", "⚠️  Disconnected from {}: {:?}", self.addr, err);
-								socket = NodeSocket::wait_reconnect();
-							}
-							Poll::Ready(Ok(())) => {
-								self.socket = NodeSocket::Connected(conn);
-								return Poll::Ready(Ok(()));
+				NodeSocket::Connected(mut conn) => {
+					let result = match conn.sink.poll_ready_unpin(cx) {
+						Poll::Ready(Ok(())) => {
+							match self.as_mut().try_send_connection_messages(cx, &mut conn) {
+								Poll::Ready(Ok(())) => {
+									let this = &mut *self;
+									Self::poll_send_queue(
+										&mut conn,
+										&this.events,
+										&mut this.delivery,
+										this.max_message_age,
+										cx,
+									)
+								}
+								other => other,
 							}
-							Poll::Pending => {
-								self.socket = NodeSocket::Connected(conn);
+						}
+						other => other,
+					};
+
+					match result {
+						Poll::Ready(Err(err)) => {
+							log::warn!(target: "telemetry", "⚠️  Disconnected from {}: {:?}", self.url, err);
+							self.disconnected();
+							self.spill_unsent(&mut conn);
+							socket = self.wait_reconnect();
+						}
+						Poll::Ready(Ok(())) => {
+							self.socket = NodeSocket::Connected(conn);
+							return Poll::Ready(Ok(()));
+						}
+						Poll::Pending => {
+							// The new messages are queued until the socket accepts them, unless the
+							// queue is full and the policy is to wait.
+							let full = conn.queue.len() >= self.queue_capacity.max(1);
+							self.socket = NodeSocket::Connected(conn);
+							if full && self.queue_policy == QueuePolicy::Block {
 								return Poll::Pending;
 							}
+							return Poll::Ready(Ok(()));
 						}
 					}
-					Poll::Ready(Err(err)) => {
-						log::warn!(target: "telemetry", "⚠️  Disconnected from {}: {:?}", self.addr, err);
-						socket = NodeSocket::wait_reconnect();
-					}
-					Poll::Pending => {
-						self.socket = NodeSocket::Connected(conn);
-						return Poll::Pending;
-					}
-				},
-				NodeSocket::Dialing(mut s) => match Future::poll(Pin::new(&mut s), cx) {
+				}
+				NodeSocket::Dialing(mut s, mut timeout) => match Future::poll(Pin::new(&mut s), cx) {
 					Poll::Ready(Ok(sink)) => {
-						log::debug!(target: "telemetry", "✅ Connected to {}", self.addr);
+						log::debug!(target: "telemetry", "✅ Connected to {}", self.url);
+						let addr = self.addr.clone();
+						self.events.send(TelemetryEvent::Connected(addr));
+						self.disconnected_since = None;
+						self.last_connected = Some(Instant::now());
+						self.connections += 1;
+						self.failed_attempts = 0;
 
-						for sender in self.telemetry_connection_notifier.iter_mut() {
-							let _ = sender.send(());
+						if !self.standby {
+							self.notify_connected();
 						}
 
-						let buf = self
-							.connection_messages
-							.iter()
-							.map(|json| {
-								let mut json = json.clone();
-								json.insert(
-									"ts".to_string(),
-									chrono::Local::now().to_rfc3339().into(),
-								);
-								json
-							})
-							.filter_map(|json| match serde_json::to_vec(&json) {
-								Ok(message) => Some(message),
-								Err(err) => {
-									log::error!(
-										target: "telemetry",
-										"An error occurred while generating new connection \
-										messages: {}",
-										err,
-									);
-									None
-								}
-							})
-							.collect();
-
-						socket = NodeSocket::Connected(NodeSocketConnected { sink, buf });
+						let buf = self.connection_messages_buffer();
+						let mut conn = Box::new(NodeSocketConnected {
+							sink,
+							buf,
+							connected_since: Instant::now(),
+							queue: BudgetedQueue::new(self.budget.clone()),
+							batch: BudgetedQueue::new(self.budget.clone()),
+							batch_started: None,
+							// Files and logs don't need to be kept alive.
+							keepalive: self
+								.keepalive_interval
+								.filter(|_| self.kind == EndpointKind::Server)
+								.map(|interval| (interval, Delay::new(interval))),
+							unflushed: false,
+							_connected: ConnectedEndpoint::new(self.events.stats().clone()),
+						});
+						self.replay_spill(&mut conn);
+						self.send_held(&mut conn);
+						socket = NodeSocket::Connected(conn);
+					}
+					Poll::Pending => {
+						if Future::poll(Pin::new(&mut timeout), cx).is_pending() {
+							break NodeSocket::Dialing(s, timeout);
+						}
+						log::warn!(
+							target: "telemetry",
+							"❌ Timeout while dialing {} after {:?}",
+							self.url,
+							self.connect_timeout,
+						);
+						let error = format!("connection timed out after {:?}", self.connect_timeout);
+						self.connection_failed(&error);
+						socket = self.wait_reconnect();
 					}
-					Poll::Pending => break NodeSocket::Dialing(s),
 					Poll::Ready(Err(err)) => {
-						log::warn!(target: "telemetry", "❌ Error while dialing {}: {:?}", self.addr, err);
-						socket = NodeSocket::wait_reconnect();
+						log::warn!(target: "telemetry", "❌ Error while dialing {}: {:?}", self.url, err);
+						self.connection_failed(&err);
+						socket = self.wait_reconnect();
 					}
 				},
 				NodeSocket::ReconnectNow => match self.transport.clone().dial(self.addr.clone()) {
 					Ok(d) => {
-						log::debug!(target: "telemetry", "Started dialing {}", self.addr);
-						socket = NodeSocket::Dialing(d);
+						log::debug!(target: "telemetry", "Started dialing {}", self.url);
+						let timeout = Instant::now() + self.connect_timeout;
+						let timeout = Delay::new_handle(timeout, self.timer.clone());
+						socket = NodeSocket::Dialing(d, timeout);
 					}
 					Err(err) => {
-						log::warn!(target: "telemetry", "❌ Error while dialing {}: {:?}", self.addr, err);
-						socket = NodeSocket::wait_reconnect();
+						log::warn!(target: "telemetry", "❌ Error while dialing {}: {:?}", self.url, err);
+						self.connection_failed(&err);
+						socket = self.wait_reconnect();
 					}
 				},
-				NodeSocket::WaitingReconnect(mut s) => {
+				NodeSocket::WaitingReconnect(mut s, until) => {
 					if let Poll::Ready(_) = Future::poll(Pin::new(&mut s), cx) {
 						socket = NodeSocket::ReconnectNow;
 					} else {
-						break NodeSocket::WaitingReconnect(s);
+						break NodeSocket::WaitingReconnect(s, until);
 					}
 				}
+				NodeSocket::Disabled => break NodeSocket::Disabled,
 				NodeSocket::Poisoned => {
-					log::error!(target: "telemetry", "‼️ Poisoned connection with {}", self.addr);
+					log::error!(target: "telemetry", "‼️ Poisoned connection with {}", self.url);
 					break NodeSocket::Poisoned;
 				}
 			}
@@ -234,33 +1183,145 @@ where
 		Poll::Ready(Ok(()))
 	}
 
-	fn start_send(mut self: Pin<&mut Self>, item: String) -> Result<(), Self::Error> {
-		match &mut self.socket {
+	fn start_send(mut self: Pin<&mut Self>, item: TelemetryMessage) -> Result<(), Self::Error> {
+		let TelemetryMessage {
+			payload: item,
+			enqueued_at,
+			verbosity,
+			..
+		} = item;
+		let this = &mut *self;
+		this.record_evicted();
+		let item = match &mut this.dedup {
+			Some(dedup) => match dedup.filter(item, Instant::now()) {
+				Some(item) => item,
+				None => {
+					log::trace!(
+						target: "telemetry",
+						"Suppressed a repeated message for {}",
+						this.url,
+					);
+					return Ok(());
+				}
+			},
+			None => item,
+		};
+		// The messages of a disabled endpoint are discarded even if the spill is enabled.
+		let spill = this.spill.is_some() && this.is_enabled();
+		let dropped = match &mut this.socket {
+			NodeSocket::Connected(conn) if this.batch_config.is_some() => {
+				conn.batch_started.get_or_insert_with(Instant::now);
+				conn.batch.push_queued_at(item, enqueued_at)
+			}
 			NodeSocket::Connected(conn) => {
-				let _ = conn.sink.start_send_unpin(item.into()).expect("boo");
+				conn.enqueue(item, enqueued_at, this.queue_capacity, this.queue_policy)
 			}
-			_socket => {
+			_ if spill => {
+				this.spill_message(item);
+				0
+			}
+			NodeSocket::Disabled => {
 				log::trace!(
 					target: "telemetry",
 					"Message has been discarded: {}",
 					item,
 				);
+				this.discarded(1);
+				0
 			}
-		}
+			_socket => {
+				let discarded = this.held.push(verbosity, item, enqueued_at);
+				if discarded > 0 {
+					log::trace!(
+						target: "telemetry",
+						"Discarded {} message(s) held for {}",
+						discarded,
+						this.url,
+					);
+					this.discarded(discarded as u64);
+				}
+				0
+			}
+		};
+		this.record_dropped(dropped);
 		Ok(())
 	}
 
 	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-		match &mut self.socket {
-			NodeSocket::Connected(conn) => match conn.sink.poll_flush_unpin(cx) {
-				Poll::Ready(Err(_)) => {
-					self.socket = NodeSocket::wait_reconnect();
-					Poll::Ready(Ok(()))
+		let this = &mut *self;
+		this.record_evicted();
+		let mut dropped = 0;
+		let mut alive = true;
+		let result = match &mut this.socket {
+			NodeSocket::Connected(conn) => {
+				if let Some(batch_config) = this.batch_config {
+					if conn.batch_is_due(batch_config) {
+						let (frame, queued_at) = conn.take_batch();
+						dropped =
+							conn.enqueue(frame, queued_at, this.queue_capacity, this.queue_policy);
+					}
 				}
-				Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
-				Poll::Pending => Poll::Pending,
-			},
+
+				match Self::poll_keepalive(conn, cx) {
+					Ok(true) => {
+						let sent = Self::poll_send_queue(
+							conn,
+							&this.events,
+							&mut this.delivery,
+							this.max_message_age,
+							cx,
+						);
+						match sent {
+							Poll::Ready(Ok(())) => {
+								let flushed = conn.sink.poll_flush_unpin(cx);
+								if let Poll::Ready(Ok(())) = flushed {
+									conn.unflushed = false;
+								}
+								flushed
+							}
+							other => other,
+						}
+					}
+					Ok(false) => {
+						alive = false;
+						Poll::Ready(Ok(()))
+					}
+					Err(err) => Poll::Ready(Err(err)),
+				}
+			}
 			_ => Poll::Ready(Ok(())),
+		};
+		this.record_dropped(dropped);
+
+		if !alive {
+			log::warn!(
+				target: "telemetry",
+				"⚠️  Connection to {} is stalled, reconnecting",
+				self.url,
+			);
+			// The server is probably reachable, only the connection has been dropped along the
+			// way.
+			let socket = mem::replace(&mut self.socket, NodeSocket::ReconnectNow);
+			if let NodeSocket::Connected(mut conn) = socket {
+				self.spill_unsent(&mut conn);
+			}
+			self.disconnected();
+			return Poll::Ready(Ok(()));
+		}
+
+		match result {
+			Poll::Ready(Err(err)) => {
+				log::warn!(target: "telemetry", "⚠️  Disconnected from {}: {:?}", self.url, err);
+				let waiting = self.wait_reconnect();
+				let socket = mem::replace(&mut self.socket, waiting);
+				if let NodeSocket::Connected(mut conn) = socket {
+					self.spill_unsent(&mut conn);
+				}
+				self.disconnected();
+				Poll::Ready(Ok(()))
+			}
+			Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
+			Poll::Pending => Poll::Pending,
 		}
 	}
 
@@ -272,15 +1333,147 @@ where
 	}
 }
 
+/// Whether the connection message at `index` announces the same node as an earlier one, with the
+/// same name on the same network.
+fn is_duplicate(messages: &[serde_json::Map<String, serde_json::Value>], index: usize) -> bool {
+	let identity = |message: &serde_json::Map<String, serde_json::Value>| {
+		let payload = message.get("payload")?;
+		Some((payload.get("name")?.clone(), payload.get("network_id")?.clone()))
+	};
+
+	match identity(&messages[index]) {
+		Some(node) => messages[..index].iter().any(|other| identity(other).as_ref() == Some(&node)),
+		None => false,
+	}
+}
+
 impl<TTrans: Transport> fmt::Debug for NodeSocket<TTrans> {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		use NodeSocket::*;
 		f.write_str(match self {
 			Connected(_) => "Connected",
-			Dialing(_) => "Dialing",
+			Dialing(..) => "Dialing",
 			ReconnectNow => "ReconnectNow",
-			WaitingReconnect(_) => "WaitingReconnect",
+			WaitingReconnect(..) => "WaitingReconnect",
+			Disabled => "Disabled",
 			Poisoned => "Poisoned",
 		})
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{boxed_transport, event_channel};
+	use libp2p::core::transport::MemoryTransport;
+	use wasm_timer::Timer;
+
+	#[test]
+	fn repeated_messages_are_suppressed_within_the_window() {
+		let mut dedup = Dedup::new(Duration::from_secs(10));
+		let start = Instant::now();
+		let at = |secs| start + Duration::from_secs(secs);
+		let mut filter = |message: &str, secs| {
+			dedup
+				.filter(message.into(), at(secs))
+				.map(|message| serde_json::from_str::<serde_json::Value>(&message).unwrap())
+		};
+		let peers = |count: u64, ts: u64| {
+			format!(r#"{{"id":1,"payload":{{"msg":"peers","count":{}}},"ts":{}}}"#, count, ts)
+		};
+
+		assert!(filter(&peers(0, 0), 0).is_some());
+		// Only the timestamp differs.
+		assert!(filter(&peers(0, 1), 1).is_none());
+		assert!(filter(&peers(0, 2), 2).is_none());
+		assert_eq!(
+			filter(&peers(1, 3), 3),
+			Some(serde_json::json!({
+				"id": 1,
+				"payload": { "msg": "peers", "count": 1 },
+				"ts": 3,
+				"suppressed": 2,
+			})),
+		);
+		// The window starts at the last message that has been sent.
+		assert!(filter(&peers(1, 12), 12).is_none());
+		assert_eq!(filter(&peers(1, 13), 13).unwrap()["suppressed"], 1);
+		assert!(filter(&peers(1, 14), 14).is_none());
+
+		// A different message in between is not suppressed and resets the comparison.
+		assert_eq!(filter(&peers(0, 15), 15).unwrap()["suppressed"], 1);
+		assert!(filter(&peers(1, 16), 16).unwrap().get("suppressed").is_none());
+		// Only JSON objects are compared.
+		assert!(filter("[1]", 17).is_some());
+		assert!(filter("[1]", 17).is_some());
+	}
+
+	#[test]
+	fn reconnection_delay_doubles_up_to_the_maximum() {
+		let max = Duration::from_secs(60);
+		let delays = (0..8)
+			.map(|attempts| reconnect_delay(attempts, max, 0.0).as_secs())
+			.collect::<Vec<_>>();
+		assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+		assert_eq!(reconnect_delay(u32::MAX, max, 0.0), max);
+
+		assert_eq!(reconnect_delay(2, max, 0.5), Duration::from_secs(6));
+		assert_eq!(reconnect_delay(2, max, -0.5), Duration::from_secs(2));
+		// The jitter doesn't go above the maximum.
+		assert_eq!(reconnect_delay(10, max, 0.5), max);
+		assert_eq!(reconnect_delay(10, max, -0.5), Duration::from_secs(30));
+	}
+
+	#[test]
+	fn failed_connection_attempts_back_off_until_a_connection_is_established() {
+		let addr: Multiaddr = "/memory/10218".parse().unwrap();
+		let config = TelemetryConfig {
+			max_reconnect_delay: Duration::from_secs(10),
+			..Default::default()
+		};
+		let mut node = Node::new(
+			boxed_transport(MemoryTransport),
+			addr.clone(),
+			Vec::new(),
+			Vec::new(),
+			BufferBudget::new(usize::MAX, Default::default()),
+			event_channel().0,
+			&config,
+		);
+		let mut timer = Timer::new();
+		node.timer = timer.handle();
+		let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+		// Nothing listens on the address: every attempt fails right away.
+		for (attempt, expected) in [1, 2, 4, 8, 10, 10].iter().enumerate() {
+			let before = Instant::now();
+			assert!(node.poll_ready_unpin(&mut cx).is_ready());
+			let after = Instant::now();
+			let status = node.status();
+			assert_eq!(status.failed_attempts, attempt as u32 + 1);
+			let until = match status.state {
+				ConnectionState::Retrying { until } => until,
+				state => panic!("unexpected state {:?}", state),
+			};
+			// The delay starts in between.
+			let (longest, shortest) = (until - before, until - after);
+			let expected = Duration::from_secs(*expected);
+			assert!(longest >= expected.mul_f64(1.0 - RECONNECT_JITTER), "{:?}", longest);
+			assert!(shortest <= expected.mul_f64(1.0 + RECONNECT_JITTER), "{:?}", shortest);
+			assert!(shortest <= config.max_reconnect_delay, "{:?}", shortest);
+
+			// No new attempt until the delay expires.
+			assert!(timer.poll_unpin(&mut cx).is_pending());
+			assert_eq!(timer.next_event(), Some(until));
+			assert!(node.poll_ready_unpin(&mut cx).is_ready());
+			assert_eq!(node.status().failed_attempts, attempt as u32 + 1);
+			timer.advance_to(until);
+		}
+
+		let _listener = MemoryTransport.listen_on(addr).unwrap();
+		assert!(node.poll_ready_unpin(&mut cx).is_ready());
+		let status = node.status();
+		assert!(status.connected);
+		assert_eq!(status.failed_attempts, 0);
+	}
+}
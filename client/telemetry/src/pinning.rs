@@ -0,0 +1,379 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+#[cfg(not(target_os = "unknown"))]
+use futures::{future::BoxFuture, prelude::*, stream::BoxStream};
+#[cfg(not(target_os = "unknown"))]
+use futures_rustls::{client::TlsStream, rustls, webpki, TlsConnector};
+#[cfg(not(target_os = "unknown"))]
+use libp2p::{
+	core::{
+		either::EitherOutput,
+		transport::{ListenerEvent, TransportError},
+	},
+	multiaddr::Protocol,
+	Multiaddr, Transport,
+};
+use sha2::{Digest, Sha256};
+#[cfg(not(target_os = "unknown"))]
+use std::{collections::HashMap, io, sync::Arc};
+use std::{error, fmt, str::FromStr};
+
+/// Server name used for the TLS handshake with the servers that don't have a DNS name. It is not
+/// sent to the server.
+#[cfg(not(target_os = "unknown"))]
+const NO_SERVER_NAME: &str = "ip-address.invalid";
+
+/// SHA-256 fingerprint of the DER-encoded certificate of a telemetry server.
+///
+/// It can be parsed from 64 hexadecimal digits, optionally separated by colons, as printed by
+/// `openssl x509 -noout -fingerprint -sha256`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CertificatePin([u8; 32]);
+
+impl CertificatePin {
+	/// Fingerprint of a DER-encoded certificate.
+	pub fn from_der(certificate: &[u8]) -> Self {
+		CertificatePin(Sha256::digest(certificate).into())
+	}
+}
+
+impl fmt::Display for CertificatePin {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (i, byte) in self.0.iter().enumerate() {
+			if i > 0 {
+				write!(f, ":")?;
+			}
+			write!(f, "{:02X}", byte)?;
+		}
+		Ok(())
+	}
+}
+
+/// Error while parsing a [`CertificatePin`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinParseError(String);
+
+impl fmt::Display for PinParseError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"Invalid certificate pin {:?}, expected a SHA-256 fingerprint in hexadecimal",
+			self.0,
+		)
+	}
+}
+
+impl error::Error for PinParseError {}
+
+impl FromStr for CertificatePin {
+	type Err = PinParseError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let digits = s.trim().bytes().filter(|b| *b != b':').collect::<Vec<_>>();
+		if digits.len() != 64 || !digits.iter().all(u8::is_ascii_hexdigit) {
+			return Err(PinParseError(s.to_string()));
+		}
+
+		let mut pin = [0; 32];
+		for (byte, pair) in pin.iter_mut().zip(digits.chunks(2)) {
+			let pair = std::str::from_utf8(pair).expect("hexadecimal digits are ASCII; qed");
+			*byte = u8::from_str_radix(pair, 16).expect("two hexadecimal digits; qed");
+		}
+		Ok(CertificatePin(pin))
+	}
+}
+
+/// Pins of the telemetry servers, indexed by the `/<host>/tcp/<port>` part of their address.
+#[cfg(not(target_os = "unknown"))]
+pub(crate) type Pins = Arc<HashMap<Multiaddr, CertificatePin>>;
+
+/// Index the pins of [`TelemetryConfig::certificate_pins`](crate::TelemetryConfig) by the
+/// `/<host>/tcp/<port>` part of their address.
+#[cfg(not(target_os = "unknown"))]
+pub(crate) fn tcp_pins(certificate_pins: &HashMap<Multiaddr, CertificatePin>) -> Pins {
+	let mut pins = HashMap::new();
+
+	for (addr, pin) in certificate_pins {
+		match split_wss(addr) {
+			Some((tcp, _)) => {
+				pins.insert(tcp, *pin);
+			}
+			None => log::warn!(
+				target: "telemetry",
+				"Certificate pin for {} is ignored: it is not a secure WebSocket address",
+				addr,
+			),
+		}
+	}
+
+	Arc::new(pins)
+}
+
+/// Split a secure WebSocket address into its `/<host>/tcp/<port>` part and its path.
+#[cfg(not(target_os = "unknown"))]
+fn split_wss(addr: &Multiaddr) -> Option<(Multiaddr, String)> {
+	let mut tcp = addr.clone();
+	match tcp.pop()? {
+		Protocol::Wss(path) => Some((tcp, path.into_owned())),
+		_ => None,
+	}
+}
+
+/// Wraps around a WebSocket transport and turns the secure WebSocket addresses of the pinned
+/// servers into plain WebSocket addresses: the TLS session is then established by the
+/// [`PinnedTlsTransport`] underneath.
+#[cfg(not(target_os = "unknown"))]
+#[derive(Debug, Clone)]
+pub(crate) struct PinnedWsTransport<T> {
+	inner: T,
+	pins: Pins,
+}
+
+#[cfg(not(target_os = "unknown"))]
+impl<T> PinnedWsTransport<T> {
+	pub(crate) fn new(inner: T, pins: Pins) -> Self {
+		Self { inner, pins }
+	}
+}
+
+#[cfg(not(target_os = "unknown"))]
+impl<T: Transport> Transport for PinnedWsTransport<T> {
+	type Output = T::Output;
+	type Error = T::Error;
+	type Listener = T::Listener;
+	type ListenerUpgrade = T::ListenerUpgrade;
+	type Dial = T::Dial;
+
+	fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+		self.inner.listen_on(addr)
+	}
+
+	fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+		match split_wss(&addr) {
+			Some((tcp, path)) if self.pins.contains_key(&tcp) => {
+				self.inner.dial(tcp.with(Protocol::Ws(path.into())))
+			}
+			_ => self.inner.dial(addr),
+		}
+	}
+
+	fn address_translation(&self, server: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+		self.inner.address_translation(server, observed)
+	}
+}
+
+/// Error while establishing a connection with a pinned server.
+#[cfg(not(target_os = "unknown"))]
+#[derive(Debug)]
+pub(crate) enum PinnedTlsError<TErr> {
+	/// Error of the underlying transport.
+	Transport(TErr),
+	/// The TLS handshake failed, e.g. because the certificate doesn't match the pin.
+	Tls(io::Error),
+}
+
+#[cfg(not(target_os = "unknown"))]
+impl<TErr: fmt::Display> fmt::Display for PinnedTlsError<TErr> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			PinnedTlsError::Transport(err) => write!(f, "{}", err),
+			PinnedTlsError::Tls(err) => write!(f, "TLS handshake failed: {}", err),
+		}
+	}
+}
+
+#[cfg(not(target_os = "unknown"))]
+impl<TErr: error::Error + 'static> error::Error for PinnedTlsError<TErr> {
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match self {
+			PinnedTlsError::Transport(err) => Some(err),
+			PinnedTlsError::Tls(err) => Some(err),
+		}
+	}
+}
+
+/// Wraps around a TCP transport and establishes a TLS session with the pinned servers, checking
+/// their certificate against the pin instead of the certificate authorities.
+#[cfg(not(target_os = "unknown"))]
+#[derive(Debug, Clone)]
+pub(crate) struct PinnedTlsTransport<T> {
+	inner: T,
+	pins: Pins,
+}
+
+#[cfg(not(target_os = "unknown"))]
+impl<T> PinnedTlsTransport<T> {
+	pub(crate) fn new(inner: T, pins: Pins) -> Self {
+		Self { inner, pins }
+	}
+}
+
+#[cfg(not(target_os = "unknown"))]
+impl<T> Transport for PinnedTlsTransport<T>
+where
+	T: Transport + Send + 'static,
+	T::Output: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+	T::Error: Send + 'static,
+	T::Dial: Send + 'static,
+{
+	type Output = EitherOutput<TlsStream<T::Output>, T::Output>;
+	type Error = PinnedTlsError<T::Error>;
+	type Listener =
+		BoxStream<'static, Result<ListenerEvent<Self::ListenerUpgrade, Self::Error>, Self::Error>>;
+	type ListenerUpgrade = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+	type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+	fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+		Err(TransportError::MultiaddrNotSupported(addr))
+	}
+
+	fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+		let pin = self.pins.get(&addr).copied();
+		let dial = self
+			.inner
+			.dial(addr.clone())
+			.map_err(|err| err.map(PinnedTlsError::Transport))?;
+
+		let pin = match pin {
+			Some(pin) => pin,
+			None => {
+				return Ok(dial
+					.map_ok(EitherOutput::Second)
+					.map_err(PinnedTlsError::Transport)
+					.boxed())
+			}
+		};
+
+		let server_name = match addr.iter().next() {
+			Some(Protocol::Dns(host)) | Some(Protocol::Dns4(host)) | Some(Protocol::Dns6(host)) => {
+				webpki::DNSNameRef::try_from_ascii_str(&host)
+					.ok()
+					.map(|name| name.to_owned())
+			}
+			_ => None,
+		};
+
+		let mut config = rustls::ClientConfig::new();
+		config.enable_sni = server_name.is_some();
+		config
+			.dangerous()
+			.set_certificate_verifier(Arc::new(PinVerifier(pin)));
+		let connector = TlsConnector::from(Arc::new(config));
+
+		Ok(async move {
+			let stream = dial.await.map_err(PinnedTlsError::Transport)?;
+			let server_name = match &server_name {
+				Some(name) => name.as_ref(),
+				None => webpki::DNSNameRef::try_from_ascii_str(NO_SERVER_NAME)
+					.expect("NO_SERVER_NAME is a valid DNS name; qed"),
+			};
+			let stream = connector
+				.connect(server_name, stream)
+				.await
+				.map_err(PinnedTlsError::Tls)?;
+			Ok(EitherOutput::First(stream))
+		}
+		.boxed())
+	}
+
+	fn address_translation(&self, _: &Multiaddr, _: &Multiaddr) -> Option<Multiaddr> {
+		None
+	}
+}
+
+/// Accepts the certificate of a server if and only if it matches the pin.
+#[cfg(not(target_os = "unknown"))]
+struct PinVerifier(CertificatePin);
+
+#[cfg(not(target_os = "unknown"))]
+impl rustls::ServerCertVerifier for PinVerifier {
+	fn verify_server_cert(
+		&self,
+		_roots: &rustls::RootCertStore,
+		presented_certs: &[rustls::Certificate],
+		_dns_name: webpki::DNSNameRef,
+		_ocsp_response: &[u8],
+	) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+		let certificate = presented_certs
+			.first()
+			.ok_or(rustls::TLSError::NoCertificatesPresented)?;
+		let pin = CertificatePin::from_der(&certificate.0);
+
+		if pin == self.0 {
+			Ok(rustls::ServerCertVerified::assertion())
+		} else {
+			Err(rustls::TLSError::General(format!(
+				"certificate pin mismatch: expected {}, got {}",
+				self.0, pin,
+			)))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_certificate_pin() {
+		let pin: CertificatePin =
+			"30:D8:34:2C:C6:2A:8F:FD:28:02:91:F6:79:DD:24:62:9F:16:76:30:8C:AB:5B:6B:F3:F4:EF:56:A3:0D:63:CC"
+				.parse()
+				.unwrap();
+		assert_eq!(
+			pin,
+			CertificatePin::from_der(include_bytes!("../res/test-certificate.der")),
+		);
+		assert_eq!(
+			"30d8342cc62a8ffd280291f679dd24629f1676308cab5b6bf3f4ef56a30d63cc"
+				.parse::<CertificatePin>()
+				.unwrap(),
+			pin,
+		);
+		assert_eq!(pin.to_string().parse::<CertificatePin>().unwrap(), pin);
+
+		for invalid in &[
+			"",
+			"30d8342cc62a8ffd280291f679dd24629f1676308cab5b6bf3f4ef56a30d63",
+			"30d8342cc62a8ffd280291f679dd24629f1676308cab5b6bf3f4ef56a30d63cc00",
+			"+0d8342cc62a8ffd280291f679dd24629f1676308cab5b6bf3f4ef56a30d63cc",
+			"zzd8342cc62a8ffd280291f679dd24629f1676308cab5b6bf3f4ef56a30d63cc",
+		] {
+			assert!(invalid.parse::<CertificatePin>().is_err(), "{}", invalid);
+		}
+	}
+
+	#[test]
+	fn pins_are_indexed_by_tcp_address() {
+		let pin = CertificatePin([1; 32]);
+		let mut certificate_pins = HashMap::new();
+		certificate_pins.insert(
+			"/dns/telemetry.polkadot.io/tcp/443/x-parity-wss/%2Fsubmit%2F".parse().unwrap(),
+			pin,
+		);
+		certificate_pins.insert("/ip4/127.0.0.1/tcp/8000/ws".parse().unwrap(), pin);
+
+		let pins = tcp_pins(&certificate_pins);
+		assert_eq!(pins.len(), 1);
+		assert_eq!(
+			pins.get(&"/dns/telemetry.polkadot.io/tcp/443".parse().unwrap()),
+			Some(&pin),
+		);
+	}
+}
@@ -0,0 +1,537 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+#[cfg(not(target_os = "unknown"))]
+use futures::{future::BoxFuture, prelude::*, stream::BoxStream};
+#[cfg(not(target_os = "unknown"))]
+use libp2p::{
+	core::transport::{ListenerEvent, TransportError},
+	Transport,
+};
+use libp2p::{multiaddr::Protocol, Multiaddr};
+#[cfg(not(target_os = "unknown"))]
+use std::net::{IpAddr, Ipv6Addr};
+use std::{error, fmt, io, net::Ipv4Addr, str::FromStr};
+
+/// Name of the environment variable that overrides [`TelemetryConfig::proxy`].
+///
+/// [`TelemetryConfig::proxy`]: crate::TelemetryConfig::proxy
+pub const PROXY_ENV_VAR: &str = "SUBSTRATE_TELEMETRY_PROXY";
+
+/// Maximum size of the response of an HTTP proxy to a `CONNECT` request.
+#[cfg(not(target_os = "unknown"))]
+const MAX_HTTP_RESPONSE_SIZE: usize = 8 * 1024;
+
+/// Proxy through which the connections to the telemetry servers are established.
+///
+/// It can be parsed from `http://host:port` (HTTP proxy supporting `CONNECT`) or
+/// `socks5://host:port` (SOCKS5 proxy without authentication).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TelemetryProxy {
+	/// HTTP proxy supporting the `CONNECT` method, e.g. `/dns/proxy.local/tcp/3128`.
+	Http(Multiaddr),
+	/// SOCKS5 proxy without authentication, e.g. `/ip4/127.0.0.1/tcp/1080`.
+	Socks5(Multiaddr),
+}
+
+impl TelemetryProxy {
+	/// Read the proxy from the [`PROXY_ENV_VAR`] environment variable, if set.
+	pub fn from_env() -> io::Result<Option<Self>> {
+		match std::env::var(PROXY_ENV_VAR) {
+			Ok(proxy) if !proxy.trim().is_empty() => proxy
+				.parse()
+				.map(Some)
+				.map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)),
+			_ => Ok(None),
+		}
+	}
+
+	fn addr(&self) -> &Multiaddr {
+		match self {
+			TelemetryProxy::Http(addr) | TelemetryProxy::Socks5(addr) => addr,
+		}
+	}
+}
+
+/// Error while parsing a [`TelemetryProxy`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyParseError(String);
+
+impl fmt::Display for ProxyParseError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"Invalid telemetry proxy {:?}, expected `http://host:port` or `socks5://host:port`",
+			self.0,
+		)
+	}
+}
+
+impl error::Error for ProxyParseError {}
+
+impl FromStr for TelemetryProxy {
+	type Err = ProxyParseError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let err = || ProxyParseError(s.to_string());
+
+		let (kind, host_port): (fn(Multiaddr) -> TelemetryProxy, _) =
+			if let Some(host_port) = s.strip_prefix("http://") {
+				(TelemetryProxy::Http, host_port)
+			} else if let Some(host_port) = s.strip_prefix("socks5://") {
+				(TelemetryProxy::Socks5, host_port)
+			} else {
+				return Err(err());
+			};
+
+		let host_port = host_port.trim_end_matches('/');
+		let colon = host_port.rfind(':').ok_or_else(err)?;
+		let port = host_port[colon + 1..].parse().map_err(|_| err())?;
+		let host = &host_port[..colon];
+
+		let host = if let Some(ip) = host
+			.strip_prefix('[')
+			.and_then(|host| host.strip_suffix(']'))
+		{
+			Protocol::Ip6(ip.parse().map_err(|_| err())?)
+		} else if let Ok(ip) = host.parse::<Ipv4Addr>() {
+			Protocol::Ip4(ip)
+		} else if !host.is_empty() && !host.contains(&['/', '@', ':'][..]) {
+			Protocol::Dns(host.to_string().into())
+		} else {
+			return Err(err());
+		};
+
+		Ok(kind(Multiaddr::empty().with(host).with(Protocol::Tcp(port))))
+	}
+}
+
+/// Error while dialing through a [`TelemetryProxy`].
+#[cfg(not(target_os = "unknown"))]
+#[derive(Debug)]
+pub(crate) enum ProxyError<TErr> {
+	/// Error while connecting to the proxy.
+	Transport(TErr),
+	/// I/O error while talking to the proxy.
+	Io(io::Error),
+	/// The proxy refused to establish the connection.
+	Refused(String),
+}
+
+#[cfg(not(target_os = "unknown"))]
+impl<TErr: fmt::Display> fmt::Display for ProxyError<TErr> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ProxyError::Transport(err) => write!(f, "Failed to connect to the proxy: {}", err),
+			ProxyError::Io(err) => write!(f, "Proxy I/O error: {}", err),
+			ProxyError::Refused(reason) => write!(f, "The proxy refused the connection: {}", reason),
+		}
+	}
+}
+
+#[cfg(not(target_os = "unknown"))]
+impl<TErr: error::Error + 'static> error::Error for ProxyError<TErr> {
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match self {
+			ProxyError::Transport(err) => Some(err),
+			ProxyError::Io(err) => Some(err),
+			ProxyError::Refused(_) => None,
+		}
+	}
+}
+
+#[cfg(not(target_os = "unknown"))]
+impl<TErr> From<io::Error> for ProxyError<TErr> {
+	fn from(err: io::Error) -> Self {
+		ProxyError::Io(err)
+	}
+}
+
+/// Wraps around a TCP transport and establishes the connections through a [`TelemetryProxy`], if
+/// any.
+#[cfg(not(target_os = "unknown"))]
+#[derive(Debug, Clone)]
+pub(crate) struct ProxyTransport<T> {
+	inner: T,
+	proxy: Option<TelemetryProxy>,
+}
+
+#[cfg(not(target_os = "unknown"))]
+impl<T> ProxyTransport<T> {
+	pub(crate) fn new(inner: T, proxy: Option<TelemetryProxy>) -> Self {
+		Self { inner, proxy }
+	}
+}
+
+#[cfg(not(target_os = "unknown"))]
+impl<T> Transport for ProxyTransport<T>
+where
+	T: Transport + Send + 'static,
+	T::Output: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+	T::Error: Send + 'static,
+	T::Dial: Send + 'static,
+	T::Listener: Send + 'static,
+	T::ListenerUpgrade: Send + 'static,
+{
+	type Output = T::Output;
+	type Error = ProxyError<T::Error>;
+	type Listener =
+		BoxStream<'static, Result<ListenerEvent<Self::ListenerUpgrade, Self::Error>, Self::Error>>;
+	type ListenerUpgrade = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+	type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+	fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+		Err(TransportError::MultiaddrNotSupported(addr))
+	}
+
+	fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+		let proxy = match self.proxy {
+			Some(proxy) => proxy,
+			None => {
+				let dial = self
+					.inner
+					.dial(addr)
+					.map_err(|err| err.map(ProxyError::Transport))?;
+				return Ok(dial.map_err(ProxyError::Transport).boxed());
+			}
+		};
+
+		let target = match Target::from_multiaddr(&addr) {
+			Some(target) => target,
+			None => return Err(TransportError::MultiaddrNotSupported(addr)),
+		};
+		let dial = self
+			.inner
+			.dial(proxy.addr().clone())
+			.map_err(|err| err.map(ProxyError::Transport))?;
+
+		Ok(async move {
+			let mut stream = dial.await.map_err(ProxyError::Transport)?;
+			match proxy {
+				TelemetryProxy::Http(_) => http_connect(&mut stream, &target).await?,
+				TelemetryProxy::Socks5(_) => socks5_connect(&mut stream, &target).await?,
+			}
+			Ok(stream)
+		}
+		.boxed())
+	}
+
+	fn address_translation(&self, _: &Multiaddr, _: &Multiaddr) -> Option<Multiaddr> {
+		None
+	}
+}
+
+/// Host and port that the proxy must connect to.
+#[cfg(not(target_os = "unknown"))]
+struct Target {
+	host: TargetHost,
+	port: u16,
+}
+
+#[cfg(not(target_os = "unknown"))]
+enum TargetHost {
+	Ip(IpAddr),
+	Domain(String),
+}
+
+#[cfg(not(target_os = "unknown"))]
+impl Target {
+	/// Extract the target of a `/<ip or dns>/<host>/tcp/<port>` multiaddress.
+	fn from_multiaddr(addr: &Multiaddr) -> Option<Self> {
+		let mut iter = addr.iter();
+		let host = match iter.next()? {
+			Protocol::Ip4(ip) => TargetHost::Ip(ip.into()),
+			Protocol::Ip6(ip) => TargetHost::Ip(ip.into()),
+			Protocol::Dns(host) | Protocol::Dns4(host) | Protocol::Dns6(host) => {
+				TargetHost::Domain(host.into_owned())
+			}
+			_ => return None,
+		};
+		let port = match iter.next()? {
+			Protocol::Tcp(port) => port,
+			_ => return None,
+		};
+
+		if iter.next().is_some() {
+			return None;
+		}
+		Some(Target { host, port })
+	}
+}
+
+#[cfg(not(target_os = "unknown"))]
+impl fmt::Display for Target {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match &self.host {
+			TargetHost::Ip(IpAddr::V6(ip)) => write!(f, "[{}]:{}", ip, self.port),
+			TargetHost::Ip(ip) => write!(f, "{}:{}", ip, self.port),
+			TargetHost::Domain(host) => write!(f, "{}:{}", host, self.port),
+		}
+	}
+}
+
+/// Ask an HTTP proxy to connect to `target` with a `CONNECT` request.
+#[cfg(not(target_os = "unknown"))]
+async fn http_connect<S, E>(stream: &mut S, target: &Target) -> Result<(), ProxyError<E>>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target);
+	stream.write_all(request.as_bytes()).await?;
+	stream.flush().await?;
+
+	// The response is read byte by byte in order not to consume the data that follows it.
+	let mut response = Vec::new();
+	while !response.ends_with(b"\r\n\r\n") {
+		if response.len() >= MAX_HTTP_RESPONSE_SIZE {
+			return Err(ProxyError::Refused("response too large".into()));
+		}
+		let mut byte = [0; 1];
+		stream.read_exact(&mut byte).await?;
+		response.push(byte[0]);
+	}
+
+	let response = String::from_utf8_lossy(&response);
+	let status_line = response.lines().next().unwrap_or_default();
+	match status_line.split_whitespace().nth(1) {
+		Some(status) if status.starts_with('2') => Ok(()),
+		_ => Err(ProxyError::Refused(status_line.to_string())),
+	}
+}
+
+/// Ask a SOCKS5 proxy to connect to `target`, without authentication.
+#[cfg(not(target_os = "unknown"))]
+async fn socks5_connect<S, E>(stream: &mut S, target: &Target) -> Result<(), ProxyError<E>>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	// Version 5, one authentication method: "no authentication".
+	stream.write_all(&[5, 1, 0]).await?;
+	stream.flush().await?;
+	let mut reply = [0; 2];
+	stream.read_exact(&mut reply).await?;
+	if reply != [5, 0] {
+		return Err(ProxyError::Refused("no acceptable authentication method".into()));
+	}
+
+	// Version 5, command "connect", reserved byte, then the address and the port.
+	let mut request = vec![5, 1, 0];
+	match &target.host {
+		TargetHost::Ip(IpAddr::V4(ip)) => {
+			request.push(1);
+			request.extend_from_slice(&ip.octets());
+		}
+		TargetHost::Ip(IpAddr::V6(ip)) => {
+			request.push(4);
+			request.extend_from_slice(&ip.octets());
+		}
+		TargetHost::Domain(host) => {
+			if host.len() > usize::from(u8::MAX) {
+				return Err(ProxyError::Refused("domain name too long".into()));
+			}
+			request.push(3);
+			request.push(host.len() as u8);
+			request.extend_from_slice(host.as_bytes());
+		}
+	}
+	request.extend_from_slice(&target.port.to_be_bytes());
+	stream.write_all(&request).await?;
+	stream.flush().await?;
+
+	let mut reply = [0; 4];
+	stream.read_exact(&mut reply).await?;
+	if reply[1] != 0 {
+		return Err(ProxyError::Refused(format!("SOCKS5 error code {}", reply[1])));
+	}
+
+	// Skip the address the proxy is bound to.
+	let bound_addr_len = match reply[3] {
+		1 => Ipv4Addr::LOCALHOST.octets().len(),
+		4 => Ipv6Addr::LOCALHOST.octets().len(),
+		3 => {
+			let mut len = [0; 1];
+			stream.read_exact(&mut len).await?;
+			usize::from(len[0])
+		}
+		atyp => return Err(ProxyError::Refused(format!("unknown address type {}", atyp))),
+	};
+	let mut bound_addr = vec![0; bound_addr_len + 2];
+	stream.read_exact(&mut bound_addr).await?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{initialize_transport, TelemetryConfig};
+	use libp2p::websocket::framed::WsConfig;
+	use std::io::{Read, Write};
+	use std::net::{Shutdown, TcpListener, TcpStream};
+	use std::sync::mpsc;
+	use std::thread;
+
+	#[test]
+	fn parse_proxy() {
+		assert_eq!(
+			"http://127.0.0.1:3128".parse(),
+			Ok(TelemetryProxy::Http(
+				"/ip4/127.0.0.1/tcp/3128".parse().unwrap()
+			)),
+		);
+		assert_eq!(
+			"socks5://proxy.local:1080/".parse(),
+			Ok(TelemetryProxy::Socks5(
+				"/dns/proxy.local/tcp/1080".parse().unwrap()
+			)),
+		);
+		assert_eq!(
+			"http://[::1]:3128".parse(),
+			Ok(TelemetryProxy::Http("/ip6/::1/tcp/3128".parse().unwrap())),
+		);
+		assert!("ftp://proxy.local:21".parse::<TelemetryProxy>().is_err());
+		assert!("http://proxy.local".parse::<TelemetryProxy>().is_err());
+		assert!("http://user@proxy.local:3128".parse::<TelemetryProxy>().is_err());
+	}
+
+	/// Start a dummy HTTP proxy that accepts one `CONNECT` request. Returns its address and the
+	/// receiving end of the requested target.
+	fn dummy_http_proxy() -> (u16, mpsc::Receiver<String>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let (tx, rx) = mpsc::channel();
+
+		thread::spawn(move || {
+			let (mut client, _) = listener.accept().unwrap();
+			let mut request = Vec::new();
+			while !request.ends_with(b"\r\n\r\n") {
+				let mut byte = [0; 1];
+				client.read_exact(&mut byte).unwrap();
+				request.push(byte[0]);
+			}
+
+			let request = String::from_utf8(request).unwrap();
+			let target = request.split_whitespace().nth(1).unwrap().to_string();
+			let server = TcpStream::connect(&target).unwrap();
+			tx.send(target).unwrap();
+			client
+				.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+				.unwrap();
+			pipe(client, server);
+		});
+
+		(port, rx)
+	}
+
+	/// Copy the data between two sockets until one of them is closed.
+	fn pipe(a: TcpStream, b: TcpStream) {
+		let (mut a_read, mut b_write) = (a.try_clone().unwrap(), b.try_clone().unwrap());
+		thread::spawn(move || {
+			let _ = std::io::copy(&mut a_read, &mut b_write);
+			let _ = b_write.shutdown(Shutdown::Write);
+		});
+		let (mut b_read, mut a_write) = (b, a);
+		let _ = std::io::copy(&mut b_read, &mut a_write);
+		let _ = a_write.shutdown(Shutdown::Write);
+	}
+
+	/// Start a dummy SOCKS5 proxy that accepts one IPv4 `CONNECT` request. Returns its address and
+	/// the receiving end of the requested target.
+	fn dummy_socks5_proxy() -> (u16, mpsc::Receiver<String>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let (tx, rx) = mpsc::channel();
+
+		thread::spawn(move || {
+			let (mut client, _) = listener.accept().unwrap();
+			let mut greeting = [0; 3];
+			client.read_exact(&mut greeting).unwrap();
+			assert_eq!(greeting, [5, 1, 0]);
+			client.write_all(&[5, 0]).unwrap();
+
+			let mut request = [0; 10];
+			client.read_exact(&mut request).unwrap();
+			assert_eq!(request[..4], [5, 1, 0, 1]);
+			let ip = Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+			let target = format!("{}:{}", ip, u16::from_be_bytes([request[8], request[9]]));
+			let server = TcpStream::connect(&target).unwrap();
+			tx.send(target).unwrap();
+			client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+			pipe(client, server);
+		});
+
+		(port, rx)
+	}
+
+	/// Send a message to a local WebSocket server through `proxy`, and check that the proxy has
+	/// been asked to connect to that server.
+	fn check_proxied_connection(proxy: TelemetryProxy, targets: mpsc::Receiver<String>) {
+		let mut server = WsConfig::new(libp2p::tcp::TcpConfig::new())
+			.listen_on("/ip4/127.0.0.1/tcp/0/ws".parse().unwrap())
+			.unwrap();
+
+		let config = TelemetryConfig {
+			proxy: Some(proxy),
+			..Default::default()
+		};
+		let transport = initialize_transport(None, &config).unwrap();
+
+		futures::executor::block_on(async move {
+			let addr = match server.next().await {
+				Some(Ok(ListenerEvent::NewAddress(addr))) => addr,
+				_ => panic!("the listener reports its address first"),
+			};
+			let port = match addr.iter().nth(1) {
+				Some(Protocol::Tcp(port)) => port,
+				_ => panic!("the listener has a TCP address"),
+			};
+
+			let client = async {
+				let mut sink = transport.dial(addr).unwrap().await.unwrap();
+				sink.send(b"hello".to_vec()).await.unwrap();
+				sink
+			};
+			let server = async {
+				let mut connection = loop {
+					if let Some(Ok(ListenerEvent::Upgrade { upgrade, .. })) = server.next().await {
+						break upgrade.await.unwrap();
+					}
+				};
+				connection.next().await.unwrap().unwrap().into_bytes()
+			};
+			let (_sink, received) = future::join(client, server).await;
+
+			assert_eq!(received, b"hello".to_vec());
+			assert_eq!(targets.recv().unwrap(), format!("127.0.0.1:{}", port));
+		});
+	}
+
+	#[test]
+	fn connections_go_through_the_http_proxy() {
+		let (port, targets) = dummy_http_proxy();
+		let addr = format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap();
+		check_proxied_connection(TelemetryProxy::Http(addr), targets);
+	}
+
+	#[test]
+	fn connections_go_through_the_socks5_proxy() {
+		let (port, targets) = dummy_socks5_proxy();
+		let addr = format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap();
+		check_proxied_connection(TelemetryProxy::Socks5(addr), targets);
+	}
+}
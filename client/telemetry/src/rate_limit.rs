@@ -0,0 +1,148 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::StatsCounters;
+use std::collections::HashMap;
+use std::sync::{atomic::Ordering, Arc};
+use tracing::Id;
+use wasm_timer::Instant;
+
+/// Token bucket rate limiter of the telemetry messages, keyed by span id.
+///
+/// Every span id can send up to `burst` messages at once, and then `rate` messages per second.
+/// The messages up to the exempt verbosity are always allowed and don't consume any token.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+	rate: u32,
+	burst: u32,
+	exempt_verbosity: Option<u8>,
+	buckets: HashMap<Id, Bucket>,
+	/// Where the dropped messages are counted.
+	stats: Arc<StatsCounters>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+	tokens: f64,
+	last_refill: Instant,
+}
+
+impl RateLimiter {
+	/// Create a new [`RateLimiter`] allowing `rate` messages per second and bursts of `burst`
+	/// messages for each span id, and counting the dropped messages in `stats`.
+	pub(crate) fn new(
+		rate: u32,
+		burst: u32,
+		exempt_verbosity: Option<u8>,
+		stats: Arc<StatsCounters>,
+	) -> Self {
+		Self {
+			rate,
+			burst,
+			exempt_verbosity,
+			buckets: HashMap::new(),
+			stats,
+		}
+	}
+
+	/// Return `true` if a message of the span `id` with the given verbosity can be sent, `false`
+	/// if it must be dropped.
+	pub(crate) fn check(&mut self, id: &Id, verbosity: u8) -> bool {
+		if matches!(self.exempt_verbosity, Some(exempt) if verbosity <= exempt) {
+			return true;
+		}
+
+		let allowed = self.check_at(id, Instant::now());
+		if !allowed {
+			self.stats
+				.messages_dropped_rate_limit
+				.fetch_add(1, Ordering::Relaxed);
+		}
+		allowed
+	}
+
+	/// Forget the span ids for which `keep` returns `false`.
+	pub(crate) fn retain(&mut self, mut keep: impl FnMut(&Id) -> bool) {
+		self.buckets.retain(|id, _| keep(id));
+	}
+
+	fn check_at(&mut self, id: &Id, now: Instant) -> bool {
+		let rate = f64::from(self.rate);
+		let burst = f64::from(self.burst);
+		let bucket = self.buckets.entry(id.clone()).or_insert_with(|| Bucket {
+			tokens: burst,
+			last_refill: now,
+		});
+
+		let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+		bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+		bucket.last_refill = now;
+
+		if bucket.tokens >= 1.0 {
+			bucket.tokens -= 1.0;
+			true
+		} else {
+			false
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	#[test]
+	fn tokens_are_refilled_over_time() {
+		let mut limiter = RateLimiter::new(10, 10, None, Default::default());
+		let id = Id::from_u64(1);
+		let other_id = Id::from_u64(2);
+		let start = Instant::now();
+
+		let sent = (0..1000).filter(|_| limiter.check_at(&id, start)).count();
+		assert_eq!(sent, 10);
+		assert!(limiter.check_at(&other_id, start));
+
+		let later = start + Duration::from_millis(500);
+		let sent = (0..1000).filter(|_| limiter.check_at(&id, later)).count();
+		assert_eq!(sent, 5);
+
+		let much_later = later + Duration::from_secs(60);
+		let sent = (0..1000)
+			.filter(|_| limiter.check_at(&id, much_later))
+			.count();
+		assert_eq!(sent, 10);
+	}
+	#[test]
+	fn bursts_and_exempt_messages() {
+		let mut limiter = RateLimiter::new(10, 50, Some(0), Default::default());
+		let id = Id::from_u64(1);
+		let start = Instant::now();
+
+		let sent = (0..1000).filter(|_| limiter.check_at(&id, start)).count();
+		assert_eq!(sent, 50);
+		let later = start + Duration::from_millis(500);
+		let sent = (0..1000).filter(|_| limiter.check_at(&id, later)).count();
+		assert_eq!(sent, 5);
+
+		// The bucket is empty, but the exempt messages are not counted.
+		assert!((0..1000).all(|_| limiter.check(&id, 0)));
+		assert!(!limiter.check(&id, 1));
+		assert_eq!(limiter.stats.snapshot().messages_dropped_rate_limit, 1);
+	}
+}
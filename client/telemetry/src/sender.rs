@@ -0,0 +1,191 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{layer::wrap_payload, TelemetryMessage, TimestampFormat};
+use futures::channel::mpsc;
+use std::fmt;
+#[cfg(not(target_os = "unknown"))]
+use std::task::{Context, Poll};
+use tracing::Id;
+
+/// Sender of telemetry messages to the [`TelemetryWorker`](crate::TelemetryWorker), for the
+/// telemetry that isn't logged with the [`telemetry!`](crate::telemetry) macro.
+///
+/// The messages are queued like those of the [`TelemetryLayer`](crate::TelemetryLayer) and sent
+/// to the telemetry servers of the telemetry span `id`, up to their verbosity.
+#[derive(Debug, Clone)]
+pub struct TelemetrySender {
+	sender: mpsc::Sender<TelemetryMessage>,
+	timestamp_format: TimestampFormat,
+}
+
+impl TelemetrySender {
+	pub(crate) fn new(
+		sender: mpsc::Sender<TelemetryMessage>,
+		timestamp_format: TimestampFormat,
+	) -> Self {
+		Self {
+			sender,
+			timestamp_format,
+		}
+	}
+
+	/// Send the message `payload` of the telemetry span `id` as is.
+	///
+	/// The `payload` is the complete message received by the telemetry servers, see
+	/// [`TelemetrySender::send_json`] to build it.
+	pub fn send(
+		&mut self,
+		id: &Id,
+		verbosity: u8,
+		payload: impl Into<String>,
+	) -> Result<(), TrySendError> {
+		let message = TelemetryMessage::new(id.clone(), verbosity, payload.into());
+		self.try_send(message).map_err(|err| {
+			if err.is_full() {
+				TrySendError::Full
+			} else {
+				TrySendError::Disconnected
+			}
+		})
+	}
+
+	/// Send the JSON object `json` as the payload of a message of the telemetry span `id`, along
+	/// with the id of the span and the current time, like the [`telemetry!`](crate::telemetry)
+	/// macro does.
+	pub fn send_json(
+		&mut self,
+		id: &Id,
+		verbosity: u8,
+		json: serde_json::Value,
+	) -> Result<(), TrySendError> {
+		let payload = match json {
+			serde_json::Value::Object(payload) => payload,
+			_ => return Err(TrySendError::NotAnObject),
+		};
+		let message = wrap_payload(id, payload, self.timestamp_format)?;
+		self.send(id, verbosity, message)
+	}
+
+	/// Queue `message` for the worker.
+	pub(crate) fn try_send(
+		&mut self,
+		message: TelemetryMessage,
+	) -> Result<(), mpsc::TrySendError<TelemetryMessage>> {
+		self.sender.try_send(message)
+	}
+
+	/// Whether the worker has stopped.
+	pub(crate) fn is_closed(&self) -> bool {
+		self.sender.is_closed()
+	}
+
+	/// Poll until there is room for a message in the queue to the worker.
+	#[cfg(not(target_os = "unknown"))]
+	pub(crate) fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), mpsc::SendError>> {
+		self.sender.poll_ready(cx)
+	}
+}
+
+/// Error returned by [`TelemetrySender`].
+#[derive(Debug)]
+pub enum TrySendError {
+	/// The queue to the [`TelemetryWorker`](crate::TelemetryWorker) is full.
+	Full,
+	/// The [`TelemetryWorker`](crate::TelemetryWorker) has stopped.
+	Disconnected,
+	/// The JSON payload of the message is not an object.
+	NotAnObject,
+	/// The message could not be serialized.
+	Json(serde_json::Error),
+}
+
+impl TrySendError {
+	/// Whether the message could not be sent because the queue to the worker is full.
+	pub fn is_full(&self) -> bool {
+		matches!(self, TrySendError::Full)
+	}
+
+	/// Whether the message could not be sent because the worker has stopped.
+	pub fn is_disconnected(&self) -> bool {
+		matches!(self, TrySendError::Disconnected)
+	}
+}
+
+impl fmt::Display for TrySendError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			TrySendError::Full => write!(f, "the queue to the telemetry worker is full"),
+			TrySendError::Disconnected => write!(f, "the telemetry worker has stopped"),
+			TrySendError::NotAnObject => {
+				write!(f, "the payload of a telemetry message must be a JSON object")
+			}
+			TrySendError::Json(err) => write!(f, "invalid telemetry message: {}", err),
+		}
+	}
+}
+
+impl std::error::Error for TrySendError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			TrySendError::Json(err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+impl From<serde_json::Error> for TrySendError {
+	fn from(err: serde_json::Error) -> Self {
+		TrySendError::Json(err)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::SUBSTRATE_INFO;
+	use futures::prelude::*;
+
+	#[test]
+	fn json_payloads_are_wrapped_like_the_logged_ones() {
+		let (sender, mut receiver) = mpsc::channel(0);
+		let mut sender = TelemetrySender::new(sender, TimestampFormat::UnixMillis);
+		let id = Id::from_u64(42);
+
+		sender
+			.send_json(&id, SUBSTRATE_INFO, serde_json::json!({ "msg": "test.json" }))
+			.unwrap();
+		let message = receiver.next().now_or_never().flatten().unwrap();
+		assert_eq!(message.id, id);
+		assert_eq!(message.verbosity, SUBSTRATE_INFO);
+		let json: serde_json::Value = serde_json::from_str(&message.payload).unwrap();
+		assert_eq!(json["id"], 42);
+		assert!(json["ts"].is_u64());
+		assert_eq!(json["payload"], serde_json::json!({ "msg": "test.json" }));
+
+		assert!(matches!(
+			sender.send_json(&id, SUBSTRATE_INFO, serde_json::json!("test.json")),
+			Err(TrySendError::NotAnObject),
+		));
+		sender.send(&id, SUBSTRATE_INFO, r#"{"msg":"0"}"#).unwrap();
+		assert!(sender.send(&id, SUBSTRATE_INFO, r#"{"msg":"1"}"#).unwrap_err().is_full());
+		drop(receiver);
+		let err = sender.send(&id, SUBSTRATE_INFO, r#"{"msg":"2"}"#).unwrap_err();
+		assert!(err.is_disconnected());
+	}
+}
@@ -0,0 +1,150 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{TelemetryMessage, TelemetrySender};
+use futures::future::{self, BoxFuture, FutureExt};
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Arc;
+use tracing::Id;
+
+/// Destination of the telemetry other than a telemetry server, registered with
+/// [`TelemetryWorker::add_sink`](crate::TelemetryWorker::add_sink).
+///
+/// A sink receives the same messages as the telemetry servers: the JSON object of every
+/// telemetry message of the telemetry spans, along with the id of the span. The worker waits
+/// for [`TelemetrySink::send`] to complete before it dispatches the next message, so a slow sink
+/// delays the telemetry servers.
+pub trait TelemetrySink: Send {
+	/// Send a telemetry `message` to the sink registered as `label`.
+	fn send<'a>(&'a mut self, label: &'a str, message: &'a str) -> BoxFuture<'a, io::Result<()>>;
+
+	/// Make sure that the messages sent so far have reached their destination. This is called
+	/// when the worker shuts down.
+	fn flush(&mut self) -> BoxFuture<'_, io::Result<()>>;
+}
+
+/// [`TelemetrySink`] printing the telemetry messages to the standard output, one JSON object per
+/// line.
+#[derive(Debug, Default)]
+pub struct StdoutSink;
+
+impl TelemetrySink for StdoutSink {
+	fn send<'a>(&'a mut self, _: &'a str, message: &'a str) -> BoxFuture<'a, io::Result<()>> {
+		future::ready(writeln!(io::stdout().lock(), "{}", message)).boxed()
+	}
+
+	fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+		future::ready(io::stdout().flush()).boxed()
+	}
+}
+
+/// The [`TelemetrySink`]s registered with the worker, and the other workers to which the
+/// telemetry is forwarded.
+#[derive(Default)]
+pub(crate) struct Sinks {
+	/// Label, verbosity and sink.
+	sinks: Vec<(String, u8, Box<dyn TelemetrySink>)>,
+	/// Span of the other worker, verbosity and sender to the other worker.
+	forwards: Vec<(Id, u8, TelemetrySender)>,
+}
+
+impl Sinks {
+	/// Add a sink receiving the messages up to `verbosity`.
+	pub(crate) fn push(&mut self, label: String, verbosity: u8, sink: Box<dyn TelemetrySink>) {
+		self.sinks.push((label, verbosity, sink));
+	}
+
+	/// Forward the messages up to `verbosity` to another worker through `sender`, as messages of
+	/// its telemetry span `id`.
+	pub(crate) fn forward(&mut self, id: Id, verbosity: u8, sender: TelemetrySender) {
+		self.forwards.push((id, verbosity, sender));
+	}
+
+	/// Send `message` to the sinks and to the other workers whose verbosity is at least
+	/// `verbosity`.
+	pub(crate) async fn send(&mut self, verbosity: u8, message: &Arc<str>) {
+		for (label, max_verbosity, sink) in &mut self.sinks {
+			if verbosity > *max_verbosity {
+				continue;
+			}
+			if let Err(err) = sink.send(label, message).await {
+				log::warn!(
+					target: "telemetry",
+					"Could not send a telemetry message to sink {}: {}",
+					label,
+					err,
+				);
+			}
+		}
+
+		for (id, max_verbosity, sender) in &mut self.forwards {
+			if verbosity > *max_verbosity {
+				continue;
+			}
+			// The other worker must not delay this one: the message is dropped instead.
+			let forwarded = TelemetryMessage::new(id.clone(), verbosity, message.clone());
+			if let Err(err) = sender.try_send(forwarded) {
+				if err.is_full() {
+					log::debug!(
+						target: "telemetry",
+						"Could not forward a telemetry message: the queue to the other \
+						telemetry worker is full",
+					);
+				}
+			}
+		}
+		self.forwards.retain(|(id, _, sender)| {
+			let closed = sender.is_closed();
+			if closed {
+				log::warn!(
+					target: "telemetry",
+					"Stopped forwarding the telemetry to span {:?}: the other telemetry worker \
+					has stopped",
+					id,
+				);
+			}
+			!closed
+		});
+	}
+
+	/// Flush every sink.
+	pub(crate) async fn flush(&mut self) {
+		for (label, _, sink) in &mut self.sinks {
+			if let Err(err) = sink.flush().await {
+				log::warn!(
+					target: "telemetry",
+					"Could not flush telemetry sink {}: {}",
+					label,
+					err,
+				);
+			}
+		}
+	}
+}
+
+impl fmt::Debug for Sinks {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let sinks = self.sinks.iter().map(|(label, verbosity, _)| (label, verbosity));
+		let forwards = self.forwards.iter().map(|(id, verbosity, _)| (id, verbosity));
+		f.debug_struct("Sinks")
+			.field("sinks", &sinks.collect::<Vec<_>>())
+			.field("forwards", &forwards.collect::<Vec<_>>())
+			.finish()
+	}
+}
@@ -0,0 +1,174 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use libp2p::Multiaddr;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// On-disk storage of the telemetry that could not be delivered to a telemetry server.
+///
+/// The messages are held in memory until [`Spill::persist`] appends them to the file of the
+/// endpoint, one message per line preceded by the time at which it has been spilled, in
+/// milliseconds since the Unix epoch.
+#[derive(Debug)]
+pub(crate) struct Spill {
+	/// File of the endpoint.
+	path: PathBuf,
+	/// Messages that have not been written to the file yet, along with when they have been
+	/// spilled.
+	pending: Vec<(u64, Arc<str>)>,
+	/// Number of pending messages after which they should be written to the file.
+	threshold: usize,
+	/// Age after which the spilled messages are not replayed.
+	max_age: Duration,
+}
+
+impl Spill {
+	/// Create the spill of the endpoint `addr` in the directory `dir`.
+	pub(crate) fn new(dir: &Path, addr: &Multiaddr, threshold: usize, max_age: Duration) -> Self {
+		Self {
+			path: dir.join(file_name(addr)),
+			pending: Vec::new(),
+			threshold,
+			max_age,
+		}
+	}
+
+	/// Spill a message.
+	///
+	/// Returns `true` if the pending messages should now be written to the file.
+	pub(crate) fn push(&mut self, message: Arc<str>) -> bool {
+		self.pending.push((now_millis(), message));
+		self.pending.len() >= self.threshold.max(1)
+	}
+
+	/// Number of messages that have not been written to the file yet.
+	pub(crate) fn pending(&self) -> usize {
+		self.pending.len()
+	}
+
+	/// Append the pending messages to the file.
+	///
+	/// The pending messages are discarded even if this fails.
+	pub(crate) fn persist(&mut self) -> io::Result<()> {
+		if self.pending.is_empty() {
+			return Ok(());
+		}
+
+		let mut lines = Vec::new();
+		for (spilled_at, message) in self.pending.drain(..) {
+			lines.extend(spilled_at.to_string().into_bytes());
+			lines.push(b' ');
+			lines.extend(message.as_bytes());
+			lines.push(b'\n');
+		}
+
+		if let Some(dir) = self.path.parent() {
+			fs::create_dir_all(dir)?;
+		}
+		let mut file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&self.path)?;
+		file.write_all(&lines)?;
+		file.sync_data()
+	}
+
+	/// Take every spilled message that is not older than the maximum age, oldest first, and
+	/// remove the file.
+	pub(crate) fn take(&mut self) -> io::Result<Vec<Arc<str>>> {
+		let content = match fs::read(&self.path) {
+			Ok(content) => content,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+			Err(err) => return Err(err),
+		};
+		let mut spilled = String::from_utf8_lossy(&content)
+			.lines()
+			.filter_map(|line| {
+				let mut fields = line.splitn(2, ' ');
+				let spilled_at = fields.next()?.parse().ok()?;
+				Some((spilled_at, Arc::from(fields.next()?)))
+			})
+			.collect::<Vec<_>>();
+		spilled.append(&mut self.pending);
+
+		if !content.is_empty() {
+			fs::remove_file(&self.path)?;
+		}
+
+		let oldest = now_millis().saturating_sub(self.max_age.as_millis() as u64);
+		Ok(spilled
+			.into_iter()
+			.filter(|(spilled_at, _)| *spilled_at >= oldest)
+			.map(|(_, message)| message)
+			.collect())
+	}
+}
+
+/// Name of the file of the endpoint `addr`.
+fn file_name(addr: &Multiaddr) -> String {
+	let name = addr
+		.to_string()
+		.chars()
+		.map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+		.collect::<String>();
+	format!("{}.jsonl", name)
+}
+
+fn now_millis() -> u64 {
+	// `SystemTime::now` panics in the browser.
+	chrono::Utc::now().timestamp_millis().max(0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn expired_messages_are_not_replayed() {
+		let dir = std::env::temp_dir().join(format!("sc-telemetry-spill-{}", std::process::id()));
+		let addr: Multiaddr = "/dns/telemetry.polkadot.io/tcp/443/x-parity-wss/%2Fsubmit%2F"
+			.parse()
+			.unwrap();
+		let mut spill = Spill::new(&dir, &addr, 2, Duration::from_secs(60));
+		assert_eq!(
+			spill.path.file_name().unwrap(),
+			"_dns_telemetry.polkadot.io_tcp_443_x-parity-wss__2Fsubmit_2F.jsonl",
+		);
+
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(&spill.path, b"0 {\"msg\":\"expired\"}\ngarbage\n").unwrap();
+		assert!(!spill.push(r#"{"msg":"0"}"#.into()));
+		assert!(spill.push(r#"{"msg":"1"}"#.into()));
+		spill.persist().unwrap();
+		assert_eq!(spill.pending(), 0);
+		spill.push(r#"{"msg":"2"}"#.into());
+
+		let messages = spill.take().unwrap();
+		assert_eq!(
+			messages.iter().map(|message| &**message).collect::<Vec<_>>(),
+			vec![r#"{"msg":"0"}"#, r#"{"msg":"1"}"#, r#"{"msg":"2"}"#],
+		);
+		assert!(!spill.path.exists());
+		assert!(spill.take().unwrap().is_empty());
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
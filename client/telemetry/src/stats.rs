@@ -0,0 +1,190 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::{
+	atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+	Arc,
+};
+use std::time::Duration;
+
+/// Upper bounds of the buckets of the [`ProcessingTimes`], in microseconds. The times above the
+/// last bound are counted in an extra bucket.
+const PROCESSING_TIME_BOUNDS: [u64; 8] =
+	[100, 1_000, 10_000, 50_000, 100_000, 200_000, 500_000, 1_000_000];
+
+/// Statistics about the telemetry messages, see
+/// [`TelemetryHandle::stats`](crate::TelemetryHandle::stats).
+///
+/// A message sent to several telemetry servers counts once per server, except in
+/// `messages_queued`, `messages_queued_priority`, `messages_dropped_buffer` and
+/// `messages_dropped_rate_limit`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TelemetryStats {
+	/// Messages written to the connection of a telemetry server, including the connection
+	/// messages.
+	pub messages_sent: u64,
+	/// Messages queued by the [`TelemetryLayer`](crate::TelemetryLayer) for the
+	/// [`TelemetryWorker`](crate::TelemetryWorker) in the main buffer.
+	pub messages_queued: u64,
+	/// Messages queued by the [`TelemetryLayer`](crate::TelemetryLayer) in the priority buffer
+	/// because the main buffer was full, see
+	/// [`TelemetryConfig::priority_buffer_size`](crate::TelemetryConfig).
+	pub messages_queued_priority: u64,
+	/// Messages dropped by the [`TelemetryLayer`](crate::TelemetryLayer) because the buffer to
+	/// the [`TelemetryWorker`](crate::TelemetryWorker) was full.
+	pub messages_dropped_buffer: u64,
+	/// Messages dropped because the queue of a telemetry server was full.
+	pub messages_dropped_queue: u64,
+	/// Messages dropped because a telemetry server was not connected.
+	pub messages_dropped_disconnected: u64,
+	/// Messages dropped because their telemetry span exceeded its rate limit, see
+	/// [`TelemetryConfig::max_messages_per_second`](crate::TelemetryConfig).
+	pub messages_dropped_rate_limit: u64,
+	/// Messages dropped because they had been queued for a telemetry server for longer than
+	/// [`TelemetryConfig::max_message_age`](crate::TelemetryConfig).
+	pub messages_dropped_expired: u64,
+	/// Approximate number of bytes currently held in the buffers of the
+	/// [`TelemetryWorker`](crate::TelemetryWorker), see
+	/// [`TelemetryConfig::max_buffered_bytes`](crate::TelemetryConfig).
+	pub buffered_bytes: u64,
+}
+
+/// Histogram of the time taken by the [`TelemetryWorker`](crate::TelemetryWorker) to dispatch
+/// each telemetry message to the telemetry servers and the sinks, see
+/// [`TelemetryHandle::processing_times`](crate::TelemetryHandle::processing_times).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProcessingTimes {
+	counts: [u64; PROCESSING_TIME_BOUNDS.len() + 1],
+}
+
+impl ProcessingTimes {
+	/// Number of messages per bucket along with the upper bound of the bucket, from the fastest
+	/// to the slowest. The last bucket has no upper bound.
+	pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+		PROCESSING_TIME_BOUNDS
+			.iter()
+			.map(|bound| Some(Duration::from_micros(*bound)))
+			.chain(std::iter::once(None))
+			.zip(self.counts.iter().copied())
+	}
+
+	/// Number of messages dispatched.
+	pub fn count(&self) -> u64 {
+		self.counts.iter().sum()
+	}
+}
+
+/// Counters of the [`TelemetryStats`], shared by the layer, the worker and the nodes.
+#[derive(Debug, Default)]
+pub(crate) struct StatsCounters {
+	pub(crate) messages_sent: AtomicU64,
+	pub(crate) messages_queued: AtomicU64,
+	pub(crate) messages_queued_priority: AtomicU64,
+	pub(crate) messages_dropped_buffer: AtomicU64,
+	pub(crate) messages_dropped_queue: AtomicU64,
+	pub(crate) messages_dropped_disconnected: AtomicU64,
+	pub(crate) messages_dropped_rate_limit: AtomicU64,
+	pub(crate) messages_dropped_expired: AtomicU64,
+	pub(crate) buffered_bytes: AtomicUsize,
+	processing_times: [AtomicU64; PROCESSING_TIME_BOUNDS.len() + 1],
+	connected_endpoints: AtomicUsize,
+	worker_running: AtomicBool,
+}
+
+impl StatsCounters {
+	/// Read the current value of the counters.
+	pub(crate) fn snapshot(&self) -> TelemetryStats {
+		TelemetryStats {
+			messages_sent: self.messages_sent.load(Ordering::Relaxed),
+			messages_queued: self.messages_queued.load(Ordering::Relaxed),
+			messages_queued_priority: self.messages_queued_priority.load(Ordering::Relaxed),
+			messages_dropped_buffer: self.messages_dropped_buffer.load(Ordering::Relaxed),
+			messages_dropped_queue: self.messages_dropped_queue.load(Ordering::Relaxed),
+			messages_dropped_disconnected: self
+				.messages_dropped_disconnected
+				.load(Ordering::Relaxed),
+			messages_dropped_rate_limit: self.messages_dropped_rate_limit.load(Ordering::Relaxed),
+			messages_dropped_expired: self.messages_dropped_expired.load(Ordering::Relaxed),
+			buffered_bytes: self.buffered_bytes.load(Ordering::Relaxed) as u64,
+		}
+	}
+
+	/// Count a message dispatched in `elapsed` in the [`ProcessingTimes`].
+	pub(crate) fn record_processing_time(&self, elapsed: Duration) {
+		let micros = elapsed.as_micros();
+		let bucket = PROCESSING_TIME_BOUNDS
+			.iter()
+			.position(|bound| micros <= u128::from(*bound))
+			.unwrap_or(PROCESSING_TIME_BOUNDS.len());
+		self.processing_times[bucket].fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Read the current [`ProcessingTimes`].
+	pub(crate) fn processing_times(&self) -> ProcessingTimes {
+		let mut times = ProcessingTimes::default();
+		for (count, counter) in times.counts.iter_mut().zip(&self.processing_times) {
+			*count = counter.load(Ordering::Relaxed);
+		}
+		times
+	}
+
+	/// Number of telemetry servers currently connected.
+	pub(crate) fn connected_endpoints(&self) -> usize {
+		self.connected_endpoints.load(Ordering::Relaxed)
+	}
+
+	/// Whether the future of the worker is alive.
+	pub(crate) fn worker_running(&self) -> bool {
+		self.worker_running.load(Ordering::Relaxed)
+	}
+}
+
+/// Counts a connection to a telemetry server in the [`StatsCounters`] for as long as it is
+/// alive.
+#[derive(Debug)]
+pub(crate) struct ConnectedEndpoint(Arc<StatsCounters>);
+
+impl ConnectedEndpoint {
+	pub(crate) fn new(stats: Arc<StatsCounters>) -> Self {
+		stats.connected_endpoints.fetch_add(1, Ordering::Relaxed);
+		ConnectedEndpoint(stats)
+	}
+}
+
+impl Drop for ConnectedEndpoint {
+	fn drop(&mut self) {
+		self.0.connected_endpoints.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+/// Marks the worker as running in the [`StatsCounters`] for as long as it is alive.
+#[derive(Debug)]
+pub(crate) struct RunningWorker(Arc<StatsCounters>);
+
+impl RunningWorker {
+	pub(crate) fn new(stats: Arc<StatsCounters>) -> Self {
+		stats.worker_running.store(true, Ordering::Relaxed);
+		RunningWorker(stats)
+	}
+}
+
+impl Drop for RunningWorker {
+	fn drop(&mut self) {
+		self.0.worker_running.store(false, Ordering::Relaxed);
+	}
+}
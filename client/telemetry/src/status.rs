@@ -0,0 +1,78 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use libp2p::Multiaddr;
+use tracing::Id;
+use wasm_timer::Instant;
+
+/// State of the connection to a telemetry server, see
+/// [`TelemetryHandle::endpoint_status`](crate::TelemetryHandle::endpoint_status).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointStatus {
+	/// Address of the telemetry server.
+	pub addr: Multiaddr,
+	/// Whether the connection is currently established.
+	pub connected: bool,
+	/// When the last connection has been established, if any.
+	pub last_connected: Option<Instant>,
+	/// When a message has last been written to the connection, if any.
+	pub last_sent: Option<Instant>,
+	/// Number of connections established after the first one.
+	pub reconnects: u64,
+	/// Number of connection attempts that have failed since the last connection has been
+	/// established. The delay before the next attempt doubles with each of them.
+	pub failed_attempts: u32,
+	/// State of the connection.
+	pub state: ConnectionState,
+	/// Number of messages written to the connections, including the connection messages.
+	pub messages_sent: u64,
+	/// Number of messages that have not been sent to the telemetry server, because it was not
+	/// connected, because its queue was full or because they were too old.
+	pub messages_dropped: u64,
+}
+
+/// State of the connection to a telemetry server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+	/// The connection is established.
+	Connected {
+		/// When the connection has been established.
+		since: Instant,
+	},
+	/// The connection is being established.
+	Connecting,
+	/// The last connection attempt has failed or the connection has been lost, and the next
+	/// attempt starts at `until`.
+	Retrying {
+		/// When the next connection attempt starts.
+		until: Instant,
+	},
+	/// The endpoint has been disabled.
+	Disabled,
+}
+
+/// Endpoints of a telemetry span, see
+/// [`TelemetryHandle::status`](crate::TelemetryHandle::status).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TelemetryStatusEntry {
+	/// Id of the telemetry span.
+	pub id: Id,
+	/// Every endpoint of the span, along with the maximum verbosity of the messages of the span
+	/// that it receives.
+	pub endpoints: Vec<(u8, EndpointStatus)>,
+}
@@ -16,39 +16,60 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::{EndpointKind, FileTransport, LogTransport, TelemetryConfig, TelemetryEndpoint};
+#[cfg(not(target_os = "unknown"))]
+use crate::{tcp_pins, PinnedTlsTransport, PinnedWsTransport, ProxyTransport, TelemetryProxy};
 use futures::{
 	prelude::*,
 	ready,
 	task::{Context, Poll},
 };
-use libp2p::{
-	core::transport::{timeout::TransportTimeout, OptionalTransport},
-	wasm_ext, Transport,
-};
+use libp2p::{core::transport::OptionalTransport, multiaddr::Protocol, wasm_ext, Transport};
 use std::io;
 use std::pin::Pin;
-use std::time::Duration;
-
-/// Timeout after which a connection attempt is considered failed. Includes the WebSocket HTTP
-/// upgrading.
-const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
 
-pub(crate) fn initialize_transport(
+/// Build the transport used by default to connect to the telemetry servers, according to
+/// `config`.
+///
+/// It dials the telemetry servers with `wasm_external_transport` if any, and with
+/// DNS+TCP+WebSocket outside of the browser. See [`TelemetryLayer::with_transport`] to use another
+/// transport.
+///
+/// The `file://` and `log:` endpoints don't use this transport, see [`EndpointKind`].
+///
+/// [`TelemetryLayer::with_transport`]: crate::TelemetryLayer::with_transport
+pub fn initialize_transport(
 	wasm_external_transport: Option<wasm_ext::ExtTransport>,
-) -> Result<WsTrans, io::Error> {
+	config: &TelemetryConfig,
+) -> Result<TelemetryTransport, io::Error> {
 	let transport = match wasm_external_transport.clone() {
 		Some(t) => OptionalTransport::some(t),
 		None => OptionalTransport::none(),
 	}
 	.map((|inner, _| StreamSink::from(inner)) as fn(_, _) -> _);
 
+	// In tests, the telemetry servers can be in-process and listen on `/memory/<n>` addresses.
+	#[cfg(feature = "test-helpers")]
+	let transport = transport.or_transport(
+		libp2p::core::transport::MemoryTransport
+			.map((|inner, _| StreamSink::from(inner)) as fn(_, _) -> _),
+	);
+
 	// The main transport is the `wasm_external_transport`, but if we're on desktop we add
 	// support for TCP+WebSocket+DNS as a fallback. In practice, you're not expected to pass
 	// an external transport on desktop and the fallback is used all the time.
 	#[cfg(not(target_os = "unknown"))]
 	let transport = transport.or_transport({
 		let inner = libp2p::dns::DnsConfig::new(libp2p::tcp::TcpConfig::new())?;
-		libp2p::websocket::framed::WsConfig::new(inner).and_then(|connec, _| {
+		let proxy = TelemetryProxy::from_env()?.or_else(|| config.proxy.clone());
+		let inner = ProxyTransport::new(inner, proxy);
+		// The TLS sessions with the pinned servers are established by `PinnedTlsTransport`, and
+		// `PinnedWsTransport` makes the WebSocket transport use them as plain connections.
+		let pins = tcp_pins(&config.certificate_pins);
+		let inner = PinnedTlsTransport::new(inner, pins.clone());
+		let mut ws = libp2p::websocket::framed::WsConfig::new(inner);
+		ws.use_deflate(config.websocket_deflate);
+		PinnedWsTransport::new(ws, pins).and_then(|connec, _| {
 			let connec = connec
 				.with(|item| {
 					let item = libp2p::websocket::framed::OutgoingData::Binary(item);
@@ -60,24 +81,72 @@ pub(crate) fn initialize_transport(
 		})
 	});
 
-	Ok(TransportTimeout::new(
-		transport.map(|out, _| {
+	// The connection attempts are abandoned by the nodes, after the connect timeout of their
+	// endpoint.
+	Ok(transport
+		.map(|out, _| {
 			let out = out
 				.map_err(|err| io::Error::new(io::ErrorKind::Other, err))
 				.sink_map_err(|err| io::Error::new(io::ErrorKind::Other, err));
 			Box::pin(out) as Pin<Box<_>>
-		}),
-		CONNECT_TIMEOUT,
-	)
-	.boxed())
+		})
+		.boxed())
+}
+
+/// Build the transport of the endpoints of `kind`, or return `None` for the telemetry servers,
+/// which use the transport of the worker.
+pub(crate) fn endpoint_transport(
+	kind: &EndpointKind,
+	config: &TelemetryConfig,
+) -> Option<TelemetryTransport> {
+	match kind {
+		EndpointKind::Server => None,
+		EndpointKind::File(path) => Some(
+			FileTransport::new(path.clone(), config.file_sync_interval)
+				.map(|out, _| Box::pin(out) as Pin<Box<_>>)
+				.boxed(),
+		),
+		EndpointKind::Log => Some(
+			LogTransport
+				.map(|out, _| Box::pin(out) as Pin<Box<_>>)
+				.boxed(),
+		),
+	}
+}
+
+/// Return why `endpoint` can't be connected to, if that is known before dialing.
+pub(crate) fn unsupported_reason(
+	endpoint: &TelemetryEndpoint,
+	config: &TelemetryConfig,
+) -> Option<&'static str> {
+	let addr = endpoint.addr();
+	let is_ip = matches!(
+		addr.iter().next(),
+		Some(Protocol::Ip4(_)) | Some(Protocol::Ip6(_))
+	);
+	let is_wss = addr.iter().any(|protocol| matches!(protocol, Protocol::Wss(_)));
+
+	let is_file = matches!(endpoint.kind(), EndpointKind::File(_));
+
+	if cfg!(target_os = "unknown") && is_file {
+		Some("files are not supported in the browser")
+	} else if is_ip && is_wss && !config.certificate_pins.contains_key(addr) {
+		// The certificate of a pinned server is not checked against its name.
+		Some("secure WebSockets need a DNS name to verify the certificate of the server")
+	} else {
+		None
+	}
 }
 
 /// A trait that implements `Stream` and `Sink`.
-pub(crate) trait StreamAndSink<I>: Stream + Sink<I> {}
+pub trait StreamAndSink<I>: Stream + Sink<I> {}
 impl<T: ?Sized + Stream + Sink<I>, I> StreamAndSink<I> for T {}
 
-/// A type alias for the WebSocket transport.
-pub(crate) type WsTrans = libp2p::core::transport::Boxed<
+/// Transport used to connect to the telemetry servers.
+///
+/// Every item sent to a connection is a telemetry message, which must reach the telemetry server
+/// as a whole, e.g. as a single WebSocket frame.
+pub type TelemetryTransport = libp2p::core::transport::Boxed<
 	Pin<
 		Box<
 			dyn StreamAndSink<Vec<u8>, Item = Result<Vec<u8>, io::Error>, Error = io::Error> + Send,
@@ -85,6 +154,29 @@ pub(crate) type WsTrans = libp2p::core::transport::Boxed<
 	>,
 >;
 
+/// A type alias for the WebSocket transport.
+pub(crate) type WsTrans = TelemetryTransport;
+
+/// Turn a transport whose connections are streams of bytes into a [`TelemetryTransport`], where
+/// each telemetry message is written to the connection with a single call to `write`.
+///
+/// This allows to use e.g. a `MemoryTransport` with [`TelemetryLayer::with_transport`].
+///
+/// [`TelemetryLayer::with_transport`]: crate::TelemetryLayer::with_transport
+pub fn boxed_transport<T>(transport: T) -> TelemetryTransport
+where
+	T: Transport + Clone + Send + Sync + 'static,
+	T::Output: AsyncRead + AsyncWrite + Send + 'static,
+	T::Error: Send + Sync,
+	T::Dial: Send + 'static,
+	T::Listener: Send + 'static,
+	T::ListenerUpgrade: Send + 'static,
+{
+	transport
+		.map(|connection, _| Box::pin(StreamSink::from(connection)) as Pin<Box<_>>)
+		.boxed()
+}
+
 /// Wraps around an `AsyncWrite` and implements `Sink`. Guarantees that each item being sent maps
 /// to one call of `write`.
 ///
@@ -161,3 +253,263 @@ impl<T: AsyncWrite> Sink<Vec<u8>> for StreamSink<T> {
 		AsyncWrite::poll_close(this.0, cx)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use libp2p::core::transport::ListenerEvent;
+	use crate::CertificatePin;
+	use libp2p::Multiaddr;
+	use libp2p::websocket::{framed::WsConfig, tls};
+	use std::sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	};
+
+	/// Wraps around a socket and counts the bytes read from it.
+	struct CountingReads<T> {
+		inner: T,
+		read: Arc<AtomicUsize>,
+	}
+
+	impl<T: AsyncRead + Unpin> AsyncRead for CountingReads<T> {
+		fn poll_read(
+			mut self: Pin<&mut Self>,
+			cx: &mut Context,
+			buf: &mut [u8],
+		) -> Poll<io::Result<usize>> {
+			let n = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+			self.read.fetch_add(n, Ordering::Relaxed);
+			Poll::Ready(Ok(n))
+		}
+	}
+
+	impl<T: AsyncWrite + Unpin> AsyncWrite for CountingReads<T> {
+		fn poll_write(
+			mut self: Pin<&mut Self>,
+			cx: &mut Context,
+			buf: &[u8],
+		) -> Poll<io::Result<usize>> {
+			Pin::new(&mut self.inner).poll_write(cx, buf)
+		}
+
+		fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+			Pin::new(&mut self.inner).poll_flush(cx)
+		}
+
+		fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+			Pin::new(&mut self.inner).poll_close(cx)
+		}
+	}
+
+	/// Send `messages` to a local WebSocket server and return the number of bytes it received.
+	fn bytes_received(websocket_deflate: bool, messages: &[Vec<u8>]) -> usize {
+		let read = Arc::new(AtomicUsize::new(0));
+		let tcp = {
+			let read = read.clone();
+			libp2p::tcp::TcpConfig::new().map(move |inner, _| CountingReads {
+				inner,
+				read: read.clone(),
+			})
+		};
+		let mut server = WsConfig::new(tcp);
+		server.use_deflate(websocket_deflate);
+		let mut listener = server
+			.listen_on("/ip4/127.0.0.1/tcp/0/ws".parse().unwrap())
+			.unwrap();
+
+		let config = TelemetryConfig {
+			websocket_deflate,
+			..Default::default()
+		};
+		let transport = initialize_transport(None, &config).unwrap();
+
+		futures::executor::block_on(async move {
+			let addr = match listener.next().await {
+				Some(Ok(ListenerEvent::NewAddress(addr))) => addr,
+				_ => panic!("the listener reports its address first"),
+			};
+
+			let client = async {
+				let mut sink = transport.dial(addr).unwrap().await.unwrap();
+				for message in messages {
+					sink.send(message.clone()).await.unwrap();
+				}
+				sink
+			};
+			let server = async {
+				let mut connection = loop {
+					if let Some(Ok(ListenerEvent::Upgrade { upgrade, .. })) = listener.next().await {
+						break upgrade.await.unwrap();
+					}
+				};
+				for message in messages {
+					let data = connection.next().await.unwrap().unwrap();
+					assert_eq!(&data.into_bytes(), message);
+				}
+			};
+			let (_sink, ()) = future::join(client, server).await;
+		});
+
+		read.load(Ordering::Relaxed)
+	}
+
+	#[test]
+	fn websocket_deflate_reduces_bandwidth() {
+		let messages = (0..100)
+			.map(|i| {
+				format!(
+					r#"{{"id":1,"payload":{{"msg":"system.interval","peers":{},"height":{},"best":"0x{:064x}","txcount":0,"bandwidth_download":1024,"bandwidth_upload":2048}},"ts":"2021-02-01T12:00:{:02}.000000+00:00"}}"#,
+					i % 25,
+					1000 + i,
+					i,
+					i % 60,
+				)
+				.into_bytes()
+			})
+			.collect::<Vec<_>>();
+
+		// Each message is compressed independently. With these messages, the server receives
+		// about 25.6 KiB uncompressed and about 15.2 KiB compressed, i.e. about 40% less.
+		let uncompressed = bytes_received(false, &messages);
+		let compressed = bytes_received(true, &messages);
+		assert!(
+			compressed * 4 < uncompressed * 3,
+			"uncompressed: {} bytes, compressed: {} bytes",
+			uncompressed,
+			compressed,
+		);
+	}
+
+	#[test]
+	fn ipv6_loopback_connection() {
+		let mut server = WsConfig::new(libp2p::tcp::TcpConfig::new())
+			.listen_on("/ip6/::1/tcp/0/ws".parse().unwrap())
+			.unwrap();
+		let transport = initialize_transport(None, &Default::default()).unwrap();
+
+		futures::executor::block_on(async move {
+			let addr = match server.next().await {
+				Some(Ok(ListenerEvent::NewAddress(addr))) => addr,
+				_ => panic!("the listener reports its address first"),
+			};
+			assert!(matches!(addr.iter().next(), Some(Protocol::Ip6(_))));
+
+			let client = async {
+				let mut sink = transport.dial(addr).unwrap().await.unwrap();
+				sink.send(b"hello".to_vec()).await.unwrap();
+				sink
+			};
+			let server = async {
+				let mut connection = loop {
+					if let Some(Ok(ListenerEvent::Upgrade { upgrade, .. })) = server.next().await {
+						break upgrade.await.unwrap();
+					}
+				};
+				connection.next().await.unwrap().unwrap().into_bytes()
+			};
+			let (_sink, received) = future::join(client, server).await;
+			assert_eq!(received, b"hello".to_vec());
+		});
+	}
+
+	#[test]
+	fn secure_websockets_to_ip_addresses_are_reported() {
+		let config = TelemetryConfig::default();
+		for addr in &[
+			"/ip6/2001:db8::1/tcp/443/wss",
+			"/ip4/80.123.90.4/tcp/443/x-parity-wss/%2Fsubmit%2F",
+		] {
+			let endpoint = TelemetryEndpoint::new(addr.parse().unwrap(), 0);
+			assert!(unsupported_reason(&endpoint, &config).is_some());
+		}
+
+		for addr in &[
+			"/ip6/2001:db8::1/tcp/80/ws",
+			"/dns6/telemetry.example.com/tcp/443/wss",
+			"/dns/telemetry.polkadot.io/tcp/443/x-parity-wss/%2Fsubmit%2F",
+		] {
+			let endpoint = TelemetryEndpoint::new(addr.parse().unwrap(), 0);
+			assert!(unsupported_reason(&endpoint, &config).is_none());
+		}
+
+		let addr: Multiaddr = "/ip4/80.123.90.4/tcp/443/wss".parse().unwrap();
+		let mut config = TelemetryConfig::default();
+		config.certificate_pins.insert(addr.clone(), CertificatePin::from_der(&[]));
+		let endpoint = TelemetryEndpoint::new(addr.clone(), 0);
+		assert!(unsupported_reason(&endpoint, &config).is_none());
+	}
+
+	/// Send a message to a local secure WebSocket server using the test certificate, with `pin`
+	/// as the pinned certificate, and return what the server received.
+	fn send_to_pinned_server(pin: Option<CertificatePin>) -> Result<Vec<u8>, String> {
+		let certificate = include_bytes!("../res/test-certificate.der").to_vec();
+		let key = include_bytes!("../res/test-private-key.der").to_vec();
+		let tls_config = tls::Config::new(
+			tls::PrivateKey::new(key),
+			vec![tls::Certificate::new(certificate)],
+		)
+		.unwrap();
+		let mut server = WsConfig::new(libp2p::tcp::TcpConfig::new());
+		server.set_tls_config(tls_config);
+		let mut listener = server
+			.listen_on("/ip4/127.0.0.1/tcp/0/wss".parse().unwrap())
+			.unwrap();
+
+		futures::executor::block_on(async move {
+			let addr = match listener.next().await {
+				Some(Ok(ListenerEvent::NewAddress(addr))) => addr,
+				_ => panic!("the listener reports its address first"),
+			};
+			let mut config = TelemetryConfig::default();
+			config.certificate_pins.extend(pin.map(|pin| (addr.clone(), pin)));
+			let transport = initialize_transport(None, &config).unwrap();
+
+			let client = async {
+				let dial = transport.dial(addr).map_err(|err| err.to_string())?;
+				let mut sink = dial.await.map_err(|err| err.to_string())?;
+				sink.send(b"hello".to_vec())
+					.await
+					.map_err(|err| err.to_string())?;
+				Ok::<_, String>(sink)
+			};
+			let server = async {
+				let upgrade = loop {
+					if let Some(Ok(ListenerEvent::Upgrade { upgrade, .. })) = listener.next().await {
+						break upgrade;
+					}
+				};
+				match upgrade.await {
+					Ok(mut connection) => Some(connection.next().await?.ok()?.into_bytes()),
+					Err(_) => None,
+				}
+			};
+
+			// The server never sees a connection if the client fails before connecting.
+			match future::select(client.boxed(), server.boxed()).await {
+				future::Either::Left((Ok(_sink), server)) => {
+					Ok(server.await.expect("the client has sent a message"))
+				}
+				future::Either::Left((Err(err), _)) => Err(err),
+				future::Either::Right((received, client)) => {
+					let _sink = client.await?;
+					Ok(received.expect("the client has sent a message"))
+				}
+			}
+		})
+	}
+
+	#[test]
+	fn pinned_certificates() {
+		let certificate = include_bytes!("../res/test-certificate.der");
+
+		let received = send_to_pinned_server(Some(CertificatePin::from_der(certificate)));
+		assert_eq!(received, Ok(b"hello".to_vec()));
+
+		let err = send_to_pinned_server(Some(CertificatePin::from_der(b"wrong"))).unwrap_err();
+		assert!(err.contains("certificate pin mismatch"), "{}", err);
+
+		// Without a pin, the certificate can't be checked against an IP address.
+		assert!(send_to_pinned_server(None).is_err());
+	}
+}
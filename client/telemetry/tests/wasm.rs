@@ -0,0 +1,83 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Telemetry in the browser, with the [`ExtTransport`] of the embedder.
+//!
+//! # Running
+//! Running this test can be done with
+//! ```text
+//! wasm-pack test --firefox --headless client/telemetry -- --test wasm
+//! ```
+//! The unit tests of the crate need the native transport and are not run in the browser.
+
+#![cfg(target_arch = "wasm32")]
+
+use futures::FutureExt;
+use libp2p::wasm_ext::ffi;
+use sc_telemetry::{
+	telemetry, ConnectionMessage, ExtTransport, TelemetryEndpoints, TelemetryLayer,
+	TelemetrySpan, SUBSTRATE_INFO,
+};
+use std::time::Duration;
+use tracing_subscriber::prelude::*;
+use wasm_bindgen_test::{wasm_bindgen_test, wasm_bindgen_test_configure};
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+fn connection_message() -> ConnectionMessage {
+	ConnectionMessage {
+		name: "browser".into(),
+		implementation: "browser".into(),
+		version: "0.1.0".into(),
+		config: "".into(),
+		chain: "test".into(),
+		genesis_hash: "0x00".into(),
+		authority: false,
+		startup_time: "0".into(),
+		network_id: "browser".into(),
+	}
+}
+
+#[wasm_bindgen_test]
+async fn messages_go_through_the_worker_in_the_browser() {
+	let transport = ExtTransport::new(ffi::websocket_transport());
+	let (layer, worker) = TelemetryLayer::new(None, Some(transport)).unwrap();
+	let mut handle = worker.handle();
+	let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+	wasm_bindgen_futures::spawn_local(worker.run().map(|_| ()));
+
+	// Nothing listens on this port: the message is dispatched to the node of the endpoint, which
+	// holds it while it is not connected.
+	let endpoints =
+		TelemetryEndpoints::new(vec![("/dns/localhost/tcp/9/ws".into(), SUBSTRATE_INFO)]).unwrap();
+	tracing::dispatcher::with_default(&dispatch, || {
+		let span = TelemetrySpan::new();
+		handle.start_telemetry(span.clone(), endpoints, connection_message());
+		let _enter = span.enter();
+		telemetry!(SUBSTRATE_INFO; "test.message"; "height" => 1);
+	});
+	assert_eq!(handle.stats().messages_queued, 1);
+
+	for _ in 0..100 {
+		if handle.processing_times().count() == 1 {
+			return;
+		}
+		wasm_timer::Delay::new(Duration::from_millis(10)).await.unwrap();
+	}
+	panic!("the telemetry worker has not dispatched the message");
+}